@@ -101,8 +101,8 @@ where
                 Duration::default(),
             )?;
         }
-        Err(exit_code) => {
-            error!("failed to create service, exit code: {:?}", exit_code);
+        Err(err) => {
+            error!("failed to create service, error: {}", err);
 
             // Report running state
             set_service_status(
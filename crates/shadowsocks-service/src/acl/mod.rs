@@ -13,6 +13,7 @@ use std::{
     str,
 };
 
+use flate2::bufread::GzDecoder;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
 use iprange::IpRange;
 use log::{trace, warn};
@@ -21,10 +22,15 @@ use regex::bytes::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 
 use shadowsocks::{context::Context, relay::socks5::Address};
 
+use crate::net::utils::normalize_ip_addr;
+
 use self::sub_domains_tree::SubDomainsTree;
 
 mod sub_domains_tree;
 
+/// Magic bytes of gzip compressed files
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
 /// Strategy mode that ACL is running
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Mode {
@@ -115,27 +121,20 @@ impl Rules {
     }
 
     /// Check if the specified address matches any rules
+    ///
+    /// IPv4 mapped IPv6 addresses are normalized to IPv4 first, so they could be matched by both IPv4 rules
+    /// and IPv6 rules of the mapped range (`::ffff:0:0/96`).
     fn check_ip_matched(&self, addr: &IpAddr) -> bool {
-        match addr {
+        match normalize_ip_addr(*addr) {
             IpAddr::V4(v4) => {
-                if self.ipv4.contains(v4) {
+                if self.ipv4.contains(&v4) {
                     return true;
                 }
 
                 let mapped_ipv6 = v4.to_ipv6_mapped();
                 self.ipv6.contains(&mapped_ipv6)
             }
-            IpAddr::V6(v6) => {
-                if self.ipv6.contains(v6) {
-                    return true;
-                }
-
-                if let Some(mapped_ipv4) = v6.to_ipv4_mapped() {
-                    return self.ipv4.contains(&mapped_ipv4);
-                }
-
-                false
-            }
+            IpAddr::V6(v6) => self.ipv6.contains(&v6),
         }
     }
 
@@ -343,6 +342,8 @@ pub struct AccessControl {
 
 impl AccessControl {
     /// Load ACL rules from a file
+    ///
+    /// gzip compressed files (with `.gz` extension or gzip magic bytes) are decompressed transparently.
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AccessControl> {
         trace!("ACL loading from {:?}", p.as_ref());
 
@@ -350,7 +351,16 @@ impl AccessControl {
         let file_path = file_path_ref.to_path_buf();
 
         let fp = File::open(file_path_ref)?;
-        let r = BufReader::new(fp);
+        let mut fp = BufReader::new(fp);
+
+        let is_gzip =
+            file_path_ref.extension().is_some_and(|ext| ext == "gz") || fp.fill_buf()?.starts_with(GZIP_MAGIC);
+        let r: Box<dyn BufRead> = if is_gzip {
+            trace!("ACL {:?} is gzip compressed", file_path_ref);
+            Box::new(BufReader::new(GzDecoder::new(fp)))
+        } else {
+            Box::new(fp)
+        };
 
         let mut mode = Mode::BlackList;
 
@@ -362,7 +372,16 @@ impl AccessControl {
         trace!("ACL parsing start from mode {:?} and black_list / bypass_list", mode);
 
         for line in r.lines() {
-            let line = line?;
+            let line = line.map_err(|err| {
+                if is_gzip {
+                    Error::new(
+                        err.kind(),
+                        format!("failed to decompress ACL {:?}, {}", file_path_ref, err),
+                    )
+                } else {
+                    err
+                }
+            })?;
             if line.is_empty() {
                 continue;
             }
@@ -588,3 +607,88 @@ impl AccessControl {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, io::Write, process};
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const ACL_RULES: &str = "[proxy_all]\n[bypass_list]\n10.0.0.0/8\n||example.com\n";
+
+    #[test]
+    fn load_gzip_compressed_acl() {
+        let dir = env::temp_dir();
+        let plain_path = dir.join(format!("shadowsocks-acl-test-{}.acl", process::id()));
+        let gzip_path = dir.join(format!("shadowsocks-acl-test-{}.acl.gz", process::id()));
+        // Detected by magic bytes without the extension
+        let gzip_noext_path = dir.join(format!("shadowsocks-acl-test-{}.gzipped", process::id()));
+
+        fs::write(&plain_path, ACL_RULES).unwrap();
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(ACL_RULES.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        fs::write(&gzip_path, &compressed).unwrap();
+        fs::write(&gzip_noext_path, &compressed).unwrap();
+
+        let plain = AccessControl::load_from_file(&plain_path).unwrap();
+        for path in [&gzip_path, &gzip_noext_path] {
+            let acl = AccessControl::load_from_file(path).unwrap();
+
+            assert_eq!(acl.mode, plain.mode);
+            for host in ["example.com", "www.example.com", "example.org"] {
+                assert_eq!(acl.check_host_in_proxy_list(host), plain.check_host_in_proxy_list(host));
+            }
+            for ip in ["10.1.2.3", "8.8.8.8"] {
+                let ip = ip.parse::<IpAddr>().unwrap();
+                assert_eq!(acl.check_ip_in_proxy_list(&ip), plain.check_ip_in_proxy_list(&ip));
+            }
+        }
+        assert_eq!(plain.check_host_in_proxy_list("www.example.com"), Some(false));
+
+        // Corrupted gzip stream
+        fs::write(&gzip_path, &compressed[..compressed.len() / 2]).unwrap();
+        assert!(AccessControl::load_from_file(&gzip_path).is_err());
+
+        let _ = fs::remove_file(plain_path);
+        let _ = fs::remove_file(gzip_path);
+        let _ = fs::remove_file(gzip_noext_path);
+    }
+
+    #[test]
+    fn check_ipv4_mapped_ipv6() {
+        let path = env::temp_dir().join(format!("shadowsocks-acl-mapped-test-{}.acl", process::id()));
+        fs::write(
+            &path,
+            "[proxy_all]\n[bypass_list]\n10.0.0.0/8\n::ffff:192.168.0.0/112\n2001:db8::/32\n",
+        )
+        .unwrap();
+        let acl = AccessControl::load_from_file(&path).unwrap();
+        let _ = fs::remove_file(path);
+
+        let bypassed = |ip: &str| !acl.check_ip_in_proxy_list(&ip.parse::<IpAddr>().unwrap());
+
+        // IPv4 rule
+        assert!(bypassed("10.1.2.3"));
+        assert!(bypassed("::ffff:10.1.2.3"));
+        // IPv6 rule of the mapped range
+        assert!(bypassed("192.168.1.1"));
+        assert!(bypassed("::ffff:192.168.1.1"));
+        // IPv6 rule
+        assert!(bypassed("2001:db8::1"));
+
+        assert!(!bypassed("8.8.8.8"));
+        assert!(!bypassed("::ffff:8.8.8.8"));
+        assert!(!bypassed("2001:4860::8888"));
+
+        let mapped_client = "[::ffff:10.1.2.3]:1234".parse::<SocketAddr>().unwrap();
+        let client = "10.1.2.3:1234".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            acl.check_client_blocked(&mapped_client),
+            acl.check_client_blocked(&client)
+        );
+    }
+}
@@ -48,7 +48,7 @@ use std::{
     env,
     fmt::{self, Debug, Display, Formatter},
     fs::OpenOptions,
-    io::Read,
+    io::{self, Read},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     option::Option,
     path::{Path, PathBuf},
@@ -77,15 +77,15 @@ use shadowsocks::{
         ServerUserManager,
         ServerWeight,
     },
-    crypto::CipherKind,
+    crypto::{CipherCategory, CipherKind},
     plugin::PluginConfig,
 };
 
-use crate::acl::AccessControl;
 #[cfg(feature = "local-dns")]
 use crate::local::dns::NameServerAddr;
 #[cfg(feature = "local")]
 use crate::local::socks::config::Socks5AuthConfig;
+use crate::{acl::AccessControl, net::capture::DEFAULT_CAPTURE_MAX_SIZE};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
@@ -95,6 +95,14 @@ enum SSDnsConfig {
     HickoryDns(ResolverConfig),
 }
 
+/// `local_address` of the basic local, one listener is created for each address
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum SSLocalAddress {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct SSSecurityConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -105,6 +113,8 @@ struct SSSecurityConfig {
 struct SSSecurityReplayAttackConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     policy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_timestamp_diff: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -115,18 +125,40 @@ struct SSBalancerConfig {
     check_interval: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     check_best_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit_breaker_failures: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit_breaker_window: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit_breaker_cooldown: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_weight: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loss_weight: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jitter_weight: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strategy: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     server: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_port"
+    )]
     server_port: Option<u16>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    local_address: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    local_address: Option<SSLocalAddress>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_port"
+    )]
     local_port: Option<u16>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -134,7 +166,11 @@ struct SSConfig {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     manager_address: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_port"
+    )]
     manager_port: Option<u16>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -151,6 +187,9 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     plugin_mode: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback_methods: Option<Vec<String>>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     timeout: Option<u64>,
 
@@ -161,9 +200,57 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     udp_mtu: Option<usize>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relay_concurrency: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection_log_sample_rate: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_connect_jitter: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_connect_timeout: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_connect_concurrency: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_retry_budget: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_selected_server: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy_dns: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    liveness_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    liveness_interval: Option<u64>,
+
+    #[cfg(feature = "local-health-api")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_addr: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_log: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture_max_size: Option<u64>,
+
     #[serde(skip_serializing_if = "Option::is_none", alias = "shadowsocks")]
     servers: Option<Vec<SSServerExtConfig>>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_list: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_list_public_key: Option<String>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     locals: Option<Vec<SSLocalExtConfig>>,
 
@@ -176,7 +263,7 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     mode: Option<String>,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(skip_serializing_if = "Option::is_none", alias = "tcp_nodelay")]
     no_delay: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -189,6 +276,10 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     ipv6_first: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    dns_ipv4_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns_ipv6_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     ipv6_only: Option<bool>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -211,6 +302,15 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     outbound_bind_interface: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inbound_send_buffer_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inbound_recv_buffer_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_send_buffer_size: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outbound_recv_buffer_size: Option<u32>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     security: Option<SSSecurityConfig>,
 
@@ -225,7 +325,11 @@ struct SSConfig {
 struct SSLocalExtConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     local_address: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_port"
+    )]
     local_port: Option<u16>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -236,7 +340,11 @@ struct SSLocalExtConfig {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     local_udp_address: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_port"
+    )]
     local_udp_port: Option<u16>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -266,7 +374,11 @@ struct SSLocalExtConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     local_dns_address: Option<String>,
     #[cfg(feature = "local-dns")]
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_port"
+    )]
     local_dns_port: Option<u16>,
     /// Remote DNS's address
     ///
@@ -275,19 +387,33 @@ struct SSLocalExtConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     remote_dns_address: Option<String>,
     #[cfg(feature = "local-dns")]
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_port"
+    )]
     remote_dns_port: Option<u16>,
     #[cfg(feature = "local-dns")]
     #[serde(skip_serializing_if = "Option::is_none")]
     client_cache_size: Option<usize>,
+    #[cfg(feature = "local-dns")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    answer_cache_size: Option<usize>,
 
     /// Tunnel
     #[cfg(feature = "local-tunnel")]
     #[serde(skip_serializing_if = "Option::is_none")]
     forward_address: Option<String>,
     #[cfg(feature = "local-tunnel")]
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_optional_port"
+    )]
     forward_port: Option<u16>,
+    #[cfg(feature = "local-tunnel")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    forward_addresses: Option<Vec<String>>,
 
     /// Tun
     #[cfg(feature = "local-tun")]
@@ -299,6 +425,9 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local-tun")]
     #[serde(skip_serializing_if = "Option::is_none")]
     tun_interface_destination: Option<String>,
+    #[cfg(feature = "local-tun")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun_interface_mtu: Option<u16>,
     #[cfg(all(feature = "local-tun", unix))]
     #[serde(skip_serializing_if = "Option::is_none")]
     tun_device_fd_from_path: Option<String>,
@@ -307,6 +436,15 @@ struct SSLocalExtConfig {
     #[cfg(feature = "local")]
     #[serde(skip_serializing_if = "Option::is_none")]
     socks5_auth_config_path: Option<String>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks_greeting_timeout: Option<u64>,
+    #[cfg(feature = "local-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks_greeting_allow_http: Option<bool>,
+    #[cfg(feature = "local")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socks5_udp_associate_grace_period: Option<u64>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     acl: Option<String>,
@@ -318,6 +456,109 @@ struct SSServerUserConfig {
     password: String,
 }
 
+/// Out-of-band server list, referenced by `server_list`
+#[derive(Deserialize, Debug)]
+struct SSServerListConfig {
+    servers: Vec<SSServerExtConfig>,
+}
+
+/// Verify the detached ed25519 `signature` of `content`, both `signature` and `public_key` are base64 encoded
+#[cfg(feature = "server-list-signature")]
+fn verify_server_list_signature(content: &[u8], signature: &str, public_key: &str) -> Result<(), Error> {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    let public_key = STANDARD.decode(public_key.trim()).map_err(|err| {
+        Error::new(
+            ErrorKind::Invalid,
+            "invalid `server_list_public_key`, must be a base64 encoded ed25519 public key",
+            Some(err.to_string()),
+        )
+    })?;
+    let signature = STANDARD.decode(signature.trim()).map_err(|err| {
+        Error::new(
+            ErrorKind::Invalid,
+            "invalid server list signature, must be base64 encoded",
+            Some(err.to_string()),
+        )
+    })?;
+
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(content, &signature)
+        .map_err(|_| Error::new(ErrorKind::Invalid, "server list signature verification failed", None))
+}
+
+#[cfg(not(feature = "server-list-signature"))]
+fn verify_server_list_signature(_content: &[u8], _signature: &str, _public_key: &str) -> Result<(), Error> {
+    Err(Error::new(
+        ErrorKind::Invalid,
+        "`server_list_public_key` requires feature \"server-list-signature\"",
+        None,
+    ))
+}
+
+impl SSConfig {
+    /// Append servers of the `server_list` file
+    ///
+    /// If `server_list_public_key` is set, the file must carry a detached signature in `{server_list}.sig`,
+    /// which is verified before any of its servers are applied.
+    /// `${VAR_NAME}`s in the server list are replaced by environment variables if `expand_env` is set.
+    /// Relative paths are resolved against `config_dir`, the directory of the configuration file, if it has one
+    fn load_server_list(&mut self, expand_env: bool, config_dir: Option<&Path>) -> Result<(), Error> {
+        let path = match self.server_list.take() {
+            Some(p) => match config_dir {
+                Some(dir) if Path::new(&p).is_relative() => dir.join(p),
+                _ => PathBuf::from(p),
+            },
+            None => {
+                if self.server_list_public_key.is_some() {
+                    return Err(Error::new(
+                        ErrorKind::Malformed,
+                        "`server_list_public_key` is set without `server_list`",
+                        None,
+                    ));
+                }
+                return Ok(());
+            }
+        };
+
+        let content = std::fs::read(&path).map_err(|err| {
+            Error::new(
+                ErrorKind::IoError,
+                "failed to read `server_list`",
+                Some(format!("{}: {}", path.display(), err)),
+            )
+        })?;
+
+        if let Some(public_key) = self.server_list_public_key.take() {
+            let mut signature_path = path.clone().into_os_string();
+            signature_path.push(".sig");
+            let signature_path = PathBuf::from(signature_path);
+
+            let signature = std::fs::read_to_string(&signature_path).map_err(|err| {
+                Error::new(
+                    ErrorKind::Invalid,
+                    "server list is not signed",
+                    Some(format!("{}: {}", signature_path.display(), err)),
+                )
+            })?;
+            verify_server_list_signature(&content, &signature, &public_key)?;
+        }
+
+        let content = String::from_utf8(content)
+            .map_err(|_| Error::new(ErrorKind::JsonParsingError, "`server_list` is not valid UTF-8", None))?;
+        let content = if expand_env {
+            expand_env_variables(&content)?.into_owned()
+        } else {
+            content
+        };
+        let server_list = json5::from_str::<SSServerListConfig>(&content)?;
+        self.servers.get_or_insert_with(Vec::new).extend(server_list.servers);
+
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct SSServerExtConfig {
     // SIP008 https://github.com/shadowsocks/shadowsocks-org/issues/89
@@ -325,12 +566,14 @@ struct SSServerExtConfig {
     // `address` and `port` are non-standard field name only for shadowsocks-rust
     #[serde(alias = "address")]
     server: String,
-    #[serde(alias = "port")]
+    #[serde(alias = "port", deserialize_with = "deserialize_port")]
     server_port: u16,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     password: Option<String>,
     method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback_methods: Option<Vec<String>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     users: Option<Vec<SSServerUserConfig>>,
@@ -829,6 +1072,10 @@ impl FromStr for ProtocolType {
     }
 }
 
+/// Minimum MTU of tun interfaces, which is the minimum MTU of IPv4 (RFC 791)
+#[cfg(feature = "local-tun")]
+const MIN_TUN_INTERFACE_MTU: u16 = 576;
+
 /// Local server configuration
 #[derive(Clone, Debug)]
 pub struct LocalConfig {
@@ -849,6 +1096,11 @@ pub struct LocalConfig {
     /// Destination address for tunnel
     #[cfg(feature = "local-tunnel")]
     pub forward_addr: Option<Address>,
+    /// More destination addresses for tunnel
+    ///
+    /// Connections are forwarded to `forward_addr` and these addresses in round-robin order.
+    #[cfg(feature = "local-tunnel")]
+    pub forward_addrs: Vec<Address>,
 
     /// TCP Transparent Proxy type
     #[cfg(feature = "local-redir")]
@@ -872,6 +1124,12 @@ pub struct LocalConfig {
     // increase the size
     #[cfg(feature = "local-dns")]
     pub client_cache_size: Option<usize>,
+    /// Maximum number of answers cached by the DNS relay
+    ///
+    /// Cached answers are returned directly with the routing decision that was made when they were resolved,
+    /// negative answers (NXDOMAIN or NODATA) are cached by SOA's MINIMUM. `0` or `None` disables the cache.
+    #[cfg(feature = "local-dns")]
+    pub answer_cache_size: Option<usize>,
 
     /// Tun interface's name
     ///
@@ -885,6 +1143,14 @@ pub struct LocalConfig {
     /// Tun interface's destination address and netmask
     #[cfg(feature = "local-tun")]
     pub tun_interface_destination: Option<IpNet>,
+    /// Tun interface's MTU
+    ///
+    /// Packets read from the tun device are relayed as payloads of the tunnel, so it doesn't have to leave room for
+    /// shadowsocks' overhead. But it shouldn't be larger than the MTU of the path to the remote server's network, or
+    /// TCP's MSS derived from it would produce segments that stall on constrained links. Uses the device's MTU if not
+    /// set, or 1500 if it is unknown.
+    #[cfg(feature = "local-tun")]
+    pub tun_interface_mtu: Option<u16>,
     /// Tun interface's file descriptor
     #[cfg(all(feature = "local-tun", unix))]
     pub tun_device_fd: Option<std::os::unix::io::RawFd>,
@@ -935,6 +1201,25 @@ pub struct LocalConfig {
     /// SOCKS5 Authentication configuration
     #[cfg(feature = "local")]
     pub socks5_auth: Socks5AuthConfig,
+
+    /// SOCKS greeting timeout, enables probe resistance if set
+    ///
+    /// Clients have to send the SOCKS greeting within this duration, connections that timed out or sent
+    /// unexpected bytes will be closed silently without any response.
+    #[cfg(feature = "local")]
+    pub socks_greeting_timeout: Option<Duration>,
+
+    /// Serve HTTP requests on the SOCKS port even if `socks_greeting_timeout` is set
+    ///
+    /// HTTP requests are answered, so probers could tell that it is a proxy. Disabled by default.
+    #[cfg(feature = "local-http")]
+    pub socks_greeting_allow_http: bool,
+
+    /// Close SOCKS5 UDP associations after their UDP ASSOCIATE TCP connections have been closed for this duration
+    ///
+    /// Associations are closed as soon as their UDP ASSOCIATE TCP connections are closed if it is not set.
+    #[cfg(feature = "local")]
+    pub socks5_udp_associate_grace_period: Option<Duration>,
 }
 
 impl LocalConfig {
@@ -958,6 +1243,8 @@ impl LocalConfig {
 
             #[cfg(feature = "local-tunnel")]
             forward_addr: None,
+            #[cfg(feature = "local-tunnel")]
+            forward_addrs: Vec::new(),
 
             #[cfg(feature = "local-redir")]
             tcp_redir: RedirType::tcp_default(),
@@ -970,6 +1257,8 @@ impl LocalConfig {
             remote_dns_addr: None,
             #[cfg(feature = "local-dns")]
             client_cache_size: None,
+            #[cfg(feature = "local-dns")]
+            answer_cache_size: None,
 
             #[cfg(feature = "local-tun")]
             tun_interface_name: None,
@@ -977,6 +1266,8 @@ impl LocalConfig {
             tun_interface_address: None,
             #[cfg(feature = "local-tun")]
             tun_interface_destination: None,
+            #[cfg(feature = "local-tun")]
+            tun_interface_mtu: None,
             #[cfg(all(feature = "local-tun", unix))]
             tun_device_fd: None,
             #[cfg(all(feature = "local-tun", unix))]
@@ -991,6 +1282,12 @@ impl LocalConfig {
 
             #[cfg(feature = "local")]
             socks5_auth: Socks5AuthConfig::default(),
+            #[cfg(feature = "local")]
+            socks_greeting_timeout: None,
+            #[cfg(feature = "local-http")]
+            socks_greeting_allow_http: false,
+            #[cfg(feature = "local")]
+            socks5_udp_associate_grace_period: None,
         }
     }
 
@@ -1028,7 +1325,7 @@ impl LocalConfig {
             }
             #[cfg(feature = "local-tunnel")]
             ProtocolType::Tunnel => {
-                if self.forward_addr.is_none() {
+                if self.forward_addr.is_none() && self.forward_addrs.is_empty() {
                     let err = Error::new(ErrorKind::MissingField, "missing `forward_addr` in configuration", None);
                     return Err(err);
                 }
@@ -1055,7 +1352,7 @@ impl LocalConfig {
         }
 
         #[cfg(feature = "local-tunnel")]
-        if self.forward_addr.is_some() {
+        if self.forward_addr.is_some() || !self.forward_addrs.is_empty() {
             return false;
         }
 
@@ -1083,6 +1380,107 @@ pub enum DnsConfig {
     LocalDns(NameServerAddr),
 }
 
+/// Pre-defined well-known DNS servers, some of them may require extra features
+const PREDEFINED_DNS_SERVERS: &[&str] = &[
+    "system",
+    "google",
+    "google_tls",
+    "google_https",
+    "google_h3",
+    "cloudflare",
+    "cloudflare_tls",
+    "cloudflare_https",
+    "quad9",
+    "quad9_tls",
+    "quad9_https",
+];
+
+fn fallback_methods_to_strings(svr: &ServerConfig) -> Option<Vec<String>> {
+    let methods = svr.fallback_methods().map(|m| m.to_string()).collect::<Vec<_>>();
+    if methods.is_empty() {
+        None
+    } else {
+        Some(methods)
+    }
+}
+
+/// Parse `fallback_methods` of a server with `method`, both of them have to be AEAD methods
+fn parse_fallback_methods(method: CipherKind, fallback_methods: &[String]) -> Result<Vec<CipherKind>, Error> {
+    let mut methods = Vec::with_capacity(fallback_methods.len());
+    for m in fallback_methods {
+        let fallback_method = match m.parse::<CipherKind>() {
+            Ok(m) => m,
+            Err(..) => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "unsupported method in `fallback_methods`",
+                    Some(format!("`{}` is not a supported method", m)),
+                );
+                return Err(err);
+            }
+        };
+
+        if method.category() != CipherCategory::Aead || fallback_method.category() != CipherCategory::Aead {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`fallback_methods` could only be used with AEAD methods, AEAD-2022 and stream ciphers are not supported",
+                Some(format!("method {}, fallback method {}", method, fallback_method)),
+            );
+            return Err(err);
+        }
+
+        methods.push(fallback_method);
+    }
+    Ok(methods)
+}
+
+fn invalid_name_server_addr() -> Error {
+    Error::new(
+        ErrorKind::Invalid,
+        "invalid `dns` value, can only be [(tcp|udp)://]host[:port][,host[:port]]..., or unix:///path/to/dns, or predefined keys like \"google\", \"cloudflare\"",
+        None,
+    )
+}
+
+/// Parse a nameserver formatted as `host[:port]`, port is 53 by default
+fn parse_name_server_addr(addr: &str) -> Result<SocketAddr, Error> {
+    if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+        Ok(socket_addr)
+    } else if let Ok(ipaddr) = addr.parse::<IpAddr>() {
+        Ok(SocketAddr::new(ipaddr, 53))
+    } else {
+        Err(invalid_name_server_addr())
+    }
+}
+
+/// Validate `dns` value without building the resolver's configuration
+///
+/// 1. `[(unix|tcp|udp)://]host[:port][,host[:port]]...`
+/// 2. Pre-defined. Like `google`, `cloudflare`
+pub fn validate_name_server_addr(dns: &str) -> Result<(), Error> {
+    if PREDEFINED_DNS_SERVERS.contains(&dns) {
+        return Ok(());
+    }
+
+    if let Some(path) = dns.strip_prefix("unix://") {
+        return if path.is_empty() {
+            Err(invalid_name_server_addr())
+        } else {
+            Ok(())
+        };
+    }
+
+    let nameservers = dns
+        .strip_prefix("tcp://")
+        .or_else(|| dns.strip_prefix("udp://"))
+        .unwrap_or(dns);
+    for part in nameservers.split(',') {
+        parse_name_server_addr(part)?;
+    }
+
+    Ok(())
+}
+
 /// Security Config
 #[derive(Clone, Debug, Default)]
 pub struct SecurityConfig {
@@ -1092,6 +1490,10 @@ pub struct SecurityConfig {
 #[derive(Clone, Debug, Default)]
 pub struct SecurityReplayAttackConfig {
     pub policy: ReplayAttackPolicy,
+    /// Maximum difference between timestamps of AEAD-2022 requests and now, 30 seconds (the spec's window) by default
+    ///
+    /// Widen it for clients with unreliable clocks, salts are remembered for twice of it for rejecting replays.
+    pub max_timestamp_diff: Option<Duration>,
 }
 
 /// Balancer Config
@@ -1103,6 +1505,72 @@ pub struct BalancerConfig {
     pub check_interval: Option<Duration>,
     /// Interval for checking the best server
     pub check_best_interval: Option<Duration>,
+    /// Consecutive failures to open a server's circuit breaker, `None` or 0 for disabled
+    pub circuit_breaker_failures: Option<u32>,
+    /// Window that the consecutive failures have to be happened in
+    pub circuit_breaker_window: Option<Duration>,
+    /// Duration that an opened circuit breaker stays open before the server is tested again
+    pub circuit_breaker_cooldown: Option<Duration>,
+    /// Weight of latency in servers' scores, defaults to 1.0
+    pub latency_weight: Option<f64>,
+    /// Weight of packet loss (failed probes and connections) in servers' scores, defaults to 3.0
+    pub loss_weight: Option<f64>,
+    /// Weight of jitter (latency's standard deviation) in servers' scores, defaults to 1.0
+    pub jitter_weight: Option<f64>,
+    /// Strategy of choosing servers for TCP connections
+    pub strategy: BalancerStrategy,
+}
+
+/// Strategy of choosing servers for TCP connections
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BalancerStrategy {
+    /// Server with the best score, which is calculated from latency and failures
+    #[default]
+    Latency,
+    /// Server with the fewest active TCP connections, servers with better scores are preferred if equal
+    LeastConnections,
+    /// Random server among servers with similar scores, servers with higher `tcp_weight` are chosen more often
+    Weighted,
+}
+
+impl BalancerStrategy {
+    /// As string representation
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            BalancerStrategy::Latency => "latency",
+            BalancerStrategy::LeastConnections => "least_connections",
+            BalancerStrategy::Weighted => "weighted",
+        }
+    }
+}
+
+impl Display for BalancerStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error while parsing `BalancerStrategy` from string
+#[derive(Debug)]
+pub struct BalancerStrategyError;
+
+impl Display for BalancerStrategyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid BalancerStrategy")
+    }
+}
+
+impl FromStr for BalancerStrategy {
+    type Err = BalancerStrategyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "latency" => Ok(BalancerStrategy::Latency),
+            "least_connections" => Ok(BalancerStrategy::LeastConnections),
+            "weighted" => Ok(BalancerStrategy::Weighted),
+            _ => Err(BalancerStrategyError),
+        }
+    }
 }
 
 /// Address for local to report flow statistic data
@@ -1156,6 +1624,36 @@ impl LocalInstanceConfig {
     }
 }
 
+/// Minimum of sockets' `SO_SNDBUF` and `SO_RCVBUF` options
+pub const MIN_SOCKET_BUFFER_SIZE: u32 = 4 * 1024;
+/// Maximum of sockets' `SO_SNDBUF` and `SO_RCVBUF` options
+///
+/// Kernels allocate buffers for every socket, an extra zero could exhaust memory with lots of connections.
+pub const MAX_SOCKET_BUFFER_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Clamp socket buffer size `size` of option `name` into [`MIN_SOCKET_BUFFER_SIZE`, `MAX_SOCKET_BUFFER_SIZE`]
+pub fn clamp_socket_buffer_size(name: &str, size: u32) -> u32 {
+    let clamped = size.clamp(MIN_SOCKET_BUFFER_SIZE, MAX_SOCKET_BUFFER_SIZE);
+    if clamped != size {
+        warn!(
+            "{} {} is out of range [{}, {}], clamped to {}",
+            name, size, MIN_SOCKET_BUFFER_SIZE, MAX_SOCKET_BUFFER_SIZE, clamped
+        );
+    }
+    clamped
+}
+
+/// Plaintext capture of TCP tunnels to one target
+#[derive(Clone, Debug)]
+pub struct PlaintextCaptureConfig {
+    /// `host:port`, or `host` for all ports
+    pub target: String,
+    /// Path of the raw capture file, records are appended
+    pub path: PathBuf,
+    /// Capturing stops after `max_size` bytes, 16MiB by default
+    pub max_size: u64,
+}
+
 /// Configuration
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -1179,10 +1677,17 @@ pub struct Config {
     ///
     /// Set to `true` if you want to query IPv6 addresses before IPv4
     pub ipv6_first: bool,
+    /// Uses only IPv4 addresses of resolved hostnames, conflicts with `dns_ipv6_only`
+    pub dns_ipv4_only: bool,
+    /// Uses only IPv6 addresses of resolved hostnames, conflicts with `dns_ipv4_only`
+    pub dns_ipv6_only: bool,
     /// Set `IPV6_V6ONLY` for listener sockets
     pub ipv6_only: bool,
 
-    /// Set `TCP_NODELAY` socket option
+    /// Set `TCP_NODELAY` socket option, disabled by default
+    ///
+    /// Applies to all TCP sockets uniformly: inbound accepted sockets, outbound sockets to servers (proxied)
+    /// and outbound sockets to targets (bypassed). Could also be set by `tcp_nodelay` in configuration files.
     pub no_delay: bool,
     /// Set `TCP_FASTOPEN` socket option
     pub fast_open: bool,
@@ -1214,6 +1719,8 @@ pub struct Config {
     pub outbound_vpn_protect_path: Option<PathBuf>,
 
     /// Set `SO_SNDBUF` for inbound sockets
+    ///
+    /// Buffer sizes are clamped by `clamp_socket_buffer_size` when they are applied to sockets.
     pub inbound_send_buffer_size: Option<u32>,
     /// Set `SO_RCVBUF` for inbound sockets
     pub inbound_recv_buffer_size: Option<u32>,
@@ -1237,6 +1744,72 @@ pub struct Config {
     /// NOTE: mtu includes IP header, UDP header, UDP payload
     pub udp_mtu: Option<usize>,
 
+    /// Maximum number of TCP connections relayed simultaneously by local servers, unlimited by default
+    ///
+    /// Could be adjusted at runtime by `ServiceContext::relay_concurrency`
+    pub relay_concurrency: Option<usize>,
+
+    /// Log lifecycle of 1-in-N TCP connections at `info` level, disabled by default (or 0)
+    pub connection_log_sample_rate: Option<u32>,
+
+    /// Maximum random delay before connecting to remote servers, disabled by default (or 0)
+    ///
+    /// Spreads reconnection storms, for example, after mobile network transitions.
+    pub outbound_connect_jitter: Option<Duration>,
+
+    /// Timeout for connecting to targets or remote servers, OS's default if not configured
+    ///
+    /// Timed out connects to remote servers are reported as failures to the load balancer. It doesn't limit
+    /// established connections, see `timeout`.
+    pub outbound_connect_timeout: Option<Duration>,
+
+    /// Maximum number of TCP connections that are connecting to targets or remote servers simultaneously,
+    /// 256 by default, 0 for unlimited
+    ///
+    /// Bursts of connects queue briefly instead of overwhelming the outbound path and DNS. Established connections
+    /// are not counted, see `relay_concurrency`. Queued connects fail after waiting for `outbound_connect_timeout`,
+    /// or 10 seconds if it isn't set.
+    pub outbound_connect_concurrency: Option<usize>,
+
+    /// Maximum retries per second across all connections, unlimited by default (or 0)
+    ///
+    /// Retries fail fast after the budget is exhausted, which protects the device and servers from retry storms
+    /// during outages.
+    pub outbound_retry_budget: Option<u32>,
+
+    /// Log the remote server chosen for each proxied TCP connection at `info` level, disabled by default
+    ///
+    /// Only sampled connections are logged if `connection_log_sample_rate` is set.
+    pub log_selected_server: bool,
+
+    /// Resolve hostnames of proxied targets by remote servers only, disabled by default
+    ///
+    /// ACL domain rules are still checked, but hostnames won't be resolved locally for matching IP rules, neither by
+    /// connections nor by the DNS relay (`local-dns`). Servers' hostnames have to be resolved by the local resolver
+    /// (`dns`) for bootstrapping.
+    pub proxy_dns: bool,
+
+    /// Path of the liveness file, which is touched periodically while services are running
+    ///
+    /// It is removed when services are stopped.
+    pub liveness_file: Option<PathBuf>,
+    /// Interval for touching the liveness file, 10 seconds by default
+    pub liveness_interval: Option<Duration>,
+
+    /// Address of the HTTP health endpoint of local servers, disabled by default
+    #[cfg(feature = "local-health-api")]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Path of the access log, which records closed TCP tunnels of local servers in Common Log Format
+    ///
+    /// Access log is disabled by default, and it is separated from the debug log.
+    pub access_log: Option<PathBuf>,
+
+    /// Capture DECRYPTED bytes of TCP tunnels to one target into a local file, for debugging only
+    ///
+    /// Disabled by default, enabled only if both `capture_target` and `capture_file` are set.
+    pub plaintext_capture: Option<PlaintextCaptureConfig>,
+
     /// ACL configuration (Global)
     ///
     /// Could be overwritten by servers/locals' private `acl`
@@ -1321,6 +1894,8 @@ impl Display for Error {
     }
 }
 
+impl std::error::Error for Error {}
+
 impl Config {
     /// Creates an empty configuration
     pub fn new(config_type: ConfigType) -> Config {
@@ -1331,6 +1906,8 @@ impl Config {
             dns: DnsConfig::default(),
             dns_cache_size: None,
             ipv6_first: false,
+            dns_ipv4_only: false,
+            dns_ipv6_only: false,
             ipv6_only: false,
 
             no_delay: false,
@@ -1363,6 +1940,21 @@ impl Config {
             udp_max_associations: None,
             udp_mtu: None,
 
+            relay_concurrency: None,
+            connection_log_sample_rate: None,
+            outbound_connect_jitter: None,
+            outbound_connect_timeout: None,
+            outbound_connect_concurrency: None,
+            outbound_retry_budget: None,
+            log_selected_server: false,
+            proxy_dns: false,
+            liveness_file: None,
+            liveness_interval: None,
+            #[cfg(feature = "local-health-api")]
+            health_addr: None,
+            access_log: None,
+            plaintext_capture: None,
+
             acl: None,
 
             #[cfg(feature = "local-flow-stat")]
@@ -1378,7 +1970,8 @@ impl Config {
         }
     }
 
-    fn load_from_ssconfig(config: SSConfig, config_type: ConfigType) -> Result<Config, Error> {
+    /// `env_expanded` is set if `${VAR_NAME}`s in `config` have already been replaced by `expand_env_variables`
+    fn load_from_ssconfig(config: SSConfig, config_type: ConfigType, env_expanded: bool) -> Result<Config, Error> {
         let mut nconfig = Config::new(config_type);
 
         // Client
@@ -1431,21 +2024,37 @@ impl Config {
         match config_type {
             ConfigType::Local => {
                 // Standard config
-                if config.local_address.is_some() && config.local_port.unwrap_or(0) == 0 {
+                if config.local_address.is_some() && config.local_port.is_none() {
                     let err = Error::new(ErrorKind::MissingField, "missing `local_port`", None);
                     return Err(err);
                 }
 
+                // `local_port` could be 0, the actual port will be assigned by the OS and could be
+                // queried from the started local server instances.
                 if let Some(local_port) = config.local_port {
-                    // local_port won't be 0, it was checked above
-                    assert_ne!(local_port, 0);
-
-                    let local_addr =
-                        get_local_address(config.local_address, local_port, config.ipv6_first.unwrap_or(false));
+                    let local_addrs = match config.local_address {
+                        None => vec![None],
+                        Some(SSLocalAddress::Single(addr)) => vec![Some(addr)],
+                        Some(SSLocalAddress::Multiple(addrs)) => {
+                            if addrs.is_empty() {
+                                let err = Error::new(ErrorKind::Malformed, "`local_address` cannot be empty", None);
+                                return Err(err);
+                            }
+                            // OS would assign a different port for each of them
+                            if addrs.len() > 1 && local_port == 0 {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`local_port` cannot be 0 with multiple `local_address`",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            addrs.into_iter().map(Some).collect()
+                        }
+                    };
 
                     // shadowsocks uses SOCKS5 by default
                     let mut local_config = LocalConfig::new(ProtocolType::Socks);
-                    local_config.addr = Some(local_addr);
                     local_config.mode = global_mode;
                     local_config.protocol = match config.protocol {
                         None => ProtocolType::Socks,
@@ -1462,12 +2071,22 @@ impl Config {
                         },
                     };
 
-                    let local_instance = LocalInstanceConfig {
-                        config: local_config,
-                        acl: None,
-                    };
+                    // Listeners of all addresses share the same protocol and port
+                    for local_address in local_addrs {
+                        let mut local_config = local_config.clone();
+                        local_config.addr = Some(get_local_address(
+                            local_address,
+                            local_port,
+                            config.ipv6_first.unwrap_or(false),
+                        ));
+
+                        let local_instance = LocalInstanceConfig {
+                            config: local_config,
+                            acl: None,
+                        };
 
-                    nconfig.local.push(local_instance);
+                        nconfig.local.push(local_instance);
+                    }
                 }
 
                 // Ext locals
@@ -1496,11 +2115,6 @@ impl Config {
                         let mut local_config = LocalConfig::new(protocol);
 
                         if let Some(local_port) = local.local_port {
-                            if local_port == 0 {
-                                let err = Error::new(ErrorKind::Malformed, "`local_port` cannot be 0", None);
-                                return Err(err);
-                            }
-
                             let local_addr =
                                 get_local_address(local.local_address, local_port, config.ipv6_first.unwrap_or(false));
                             local_config.addr = Some(local_addr);
@@ -1553,6 +2167,11 @@ impl Config {
 
                         #[cfg(feature = "local-tunnel")]
                         if let Some(forward_address) = local.forward_address {
+                            if forward_address.is_empty() {
+                                let err = Error::new(ErrorKind::Malformed, "`forward_address` cannot be empty", None);
+                                return Err(err);
+                            }
+
                             let forward_port = match local.forward_port {
                                 None | Some(0) => {
                                     let err =
@@ -1568,6 +2187,25 @@ impl Config {
                             });
                         }
 
+                        #[cfg(feature = "local-tunnel")]
+                        if let Some(forward_addresses) = local.forward_addresses {
+                            for forward_address in forward_addresses {
+                                match forward_address.parse::<Address>() {
+                                    Ok(addr) if addr.port() != 0 && forward_address.contains(':') => {
+                                        local_config.forward_addrs.push(addr)
+                                    }
+                                    _ => {
+                                        let err = Error::new(
+                                            ErrorKind::Malformed,
+                                            "`forward_addresses` invalid",
+                                            Some(format!("{forward_address:?} is not a valid address with port")),
+                                        );
+                                        return Err(err);
+                                    }
+                                }
+                            }
+                        }
+
                         #[cfg(feature = "local-redir")]
                         if let Some(tcp_redir) = local.tcp_redir {
                             match tcp_redir.parse::<RedirType>() {
@@ -1617,6 +2255,11 @@ impl Config {
                             local_config.client_cache_size = Some(client_cache_size);
                         }
 
+                        #[cfg(feature = "local-dns")]
+                        if let Some(answer_cache_size) = local.answer_cache_size {
+                            local_config.answer_cache_size = Some(answer_cache_size);
+                        }
+
                         #[cfg(feature = "local-dns")]
                         if let Some(remote_dns_address) = local.remote_dns_address {
                             let remote_dns_port = local.remote_dns_port.unwrap_or(53);
@@ -1650,11 +2293,24 @@ impl Config {
                         }
 
                         #[cfg(feature = "local-tun")]
-                        if let Some(tun_interface_name) = local.tun_interface_name {
-                            local_config.tun_interface_name = Some(tun_interface_name);
-                        }
-
-                        #[cfg(all(feature = "local-tun", unix))]
+                        if let Some(tun_interface_mtu) = local.tun_interface_mtu {
+                            if tun_interface_mtu < MIN_TUN_INTERFACE_MTU {
+                                let err = Error::new(
+                                    ErrorKind::Invalid,
+                                    "`tun_interface_mtu` must be at least 576, the minimum MTU of IPv4",
+                                    None,
+                                );
+                                return Err(err);
+                            }
+                            local_config.tun_interface_mtu = Some(tun_interface_mtu);
+                        }
+
+                        #[cfg(feature = "local-tun")]
+                        if let Some(tun_interface_name) = local.tun_interface_name {
+                            local_config.tun_interface_name = Some(tun_interface_name);
+                        }
+
+                        #[cfg(all(feature = "local-tun", unix))]
                         if let Some(tun_device_fd_from_path) = local.tun_device_fd_from_path {
                             local_config.tun_device_fd_from_path = Some(From::from(tun_device_fd_from_path));
                         }
@@ -1664,6 +2320,21 @@ impl Config {
                             local_config.socks5_auth = Socks5AuthConfig::load_from_file(&socks5_auth_config_path)?;
                         }
 
+                        #[cfg(feature = "local")]
+                        if let Some(socks_greeting_timeout) = local.socks_greeting_timeout {
+                            local_config.socks_greeting_timeout = Some(Duration::from_secs(socks_greeting_timeout));
+                        }
+
+                        #[cfg(feature = "local-http")]
+                        if let Some(allow_http) = local.socks_greeting_allow_http {
+                            local_config.socks_greeting_allow_http = allow_http;
+                        }
+
+                        #[cfg(feature = "local")]
+                        if let Some(grace_period) = local.socks5_udp_associate_grace_period {
+                            local_config.socks5_udp_associate_grace_period = Some(Duration::from_secs(grace_period));
+                        }
+
                         let mut local_instance = LocalInstanceConfig {
                             config: local_config,
                             acl: None,
@@ -1722,7 +2393,8 @@ impl Config {
 
                 // Only "password" support getting from environment variable.
                 let password = match pwd_opt {
-                    Some(ref pwd) => read_variable_field_value(pwd),
+                    Some(ref pwd) if !env_expanded => read_variable_field_value(pwd),
+                    Some(ref pwd) => pwd.as_str().into(),
                     None => {
                         if method.is_none() {
                             String::new().into()
@@ -1737,6 +2409,11 @@ impl Config {
                     }
                 };
 
+                if let Err(err) = ServerConfig::check_password(method, &password) {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `password`", Some(err.to_string()));
+                    return Err(err);
+                }
+
                 let mut nsvr = ServerConfig::new(addr, password, method);
                 nsvr.set_mode(global_mode);
 
@@ -1767,6 +2444,10 @@ impl Config {
                     }
                 }
 
+                if let Some(ref fallback_methods) = config.fallback_methods {
+                    nsvr.set_fallback_methods(&parse_fallback_methods(method, fallback_methods)?);
+                }
+
                 if let Some(timeout) = config.timeout.map(Duration::from_secs) {
                     nsvr.set_timeout(timeout);
                 }
@@ -1827,7 +2508,8 @@ impl Config {
 
                 // Only "password" support getting from environment variable.
                 let password = match svr.password {
-                    Some(ref pwd) => read_variable_field_value(pwd),
+                    Some(ref pwd) if !env_expanded => read_variable_field_value(pwd),
+                    Some(ref pwd) => pwd.as_str().into(),
                     None => {
                         if method.is_none() {
                             String::new().into()
@@ -1842,6 +2524,11 @@ impl Config {
                     }
                 };
 
+                if let Err(err) = ServerConfig::check_password(method, &password) {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `password`", Some(err.to_string()));
+                    return Err(err);
+                }
+
                 let mut nsvr = ServerConfig::new(addr, password, method);
 
                 // Extensible Identity Header, Users
@@ -1910,6 +2597,10 @@ impl Config {
                     }
                 }
 
+                if let Some(ref fallback_methods) = svr.fallback_methods {
+                    nsvr.set_fallback_methods(&parse_fallback_methods(method, fallback_methods)?);
+                }
+
                 if let Some(timeout) = config.timeout.map(Duration::from_secs) {
                     nsvr.set_timeout(timeout);
                 }
@@ -2089,6 +2780,85 @@ impl Config {
         // MTU for UDP
         nconfig.udp_mtu = config.udp_mtu;
 
+        // Concurrent TCP relays
+        nconfig.relay_concurrency = config.relay_concurrency;
+
+        // Sampled connection logs
+        nconfig.connection_log_sample_rate = config.connection_log_sample_rate;
+
+        // Jitter before connecting to remote servers, in milliseconds
+        nconfig.outbound_connect_jitter = config.outbound_connect_jitter.map(Duration::from_millis);
+
+        // Timeout for connecting to targets or remote servers, in seconds
+        if let Some(timeout) = config.outbound_connect_timeout {
+            if timeout == 0 {
+                let err = Error::new(ErrorKind::Malformed, "`outbound_connect_timeout` cannot be 0", None);
+                return Err(err);
+            }
+            nconfig.outbound_connect_timeout = Some(Duration::from_secs(timeout));
+        }
+
+        // Concurrent connection establishments
+        nconfig.outbound_connect_concurrency = config.outbound_connect_concurrency;
+
+        // Retries per second across all connections
+        nconfig.outbound_retry_budget = config.outbound_retry_budget;
+
+        // Log servers chosen for proxied connections
+        if let Some(b) = config.log_selected_server {
+            nconfig.log_selected_server = b;
+        }
+
+        // Resolve proxied hostnames by remote servers
+        if let Some(b) = config.proxy_dns {
+            nconfig.proxy_dns = b;
+        }
+
+        // Liveness file
+        nconfig.liveness_file = config.liveness_file.map(PathBuf::from);
+        if let Some(intv) = config.liveness_interval {
+            if intv == 0 {
+                let err = Error::new(ErrorKind::Malformed, "`liveness_interval` cannot be 0", None);
+                return Err(err);
+            }
+            nconfig.liveness_interval = Some(Duration::from_secs(intv));
+        }
+
+        // HTTP health endpoint
+        #[cfg(feature = "local-health-api")]
+        if let Some(addr) = config.health_addr {
+            match addr.parse::<SocketAddr>() {
+                Ok(addr) => nconfig.health_addr = Some(addr),
+                Err(..) => {
+                    let err = Error::new(ErrorKind::Invalid, "invalid `health_addr`", Some(addr));
+                    return Err(err);
+                }
+            }
+        }
+
+        // Access log
+        nconfig.access_log = config.access_log.map(PathBuf::from);
+
+        // Plaintext capture for debugging, target and file must be set explicitly
+        match (config.capture_target, config.capture_file) {
+            (Some(target), Some(path)) => {
+                nconfig.plaintext_capture = Some(PlaintextCaptureConfig {
+                    target,
+                    path: PathBuf::from(path),
+                    max_size: config.capture_max_size.unwrap_or(DEFAULT_CAPTURE_MAX_SIZE),
+                });
+            }
+            (None, None) => {}
+            _ => {
+                let err = Error::new(
+                    ErrorKind::Malformed,
+                    "`capture_target` and `capture_file` must be set together",
+                    None,
+                );
+                return Err(err);
+            }
+        }
+
         // RLIMIT_NOFILE
         #[cfg(all(unix, not(target_os = "android")))]
         {
@@ -2100,6 +2870,18 @@ impl Config {
             nconfig.ipv6_first = f;
         }
 
+        // Address families of resolved hostnames
+        nconfig.dns_ipv4_only = config.dns_ipv4_only.unwrap_or(false);
+        nconfig.dns_ipv6_only = config.dns_ipv6_only.unwrap_or(false);
+        if nconfig.dns_ipv4_only && nconfig.dns_ipv6_only {
+            let err = Error::new(
+                ErrorKind::Invalid,
+                "`dns_ipv4_only` and `dns_ipv6_only` cannot be both enabled",
+                None,
+            );
+            return Err(err);
+        }
+
         // IPV6_V6ONLY
         if let Some(o) = config.ipv6_only {
             nconfig.ipv6_only = o;
@@ -2131,6 +2913,20 @@ impl Config {
         // Bind device / interface
         nconfig.outbound_bind_interface = config.outbound_bind_interface;
 
+        // Socket buffer sizes
+        nconfig.inbound_send_buffer_size = config
+            .inbound_send_buffer_size
+            .map(|bs| clamp_socket_buffer_size("inbound_send_buffer_size", bs));
+        nconfig.inbound_recv_buffer_size = config
+            .inbound_recv_buffer_size
+            .map(|bs| clamp_socket_buffer_size("inbound_recv_buffer_size", bs));
+        nconfig.outbound_send_buffer_size = config
+            .outbound_send_buffer_size
+            .map(|bs| clamp_socket_buffer_size("outbound_send_buffer_size", bs));
+        nconfig.outbound_recv_buffer_size = config
+            .outbound_recv_buffer_size
+            .map(|bs| clamp_socket_buffer_size("outbound_recv_buffer_size", bs));
+
         // Security
         if let Some(sec) = config.security {
             if let Some(replay_attack) = sec.replay_attack {
@@ -2143,14 +2939,65 @@ impl Config {
                         }
                     }
                 }
+
+                if let Some(diff) = replay_attack.max_timestamp_diff {
+                    if diff == 0 {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid replay attack max_timestamp_diff, must be > 0",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                    nconfig.security.replay_attack.max_timestamp_diff = Some(Duration::from_secs(diff));
+                }
             }
         }
 
         if let Some(balancer) = config.balancer {
+            let strategy = match balancer.strategy {
+                None => BalancerStrategy::default(),
+                Some(ref s) => match s.parse::<BalancerStrategy>() {
+                    Ok(s) => s,
+                    Err(..) => {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid balancer `strategy`, can only be \"latency\", \"least_connections\" or \"weighted\"",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                },
+            };
+
+            for (name, weight) in [
+                ("latency_weight", balancer.latency_weight),
+                ("loss_weight", balancer.loss_weight),
+                ("jitter_weight", balancer.jitter_weight),
+            ] {
+                if let Some(weight) = weight {
+                    if !weight.is_finite() || weight < 0.0 {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid balancer score weight, must be a non-negative number",
+                            Some(format!("{} = {}", name, weight)),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+
             nconfig.balancer = BalancerConfig {
                 max_server_rtt: balancer.max_server_rtt.map(Duration::from_secs),
                 check_interval: balancer.check_interval.map(Duration::from_secs),
                 check_best_interval: balancer.check_best_interval.map(Duration::from_secs),
+                circuit_breaker_failures: balancer.circuit_breaker_failures,
+                circuit_breaker_window: balancer.circuit_breaker_window.map(Duration::from_secs),
+                circuit_breaker_cooldown: balancer.circuit_breaker_cooldown.map(Duration::from_secs),
+                latency_weight: balancer.latency_weight,
+                loss_weight: balancer.loss_weight,
+                jitter_weight: balancer.jitter_weight,
+                strategy,
             };
         }
 
@@ -2212,7 +3059,10 @@ impl Config {
             #[cfg(all(feature = "hickory-dns", feature = "dns-over-https"))]
             "quad9_https" => DnsConfig::HickoryDns(ResolverConfig::quad9_https()),
 
-            nameservers => self.parse_dns_nameservers(nameservers)?,
+            nameservers => {
+                validate_name_server_addr(nameservers)?;
+                self.parse_dns_nameservers(nameservers)?
+            }
         };
 
         Ok(())
@@ -2270,18 +3120,7 @@ impl Config {
         //     `192.168.1.100,192.168.1.101,3.4.5.6`
         let mut c = ResolverConfig::new();
         for part in nameservers.split(',') {
-            let socket_addr = if let Ok(socket_addr) = part.parse::<SocketAddr>() {
-                socket_addr
-            } else if let Ok(ipaddr) = part.parse::<IpAddr>() {
-                SocketAddr::new(ipaddr, 53)
-            } else {
-                let e = Error::new(
-                    ErrorKind::Invalid,
-                    "invalid `dns` value, can only be [(tcp|udp)://]host[:port][,host[:port]]..., or unix:///path/to/dns, or predefined keys like \"google\", \"cloudflare\"",
-                    None,
-                );
-                return Err(e);
-            };
+            let socket_addr = parse_name_server_addr(part)?;
 
             if protocol.enable_udp() {
                 let ns_config = NameServerConfig::new(socket_addr, Protocol::Udp);
@@ -2301,17 +3140,34 @@ impl Config {
     }
 
     #[cfg(not(any(feature = "hickory-dns", feature = "local-dns")))]
-    fn parse_dns_nameservers(&mut self, _nameservers: &str) -> Result<DnsConfig, Error> {
+    fn parse_dns_nameservers(&mut self, nameservers: &str) -> Result<DnsConfig, Error> {
+        warn!(
+            "`dns` {} is ignored, customized nameservers require feature \"hickory-dns\"",
+            nameservers
+        );
         Ok(DnsConfig::System)
     }
 
     /// Load Config from a `str`
     pub fn load_from_str(s: &str, config_type: ConfigType) -> Result<Config, Error> {
-        let c = json5::from_str::<SSConfig>(s)?;
-        Config::load_from_ssconfig(c, config_type)
+        Config::load_from_json_str(s, config_type, false, None)
+    }
+
+    fn load_from_json_str(
+        s: &str,
+        config_type: ConfigType,
+        env_expanded: bool,
+        config_dir: Option<&Path>,
+    ) -> Result<Config, Error> {
+        let mut c = json5::from_str::<SSConfig>(s)?;
+        c.load_server_list(env_expanded, config_dir)?;
+        Config::load_from_ssconfig(c, config_type, env_expanded)
     }
 
     /// Load Config from a File
+    ///
+    /// `${VAR_NAME}` references in the file and its `server_list` are replaced by environment variables before
+    /// parsing, see `expand_env_variables`. Values of the variables are used as is, they are not expanded again.
     pub fn load_from_file<P: AsRef<Path>>(filename: P, config_type: ConfigType) -> Result<Config, Error> {
         let filename = filename.as_ref();
 
@@ -2319,7 +3175,8 @@ impl Config {
         let mut content = String::new();
         reader.read_to_string(&mut content)?;
 
-        let mut config = Config::load_from_str(&content[..], config_type)?;
+        let content = expand_env_variables(&content)?;
+        let mut config = Config::load_from_json_str(&content, config_type, true, filename.parent())?;
 
         // Record the path of the configuration for auto-reloading
         config.config_path = Some(filename.to_owned());
@@ -2327,6 +3184,17 @@ impl Config {
         Ok(config)
     }
 
+    /// Load Config from a File without blocking the async runtime
+    ///
+    /// Reading and parsing, including the ACL files referenced by the configuration, run on tokio's blocking thread
+    /// pool. Large configurations won't stall other tasks while being reloaded.
+    pub async fn load_from_file_async<P: AsRef<Path>>(filename: P, config_type: ConfigType) -> Result<Config, Error> {
+        let filename = filename.as_ref().to_owned();
+        tokio::task::spawn_blocking(move || Config::load_from_file(filename, config_type))
+            .await
+            .map_err(io::Error::from)?
+    }
+
     /// Check if there are any plugin are enabled with servers
     pub fn has_server_plugins(&self) -> bool {
         for inst in &self.server {
@@ -2339,8 +3207,96 @@ impl Config {
         false
     }
 
+    /// Check if any two local servers are going to listen on the same TCP or UDP address
+    fn check_local_listen_conflicts(&self) -> Result<(), Error> {
+        fn listen_addr_conflicts(a: &ServerAddr, b: &ServerAddr) -> bool {
+            match (a, b) {
+                (ServerAddr::SocketAddr(a), ServerAddr::SocketAddr(b)) => {
+                    // Port 0 is assigned by the system
+                    a.port() != 0
+                        && a.port() == b.port()
+                        && a.is_ipv4() == b.is_ipv4()
+                        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+                }
+                (ServerAddr::DomainName(ah, ap), ServerAddr::DomainName(bh, bp)) => *ap != 0 && ap == bp && ah == bh,
+                _ => false,
+            }
+        }
+
+        let mut tcp_listeners: Vec<(&ServerAddr, ProtocolType)> = Vec::new();
+        let mut udp_listeners: Vec<(&ServerAddr, ProtocolType)> = Vec::new();
+
+        for local_instance in &self.local {
+            let local_config = &local_instance.config;
+            let addr = match local_config.addr {
+                Some(ref addr) => addr,
+                None => continue,
+            };
+
+            #[allow(unused_mut)]
+            let mut serve_udp = true;
+            #[cfg(feature = "local-http")]
+            if local_config.protocol == ProtocolType::Http {
+                serve_udp = false;
+            }
+
+            let mut listeners = Vec::with_capacity(2);
+            if local_config.mode.enable_tcp() {
+                listeners.push(("TCP", addr, &mut tcp_listeners));
+            }
+            if serve_udp && local_config.mode.enable_udp() {
+                let udp_addr = local_config.udp_addr.as_ref().unwrap_or(addr);
+                listeners.push(("UDP", udp_addr, &mut udp_listeners));
+            }
+
+            for (network, addr, bound) in listeners {
+                if let Some((bound_addr, bound_protocol)) = bound.iter().find(|(b, _)| listen_addr_conflicts(b, addr)) {
+                    let err = Error::new(
+                        ErrorKind::Malformed,
+                        "`locals` have conflicting listen addresses",
+                        Some(format!(
+                            "{} {} {} conflicts with {} {}",
+                            local_config.protocol.as_str(),
+                            network,
+                            addr,
+                            bound_protocol.as_str(),
+                            bound_addr
+                        )),
+                    );
+                    return Err(err);
+                }
+                bound.push((addr, local_config.protocol));
+            }
+        }
+
+        Ok(())
+    }
+
     /// Check if all required fields are already set
     pub fn check_integrity(&self) -> Result<(), Error> {
+        match self.integrity_errors().into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Load Config from a File and check it without starting any services
+    ///
+    /// Unlike `check_integrity`, every problem found is reported instead of only the first one. Errors in parsing
+    /// stop the check early, because the rest of the configuration couldn't be checked.
+    pub fn check_file<P: AsRef<Path>>(filename: P, config_type: ConfigType) -> Result<Config, Vec<Error>> {
+        let config = Config::load_from_file(filename, config_type).map_err(|err| vec![err])?;
+
+        let errors = config.integrity_errors();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(config)
+    }
+
+    fn integrity_errors(&self) -> Vec<Error> {
+        let mut errors = Vec::new();
+
         if self.config_type.is_local() {
             if self.local.is_empty() {
                 let err = Error::new(
@@ -2348,25 +3304,31 @@ impl Config {
                     "missing `locals` for client configuration",
                     None,
                 );
-                return Err(err);
+                errors.push(err);
             }
 
             for local_config in &self.local {
-                local_config.config.check_integrity()?;
+                if let Err(err) = local_config.config.check_integrity() {
+                    errors.push(err);
+                }
+            }
+
+            if let Err(err) = self.check_local_listen_conflicts() {
+                errors.push(err);
             }
 
             // Balancer related checks
             if let Some(rtt) = self.balancer.max_server_rtt {
                 if rtt.as_secs() == 0 {
                     let err = Error::new(ErrorKind::Invalid, "balancer.max_server_rtt must be > 0", None);
-                    return Err(err);
+                    errors.push(err);
                 }
             }
 
             if let Some(intv) = self.balancer.check_interval {
                 if intv.as_secs() == 0 {
                     let err = Error::new(ErrorKind::Invalid, "balancer.check_interval must be > 0", None);
-                    return Err(err);
+                    errors.push(err);
                 }
             }
         }
@@ -2377,7 +3339,7 @@ impl Config {
                 "missing any valid servers in configuration",
                 None,
             );
-            return Err(err);
+            errors.push(err);
         }
 
         if self.config_type.is_manager() && self.manager.is_none() {
@@ -2386,7 +3348,7 @@ impl Config {
                 "missing `manager_addr` and `manager_port` in configuration",
                 None,
             );
-            return Err(err);
+            errors.push(err);
         }
 
         for inst in &self.server {
@@ -2396,7 +3358,7 @@ impl Config {
             if let Some(plugin) = server.plugin() {
                 if plugin.plugin.trim().is_empty() {
                     let err = Error::new(ErrorKind::Malformed, "`plugin` shouldn't be an empty string", None);
-                    return Err(err);
+                    errors.push(err);
                 }
             }
 
@@ -2405,7 +3367,7 @@ impl Config {
                 ServerAddr::SocketAddr(sa) => {
                     if sa.port() == 0 {
                         let err = Error::new(ErrorKind::Malformed, "`server_port` shouldn't be 0", None);
-                        return Err(err);
+                        errors.push(err);
                     }
 
                     if self.config_type.is_local() {
@@ -2417,7 +3379,7 @@ impl Config {
                                 "`server` shouldn't be an unspecified address (INADDR_ANY)",
                                 None,
                             );
-                            return Err(err);
+                            errors.push(err);
                         }
                     }
                 }
@@ -2428,7 +3390,7 @@ impl Config {
                             "`server` shouldn't be an empty string, `server_port` shouldn't be 0",
                             None,
                         );
-                        return Err(err);
+                        errors.push(err);
                     }
                 }
             }
@@ -2443,13 +3405,14 @@ impl Config {
                             "`users[].password` length must be exactly the same as method's key length",
                             None,
                         );
-                        return Err(err);
+                        errors.push(err);
+                        break;
                     }
                 }
             }
         }
 
-        Ok(())
+        errors
     }
 }
 
@@ -2465,10 +3428,10 @@ impl fmt::Display for Config {
                 let local_instance = &self.local[0];
                 let local = &local_instance.config;
                 if let Some(ref a) = local.addr {
-                    jconf.local_address = Some(match a {
+                    jconf.local_address = Some(SSLocalAddress::Single(match a {
                         ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
                         ServerAddr::DomainName(ref dm, ..) => dm.to_string(),
-                    });
+                    }));
                     jconf.local_port = Some(match a {
                         ServerAddr::SocketAddr(ref sa) => sa.port(),
                         ServerAddr::DomainName(.., port) => *port,
@@ -2544,6 +3507,12 @@ impl fmt::Display for Config {
                                 Address::DomainNameAddress(.., port) => Some(*port),
                             },
                         },
+                        #[cfg(feature = "local-tunnel")]
+                        forward_addresses: if local.forward_addrs.is_empty() {
+                            None
+                        } else {
+                            Some(local.forward_addrs.iter().map(ToString::to_string).collect())
+                        },
                         #[cfg(feature = "local-dns")]
                         local_dns_address: match local.local_dns_addr {
                             None => None,
@@ -2582,12 +3551,16 @@ impl fmt::Display for Config {
                         },
                         #[cfg(feature = "local-dns")]
                         client_cache_size: local.client_cache_size,
+                        #[cfg(feature = "local-dns")]
+                        answer_cache_size: local.answer_cache_size,
                         #[cfg(feature = "local-tun")]
                         tun_interface_name: local.tun_interface_name.clone(),
                         #[cfg(feature = "local-tun")]
                         tun_interface_address: local.tun_interface_address.as_ref().map(ToString::to_string),
                         #[cfg(feature = "local-tun")]
                         tun_interface_destination: local.tun_interface_destination.as_ref().map(ToString::to_string),
+                        #[cfg(feature = "local-tun")]
+                        tun_interface_mtu: local.tun_interface_mtu,
                         #[cfg(all(feature = "local-tun", unix))]
                         tun_device_fd_from_path: local
                             .tun_device_fd_from_path
@@ -2596,6 +3569,19 @@ impl fmt::Display for Config {
 
                         #[cfg(feature = "local")]
                         socks5_auth_config_path: None,
+                        #[cfg(feature = "local")]
+                        socks_greeting_timeout: local.socks_greeting_timeout.as_ref().map(Duration::as_secs),
+                        #[cfg(feature = "local-http")]
+                        socks_greeting_allow_http: if local.socks_greeting_allow_http {
+                            Some(true)
+                        } else {
+                            None
+                        },
+                        #[cfg(feature = "local")]
+                        socks5_udp_associate_grace_period: local
+                            .socks5_udp_associate_grace_period
+                            .as_ref()
+                            .map(Duration::as_secs),
 
                         acl: local_instance
                             .acl
@@ -2648,6 +3634,7 @@ impl fmt::Display for Config {
                 };
                 jconf.timeout = svr.timeout().map(|t| t.as_secs());
                 jconf.mode = Some(svr.mode().to_string());
+                jconf.fallback_methods = fallback_methods_to_strings(svr);
 
                 if let Some(ref acl) = inst.acl {
                     jconf.acl = Some(acl.file_path().to_str().unwrap().to_owned());
@@ -2675,6 +3662,7 @@ impl fmt::Display for Config {
                             Some(svr.password().to_string())
                         },
                         method: svr.method().to_string(),
+                        fallback_methods: fallback_methods_to_strings(svr),
                         users: svr.user_manager().map(|m| {
                             let mut vu = Vec::new();
                             for u in m.users_iter() {
@@ -2801,6 +3789,44 @@ impl fmt::Display for Config {
 
         jconf.udp_mtu = self.udp_mtu;
 
+        jconf.relay_concurrency = self.relay_concurrency;
+
+        jconf.connection_log_sample_rate = self.connection_log_sample_rate;
+
+        jconf.outbound_connect_jitter = self.outbound_connect_jitter.as_ref().map(|d| d.as_millis() as u64);
+        jconf.outbound_connect_timeout = self.outbound_connect_timeout.as_ref().map(Duration::as_secs);
+
+        jconf.outbound_connect_concurrency = self.outbound_connect_concurrency;
+
+        jconf.outbound_retry_budget = self.outbound_retry_budget;
+
+        if self.log_selected_server {
+            jconf.log_selected_server = Some(self.log_selected_server);
+        }
+
+        if self.proxy_dns {
+            jconf.proxy_dns = Some(self.proxy_dns);
+        }
+
+        jconf.liveness_file = self
+            .liveness_file
+            .as_ref()
+            .and_then(|p| p.to_str().map(ToOwned::to_owned));
+        jconf.liveness_interval = self.liveness_interval.as_ref().map(Duration::as_secs);
+
+        #[cfg(feature = "local-health-api")]
+        {
+            jconf.health_addr = self.health_addr.as_ref().map(ToString::to_string);
+        }
+
+        jconf.access_log = self.access_log.as_ref().and_then(|p| p.to_str().map(ToOwned::to_owned));
+
+        if let Some(ref capture) = self.plaintext_capture {
+            jconf.capture_target = Some(capture.target.clone());
+            jconf.capture_file = capture.path.to_str().map(ToOwned::to_owned);
+            jconf.capture_max_size = Some(capture.max_size);
+        }
+
         #[cfg(all(unix, not(target_os = "android")))]
         {
             jconf.nofile = self.nofile;
@@ -2810,6 +3836,14 @@ impl fmt::Display for Config {
             jconf.ipv6_first = Some(self.ipv6_first);
         }
 
+        if self.dns_ipv4_only {
+            jconf.dns_ipv4_only = Some(self.dns_ipv4_only);
+        }
+
+        if self.dns_ipv6_only {
+            jconf.dns_ipv6_only = Some(self.dns_ipv6_only);
+        }
+
         if self.ipv6_only {
             jconf.ipv6_only = Some(self.ipv6_only);
         }
@@ -2827,21 +3861,52 @@ impl fmt::Display for Config {
         jconf.outbound_bind_addr = self.outbound_bind_addr.map(|i| i.to_string());
         jconf.outbound_bind_interface = self.outbound_bind_interface.clone();
 
+        jconf.inbound_send_buffer_size = self.inbound_send_buffer_size;
+        jconf.inbound_recv_buffer_size = self.inbound_recv_buffer_size;
+        jconf.outbound_send_buffer_size = self.outbound_send_buffer_size;
+        jconf.outbound_recv_buffer_size = self.outbound_recv_buffer_size;
+
         // Security
-        if self.security.replay_attack.policy != ReplayAttackPolicy::default() {
+        if self.security.replay_attack.policy != ReplayAttackPolicy::default()
+            || self.security.replay_attack.max_timestamp_diff.is_some()
+        {
+            let replay_attack = &self.security.replay_attack;
             jconf.security = Some(SSSecurityConfig {
                 replay_attack: Some(SSSecurityReplayAttackConfig {
-                    policy: Some(self.security.replay_attack.policy.to_string()),
+                    policy: if replay_attack.policy != ReplayAttackPolicy::default() {
+                        Some(replay_attack.policy.to_string())
+                    } else {
+                        None
+                    },
+                    max_timestamp_diff: replay_attack.max_timestamp_diff.as_ref().map(Duration::as_secs),
                 }),
             });
         }
 
         // Balancer
-        if self.balancer.max_server_rtt.is_some() || self.balancer.check_interval.is_some() {
+        if self.balancer.max_server_rtt.is_some()
+            || self.balancer.check_interval.is_some()
+            || self.balancer.circuit_breaker_failures.is_some()
+            || self.balancer.latency_weight.is_some()
+            || self.balancer.loss_weight.is_some()
+            || self.balancer.jitter_weight.is_some()
+            || self.balancer.strategy != BalancerStrategy::default()
+        {
             jconf.balancer = Some(SSBalancerConfig {
                 max_server_rtt: self.balancer.max_server_rtt.as_ref().map(Duration::as_secs),
                 check_interval: self.balancer.check_interval.as_ref().map(Duration::as_secs),
                 check_best_interval: self.balancer.check_best_interval.as_ref().map(Duration::as_secs),
+                circuit_breaker_failures: self.balancer.circuit_breaker_failures,
+                circuit_breaker_window: self.balancer.circuit_breaker_window.as_ref().map(Duration::as_secs),
+                circuit_breaker_cooldown: self.balancer.circuit_breaker_cooldown.as_ref().map(Duration::as_secs),
+                latency_weight: self.balancer.latency_weight,
+                loss_weight: self.balancer.loss_weight,
+                jitter_weight: self.balancer.jitter_weight,
+                strategy: if self.balancer.strategy != BalancerStrategy::default() {
+                    Some(self.balancer.strategy.to_string())
+                } else {
+                    None
+                },
             });
         }
 
@@ -2854,6 +3919,120 @@ impl fmt::Display for Config {
     }
 }
 
+/// Effective cipher and mode of a remote server
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerSummary {
+    /// Server's address
+    #[serde(serialize_with = "serialize_display")]
+    pub addr: ServerAddr,
+    /// Cipher
+    #[serde(serialize_with = "serialize_display")]
+    pub method: CipherKind,
+    /// Relay mode
+    #[serde(serialize_with = "serialize_display")]
+    pub mode: Mode,
+    /// Plugin program name, if the server is connected through a plugin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugin: Option<String>,
+}
+
+/// Effective protocol and mode of a local server
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalSummary {
+    /// Inbound protocol
+    #[serde(serialize_with = "serialize_protocol_type")]
+    pub protocol: ProtocolType,
+    /// Relay mode
+    #[serde(serialize_with = "serialize_display")]
+    pub mode: Mode,
+    /// Listen address, `None` for protocols that are not listening on addresses, like `tun`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+}
+
+/// Summary of a loaded `Config`, for displaying the effective ciphers and modes before starting services
+///
+/// `Display` formats it as JSON, for example:
+///
+/// ```plain
+/// {"servers":[{"addr":"127.0.0.1:8388","method":"aes-256-gcm","mode":"tcp_and_udp"}],"locals":[{"protocol":"socks","mode":"tcp_and_udp","addr":"127.0.0.1:1080"}]}
+/// ```
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigSummary {
+    /// Remote servers
+    pub servers: Vec<ServerSummary>,
+    /// Local servers
+    pub locals: Vec<LocalSummary>,
+}
+
+impl fmt::Display for ConfigSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", json5::to_string(self).unwrap())
+    }
+}
+
+/// Summarize effective ciphers and modes of `config`
+///
+/// Values are taken from the loaded `config`, which have already been merged with global options and command line
+/// arguments, if it is called right before starting services.
+pub fn config_summary(config: &Config) -> ConfigSummary {
+    ConfigSummary {
+        servers: config
+            .server
+            .iter()
+            .map(|s| ServerSummary {
+                addr: s.config.addr().clone(),
+                method: s.config.method(),
+                mode: s.config.mode(),
+                plugin: s.config.plugin().map(|p| p.plugin.clone()),
+            })
+            .collect(),
+        locals: config
+            .local
+            .iter()
+            .map(|l| LocalSummary {
+                protocol: l.config.protocol,
+                mode: l.config.mode,
+                addr: l.config.addr.as_ref().map(ToString::to_string),
+            })
+            .collect(),
+    }
+}
+
+fn serialize_display<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Display,
+    S: serde::Serializer,
+{
+    serializer.collect_str(value)
+}
+
+fn deserialize_port<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // Parse a wider integer first, json5 casts numbers into `u16` silently
+    let port = i64::deserialize(deserializer)?;
+    u16::try_from(port).map_err(|_| serde::de::Error::custom(format!("invalid port {port}, expected 0-65535")))
+}
+
+fn deserialize_optional_port<'de, D>(deserializer: D) -> Result<Option<u16>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Port(#[serde(deserialize_with = "deserialize_port")] u16);
+
+    Option::<Port>::deserialize(deserializer).map(|p| p.map(|Port(port)| port))
+}
+
+fn serialize_protocol_type<S>(protocol: &ProtocolType, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(protocol.as_str())
+}
+
 /// Parse variable value if it is an environment variable
 ///
 /// If value is in format `${VAR_NAME}` then it will try to read from `VAR_NAME` environment variable.
@@ -2875,3 +4054,844 @@ pub fn read_variable_field_value(value: &str) -> Cow<'_, str> {
 
     value.into()
 }
+
+/// Replace `${VAR_NAME}` references in string literals of configuration `content` with environment variables
+///
+/// Only references inside string values (and keys), like `"password": "${SS_PASSWORD}"`, are replaced, so comments
+/// and the rest of the JSON5 text are never touched. Values are escaped as the string's content. Referencing an
+/// undefined variable is an error instead of an empty value.
+pub fn expand_env_variables(content: &str) -> Result<Cow<'_, str>, Error> {
+    if !content.contains("${") {
+        return Ok(content.into());
+    }
+
+    let bytes = content.as_bytes();
+    let mut expanded = String::with_capacity(content.len());
+    // `content[copied..]` hasn't been pushed to `expanded`
+    let mut copied = 0;
+    // Quote of the string literal that `i` is in
+    let mut quote = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match (quote, bytes[i]) {
+            // Skip the escaped character, which may be a quote
+            (Some(..), b'\\') => i += 2,
+            (Some(q), b) if b == q => {
+                quote = None;
+                i += 1;
+            }
+            (Some(q), b'$') if content[i..].starts_with("${") => {
+                let after = &content[i + 2..];
+                let name_len = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                if name_len == 0 || !after[name_len..].starts_with('}') {
+                    // Not a reference, keep it as is
+                    i += 2;
+                    continue;
+                }
+
+                let var_name = &after[..name_len];
+                let value = env::var(var_name).map_err(|err| {
+                    Error::new(
+                        ErrorKind::Invalid,
+                        "couldn't read environment variable",
+                        Some(format!("`${{{var_name}}}` couldn't be expanded, error: {err}")),
+                    )
+                })?;
+
+                expanded.push_str(&content[copied..i]);
+                for c in value.chars() {
+                    match c {
+                        '\\' => expanded.push_str("\\\\"),
+                        c if c == q as char => {
+                            expanded.push('\\');
+                            expanded.push(c);
+                        }
+                        c if c.is_control() => expanded.push_str(&format!("\\u{:04x}", c as u32)),
+                        c => expanded.push(c),
+                    }
+                }
+
+                i += 2 + name_len + 1;
+                copied = i;
+            }
+            (None, b @ (b'"' | b'\'')) => {
+                quote = Some(b);
+                i += 1;
+            }
+            (None, b'/') if content[i..].starts_with("//") => {
+                i = content[i..].find('\n').map_or(bytes.len(), |n| i + n);
+            }
+            (None, b'/') if content[i..].starts_with("/*") => {
+                i = content[i + 2..].find("*/").map_or(bytes.len(), |n| i + 2 + n + 2);
+            }
+            _ => i += 1,
+        }
+    }
+    expanded.push_str(&content[copied..]);
+
+    Ok(expanded.into())
+}
+
+/// Export all servers in `config` as [SIP002](https://shadowsocks.org/guide/sip002.html) URLs
+///
+/// Plugin and plugin options are encoded in the URL if present. Passwords are NOT redacted, the result is meant to be shared.
+pub fn to_sip002_urls(config: &Config) -> Vec<String> {
+    config.server.iter().map(|s| s.config.to_url()).collect()
+}
+
+#[cfg(all(test, feature = "local"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn config_summary_effective_values() {
+        let config = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_address": "127.0.0.1",
+                "local_port": 1080,
+                "mode": "tcp_only"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+
+        let summary = config_summary(&config);
+        assert_eq!(summary.servers.len(), 1);
+        assert_eq!(summary.servers[0].method, CipherKind::AES_256_GCM);
+        assert!(matches!(summary.servers[0].mode, Mode::TcpOnly));
+        assert_eq!(summary.locals.len(), 1);
+        assert_eq!(summary.locals[0].protocol, ProtocolType::Socks);
+        assert!(matches!(summary.locals[0].mode, Mode::TcpOnly));
+
+        assert_eq!(
+            summary.to_string(),
+            r#"{"servers":[{"addr":"127.0.0.1:8388","method":"aes-256-gcm","mode":"tcp_only"}],"locals":[{"protocol":"socks","mode":"tcp_only","addr":"127.0.0.1:1080"}]}"#
+        );
+    }
+
+    #[test]
+    fn dns_ip_family_exclusive() {
+        let err = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "dns_ipv4_only": true,
+                "dns_ipv6_only": true
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap_err();
+        assert_eq!(err.desc, "`dns_ipv4_only` and `dns_ipv6_only` cannot be both enabled");
+    }
+
+    #[test]
+    fn server_fallback_methods() {
+        let config = Config::load_from_str(
+            r#"{
+                "server": "0.0.0.0",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "fallback_methods": ["aes-128-gcm", "chacha20-ietf-poly1305"]
+            }"#,
+            ConfigType::Server,
+        )
+        .unwrap();
+        let methods = config.server[0].config.fallback_methods().collect::<Vec<_>>();
+        assert_eq!(methods, [CipherKind::AES_128_GCM, CipherKind::CHACHA20_POLY1305]);
+        assert!(config
+            .to_string()
+            .contains(r#""fallback_methods":["aes-128-gcm","chacha20-ietf-poly1305"]"#));
+
+        let err = Config::load_from_str(
+            r#"{
+                "server": "0.0.0.0",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "fallback_methods": ["2022-blake3-aes-128-gcm"]
+            }"#,
+            ConfigType::Server,
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Invalid));
+    }
+
+    #[test]
+    fn invalid_method() {
+        let err = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gmc"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Invalid));
+        assert!(err.to_string().contains("aes-256-gmc"), "{}", err);
+    }
+
+    #[test]
+    fn port_out_of_range() {
+        let err = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 70000,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::JsonParsingError));
+        assert!(err.to_string().contains("70000"), "{}", err);
+
+        let err = Config::load_from_str(
+            r#"{
+                "servers": [
+                    { "server": "127.0.0.1", "server_port": -1, "password": "password", "method": "aes-256-gcm" }
+                ]
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::JsonParsingError));
+
+        let err = Config::load_from_str(
+            r#"{
+                "locals": [{ "local_address": "127.0.0.1", "local_port": 65536 }],
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::JsonParsingError));
+
+        let config = Config::load_from_str(
+            r#"{
+                "local_address": "127.0.0.1",
+                "local_port": 65535,
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+        assert_eq!(config.server[0].config.addr().port(), 8388);
+        assert_eq!(config.local[0].config.addr.as_ref().unwrap().port(), 65535);
+    }
+
+    #[test]
+    fn server_plugin_config() {
+        let config = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "plugin": "v2ray-plugin",
+                "plugin_opts": "mode=quic;host=example.com",
+                "plugin_args": ["-fast-open"],
+                "servers": [
+                    { "server": "127.0.0.1", "server_port": 8389, "password": "password", "method": "aes-256-gcm" }
+                ]
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+        assert_eq!(config.server.len(), 2);
+
+        let plugin = config.server[0].config.plugin().unwrap();
+        assert_eq!(plugin.plugin, "v2ray-plugin");
+        assert_eq!(plugin.plugin_opts.as_deref(), Some("mode=quic;host=example.com"));
+        assert_eq!(plugin.plugin_args, ["-fast-open"]);
+        assert!(matches!(plugin.plugin_mode, Mode::TcpOnly));
+
+        assert!(config.server[1].config.plugin().is_none());
+    }
+
+    #[test]
+    fn export_sip002_urls() {
+        let config = Config::load_from_str(
+            r#"{
+                "servers": [
+                    {
+                        "server": "127.0.0.1",
+                        "server_port": 8388,
+                        "password": "password",
+                        "method": "aes-256-gcm",
+                        "plugin": "v2ray-plugin",
+                        "plugin_opts": "mode=quic;host=example.com"
+                    },
+                    { "server": "127.0.0.1", "server_port": 8389, "password": "password", "method": "aes-256-gcm" }
+                ]
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+
+        let urls = to_sip002_urls(&config);
+        assert_eq!(urls.len(), 2);
+        assert!(urls[0].contains("/?plugin="));
+        assert!(!urls[1].contains("plugin="));
+
+        let parsed = ServerConfig::from_url(&urls[0]).unwrap();
+        assert_eq!(parsed.addr(), config.server[0].config.addr());
+        assert_eq!(parsed.method(), CipherKind::AES_256_GCM);
+        assert_eq!(parsed.password(), "password");
+        let plugin = parsed.plugin().unwrap();
+        assert_eq!(plugin.plugin, "v2ray-plugin");
+        assert_eq!(plugin.plugin_opts.as_deref(), Some("mode=quic;host=example.com"));
+
+        let parsed = ServerConfig::from_url(&urls[1]).unwrap();
+        assert_eq!(parsed.addr(), config.server[1].config.addr());
+        assert!(parsed.plugin().is_none());
+    }
+
+    #[test]
+    fn local_udp_bind_address() {
+        let config = Config::load_from_str(
+            r#"{
+                "locals": [
+                    {
+                        "local_address": "127.0.0.1",
+                        "local_port": 1080,
+                        "local_udp_address": "127.0.0.2",
+                        "local_udp_port": 1081
+                    },
+                    {
+                        "local_address": "127.0.0.1",
+                        "local_port": 1082
+                    }
+                ],
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+
+        let expected = "127.0.0.2:1081".parse::<SocketAddr>().unwrap();
+        assert!(matches!(config.local[0].config.udp_addr, Some(ServerAddr::SocketAddr(a)) if a == expected));
+        // UDP relay binds to the local address by default
+        assert!(config.local[1].config.udp_addr.is_none());
+    }
+
+    #[cfg(feature = "server-list-signature")]
+    #[test]
+    fn signed_server_list() {
+        use base64::{engine::general_purpose::STANDARD, Engine as _};
+        use ring::{
+            rand::SystemRandom,
+            signature::{Ed25519KeyPair, KeyPair},
+        };
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        let list_path = env::temp_dir().join(format!("shadowsocks-server-list-test-{}.json", std::process::id()));
+        let sig_path = PathBuf::from(format!("{}.sig", list_path.display()));
+        let server_list =
+            r#"{"servers":[{"server":"127.0.0.1","server_port":8388,"password":"password","method":"aes-256-gcm"}]}"#;
+        std::fs::write(&list_path, server_list).unwrap();
+
+        let config = serde_json::json!({
+            "local_address": "127.0.0.1",
+            "local_port": 1080,
+            "server_list": list_path,
+            "server_list_public_key": STANDARD.encode(key_pair.public_key().as_ref()),
+        })
+        .to_string();
+
+        let err = Config::load_from_str(&config, ConfigType::Local).unwrap_err();
+        assert_eq!(err.desc, "server list is not signed");
+
+        std::fs::write(&sig_path, STANDARD.encode(key_pair.sign(server_list.as_bytes()))).unwrap();
+        let loaded = Config::load_from_str(&config, ConfigType::Local).unwrap();
+        assert_eq!(loaded.server.len(), 1);
+
+        // Tampered after signing
+        std::fs::write(&list_path, server_list.replace("8388", "8389")).unwrap();
+        let err = Config::load_from_str(&config, ConfigType::Local).unwrap_err();
+        assert_eq!(err.desc, "server list signature verification failed");
+
+        let _ = std::fs::remove_file(list_path);
+        let _ = std::fs::remove_file(sig_path);
+    }
+
+    #[test]
+    fn server_list_relative_to_config_file() {
+        let config_dir = env::temp_dir().join(format!("shadowsocks-server-list-dir-test-{}", std::process::id()));
+        std::fs::create_dir_all(&config_dir).unwrap();
+
+        std::fs::write(
+            config_dir.join("server-list.json"),
+            r#"{"servers":[{"server":"127.0.0.1","server_port":8388,"password":"password","method":"aes-256-gcm"}]}"#,
+        )
+        .unwrap();
+        let config_path = config_dir.join("config.json");
+        std::fs::write(
+            &config_path,
+            r#"{"local_address":"127.0.0.1","local_port":1080,"server_list":"server-list.json"}"#,
+        )
+        .unwrap();
+
+        let loaded = Config::load_from_file(&config_path, ConfigType::Local).unwrap();
+        assert_eq!(loaded.server.len(), 1);
+
+        let _ = std::fs::remove_dir_all(config_dir);
+    }
+
+    #[cfg(feature = "local-http")]
+    #[test]
+    fn local_listen_conflicts() {
+        let load = |locals: &str| {
+            let config = format!(
+                r#"{{
+                    "server": "127.0.0.1",
+                    "server_port": 8388,
+                    "password": "password",
+                    "method": "aes-256-gcm",
+                    "locals": {}
+                }}"#,
+                locals
+            );
+            Config::load_from_str(&config, ConfigType::Local)
+                .unwrap()
+                .check_integrity()
+        };
+
+        load(
+            r#"[{"protocol": "socks", "local_address": "127.0.0.1", "local_port": 1080, "mode": "tcp_and_udp"},
+                 {"protocol": "http", "local_address": "127.0.0.1", "local_port": 1081}]"#,
+        )
+        .unwrap();
+
+        let err = load(
+            r#"[{"protocol": "socks", "local_address": "0.0.0.0", "local_port": 1080},
+                {"protocol": "http", "local_address": "127.0.0.1", "local_port": 1080}]"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.desc, "`locals` have conflicting listen addresses");
+    }
+
+    #[cfg(feature = "local-tunnel")]
+    #[test]
+    fn tunnel_empty_forward_address() {
+        let err = Config::load_from_str(
+            r#"{
+                "locals": [
+                    {
+                        "local_address": "127.0.0.1",
+                        "local_port": 5353,
+                        "protocol": "tunnel",
+                        "forward_address": "",
+                        "forward_port": 53
+                    }
+                ],
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap_err();
+        assert_eq!(err.desc, "`forward_address` cannot be empty");
+    }
+
+    #[cfg(feature = "local-tunnel")]
+    #[test]
+    fn tunnel_multiple_forward_addresses() {
+        fn load(locals: &str) -> Result<Config, Error> {
+            Config::load_from_str(
+                &format!(
+                    r#"{{
+                        "locals": {locals},
+                        "server": "127.0.0.1",
+                        "server_port": 8388,
+                        "password": "password",
+                        "method": "aes-256-gcm"
+                    }}"#
+                ),
+                ConfigType::Local,
+            )
+        }
+
+        let config = load(
+            r#"[{
+                "local_address": "127.0.0.1",
+                "local_port": 5353,
+                "protocol": "tunnel",
+                "forward_address": "8.8.8.8",
+                "forward_port": 53,
+                "forward_addresses": ["8.8.4.4:53", "dns.example.com:5353"]
+            }]"#,
+        )
+        .unwrap();
+        let local = &config.local[0].config;
+        assert_eq!(local.forward_addr, Some("8.8.8.8:53".parse::<Address>().unwrap()));
+        assert_eq!(
+            local.forward_addrs,
+            [
+                "8.8.4.4:53".parse::<Address>().unwrap(),
+                Address::DomainNameAddress("dns.example.com".to_owned(), 5353),
+            ]
+        );
+
+        // Serialized config loads the same addresses
+        let reloaded = Config::load_from_str(&config.to_string(), ConfigType::Local).unwrap();
+        assert_eq!(reloaded.local[0].config.forward_addrs, local.forward_addrs);
+
+        // `forward_address` is not required with `forward_addresses`
+        let config = load(
+            r#"[{
+                "local_address": "127.0.0.1",
+                "local_port": 5353,
+                "protocol": "tunnel",
+                "forward_addresses": ["8.8.4.4:53"]
+            }]"#,
+        )
+        .unwrap();
+        assert!(config.local[0].config.forward_addr.is_none());
+        assert_eq!(config.local[0].config.forward_addrs.len(), 1);
+
+        for invalid in [r#""dns.example.com""#, r#""8.8.4.4:0""#, r#""8.8.4.4:65536""#] {
+            let err = load(&format!(
+                r#"[{{
+                    "local_address": "127.0.0.1",
+                    "local_port": 5353,
+                    "protocol": "tunnel",
+                    "forward_addresses": [{invalid}]
+                }}]"#
+            ))
+            .unwrap_err();
+            assert_eq!(err.desc, "`forward_addresses` invalid", "{invalid}");
+        }
+    }
+
+    #[cfg(feature = "local-tun")]
+    #[test]
+    fn tun_interface_mtu() {
+        fn load(mtu: u16) -> Result<Config, Error> {
+            Config::load_from_str(
+                &format!(
+                    r#"{{
+                        "locals": [
+                            {{
+                                "protocol": "tun",
+                                "tun_interface_address": "10.255.0.1/24",
+                                "tun_interface_mtu": {mtu}
+                            }}
+                        ],
+                        "server": "127.0.0.1",
+                        "server_port": 8388,
+                        "password": "password",
+                        "method": "aes-256-gcm"
+                    }}"#
+                ),
+                ConfigType::Local,
+            )
+        }
+
+        let config = load(1400).unwrap();
+        assert_eq!(config.local[0].config.tun_interface_mtu, Some(1400));
+
+        let config = load(576).unwrap();
+        assert_eq!(config.local[0].config.tun_interface_mtu, Some(576));
+
+        let err = load(575).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::Invalid));
+    }
+
+    #[tokio::test]
+    async fn load_multiple_local_address() {
+        let err = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_address": [],
+                "local_port": 1080
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap_err();
+        assert_eq!(err.desc, "`local_address` cannot be empty");
+
+        let err = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_address": ["127.0.0.1", "::1"],
+                "local_port": 0
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap_err();
+        assert_eq!(err.desc, "`local_port` cannot be 0 with multiple `local_address`");
+
+        // A port that is free on both loopback addresses, most probably
+        let port = match std::net::TcpListener::bind("[::1]:0") {
+            Ok(listener) => listener.local_addr().unwrap().port(),
+            // IPv6 is not available
+            Err(..) => return,
+        };
+        let config = format!(
+            r#"{{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_address": ["127.0.0.1", "::1"],
+                "local_port": {port}
+            }}"#
+        );
+        let config = Config::load_from_str(&config, ConfigType::Local).unwrap();
+        let addrs = config
+            .local
+            .iter()
+            .map(|local| local.config.addr.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addrs,
+            [
+                ServerAddr::from(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+                ServerAddr::from(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port)),
+            ]
+        );
+
+        let server = crate::local::Server::new(config).await.unwrap();
+        let listen_addrs = server
+            .socks_servers()
+            .iter()
+            .map(|socks| socks.tcp_server().unwrap().local_addr().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(listen_addrs.len(), 2);
+        assert!(listen_addrs.iter().all(|addr| addr.port() == port));
+    }
+
+    #[test]
+    fn validate_dns_name_servers() {
+        for dns in [
+            "google",
+            "cloudflare",
+            "8.8.8.8",
+            "8.8.8.8:5353",
+            "[2001:4860:4860::8888]:53",
+            "2001:4860:4860::8888",
+            "tcp://8.8.8.8,1.1.1.1:53",
+            "udp://8.8.8.8",
+            "unix:///var/run/dns.sock",
+        ] {
+            assert!(validate_name_server_addr(dns).is_ok(), "{}", dns);
+        }
+
+        for dns in [
+            "",
+            "dns.google",
+            "8.8.8.8:port",
+            "8.8.8.8:65536",
+            "8.8.8.8,",
+            "tcp://",
+            "https://8.8.8.8",
+            "unix://",
+        ] {
+            let err = validate_name_server_addr(dns).unwrap_err();
+            assert!(matches!(err.kind, ErrorKind::Invalid), "{}", dns);
+        }
+    }
+
+    #[test]
+    fn socket_buffer_size_bounds() {
+        assert_eq!(clamp_socket_buffer_size("test", 0), MIN_SOCKET_BUFFER_SIZE);
+        assert_eq!(clamp_socket_buffer_size("test", 1024), MIN_SOCKET_BUFFER_SIZE);
+        assert_eq!(clamp_socket_buffer_size("test", 256 * 1024), 256 * 1024);
+        assert_eq!(
+            clamp_socket_buffer_size("test", MAX_SOCKET_BUFFER_SIZE),
+            MAX_SOCKET_BUFFER_SIZE
+        );
+        assert_eq!(clamp_socket_buffer_size("test", u32::MAX), MAX_SOCKET_BUFFER_SIZE);
+    }
+
+    #[tokio::test]
+    async fn socket_buffer_size_clamped_when_applied() {
+        let mut config = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_address": "127.0.0.1",
+                "local_port": 0
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+        // Public fields are not checked when they are set
+        config.inbound_send_buffer_size = Some(1);
+        config.inbound_recv_buffer_size = Some(u32::MAX);
+        config.outbound_send_buffer_size = Some(u32::MAX);
+        config.outbound_recv_buffer_size = Some(256 * 1024);
+
+        let server = crate::local::Server::new(config).await.unwrap();
+        let context = server.server_balancer().context();
+        let connect_opts = context.connect_opts_ref();
+        assert_eq!(connect_opts.tcp.send_buffer_size, Some(MAX_SOCKET_BUFFER_SIZE));
+        assert_eq!(connect_opts.tcp.recv_buffer_size, Some(256 * 1024));
+        let accept_opts = context.accept_opts();
+        assert_eq!(accept_opts.tcp.send_buffer_size, Some(MIN_SOCKET_BUFFER_SIZE));
+        assert_eq!(accept_opts.tcp.recv_buffer_size, Some(MAX_SOCKET_BUFFER_SIZE));
+    }
+
+    #[cfg(feature = "local-http")]
+    #[tokio::test]
+    async fn load_multiple_local_protocols() {
+        let config = Config::load_from_str(
+            r#"{
+                "locals": [
+                    { "local_address": "127.0.0.1", "local_port": 0 },
+                    { "local_address": "127.0.0.1", "local_port": 0, "protocol": "http" }
+                ],
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+        assert!(matches!(config.local[0].config.protocol, ProtocolType::Socks));
+        assert!(matches!(config.local[1].config.protocol, ProtocolType::Http));
+
+        // Each entry is launched as its own listener
+        let server = crate::local::Server::new(config).await.unwrap();
+        assert_eq!(server.socks_servers().len(), 1);
+        assert_eq!(server.http_servers().len(), 1);
+
+        let socks_addr = server.socks_servers()[0].tcp_server().unwrap().local_addr().unwrap();
+        let http_addr = server.http_servers()[0].local_addr().unwrap();
+        assert_ne!(socks_addr, http_addr);
+    }
+
+    #[tokio::test]
+    async fn outbound_bind_connect_opts() {
+        let config = Config::load_from_str(
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_address": "127.0.0.1",
+                "local_port": 0,
+                "outbound_bind_interface": "lo",
+                "outbound_bind_addr": "127.0.0.1"
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+        assert_eq!(config.outbound_bind_interface.as_deref(), Some("lo"));
+        assert_eq!(config.outbound_bind_addr, Some(Ipv4Addr::LOCALHOST.into()));
+
+        let server = crate::local::Server::new(config).await.unwrap();
+        let context = server.server_balancer().context();
+        let connect_opts = context.connect_opts_ref();
+        assert_eq!(connect_opts.bind_interface.as_deref(), Some("lo"));
+        assert_eq!(connect_opts.bind_local_addr, Some(Ipv4Addr::LOCALHOST.into()));
+    }
+
+    #[test]
+    fn check_file_reports_all_errors() {
+        let dir = std::env::temp_dir().join(format!("ss-check-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json");
+        std::fs::write(
+            &config_path,
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 0,
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_port": 1080,
+                "balancer": {
+                    "check_interval": 0
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let errors = Config::check_file(&config_path, ConfigType::Local).unwrap_err();
+        let descs = errors.iter().map(|err| err.desc).collect::<Vec<_>>();
+        assert_eq!(
+            descs,
+            ["balancer.check_interval must be > 0", "`server_port` shouldn't be 0"]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_from_file_env_variables() {
+        let dir = std::env::temp_dir().join(format!("ss-env-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json");
+        std::fs::write(
+            &config_path,
+            r#"{
+                "server": "127.0.0.1",
+                "server_port": 8388,
+                // "password": "${SS_TEST_ENV_UNDEFINED_OLD_PASSWORD}",
+                /* "password": "${SS_TEST_ENV_UNDEFINED_OLD_PASSWORD}", */
+                "password": "${SS_TEST_ENV_PASSWORD}",
+                "method": "aes-256-gcm",
+                "local_port": 1080
+            }"#,
+        )
+        .unwrap();
+
+        env::set_var("SS_TEST_ENV_PASSWORD", r#"pass"word\"#);
+        let config = Config::load_from_file(&config_path, ConfigType::Local).unwrap();
+        assert_eq!(config.server[0].config.password(), r#"pass"word\"#);
+
+        // Values are not expanded again
+        env::set_var("SS_TEST_ENV_PASSWORD", "${SS_TEST_ENV_NESTED_PASSWORD}");
+        env::set_var("SS_TEST_ENV_NESTED_PASSWORD", "nested");
+        let config = Config::load_from_file(&config_path, ConfigType::Local).unwrap();
+        assert_eq!(config.server[0].config.password(), "${SS_TEST_ENV_NESTED_PASSWORD}");
+        env::remove_var("SS_TEST_ENV_NESTED_PASSWORD");
+
+        env::remove_var("SS_TEST_ENV_PASSWORD");
+        let err = Config::load_from_file(&config_path, ConfigType::Local).unwrap_err();
+        assert!(err.to_string().contains("SS_TEST_ENV_PASSWORD"));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn load_from_missing_file() {
+        let config_path = std::env::temp_dir().join(format!("ss-missing-config-{}.json", std::process::id()));
+        let err = Config::load_from_file(&config_path, ConfigType::Local).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::IoError));
+    }
+}
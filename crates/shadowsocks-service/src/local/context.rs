@@ -1,9 +1,19 @@
 //! Shadowsocks Local Server Context
 
-use std::sync::Arc;
 #[cfg(feature = "local-dns")]
-use std::{net::IpAddr, time::Duration};
+use std::net::IpAddr;
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+use arc_swap::ArcSwapOption;
 #[cfg(feature = "local-dns")]
 use lru_time_cache::LruCache;
 use shadowsocks::{
@@ -13,10 +23,60 @@ use shadowsocks::{
     net::{AcceptOpts, ConnectOpts},
     relay::Address,
 };
+use spin::Mutex as SpinMutex;
 #[cfg(feature = "local-dns")]
 use tokio::sync::Mutex;
 
-use crate::{acl::AccessControl, config::SecurityConfig, net::FlowStat};
+#[cfg(feature = "local-dns")]
+use crate::net::utils::normalize_ip_addr;
+use crate::{
+    acl::AccessControl,
+    config::SecurityConfig,
+    net::{
+        AccessLog,
+        ConcurrencyLimit,
+        ConnectionLogSampler,
+        DestinationFlowStat,
+        FlowStat,
+        PlaintextCapture,
+        RetryBudget,
+    },
+};
+
+/// Default limit of in-progress connection establishments, high enough for normal usage
+const DEFAULT_CONNECT_CONCURRENCY: usize = 256;
+
+/// Type of the host's network, provided by hosts that know about network transitions (like mobile apps)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NetworkType {
+    /// Not provided by the host
+    #[default]
+    Unknown,
+    /// Wi-Fi
+    Wifi,
+    /// Cellular
+    Cellular,
+    /// Wired ethernet
+    Ethernet,
+}
+
+impl NetworkType {
+    /// As string representation
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            NetworkType::Unknown => "unknown",
+            NetworkType::Wifi => "wifi",
+            NetworkType::Cellular => "cellular",
+            NetworkType::Ethernet => "ethernet",
+        }
+    }
+}
+
+impl Display for NetworkType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
 /// Local Service Context
 #[derive(Clone)]
@@ -25,12 +85,51 @@ pub struct ServiceContext {
     connect_opts: ConnectOpts,
     accept_opts: AcceptOpts,
 
-    // Access Control
-    acl: Option<Arc<AccessControl>>,
+    // Access Control, shared with cloned contexts until `set_acl` is called, so reloading affects all of them
+    acl: Arc<ArcSwapOption<AccessControl>>,
+    // Number of times that ACLs have been reloaded
+    acl_generation: Arc<AtomicU64>,
 
     // Flow statistic report
     flow_stat: Arc<FlowStat>,
 
+    // Flow statistic of recently used destination hosts
+    destination_flow_stat: Arc<DestinationFlowStat>,
+
+    // Concurrency limit of TCP relays, shared between all local instances
+    relay_concurrency: ConcurrencyLimit,
+
+    // Concurrency limit of in-progress connection establishments
+    connect_concurrency: ConcurrencyLimit,
+
+    // Budget of retries across all connections
+    retry_budget: RetryBudget,
+
+    // Sampling connections' lifecycle logs
+    connection_log_sampler: Arc<ConnectionLogSampler>,
+
+    // Access log of closed TCP tunnels
+    access_log: Option<Arc<AccessLog>>,
+
+    // Capture of decrypted streams to one target, for debugging
+    plaintext_capture: Option<Arc<PlaintextCapture>>,
+
+    // Maximum random delay before connecting to remote servers
+    connect_jitter: Duration,
+
+    // Timeout for connecting to targets or remote servers
+    connect_timeout: Option<Duration>,
+
+    // Log remote servers chosen for proxied TCP connections
+    log_selected_server: bool,
+
+    // Hostnames of proxied targets are resolved by remote servers only
+    proxy_dns: bool,
+
+    // Current network type of the host, and the number of times it has changed
+    network_type: Arc<SpinMutex<NetworkType>>,
+    network_generation: Arc<AtomicU64>,
+
     // For DNS relay's ACL domain name reverse lookup -- whether the IP shall be forwarded
     #[cfg(feature = "local-dns")]
     reverse_lookup_cache: Arc<Mutex<LruCache<IpAddr, bool>>>,
@@ -49,8 +148,22 @@ impl ServiceContext {
             context: Context::new_shared(ServerType::Local),
             connect_opts: ConnectOpts::default(),
             accept_opts: AcceptOpts::default(),
-            acl: None,
+            acl: Arc::new(ArcSwapOption::empty()),
+            acl_generation: Arc::new(AtomicU64::new(0)),
             flow_stat: Arc::new(FlowStat::new()),
+            destination_flow_stat: Arc::new(DestinationFlowStat::default()),
+            relay_concurrency: ConcurrencyLimit::default(),
+            connect_concurrency: ConcurrencyLimit::new(Some(DEFAULT_CONNECT_CONCURRENCY)),
+            retry_budget: RetryBudget::default(),
+            connection_log_sampler: Arc::new(ConnectionLogSampler::default()),
+            access_log: None,
+            plaintext_capture: None,
+            connect_jitter: Duration::ZERO,
+            connect_timeout: None,
+            log_selected_server: false,
+            proxy_dns: false,
+            network_type: Arc::new(SpinMutex::new(NetworkType::Unknown)),
+            network_generation: Arc::new(AtomicU64::new(0)),
             #[cfg(feature = "local-dns")]
             reverse_lookup_cache: Arc::new(Mutex::new(LruCache::with_expiry_duration_and_capacity(
                 Duration::from_secs(3 * 24 * 60 * 60),
@@ -90,13 +203,43 @@ impl ServiceContext {
     }
 
     /// Set Access Control List
+    ///
+    /// Contexts cloned from this context before calling this method won't see the new ACL.
     pub fn set_acl(&mut self, acl: Arc<AccessControl>) {
-        self.acl = Some(acl);
+        self.acl = Arc::new(ArcSwapOption::new(Some(acl)));
+    }
+
+    /// Get Access Control List
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.load_full()
     }
 
-    /// Get Access Control List reference
-    pub fn acl(&self) -> Option<&AccessControl> {
-        self.acl.as_deref()
+    /// Replace Access Control List with rules loaded from `path`
+    ///
+    /// The new ACL is shared with all contexts that share the current ACL. Checks that are in progress finish with
+    /// the previous ACL.
+    pub async fn reload_acl(&self, path: &Path) -> io::Result<()> {
+        let path = path.to_owned();
+        let acl = tokio::task::spawn_blocking(move || AccessControl::load_from_file(path)).await??;
+        self.replace_acl(Arc::new(acl)).await;
+        Ok(())
+    }
+
+    /// Replace Access Control List with `acl`, see `reload_acl`
+    ///
+    /// DNS relays drop their cached answers before serving the next query.
+    pub async fn replace_acl(&self, acl: Arc<AccessControl>) {
+        self.acl.store(Some(acl));
+        self.acl_generation.fetch_add(1, Ordering::AcqRel);
+
+        // Exceptions are recorded against the previous ACL
+        #[cfg(feature = "local-dns")]
+        self.reverse_lookup_cache.lock().await.clear();
+    }
+
+    /// Number of times that ACLs have been replaced, for dropping states decided by the previous ACL
+    pub fn acl_generation(&self) -> u64 {
+        self.acl_generation.load(Ordering::Acquire)
     }
 
     /// Get cloned flow statistic
@@ -109,6 +252,109 @@ impl ServiceContext {
         self.flow_stat.as_ref()
     }
 
+    /// Get cloned flow statistic of destination hosts
+    pub fn destination_flow_stat(&self) -> Arc<DestinationFlowStat> {
+        self.destination_flow_stat.clone()
+    }
+
+    /// Get flow statistic of destination hosts reference
+    pub fn destination_flow_stat_ref(&self) -> &DestinationFlowStat {
+        self.destination_flow_stat.as_ref()
+    }
+
+    /// Get TCP relay's concurrency limit
+    pub fn relay_concurrency(&self) -> &ConcurrencyLimit {
+        &self.relay_concurrency
+    }
+
+    /// Get concurrency limit of in-progress connection establishments, which is 256 by default
+    ///
+    /// Permits are held only while connecting to targets or remote servers.
+    pub fn connect_concurrency(&self) -> &ConcurrencyLimit {
+        &self.connect_concurrency
+    }
+
+    /// Get budget of retries across all connections, which is unlimited by default
+    ///
+    /// DNS query attempts and UDP associations reconnecting to servers take tokens from it.
+    pub fn retry_budget(&self) -> &RetryBudget {
+        &self.retry_budget
+    }
+
+    /// Get connection lifecycle log sampler
+    pub fn connection_log_sampler(&self) -> &ConnectionLogSampler {
+        &self.connection_log_sampler
+    }
+
+    /// Set access log, which records closed TCP tunnels
+    pub fn set_access_log(&mut self, access_log: Arc<AccessLog>) {
+        self.access_log = Some(access_log);
+    }
+
+    /// Get access log
+    pub fn access_log(&self) -> Option<&AccessLog> {
+        self.access_log.as_deref()
+    }
+
+    /// Set plaintext capture, which writes DECRYPTED bytes of TCP tunnels to its target
+    pub fn set_plaintext_capture(&mut self, capture: Arc<PlaintextCapture>) {
+        self.plaintext_capture = Some(capture);
+    }
+
+    /// Get plaintext capture
+    pub fn plaintext_capture(&self) -> Option<&PlaintextCapture> {
+        self.plaintext_capture.as_deref()
+    }
+
+    /// Set maximum random delay before connecting to remote servers, `Duration::ZERO` disables it
+    pub fn set_connect_jitter(&mut self, jitter: Duration) {
+        self.connect_jitter = jitter;
+    }
+
+    /// Get maximum random delay before connecting to remote servers
+    pub fn connect_jitter(&self) -> Duration {
+        self.connect_jitter
+    }
+
+    /// Set timeout for connecting to targets or remote servers, `None` for OS's default
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Get timeout for connecting to targets or remote servers
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// Log the remote server chosen for each proxied TCP connection at `info` level
+    ///
+    /// Connections are chosen by `connection_log_sampler` if its sample rate is set.
+    pub fn set_log_selected_server(&mut self, log_selected_server: bool) {
+        self.log_selected_server = log_selected_server;
+    }
+
+    /// Check if the remote server chosen for a proxied TCP connection should be logged
+    ///
+    /// `sampled` is the decision of `connection_log_sampler` for the connection, which should be sampled only once
+    /// so that its lifecycle logs and this log are chosen together.
+    pub fn should_log_selected_server(&self, sampled: bool) -> bool {
+        self.log_selected_server && (self.connection_log_sampler().rate() == 0 || sampled)
+    }
+
+    /// Resolve hostnames of proxied targets by remote servers only
+    ///
+    /// ACL won't resolve hostnames locally for matching IP rules, hostnames that are not matched by domain rules
+    /// follow the default mode. So does the DNS relay, which won't query the local resolver for deciding by IP rules.
+    /// Bypassed targets and servers' hostnames are still resolved by the local resolver.
+    pub fn set_proxy_dns(&mut self, proxy_dns: bool) {
+        self.proxy_dns = proxy_dns;
+    }
+
+    /// Check if hostnames of proxied targets are resolved by remote servers only
+    pub fn proxy_dns(&self) -> bool {
+        self.proxy_dns
+    }
+
     /// Set customized DNS resolver
     pub fn set_dns_resolver(&mut self, resolver: Arc<DnsResolver>) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set DNS resolver on a shared context");
@@ -122,21 +368,31 @@ impl ServiceContext {
 
     /// Check if target should be bypassed
     pub async fn check_target_bypassed(&self, addr: &Address) -> bool {
-        match self.acl {
+        match self.acl.load_full() {
             None => false,
-            Some(ref acl) => {
+            Some(acl) => {
                 #[cfg(feature = "local-dns")]
                 {
                     if let Address::SocketAddress(ref saddr) = addr {
                         // do the reverse lookup in our local cache
                         let mut reverse_lookup_cache = self.reverse_lookup_cache.lock().await;
                         // if a qname is found
-                        if let Some(forward) = reverse_lookup_cache.get(&saddr.ip()) {
+                        if let Some(forward) = reverse_lookup_cache.get(&normalize_ip_addr(saddr.ip())) {
                             return !*forward;
                         }
                     }
                 }
 
+                if self.proxy_dns {
+                    if let Address::DomainNameAddress(ref host, ..) = addr {
+                        // Don't leak hostnames to the local resolver, remote servers will resolve them
+                        return match acl.check_host_in_proxy_list(host) {
+                            Some(value) => !value,
+                            None => !acl.is_default_in_proxy_list(),
+                        };
+                    }
+                }
+
                 acl.check_target_bypassed(&self.context, addr).await
             }
         }
@@ -146,7 +402,7 @@ impl ServiceContext {
     #[cfg(feature = "local-dns")]
     pub async fn add_to_reverse_lookup_cache(&self, addr: IpAddr, forward: bool) {
         let is_exception = forward
-            != match self.acl {
+            != match *self.acl.load() {
                 // Proxy everything by default
                 None => true,
                 Some(ref a) => a.check_ip_in_proxy_list(&addr),
@@ -169,15 +425,145 @@ impl ServiceContext {
         }
     }
 
+    /// Record a transition of the host's network, returns the previous network type
+    ///
+    /// Cached DNS records are dropped immediately, DNS relays drop their cached answers and connections
+    /// before serving the next query. UDP associations drop their outbound sockets within a second.
+    pub async fn set_network_type(&self, net_type: NetworkType) -> NetworkType {
+        let prev_type = {
+            let mut network_type = self.network_type.lock();
+            std::mem::replace(&mut *network_type, net_type)
+        };
+        self.network_generation.fetch_add(1, Ordering::AcqRel);
+
+        self.context.dns_resolver().clear_cache();
+        #[cfg(feature = "local-dns")]
+        self.reverse_lookup_cache.lock().await.clear();
+
+        prev_type
+    }
+
+    /// Current network type of the host
+    pub fn network_type(&self) -> NetworkType {
+        *self.network_type.lock()
+    }
+
+    /// Number of times that the host's network has changed, for dropping states bound to the previous network
+    pub fn network_generation(&self) -> u64 {
+        self.network_generation.load(Ordering::Acquire)
+    }
+
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
     pub fn set_ipv6_first(&mut self, ipv6_first: bool) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set ipv6_first on a shared context");
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Use only IPv4 addresses of resolved hostnames
+    pub fn set_dns_ipv4_only(&mut self, dns_ipv4_only: bool) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set dns_ipv4_only on a shared context");
+        context.set_dns_ipv4_only(dns_ipv4_only);
+    }
+
+    /// Use only IPv6 addresses of resolved hostnames
+    pub fn set_dns_ipv6_only(&mut self, dns_ipv6_only: bool) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set dns_ipv6_only on a shared context");
+        context.set_dns_ipv6_only(dns_ipv6_only);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
         context.set_replay_attack_policy(security.replay_attack.policy);
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(diff) = security.replay_attack.max_timestamp_diff {
+            context.set_aead_2022_max_timestamp_diff(diff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn proxy_dns_skips_local_resolve() {
+        let path = env::temp_dir().join(format!("shadowsocks-proxy-dns-test-{}.acl", process::id()));
+        fs::write(
+            &path,
+            "[proxy_all]\n[bypass_list]\n127.0.0.0/8\n::1/128\n||bypass.example\n",
+        )
+        .unwrap();
+        let acl = AccessControl::load_from_file(&path).unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut context = ServiceContext::new();
+        context.set_acl(Arc::new(acl));
+
+        let localhost = Address::DomainNameAddress("localhost".to_owned(), 80);
+        let bypass_domain = Address::DomainNameAddress("www.bypass.example".to_owned(), 80);
+
+        // localhost is resolved to the loopback address, which matches the IP rules
+        assert!(context.check_target_bypassed(&localhost).await);
+
+        context.set_proxy_dns(true);
+        assert!(!context.check_target_bypassed(&localhost).await);
+        assert!(context.check_target_bypassed(&bypass_domain).await);
+    }
+
+    #[tokio::test]
+    async fn reload_acl_bypasses_host() {
+        let path = env::temp_dir().join(format!("shadowsocks-reload-acl-test-{}.acl", process::id()));
+        fs::write(&path, "[proxy_all]\n[bypass_list]\n||bypass.example\n").unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_acl(Arc::new(AccessControl::load_from_file(&path).unwrap()));
+        context.set_proxy_dns(true);
+        // Instances' contexts are cloned from the global context
+        let instance_context = context.clone();
+
+        let target = Address::DomainNameAddress("www.reload.example".to_owned(), 80);
+        assert!(!instance_context.check_target_bypassed(&target).await);
+
+        fs::write(
+            &path,
+            "[proxy_all]\n[bypass_list]\n||bypass.example\n||reload.example\n",
+        )
+        .unwrap();
+        context.reload_acl(&path).await.unwrap();
+        assert!(instance_context.check_target_bypassed(&target).await);
+        assert_eq!(instance_context.acl_generation(), 1);
+
+        // Failing to load keeps the current ACL
+        let _ = fs::remove_file(&path);
+        assert!(context.reload_acl(&path).await.is_err());
+        assert!(instance_context.check_target_bypassed(&target).await);
+    }
+
+    #[tokio::test]
+    async fn network_type_transition() {
+        let context = ServiceContext::new();
+        assert_eq!(context.network_type(), NetworkType::Unknown);
+        assert_eq!(context.network_generation(), 0);
+
+        assert_eq!(context.set_network_type(NetworkType::Wifi).await, NetworkType::Unknown);
+        assert_eq!(context.set_network_type(NetworkType::Cellular).await, NetworkType::Wifi);
+        assert_eq!(context.network_type(), NetworkType::Cellular);
+        assert_eq!(context.network_generation(), 2);
+    }
+
+    #[test]
+    fn log_selected_server_respects_sampler() {
+        let mut context = ServiceContext::new();
+        assert!(!context.should_log_selected_server(true));
+
+        context.set_log_selected_server(true);
+        assert!(context.should_log_selected_server(false));
+
+        context.connection_log_sampler().set_rate(2);
+        assert!(context.should_log_selected_server(true));
+        assert!(!context.should_log_selected_server(false));
     }
 }
@@ -0,0 +1,267 @@
+//! DNS answer cache
+//!
+//! Responses are cached with the forwarding decision made by ACL, so that repeated queries are answered with the
+//! same records, which are also used by the reverse lookup cache for routing targets.
+
+use std::time::Duration;
+
+use hickory_resolver::proto::{
+    op::{
+        message::{update_header_counts, HeaderCounts},
+        response_code::ResponseCode,
+        Message,
+        Query,
+    },
+    rr::{DNSClass, Name, RData, Record, RecordType},
+};
+use lru_time_cache::LruCache;
+use tokio::{sync::Mutex, time::Instant};
+
+/// Maximum time that a positive answer could be cached
+const MAX_POSITIVE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Maximum time that a negative answer (NXDOMAIN or NODATA) could be cached
+///
+/// RFC 2308 suggests a few hours, but a shorter one is friendlier for names that are going to be created
+const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(5 * 60);
+/// Time of caching a negative answer that has no SOA record in the authority section
+const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
+struct AnswerCacheKey {
+    name: Name,
+    query_type: RecordType,
+    query_class: DNSClass,
+}
+
+impl From<&Query> for AnswerCacheKey {
+    fn from(query: &Query) -> AnswerCacheKey {
+        AnswerCacheKey {
+            // Names are case insensitive
+            name: query.name().to_lowercase(),
+            query_type: query.query_type(),
+            query_class: query.query_class(),
+        }
+    }
+}
+
+struct AnswerCacheEntry {
+    message: Message,
+    forward: bool,
+    cached_at: Instant,
+    expire_at: Instant,
+}
+
+/// Cache of DNS responses, keyed by queries
+pub struct DnsAnswerCache {
+    cache: Mutex<LruCache<AnswerCacheKey, AnswerCacheEntry>>,
+}
+
+impl DnsAnswerCache {
+    /// Create a cache that holds at most `capacity` answers
+    pub fn new(capacity: usize) -> DnsAnswerCache {
+        DnsAnswerCache {
+            cache: Mutex::new(LruCache::with_capacity(capacity)),
+        }
+    }
+
+    /// Get the cached response of `query`, and whether it was resolved by the remote DNS
+    ///
+    /// TTLs of records are decreased by the time that they have been cached. Names are rewritten to the case of
+    /// `query`, because responses are shared by queries that differ only in case.
+    pub async fn get(&self, query: &Query) -> Option<(Message, bool)> {
+        let key = AnswerCacheKey::from(query);
+        let now = Instant::now();
+
+        let mut cache = self.cache.lock().await;
+        let (mut message, forward, elapsed) = match cache.get(&key) {
+            Some(entry) if entry.expire_at > now => (
+                entry.message.clone(),
+                entry.forward,
+                now.duration_since(entry.cached_at),
+            ),
+            Some(..) => {
+                cache.remove(&key);
+                return None;
+            }
+            None => return None,
+        };
+        drop(cache);
+
+        let elapsed = elapsed.as_secs().min(u32::MAX as u64) as u32;
+        let decrease_ttl = |record: &mut Record| {
+            let ttl = record.ttl().saturating_sub(elapsed);
+            record.set_ttl(ttl);
+        };
+        message.answers_mut().iter_mut().for_each(decrease_ttl);
+        message.name_servers_mut().iter_mut().for_each(decrease_ttl);
+
+        // Some clients match responses by the exact name that they asked, e.g. with DNS 0x20 case randomization
+        *message.queries_mut() = vec![query.clone()];
+        for record in message.answers_mut() {
+            // Name's equality is case insensitive
+            if record.name() == query.name() {
+                record.set_name(query.name().clone());
+            }
+        }
+        let header = update_header_counts(
+            message.header(),
+            message.truncated(),
+            HeaderCounts {
+                query_count: message.queries().len(),
+                answer_count: message.answers().len(),
+                nameserver_count: message.name_servers().len(),
+                additional_count: message.additionals().len(),
+            },
+        );
+        message.set_header(header);
+
+        Some((message, forward))
+    }
+
+    /// Cache `message`, which is the response of `query`
+    ///
+    /// Only successful answers and negative answers (NXDOMAIN or NODATA) are cached, other failures will be retried
+    /// by the next query.
+    pub async fn insert(&self, query: &Query, message: &Message, forward: bool) {
+        let ttl = match cache_ttl(message) {
+            Some(ttl) if !ttl.is_zero() => ttl,
+            _ => return,
+        };
+
+        let now = Instant::now();
+        let entry = AnswerCacheEntry {
+            message: message.clone(),
+            forward,
+            cached_at: now,
+            expire_at: now + ttl,
+        };
+
+        let mut cache = self.cache.lock().await;
+        cache.insert(AnswerCacheKey::from(query), entry);
+    }
+
+    /// Drop all cached answers
+    pub async fn clear(&self) {
+        self.cache.lock().await.clear();
+    }
+}
+
+fn cache_ttl(message: &Message) -> Option<Duration> {
+    match message.response_code() {
+        ResponseCode::NoError if !message.answers().is_empty() => {
+            let ttl = message.answers().iter().map(|r| r.ttl()).min().unwrap_or(0);
+            Some(Duration::from_secs(ttl as u64).min(MAX_POSITIVE_TTL))
+        }
+        ResponseCode::NoError | ResponseCode::NXDomain => Some(negative_ttl(message)),
+        _ => None,
+    }
+}
+
+fn negative_ttl(message: &Message) -> Duration {
+    // RFC 2308, Section 5. TTL of a negative answer is the minimum of SOA's TTL and SOA.MINIMUM
+    let soa_ttl = message.name_servers().iter().find_map(|r| match r.data() {
+        Some(RData::SOA(soa)) => Some(r.ttl().min(soa.minimum())),
+        _ => None,
+    });
+
+    match soa_ttl {
+        Some(ttl) => Duration::from_secs(ttl as u64).min(MAX_NEGATIVE_TTL),
+        None => DEFAULT_NEGATIVE_TTL,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use hickory_resolver::proto::rr::rdata::{A, SOA};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn answer_cache_positive_and_negative() {
+        let cache = DnsAnswerCache::new(16);
+
+        let name = Name::from_str("www.example.com.").unwrap();
+        let query = Query::query(name.clone(), RecordType::A);
+
+        let mut response = Message::new();
+        response.add_query(query.clone());
+        response.add_answer(Record::from_rdata(
+            name.clone(),
+            300,
+            RData::A(A::from(Ipv4Addr::new(127, 0, 0, 1))),
+        ));
+        cache.insert(&query, &response, true).await;
+
+        // Names are case insensitive, and answered in the case of the query
+        let upper_query = Query::query(Name::from_ascii("WWW.Example.COM.").unwrap(), RecordType::A);
+        let (cached, forward) = cache.get(&upper_query).await.unwrap();
+        assert!(forward);
+        assert_eq!(cached.answers(), response.answers());
+        assert_eq!(cached.query_count(), 1);
+        assert_eq!(cached.answer_count(), 1);
+        assert_eq!(cached.queries()[0].name().to_string(), "WWW.Example.COM.");
+        assert_eq!(cached.answers()[0].name().to_string(), "WWW.Example.COM.");
+
+        let (cached, _) = cache.get(&query).await.unwrap();
+        assert_eq!(cached.queries()[0].name().to_string(), "www.example.com.");
+        assert_eq!(cached.answers()[0].name().to_string(), "www.example.com.");
+
+        // NXDOMAIN is cached by SOA's MINIMUM
+        let nx_name = Name::from_str("nx.example.com.").unwrap();
+        let nx_query = Query::query(nx_name, RecordType::A);
+        let mut nx_response = Message::new();
+        nx_response.set_response_code(ResponseCode::NXDomain);
+        nx_response.add_name_server(Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            3600,
+            RData::SOA(SOA::new(
+                Name::from_str("ns.example.com.").unwrap(),
+                Name::from_str("admin.example.com.").unwrap(),
+                1,
+                3600,
+                600,
+                86400,
+                60,
+            )),
+        ));
+        assert_eq!(cache_ttl(&nx_response), Some(Duration::from_secs(60)));
+        cache.insert(&nx_query, &nx_response, false).await;
+        let (cached, forward) = cache.get(&nx_query).await.unwrap();
+        assert!(!forward);
+        assert_eq!(cached.response_code(), ResponseCode::NXDomain);
+
+        // Failures are not cached
+        let sf_query = Query::query(Name::from_str("sf.example.com.").unwrap(), RecordType::A);
+        let mut sf_response = Message::new();
+        sf_response.set_response_code(ResponseCode::ServFail);
+        cache.insert(&sf_query, &sf_response, true).await;
+        assert!(cache.get(&sf_query).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answer_cache_expiry() {
+        let cache = DnsAnswerCache::new(16);
+
+        let name = Name::from_str("www.example.com.").unwrap();
+        let query = Query::query(name.clone(), RecordType::A);
+
+        let mut response = Message::new();
+        response.add_query(query.clone());
+        response.add_answer(Record::from_rdata(
+            name,
+            300,
+            RData::A(A::from(Ipv4Addr::new(127, 0, 0, 1))),
+        ));
+        cache.insert(&query, &response, false).await;
+
+        // TTLs are decreased by the time that answers have been cached
+        tokio::time::advance(Duration::from_secs(100)).await;
+        let (cached, _) = cache.get(&query).await.unwrap();
+        assert_eq!(cached.answers()[0].ttl(), 200);
+
+        tokio::time::advance(Duration::from_secs(200)).await;
+        assert!(cache.get(&query).await.is_none());
+    }
+}
@@ -45,6 +45,11 @@ impl DnsClientCache {
         }
     }
 
+    /// Drop all cached connections, they may be bound to a network that is not available anymore
+    pub async fn clear(&self) {
+        self.cache.lock().await.clear();
+    }
+
     pub async fn lookup_local(
         &self,
         ns: SocketAddr,
@@ -5,6 +5,7 @@ pub use self::{
     server::{Dns, DnsBuilder},
 };
 
+mod answer_cache;
 mod client_cache;
 pub mod config;
 pub mod dns_resolver;
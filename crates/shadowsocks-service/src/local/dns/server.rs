@@ -8,7 +8,10 @@ use std::{
     io::{self, ErrorKind},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
     time::Duration,
 };
 
@@ -46,7 +49,7 @@ use crate::{
     },
 };
 
-use super::{client_cache::DnsClientCache, config::NameServerAddr};
+use super::{answer_cache::DnsAnswerCache, client_cache::DnsClientCache, config::NameServerAddr};
 
 /// DNS Relay server builder
 pub struct DnsBuilder {
@@ -57,6 +60,7 @@ pub struct DnsBuilder {
     bind_addr: ServerAddr,
     balancer: PingBalancer,
     client_cache_size: usize,
+    answer_cache_size: usize,
     #[cfg(target_os = "macos")]
     launchd_tcp_socket_name: Option<String>,
     #[cfg(target_os = "macos")]
@@ -100,6 +104,7 @@ impl DnsBuilder {
             bind_addr,
             balancer,
             client_cache_size,
+            answer_cache_size: 0,
             #[cfg(target_os = "macos")]
             launchd_tcp_socket_name: None,
             #[cfg(target_os = "macos")]
@@ -112,6 +117,13 @@ impl DnsBuilder {
         self.mode = mode;
     }
 
+    /// Set maximum number of cached answers, `0` disables the answer cache
+    ///
+    /// Queries that hit the cache are answered directly, with the same records that are used for routing targets.
+    pub fn set_answer_cache_size(&mut self, answer_cache_size: usize) {
+        self.answer_cache_size = answer_cache_size;
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
@@ -131,6 +143,7 @@ impl DnsBuilder {
             self.balancer,
             self.mode,
             self.client_cache_size,
+            self.answer_cache_size,
         ));
 
         let local_addr = Arc::new(self.local_addr);
@@ -604,7 +617,7 @@ fn should_forward_by_query(context: &ServiceContext, balancer: &PingBalancer, qu
         }
     }
 
-    if let Some(acl) = context.acl() {
+    if let Some(ref acl) = context.acl() {
         if query.query_class() != DNSClass::IN {
             // unconditionally use default for all non-IN queries
             Some(acl.is_default_in_proxy_list())
@@ -612,7 +625,8 @@ fn should_forward_by_query(context: &ServiceContext, balancer: &PingBalancer, qu
             Some(should_forward_by_ptr_name(acl, query.name()))
         } else {
             let result = check_name_in_proxy_list(acl, query.name());
-            if result.is_none() && acl.is_ip_empty() && acl.is_host_empty() {
+            // With proxy_dns, names are not resolved locally to check IP rules of the responses
+            if result.is_none() && ((acl.is_ip_empty() && acl.is_host_empty()) || context.proxy_dns()) {
                 Some(acl.is_default_in_proxy_list())
             } else {
                 result
@@ -707,22 +721,64 @@ fn should_forward_by_response(
 struct DnsClient {
     context: Arc<ServiceContext>,
     client_cache: DnsClientCache,
+    answer_cache: Option<DnsAnswerCache>,
     mode: Mode,
     balancer: PingBalancer,
     attempts: usize,
+    network_generation: AtomicU64,
+    acl_generation: AtomicU64,
 }
 
 impl DnsClient {
-    fn new(context: Arc<ServiceContext>, balancer: PingBalancer, mode: Mode, client_cache_size: usize) -> DnsClient {
+    fn new(
+        context: Arc<ServiceContext>,
+        balancer: PingBalancer,
+        mode: Mode,
+        client_cache_size: usize,
+        answer_cache_size: usize,
+    ) -> DnsClient {
         DnsClient {
+            network_generation: AtomicU64::new(context.network_generation()),
+            acl_generation: AtomicU64::new(context.acl_generation()),
             context,
             client_cache: DnsClientCache::new(client_cache_size),
+            answer_cache: if answer_cache_size > 0 {
+                Some(DnsAnswerCache::new(answer_cache_size))
+            } else {
+                None
+            },
             mode,
             balancer,
             attempts: 2,
         }
     }
 
+    /// Drop cached answers and connections if the host's network has changed since the last query, or only cached
+    /// answers if ACLs have been reloaded, which decide where queries are sent
+    async fn check_network_changed(&self) {
+        let network_generation = self.context.network_generation();
+        let network_changed = self
+            .network_generation
+            .swap(network_generation, atomic::Ordering::AcqRel)
+            != network_generation;
+
+        let acl_generation = self.context.acl_generation();
+        let acl_changed = self.acl_generation.swap(acl_generation, atomic::Ordering::AcqRel) != acl_generation;
+
+        if network_changed {
+            debug!("network changed, dropping cached dns answers and connections");
+            self.client_cache.clear().await;
+        } else if acl_changed {
+            debug!("acl reloaded, dropping cached dns answers");
+        } else {
+            return;
+        }
+
+        if let Some(ref answer_cache) = self.answer_cache {
+            answer_cache.clear().await;
+        }
+    }
+
     async fn resolve(
         &self,
         request: Message,
@@ -747,7 +803,26 @@ impl DnsClient {
         } else if request.query_count() > 0 {
             // Make queries according to ACL rules
 
-            let (r, forward) = self.acl_lookup(&request.queries()[0], local_addr, remote_addr).await;
+            let query = &request.queries()[0];
+
+            self.check_network_changed().await;
+
+            let (r, forward) = match self.answer_cache {
+                Some(ref answer_cache) => match answer_cache.get(query).await {
+                    Some((cached, forward)) => {
+                        trace!("dns answer cache hit: {:?} {}", query.query_type(), query.name());
+                        (Ok(cached), forward)
+                    }
+                    None => {
+                        let (r, forward) = self.acl_lookup(query, local_addr, remote_addr).await;
+                        if let Ok(ref result) = r {
+                            answer_cache.insert(query, result, forward).await;
+                        }
+                        (r, forward)
+                    }
+                },
+                None => self.acl_lookup(query, local_addr, remote_addr).await,
+            };
             if let Ok(result) = r {
                 for rec in result.answers() {
                     trace!("dns answer: {:?}", rec);
@@ -799,7 +874,7 @@ impl DnsClient {
 
         let decider = async {
             let local_response = self.lookup_local(query, local_addr).await;
-            if should_forward_by_response(self.context.acl(), &local_response, query) {
+            if should_forward_by_response(self.context.acl().as_deref(), &local_response, query) {
                 None
             } else {
                 Some(local_response)
@@ -840,7 +915,15 @@ impl DnsClient {
     async fn lookup_remote(&self, query: &Query, remote_addr: &Address) -> io::Result<Message> {
         let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
 
-        for _ in 0..self.attempts {
+        for attempt in 0..self.attempts {
+            if attempt > 0 && !self.context.retry_budget().try_acquire() {
+                debug!(
+                    "dns remote lookup {} retry throttled, retry budget is exhausted",
+                    query.name()
+                );
+                break;
+            }
+
             match self.lookup_remote_inner(query, remote_addr).await {
                 Ok(m) => {
                     return Ok(m);
@@ -919,7 +1002,15 @@ impl DnsClient {
     async fn lookup_local(&self, query: &Query, local_addr: &NameServerAddr) -> io::Result<Message> {
         let mut last_err = io::Error::new(ErrorKind::InvalidData, "resolve empty");
 
-        for _ in 0..self.attempts {
+        for attempt in 0..self.attempts {
+            if attempt > 0 && !self.context.retry_budget().try_acquire() {
+                debug!(
+                    "dns local lookup {} retry throttled, retry budget is exhausted",
+                    query.name()
+                );
+                break;
+            }
+
             match self.lookup_local_inner(query, local_addr).await {
                 Ok(m) => {
                     return Ok(m);
@@ -972,3 +1063,82 @@ impl DnsClient {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, net::Ipv4Addr, process, str::FromStr, sync::Arc};
+
+    use hickory_resolver::proto::rr::{rdata::A, Record};
+
+    use shadowsocks::{config::ServerConfig, crypto::CipherKind};
+
+    use crate::local::loadbalancing::PingBalancerBuilder;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn proxy_dns_skips_local_query() {
+        let path = env::temp_dir().join(format!("shadowsocks-dns-proxy-dns-test-{}.acl", process::id()));
+        fs::write(&path, "[proxy_all]\n[bypass_list]\n127.0.0.0/8\n||bypass.example\n").unwrap();
+        let acl = Arc::new(AccessControl::load_from_file(&path).unwrap());
+        let _ = fs::remove_file(&path);
+
+        let mut context = ServiceContext::new();
+        context.set_acl(acl);
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "password",
+            CipherKind::AES_256_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        let query = Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A);
+        let bypass_query = Query::query(Name::from_str("www.bypass.example.").unwrap(), RecordType::A);
+
+        // Inconclusive, decided by IP rules with the local response
+        assert_eq!(should_forward_by_query(&context, &balancer, &query), None);
+
+        context.set_proxy_dns(true);
+        assert_eq!(should_forward_by_query(&context, &balancer, &query), Some(true));
+        assert_eq!(should_forward_by_query(&context, &balancer, &bypass_query), Some(false));
+    }
+
+    #[tokio::test]
+    async fn dns_client_drops_answers_after_acl_reload() {
+        let path = env::temp_dir().join(format!("shadowsocks-dns-acl-test-{}.acl", process::id()));
+        fs::write(&path, "[proxy_all]\n").unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_acl(Arc::new(AccessControl::load_from_file(&path).unwrap()));
+        let _ = fs::remove_file(&path);
+        let context = Arc::new(context);
+
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let client = DnsClient::new(context.clone(), balancer, Mode::TcpOnly, 0, 16);
+        let answer_cache = client.answer_cache.as_ref().unwrap();
+
+        let name = Name::from_str("www.example.com.").unwrap();
+        let query = Query::query(name.clone(), RecordType::A);
+        let mut response = Message::new();
+        response.add_query(query.clone());
+        response.add_answer(Record::from_rdata(
+            name,
+            300,
+            RData::A(A::from(Ipv4Addr::new(127, 0, 0, 1))),
+        ));
+        answer_cache.insert(&query, &response, true).await;
+
+        client.check_network_changed().await;
+        assert!(answer_cache.get(&query).await.is_some());
+
+        // Answers were sent to where the previous ACL decided
+        let acl = context.acl().unwrap();
+        context.replace_acl(acl).await;
+        client.check_network_changed().await;
+        assert!(answer_cache.get(&query).await.is_none());
+    }
+}
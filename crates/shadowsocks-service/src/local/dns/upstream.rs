@@ -253,3 +253,110 @@ where
 
     Message::from_vec(&rsp_bytes)
 }
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use hickory_resolver::proto::{
+        op::Query,
+        rr::{Name, RecordType},
+    };
+    use tokio::io;
+
+    use super::*;
+
+    fn make_message(id: u16, name: &str) -> Message {
+        let mut message = Message::new();
+        message.set_id(id);
+        message.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        message
+    }
+
+    fn frame(message: &Message) -> Vec<u8> {
+        let message = message.to_vec().unwrap();
+        let mut framed = Vec::with_capacity(message.len() + 2);
+        framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&message);
+        framed
+    }
+
+    #[tokio::test]
+    async fn stream_query_fragmented_response() {
+        let (mut client, mut server) = io::duplex(4096);
+
+        let first = make_message(0x1234, "first.example.com.");
+        let second = make_message(0x5678, "second.example.com.");
+
+        let server_task = tokio::spawn(async move {
+            // Both responses are in one buffer, and sent in fragments, including one that splits the length field
+            let mut responses = frame(&first);
+            responses.extend_from_slice(&frame(&second));
+            let boundary = frame(&first).len();
+
+            let mut request = vec![0u8; 1024];
+            let _ = server.read(&mut request).await.unwrap();
+
+            for chunk in [
+                &responses[..1],
+                &responses[1..3],
+                &responses[3..boundary + 1],
+                &responses[boundary + 1..],
+            ] {
+                server.write_all(chunk).await.unwrap();
+                server.flush().await.unwrap();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+
+            let _ = server.read(&mut request).await.unwrap();
+            (first, second)
+        });
+
+        let request = make_message(0x1234, "first.example.com.");
+        let rsp1 = stream_query(&mut client, &request).await.unwrap();
+        let rsp2 = stream_query(&mut client, &request).await.unwrap();
+
+        let (first, second) = server_task.await.unwrap();
+        assert_eq!(rsp1.id(), first.id());
+        assert_eq!(rsp1.queries(), first.queries());
+        assert_eq!(rsp2.id(), second.id());
+        assert_eq!(rsp2.queries(), second.queries());
+    }
+
+    #[tokio::test]
+    async fn stream_query_large_response() {
+        use hickory_resolver::proto::rr::{rdata::TXT, RData, Record};
+
+        let (mut client, mut server) = io::duplex(4096);
+
+        // Longer than 1KB, the high byte of the length field matters
+        let mut response = make_message(0x4321, "large.example.com.");
+        let name = Name::from_str("large.example.com.").unwrap();
+        for _ in 0..6 {
+            let txt = TXT::new(vec!["x".repeat(250)]);
+            response.add_answer(Record::from_rdata(name.clone(), 60, RData::TXT(txt)));
+        }
+        let framed = frame(&response);
+        assert!(framed.len() > 1500);
+
+        let server_task = tokio::spawn(async move {
+            let mut request = vec![0u8; 1024];
+            let _ = server.read(&mut request).await.unwrap();
+
+            // Split inside the length field
+            for chunk in [&framed[..1], &framed[1..]] {
+                server.write_all(chunk).await.unwrap();
+                server.flush().await.unwrap();
+                time::sleep(Duration::from_millis(10)).await;
+            }
+            response
+        });
+
+        let request = make_message(0x4321, "large.example.com.");
+        let rsp = stream_query(&mut client, &request).await.unwrap();
+
+        let response = server_task.await.unwrap();
+        assert_eq!(rsp.to_vec().unwrap(), response.to_vec().unwrap());
+        assert_eq!(rsp.answer_count(), 6);
+    }
+}
@@ -0,0 +1,210 @@
+//! HTTP health endpoint of local servers
+//!
+//! Every `GET` request is answered with `200 OK` and a JSON body, for liveness and readiness probes of process
+//! managers:
+//!
+//! ```json
+//! {"uptime":42,"active_connections":3,"server":"1.2.3.4:8388",
+//!  "listeners":[{"protocol":"socks","network":"tcp","addr":"127.0.0.1:1080"}]}
+//! ```
+//!
+//! `uptime` is in seconds, `active_connections` is the number of TCP relays in progress and `server` is the server
+//! currently chosen for TCP, `null` if there is no server. `listeners` are the addresses that local servers are bound
+//! to, with the actual ports assigned by the OS if `local_port` is 0.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use log::{debug, info};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{self, Instant},
+};
+
+use crate::{
+    local::{loadbalancing::PingBalancer, ListenAddr},
+    net::ConcurrencyLimit,
+};
+
+/// Maximum size of a request's head, larger requests are rejected
+const MAX_REQUEST_HEAD_SIZE: usize = 4096;
+/// Time for a client to send the request's head
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct HealthStatus {
+    uptime: u64,
+    active_connections: usize,
+    server: Option<String>,
+    listeners: Vec<ListenerStatus>,
+}
+
+#[derive(Serialize, Clone)]
+struct ListenerStatus {
+    protocol: &'static str,
+    network: &'static str,
+    addr: SocketAddr,
+}
+
+/// HTTP health endpoint
+pub struct HealthApi {
+    listener: TcpListener,
+    started_at: Instant,
+    relay_concurrency: ConcurrencyLimit,
+    balancer: PingBalancer,
+    listeners: Vec<ListenerStatus>,
+}
+
+impl HealthApi {
+    /// Bind the endpoint to `addr`
+    pub async fn bind(
+        addr: SocketAddr,
+        relay_concurrency: ConcurrencyLimit,
+        balancer: PingBalancer,
+    ) -> io::Result<HealthApi> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(HealthApi {
+            listener,
+            started_at: Instant::now(),
+            relay_concurrency,
+            balancer,
+            listeners: Vec::new(),
+        })
+    }
+
+    /// Set addresses of local servers' listeners that are reported in `listeners`
+    pub fn set_listen_addrs(&mut self, addrs: Vec<ListenAddr>) {
+        self.listeners = addrs
+            .into_iter()
+            .map(|addr| ListenerStatus {
+                protocol: addr.protocol.as_str(),
+                network: if addr.udp { "udp" } else { "tcp" },
+                addr: addr.addr,
+            })
+            .collect();
+    }
+
+    /// Address that the endpoint is listening on
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start serving, stops when dropped
+    pub async fn run(self) -> io::Result<()> {
+        info!(
+            "shadowsocks health api listening on http://{}",
+            self.listener.local_addr()?
+        );
+
+        loop {
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(s) => s,
+                Err(err) => {
+                    debug!("health api accept failed with error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let status = self.status();
+            tokio::spawn(async move {
+                if let Err(err) = handle_request(stream, status).await {
+                    debug!("health api client {} failed with error: {}", peer_addr, err);
+                }
+            });
+        }
+    }
+
+    fn status(&self) -> HealthStatus {
+        HealthStatus {
+            uptime: self.started_at.elapsed().as_secs(),
+            active_connections: self.relay_concurrency.active(),
+            server: if self.balancer.is_empty() {
+                None
+            } else {
+                Some(self.balancer.best_tcp_server().server_config().addr().to_string())
+            },
+            listeners: self.listeners.clone(),
+        }
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, status: HealthStatus) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    let read_head = async {
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            if head.len() > MAX_REQUEST_HEAD_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too large"));
+            }
+
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            head.extend_from_slice(&buffer[..n]);
+        }
+        Ok(())
+    };
+
+    match time::timeout(REQUEST_TIMEOUT, read_head).await {
+        Ok(r) => r?,
+        Err(..) => return Err(io::ErrorKind::TimedOut.into()),
+    }
+
+    let response = if head.starts_with(b"GET ") {
+        let body = serde_json::to_string(&status).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::config::Mode;
+
+    use crate::local::loadbalancing::PingBalancerBuilder;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn health_api_status() {
+        let balancer = PingBalancerBuilder::new(Default::default(), Mode::TcpOnly)
+            .build()
+            .await
+            .unwrap();
+        let api = HealthApi::bind("127.0.0.1:0".parse().unwrap(), ConcurrencyLimit::default(), balancer)
+            .await
+            .unwrap();
+        let addr = api.local_addr().unwrap();
+        let server = tokio::spawn(api.run());
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(r#"{"uptime":0,"active_connections":0,"server":null,"listeners":[]}"#));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"POST /health HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        server.abort();
+    }
+}
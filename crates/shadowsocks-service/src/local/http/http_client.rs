@@ -14,12 +14,16 @@ use hyper::{
     Request,
     Response,
 };
-use log::{error, trace};
+use log::{error, info, trace};
 use lru_time_cache::LruCache;
 use shadowsocks::relay::Address;
 use tokio::sync::Mutex;
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer, net::AutoProxyClientStream};
+use crate::local::{
+    context::ServiceContext,
+    loadbalancing::PingBalancer,
+    net::{AutoProxyClientStream, AutoProxyIo},
+};
 
 use super::{
     http_stream::ProxyHttpStream,
@@ -158,7 +162,17 @@ impl HttpConnection {
             return Err(io::Error::new(ErrorKind::InvalidInput, "invalid scheme"));
         }
 
-        let (stream, _) = connect_host(context, &host, balancer).await?;
+        let (stream, server_opt) = connect_host(context.clone(), &host, balancer).await?;
+        if let Some(server) = server_opt.filter(|_| stream.is_proxied()) {
+            // Requests are not relayed by tunnels, which sample their connections in `establish_tcp_tunnel`
+            if context.should_log_selected_server(context.connection_log_sampler().sample()) {
+                info!(
+                    "http connection to {} through server {}",
+                    host,
+                    server.server_config().addr()
+                );
+            }
+        }
 
         if *scheme == Scheme::HTTP {
             HttpConnection::connect_http_http1(scheme, host, stream).await
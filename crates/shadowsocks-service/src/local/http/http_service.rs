@@ -19,12 +19,15 @@ use hyper::{
 use log::{debug, error, trace};
 use shadowsocks::relay::Address;
 
-use crate::local::{
-    context::ServiceContext,
-    http::{http_client::HttpClientError, tokio_rt::TokioIo},
-    loadbalancing::PingBalancer,
-    net::AutoProxyIo,
-    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+use crate::{
+    local::{
+        context::ServiceContext,
+        http::{http_client::HttpClientError, tokio_rt::TokioIo},
+        loadbalancing::PingBalancer,
+        net::AutoProxyIo,
+        utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+    },
+    net::ConnectionId,
 };
 
 use super::{
@@ -34,6 +37,7 @@ use super::{
 
 pub struct HttpService {
     context: Arc<ServiceContext>,
+    id: ConnectionId,
     peer_addr: SocketAddr,
     http_client: HttpClient,
     balancer: PingBalancer,
@@ -42,12 +46,14 @@ pub struct HttpService {
 impl HttpService {
     pub fn new(
         context: Arc<ServiceContext>,
+        id: ConnectionId,
         peer_addr: SocketAddr,
         http_client: HttpClient,
         balancer: PingBalancer,
     ) -> HttpService {
         HttpService {
             context,
+            id,
             peer_addr,
             http_client,
             balancer,
@@ -58,7 +64,7 @@ impl HttpService {
         self,
         mut req: Request<body::Incoming>,
     ) -> hyper::Result<Response<BoxBody<Bytes, hyper::Error>>> {
-        trace!("request {} {:?}", self.peer_addr, req);
+        trace!("request {} {} {:?}", self.id, self.peer_addr, req);
 
         // Parse URI
         //
@@ -85,12 +91,12 @@ impl HttpService {
             // Establish a TCP tunnel
             // https://tools.ietf.org/html/draft-luotonen-web-proxy-tunneling-01
 
-            debug!("HTTP CONNECT {}", host);
+            debug!("HTTP {} CONNECT {}", self.id, host);
 
             // Connect to Shadowsocks' remote
             //
             // FIXME: What STATUS should I return for connection error?
-            let (mut stream, server_opt) = match connect_host(self.context, &host, &self.balancer).await {
+            let (mut stream, server_opt) = match connect_host(self.context.clone(), &host, &self.balancer).await {
                 Ok(s) => s,
                 Err(err) => {
                     error!("failed to CONNECT host: {}, error: {}", host, err);
@@ -99,38 +105,51 @@ impl HttpService {
             };
 
             debug!(
-                "CONNECT relay connected {} <-> {} ({})",
+                "CONNECT relay connected {} {} <-> {} ({})",
+                self.id,
                 self.peer_addr,
                 host,
                 if stream.is_bypassed() { "bypassed" } else { "proxied" }
             );
 
+            let id = self.id;
             let client_addr = self.peer_addr;
+            let context = self.context;
             tokio::spawn(async move {
                 match hyper::upgrade::on(req).await {
                     Ok(upgraded) => {
-                        trace!("CONNECT tunnel upgrade success, {} <-> {}", client_addr, host);
+                        trace!("CONNECT tunnel upgrade success, {} {} <-> {}", id, client_addr, host);
 
                         let mut upgraded_io = TokioIo::new(upgraded);
 
                         let _ = match server_opt {
                             Some(server) => {
                                 establish_tcp_tunnel(
+                                    &context,
                                     server.server_config(),
                                     &mut upgraded_io,
                                     &mut stream,
+                                    id,
                                     client_addr,
                                     &host,
                                 )
                                 .await
                             }
                             None => {
-                                establish_tcp_tunnel_bypassed(&mut upgraded_io, &mut stream, client_addr, &host).await
+                                establish_tcp_tunnel_bypassed(
+                                    &context,
+                                    &mut upgraded_io,
+                                    &mut stream,
+                                    id,
+                                    client_addr,
+                                    &host,
+                                )
+                                .await
                             }
                         };
                     }
                     Err(err) => {
-                        error!("failed to upgrade CONNECT request, error: {}", err);
+                        error!("failed to upgrade CONNECT request {}, error: {}", id, err);
                     }
                 }
             });
@@ -142,7 +161,7 @@ impl HttpService {
 
         let method = req.method().clone();
         let version = req.version();
-        debug!("HTTP {} {} {:?}", method, host, version);
+        debug!("HTTP {} {} {} {:?}", self.id, method, host, version);
 
         // Check if client wants us to keep long connection
         let conn_keep_alive = check_keep_alive(version, req.headers(), true);
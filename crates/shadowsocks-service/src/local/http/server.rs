@@ -12,10 +12,9 @@ use tokio::{
     time,
 };
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::tcp::listener::create_standard_tcp_listener,
+use crate::{
+    local::{context::ServiceContext, loadbalancing::PingBalancer, net::tcp::listener::create_standard_tcp_listener},
+    net::ConnectionId,
 };
 
 use super::{http_client::HttpClient, http_service::HttpService, tokio_rt::TokioIo};
@@ -108,9 +107,11 @@ impl Http {
             self.listener.local_addr().expect("http local_addr")
         );
 
+        let relay_concurrency = self.context.relay_concurrency().clone();
         let handler = HttpConnectionHandler::new(self.context, self.balancer);
 
         loop {
+            let permit = relay_concurrency.acquire().await;
             let (stream, peer_addr) = match self.listener.accept().await {
                 Ok(s) => s,
                 Err(err) => {
@@ -120,11 +121,16 @@ impl Http {
                 }
             };
 
-            trace!("HTTP accepted client from {}", peer_addr);
+            let id = ConnectionId::next();
+            trace!("HTTP accepted client {} from {}", id, peer_addr);
             let handler = handler.clone();
             tokio::spawn(async move {
-                if let Err(err) = handler.serve_connection(stream, peer_addr).await {
-                    error!("HTTP connection {} handler failed with error: {}", peer_addr, err);
+                let _permit = permit;
+                if let Err(err) = handler.serve_connection(stream, id, peer_addr).await {
+                    error!(
+                        "HTTP connection {} {} handler failed with error: {}",
+                        id, peer_addr, err
+                    );
                 }
             });
         }
@@ -151,8 +157,8 @@ impl HttpConnectionHandler {
         }
     }
 
-    /// Handle a TCP HTTP connection
-    pub async fn serve_connection<S>(self, stream: S, peer_addr: SocketAddr) -> hyper::Result<()>
+    /// Handle a TCP HTTP connection, `id` is assigned when it was accepted
+    pub async fn serve_connection<S>(self, stream: S, id: ConnectionId, peer_addr: SocketAddr) -> hyper::Result<()>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
@@ -173,7 +179,7 @@ impl HttpConnectionHandler {
             .serve_connection(
                 io,
                 service::service_fn(move |req| {
-                    HttpService::new(context.clone(), peer_addr, http_client.clone(), balancer.clone())
+                    HttpService::new(context.clone(), id, peer_addr, http_client.clone(), balancer.clone())
                         .serve_connection(req)
                 }),
             )
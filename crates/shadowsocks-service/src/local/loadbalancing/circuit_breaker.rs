@@ -0,0 +1,241 @@
+//! Circuit breaker for servers that keep failing
+//!
+//! After `failure_threshold` consecutive failures within `failure_window`, the breaker is opened and the server will
+//! be skipped by the balancer. After `cooldown`, it becomes half-open and the server could be chosen again to test
+//! whether it has recovered: the next success closes the breaker, and the next failure opens it again.
+
+use std::{
+    fmt::{self, Debug},
+    time::Duration,
+};
+
+use spin::Mutex as SpinMutex;
+use tokio::time::Instant;
+
+/// Default consecutive failures to open the breaker
+pub const DEFAULT_CIRCUIT_BREAKER_FAILURES: u32 = 5;
+/// Default window that failures have to be happened in
+pub const DEFAULT_CIRCUIT_BREAKER_WINDOW_SEC: u64 = 60;
+/// Default duration that an opened breaker keeps open
+pub const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SEC: u64 = 30;
+
+/// Circuit breaker configuration
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures to open the breaker
+    pub failure_threshold: u32,
+    /// Failures have to be happened in this window
+    pub failure_window: Duration,
+    /// Duration that the breaker keeps open before half-open
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            failure_threshold: DEFAULT_CIRCUIT_BREAKER_FAILURES,
+            failure_window: Duration::from_secs(DEFAULT_CIRCUIT_BREAKER_WINDOW_SEC),
+            cooldown: Duration::from_secs(DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SEC),
+        }
+    }
+}
+
+/// State of a circuit breaker
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CircuitState {
+    /// Server is working, could be chosen
+    Closed,
+    /// Server keeps failing, won't be chosen until cooldown
+    Open,
+    /// Cooldown is finished, server could be chosen for testing recovery
+    HalfOpen,
+}
+
+struct CircuitBreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    first_failure_time: Option<Instant>,
+    opened_time: Option<Instant>,
+    trips: u64,
+}
+
+/// Circuit breaker of a server
+pub struct CircuitBreaker {
+    config: Option<CircuitBreakerConfig>,
+    state: SpinMutex<CircuitBreakerState>,
+}
+
+impl CircuitBreaker {
+    /// Create a breaker, `None` for disabled, which is always closed
+    pub fn new(config: Option<CircuitBreakerConfig>) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            state: SpinMutex::new(CircuitBreakerState {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                first_failure_time: None,
+                opened_time: None,
+                trips: 0,
+            }),
+        }
+    }
+
+    /// Current state
+    pub fn state(&self) -> CircuitState {
+        let config = match self.config {
+            None => return CircuitState::Closed,
+            Some(ref c) => c,
+        };
+
+        let mut state = self.state.lock();
+        if state.state == CircuitState::Open {
+            if let Some(opened_time) = state.opened_time {
+                if opened_time.elapsed() >= config.cooldown {
+                    state.state = CircuitState::HalfOpen;
+                }
+            }
+        }
+        state.state
+    }
+
+    /// Check if server could be chosen
+    pub fn is_available(&self) -> bool {
+        self.state() != CircuitState::Open
+    }
+
+    /// Number of times that this breaker has been opened
+    pub fn trips(&self) -> u64 {
+        self.state.lock().trips
+    }
+
+    /// Record a successful request
+    pub fn record_success(&self) {
+        if self.config.is_none() {
+            return;
+        }
+
+        // Successes during cooldown are ignored, server have to wait for cooldown before test
+        if self.state() == CircuitState::Open {
+            return;
+        }
+
+        let mut state = self.state.lock();
+        state.state = CircuitState::Closed;
+        state.consecutive_failures = 0;
+        state.first_failure_time = None;
+        state.opened_time = None;
+    }
+
+    /// Close the breaker and forget recent failures, the number of trips is kept
+    ///
+    /// Failures counted on a previous network shouldn't keep servers out after the network has changed.
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+        state.state = CircuitState::Closed;
+        state.consecutive_failures = 0;
+        state.first_failure_time = None;
+        state.opened_time = None;
+    }
+
+    /// Record a failed request, returns `true` if the breaker is opened by this failure
+    pub fn record_failure(&self) -> bool {
+        let config = match self.config {
+            None => return false,
+            Some(ref c) => c,
+        };
+
+        let current_state = self.state();
+
+        let mut state = self.state.lock();
+        let now = Instant::now();
+
+        match current_state {
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                // Test failed, open again
+                state.state = CircuitState::Open;
+                state.opened_time = Some(now);
+                state.trips += 1;
+                true
+            }
+            CircuitState::Closed => {
+                match state.first_failure_time {
+                    Some(t) if now - t <= config.failure_window => {}
+                    _ => {
+                        state.first_failure_time = Some(now);
+                        state.consecutive_failures = 0;
+                    }
+                }
+
+                state.consecutive_failures += 1;
+                if state.consecutive_failures >= config.failure_threshold {
+                    state.state = CircuitState::Open;
+                    state.opened_time = Some(now);
+                    state.consecutive_failures = 0;
+                    state.first_failure_time = None;
+                    state.trips += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+impl Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("state", &self.state())
+            .field("trips", &self.trips())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn circuit_breaker_open_half_open_close() {
+        let breaker = CircuitBreaker::new(Some(CircuitBreakerConfig {
+            failure_threshold: 3,
+            failure_window: Duration::from_secs(60),
+            cooldown: Duration::from_millis(20),
+        }));
+
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        breaker.record_success();
+        assert!(!breaker.record_failure());
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.is_available());
+
+        // Ignored during cooldown
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        tokio::time::advance(Duration::from_millis(30)).await;
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        tokio::time::advance(Duration::from_millis(30)).await;
+        breaker.record_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.trips(), 2);
+    }
+
+    #[test]
+    fn circuit_breaker_disabled() {
+        let breaker = CircuitBreaker::new(None);
+        for _ in 0..100 {
+            assert!(!breaker.record_failure());
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
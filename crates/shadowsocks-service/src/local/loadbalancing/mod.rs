@@ -1,10 +1,13 @@
 //! Load balancer
 
 pub use self::{
-    ping_balancer::{PingBalancer, PingBalancerBuilder, ServerType},
-    server_data::{ServerIdent, ServerScore},
+    circuit_breaker::{CircuitBreakerConfig, CircuitState},
+    ping_balancer::{PingBalancer, PingBalancerBuilder, ServerStatSnapshot, ServerType},
+    server_data::{ServerConnectionGuard, ServerIdent, ServerScore},
+    server_stat::ScoreWeights,
 };
 
+pub mod circuit_breaker;
 pub mod ping_balancer;
 pub mod server_data;
 pub mod server_stat;
@@ -17,6 +17,7 @@ use arc_swap::ArcSwap;
 use byte_string::ByteStr;
 use futures::future;
 use log::{debug, error, info, trace, warn};
+use rand::Rng;
 use shadowsocks::{
     config::Mode,
     plugin::{Plugin, PluginMode},
@@ -25,6 +26,7 @@ use shadowsocks::{
         tcprelay::proxy_stream::ProxyClientStream,
         udprelay::{options::UdpSocketControlData, proxy_socket::ProxySocket, MAXIMUM_UDP_PAYLOAD_SIZE},
     },
+    ServerAddr,
     ServerConfig,
 };
 use spin::Mutex as SpinMutex;
@@ -35,15 +37,24 @@ use tokio::{
     time,
 };
 
-use crate::local::context::ServiceContext;
+use crate::{
+    config::BalancerStrategy,
+    local::context::{NetworkType, ServiceContext},
+};
 
 use super::{
-    server_data::ServerIdent,
-    server_stat::{Score, DEFAULT_CHECK_INTERVAL_SEC, DEFAULT_CHECK_TIMEOUT_SEC},
+    circuit_breaker::CircuitBreakerConfig,
+    server_data::{ServerIdent, ServerScore},
+    server_stat::{Score, ScoreWeights, DEFAULT_CHECK_INTERVAL_SEC, DEFAULT_CHECK_TIMEOUT_SEC},
 };
 
 const EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW: u32 = 67;
 
+/// Servers whose unweighted scores are within 120% of the best one are similar in the weighted strategy
+const WEIGHTED_SCORE_TOLERANCE: f64 = 1.2;
+/// Plus 0.01 (scores are in 0.0001 precision), for servers that are all fast and reliable
+const WEIGHTED_SCORE_SLACK: f64 = 100.0;
+
 /// Remote Server Type
 #[derive(Debug, Clone, Copy)]
 pub enum ServerType {
@@ -62,12 +73,15 @@ impl fmt::Display for ServerType {
 
 /// Build a `PingBalancer`
 pub struct PingBalancerBuilder {
-    servers: Vec<Arc<ServerIdent>>,
+    servers: Vec<ServerConfig>,
     context: Arc<ServiceContext>,
     mode: Mode,
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    score_weights: ScoreWeights,
+    strategy: BalancerStrategy,
 }
 
 impl PingBalancerBuilder {
@@ -79,16 +93,14 @@ impl PingBalancerBuilder {
             max_server_rtt: Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC),
             check_interval: Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC),
             check_best_interval: None,
+            circuit_breaker: None,
+            score_weights: ScoreWeights::default(),
+            strategy: BalancerStrategy::default(),
         }
     }
 
     pub fn add_server(&mut self, server: ServerConfig) {
-        let ident = ServerIdent::new(
-            server,
-            self.max_server_rtt,
-            self.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
-        );
-        self.servers.push(Arc::new(ident));
+        self.servers.push(server);
     }
 
     pub fn max_server_rtt(&mut self, rtt: Duration) {
@@ -103,6 +115,21 @@ impl PingBalancerBuilder {
         self.check_best_interval = Some(intv);
     }
 
+    /// Enable circuit breakers for servers
+    pub fn circuit_breaker(&mut self, config: CircuitBreakerConfig) {
+        self.circuit_breaker = Some(config);
+    }
+
+    /// Set weights of servers' score components
+    pub fn score_weights(&mut self, weights: ScoreWeights) {
+        self.score_weights = weights;
+    }
+
+    /// Set strategy of choosing servers for TCP connections
+    pub fn strategy(&mut self, strategy: BalancerStrategy) {
+        self.strategy = strategy;
+    }
+
     fn find_best_idx(servers: &[Arc<ServerIdent>], mode: Mode) -> (usize, usize) {
         if servers.is_empty() {
             trace!("init without any TCP and UDP servers");
@@ -113,16 +140,21 @@ impl PingBalancerBuilder {
         let mut best_udp_idx = 0;
 
         if mode.enable_tcp() {
-            let mut found_tcp_idx = false;
-            for (idx, server) in servers.iter().enumerate() {
-                if PingBalancerContext::check_server_tcp_enabled(server.server_config()) {
-                    best_tcp_idx = idx;
-                    found_tcp_idx = true;
-                    break;
-                }
+            // Failover servers are chosen only if there is no other server
+            let found_tcp_idx = servers
+                .iter()
+                .position(|s| PingBalancerContext::check_server_preferred(s.server_config(), ServerType::Tcp))
+                .or_else(|| {
+                    servers
+                        .iter()
+                        .position(|s| PingBalancerContext::check_server_tcp_enabled(s.server_config()))
+                });
+
+            if let Some(idx) = found_tcp_idx {
+                best_tcp_idx = idx;
             }
 
-            if !found_tcp_idx {
+            if found_tcp_idx.is_none() {
                 warn!(
                     "no valid TCP server serving for TCP clients, consider disable TCP with \"mode\": \"udp_only\", currently chose {}",
                     ServerConfigFormatter::new(servers[best_tcp_idx].server_config())
@@ -136,16 +168,21 @@ impl PingBalancerBuilder {
         }
 
         if mode.enable_udp() {
-            let mut found_udp_idx = false;
-            for (idx, server) in servers.iter().enumerate() {
-                if PingBalancerContext::check_server_udp_enabled(server.server_config()) {
-                    best_udp_idx = idx;
-                    found_udp_idx = true;
-                    break;
-                }
+            // Failover servers are chosen only if there is no other server
+            let found_udp_idx = servers
+                .iter()
+                .position(|s| PingBalancerContext::check_server_preferred(s.server_config(), ServerType::Udp))
+                .or_else(|| {
+                    servers
+                        .iter()
+                        .position(|s| PingBalancerContext::check_server_udp_enabled(s.server_config()))
+                });
+
+            if let Some(idx) = found_udp_idx {
+                best_udp_idx = idx;
             }
 
-            if !found_udp_idx {
+            if found_udp_idx.is_none() {
                 warn!(
                     "no valid UDP server serving for UDP clients, consider disable UDP with \"mode\": \"tcp_only\", currently chose {}",
                     ServerConfigFormatter::new(servers[best_udp_idx].server_config())
@@ -171,13 +208,30 @@ impl PingBalancerBuilder {
             }
         }
 
+        let servers = self
+            .servers
+            .into_iter()
+            .map(|server| {
+                Arc::new(ServerIdent::new(
+                    server,
+                    self.max_server_rtt,
+                    self.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
+                    self.circuit_breaker,
+                    self.score_weights,
+                ))
+            })
+            .collect();
+
         let (shared_context, task_abortable) = PingBalancerContext::new(
-            self.servers,
+            servers,
             self.context,
             self.mode,
             self.max_server_rtt,
             self.check_interval,
             self.check_best_interval,
+            self.circuit_breaker,
+            self.score_weights,
+            self.strategy,
         )
         .await?;
 
@@ -208,23 +262,137 @@ struct PingBalancerContext {
     servers: Vec<Arc<ServerIdent>>,
     best_tcp_idx: AtomicUsize,
     best_udp_idx: AtomicUsize,
+    /// Index of the server pinned by user, `usize::MAX` if not pinned
+    pinned_idx: AtomicUsize,
     context: Arc<ServiceContext>,
     mode: Mode,
     max_server_rtt: Duration,
     check_interval: Duration,
     check_best_interval: Option<Duration>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    score_weights: ScoreWeights,
+    strategy: BalancerStrategy,
     best_task_notify: Notify,
 }
 
 impl PingBalancerContext {
     fn best_tcp_server(&self) -> Arc<ServerIdent> {
         assert!(!self.is_empty(), "no available server");
-        self.servers[self.best_tcp_idx.load(Ordering::Relaxed)].clone()
+
+        if let Some(server) = self.pinned_server(ServerType::Tcp) {
+            return server;
+        }
+
+        let server = match self.strategy {
+            BalancerStrategy::Latency => None,
+            BalancerStrategy::LeastConnections => self.least_connections_tcp_server(),
+            BalancerStrategy::Weighted => self.weighted_tcp_server(),
+        };
+        if let Some(server) = server {
+            return server;
+        }
+
+        let best_idx = self.best_tcp_idx.load(Ordering::Relaxed);
+        self.choose_available_server(best_idx, ServerType::Tcp)
+    }
+
+    /// Choose the available server with the fewest active TCP connections, and the lowest score if equal
+    fn least_connections_tcp_server(&self) -> Option<Arc<ServerIdent>> {
+        self.servers
+            .iter()
+            .filter(|s| {
+                PingBalancerContext::check_server_preferred(s.server_config(), ServerType::Tcp)
+                    && s.tcp_score().is_available()
+            })
+            .min_by_key(|s| (s.active_connections(), s.tcp_score().score()))
+            .cloned()
+    }
+
+    /// Choose a random available server in proportion to `tcp_weight`, among servers with similar scores
+    ///
+    /// Scores are compared without weights, otherwise servers with lower weights would never be similar.
+    fn weighted_tcp_server(&self) -> Option<Arc<ServerIdent>> {
+        let candidates = self
+            .servers
+            .iter()
+            .filter(|s| {
+                PingBalancerContext::check_server_preferred(s.server_config(), ServerType::Tcp)
+                    && s.tcp_score().is_available()
+            })
+            .map(|s| {
+                let weight = s.server_config().weight().tcp_weight() as f64;
+                (s, weight, s.tcp_score().score() as f64 * weight)
+            })
+            .collect::<Vec<_>>();
+
+        let best_score = candidates.iter().map(|(_, _, score)| *score).reduce(f64::min)?;
+        let max_score = best_score * WEIGHTED_SCORE_TOLERANCE + WEIGHTED_SCORE_SLACK;
+        let candidates = candidates
+            .into_iter()
+            .filter(|(_, _, score)| *score <= max_score)
+            .collect::<Vec<_>>();
+
+        let total_weight: f64 = candidates.iter().map(|(_, weight, _)| weight).sum();
+        let mut point = rand::thread_rng().gen_range(0.0..total_weight);
+        for (server, weight, _) in candidates.iter() {
+            if point < *weight {
+                return Some((*server).clone());
+            }
+            point -= weight;
+        }
+        candidates.last().map(|(server, ..)| (*server).clone())
     }
 
     fn best_udp_server(&self) -> Arc<ServerIdent> {
         assert!(!self.is_empty(), "no available server");
-        self.servers[self.best_udp_idx.load(Ordering::Relaxed)].clone()
+
+        if let Some(server) = self.pinned_server(ServerType::Udp) {
+            return server;
+        }
+
+        let best_idx = self.best_udp_idx.load(Ordering::Relaxed);
+        self.choose_available_server(best_idx, ServerType::Udp)
+    }
+
+    /// Get the pinned server, if it is enabled for `server_type`
+    fn pinned_server(&self, server_type: ServerType) -> Option<Arc<ServerIdent>> {
+        let pinned_idx = self.pinned_idx.load(Ordering::Relaxed);
+        self.servers
+            .get(pinned_idx)
+            .filter(|s| PingBalancerContext::check_server_enabled(s.server_config(), server_type))
+            .cloned()
+    }
+
+    /// Choose the best server, or the server with lowest score if the best server's circuit breaker is opened
+    ///
+    /// Best server will be returned if all servers' circuit breakers are opened.
+    fn choose_available_server(&self, best_idx: usize, server_type: ServerType) -> Arc<ServerIdent> {
+        let best_server = &self.servers[best_idx];
+        if server_score(best_server, server_type).is_available() {
+            return best_server.clone();
+        }
+
+        let available_server = self
+            .servers
+            .iter()
+            .filter(|s| {
+                PingBalancerContext::check_server_enabled(s.server_config(), server_type)
+                    && server_score(s, server_type).is_available()
+            })
+            .min_by_key(|s| PingBalancerContext::server_rank(s, server_type));
+
+        match available_server {
+            Some(server) => {
+                trace!(
+                    "{} server {} circuit breaker is opened, chose {}",
+                    server_type,
+                    ServerConfigFormatter::new(best_server.server_config()),
+                    ServerConfigFormatter::new(server.server_config())
+                );
+                server.clone()
+            }
+            None => best_server.clone(),
+        }
     }
 
     #[inline]
@@ -234,6 +402,7 @@ impl PingBalancerContext {
 }
 
 impl PingBalancerContext {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn new(
         mut servers: Vec<Arc<ServerIdent>>,
         context: Arc<ServiceContext>,
@@ -241,13 +410,16 @@ impl PingBalancerContext {
         max_server_rtt: Duration,
         check_interval: Duration,
         check_best_interval: Option<Duration>,
+        circuit_breaker: Option<CircuitBreakerConfig>,
+        score_weights: ScoreWeights,
+        strategy: BalancerStrategy,
     ) -> io::Result<(Arc<PingBalancerContext>, PingBalancerContextTask)> {
         let plugin_abortable = {
             // Start plugins for TCP proxies
 
             let mut plugins = Vec::with_capacity(servers.len());
 
-            for server in &mut servers {
+            for (idx, server) in servers.iter_mut().enumerate() {
                 let server = Arc::get_mut(server).unwrap();
                 let svr_cfg = server.server_config_mut();
 
@@ -255,10 +427,16 @@ impl PingBalancerContext {
                     // Start Plugin Process
                     let plugin = Plugin::start(p, svr_cfg.addr(), PluginMode::Client)?;
                     svr_cfg.set_plugin_addr(plugin.local_addr().into());
-                    plugins.push(plugin);
+                    plugins.push((plugin, idx));
                 }
             }
 
+            // Health of plugin-backed servers depends on plugins, which are tracked by their TCP scores
+            let plugins = plugins
+                .into_iter()
+                .map(|(plugin, idx)| (plugin, servers[idx].clone()))
+                .collect::<Vec<_>>();
+
             if plugins.is_empty() {
                 None
             } else {
@@ -267,27 +445,45 @@ impl PingBalancerContext {
 
                 let mut check_fut = Vec::with_capacity(plugins.len());
 
-                for plugin in &plugins {
+                for (plugin, _) in &plugins {
                     // 3 seconds is not a carefully selected value
                     // I choose that because any values bigger will make me felt too long.
                     check_fut.push(plugin.wait_started(Duration::from_secs(3)));
                 }
 
                 // Run all of them simutaneously
-                let _ = future::join_all(check_fut).await;
+                let started = future::join_all(check_fut).await;
+
+                // Servers are unhealthy until their plugins are started and the checks passed
+                for ((_, server), started) in plugins.iter().zip(started) {
+                    if !started {
+                        warn!(
+                            "plugin of server {} is not started yet, marked down until it passes checks",
+                            ServerConfigFormatter::new(server.server_config())
+                        );
+                        server.tcp_score().set_down();
+                    }
+                }
 
                 let plugin_abortable = tokio::spawn(async move {
                     let mut vfut = Vec::with_capacity(plugins.len());
 
-                    for plugin in plugins {
+                    for (plugin, server) in plugins {
                         vfut.push(async move {
-                            match plugin.join().await {
+                            let result = plugin.join().await;
+
+                            // Plugin is gone, stop choosing this server
+                            server.tcp_score().set_down();
+                            server.tcp_score().report_failure().await;
+
+                            let svr_fmt = ServerConfigFormatter::new(server.server_config());
+                            match result {
                                 Ok(status) => {
-                                    error!("plugin exited with status: {}", status);
+                                    error!("plugin of server {} exited with status: {}", svr_fmt, status);
                                     Ok(())
                                 }
                                 Err(err) => {
-                                    error!("plugin exited with error: {}", err);
+                                    error!("plugin of server {} exited with error: {}", svr_fmt, err);
                                     Err(err)
                                 }
                             }
@@ -309,11 +505,15 @@ impl PingBalancerContext {
             servers,
             best_tcp_idx: AtomicUsize::new(best_tcp_idx),
             best_udp_idx: AtomicUsize::new(best_udp_idx),
+            pinned_idx: AtomicUsize::new(usize::MAX),
             context,
             mode,
             max_server_rtt,
             check_interval,
             check_best_interval,
+            circuit_breaker,
+            score_weights,
+            strategy,
             best_task_notify: Notify::new(),
         };
 
@@ -343,11 +543,56 @@ impl PingBalancerContext {
     }
 
     fn check_server_tcp_enabled(svr_cfg: &ServerConfig) -> bool {
-        svr_cfg.mode().enable_tcp() && svr_cfg.weight().tcp_weight() > 0.0
+        svr_cfg.mode().enable_tcp()
     }
 
     fn check_server_udp_enabled(svr_cfg: &ServerConfig) -> bool {
-        svr_cfg.mode().enable_udp() && svr_cfg.weight().udp_weight() > 0.0
+        svr_cfg.mode().enable_udp()
+    }
+
+    fn check_server_enabled(svr_cfg: &ServerConfig, server_type: ServerType) -> bool {
+        match server_type {
+            ServerType::Tcp => PingBalancerContext::check_server_tcp_enabled(svr_cfg),
+            ServerType::Udp => PingBalancerContext::check_server_udp_enabled(svr_cfg),
+        }
+    }
+
+    /// Servers with weight 0 are failovers, which are chosen only if no other server is available
+    fn check_server_failover(svr_cfg: &ServerConfig, server_type: ServerType) -> bool {
+        let weight = match server_type {
+            ServerType::Tcp => svr_cfg.weight().tcp_weight(),
+            ServerType::Udp => svr_cfg.weight().udp_weight(),
+        };
+        weight <= 0.0
+    }
+
+    /// Enabled and not a failover
+    fn check_server_preferred(svr_cfg: &ServerConfig, server_type: ServerType) -> bool {
+        PingBalancerContext::check_server_enabled(svr_cfg, server_type)
+            && !PingBalancerContext::check_server_failover(svr_cfg, server_type)
+    }
+
+    /// Rank of enabled servers, the lower the better. Failover servers rank after all the other servers
+    fn server_rank(server: &ServerIdent, server_type: ServerType) -> (bool, u32) {
+        (
+            PingBalancerContext::check_server_failover(server.server_config(), server_type),
+            server_score(server, server_type).score(),
+        )
+    }
+
+    /// Index of the available server with the best rank, 0 if there is no available server
+    fn best_server_idx(servers: &[Arc<ServerIdent>], server_type: ServerType) -> usize {
+        servers
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| {
+                PingBalancerContext::check_server_enabled(s.server_config(), server_type)
+                    && server_score(s, server_type).is_available()
+                    && server_score(s, server_type).score() != u32::MAX
+            })
+            .min_by_key(|(_, s)| PingBalancerContext::server_rank(s, server_type))
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
     }
 
     fn probing_required(&self) -> bool {
@@ -439,15 +684,7 @@ impl PingBalancerContext {
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = PingBalancerContext::best_server_idx(servers, ServerType::Tcp);
             self.best_tcp_idx.store(best_idx, Ordering::Release);
 
             if first_run {
@@ -472,15 +709,7 @@ impl PingBalancerContext {
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = PingBalancerContext::best_server_idx(servers, ServerType::Udp);
             self.best_udp_idx.store(best_idx, Ordering::Release);
 
             if first_run {
@@ -550,15 +779,7 @@ impl PingBalancerContext {
         if self.mode.enable_tcp() && check_tcp {
             let old_best_idx = self.best_tcp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.tcp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = PingBalancerContext::best_server_idx(servers, ServerType::Tcp);
             self.best_tcp_idx.store(best_idx, Ordering::Release);
 
             if best_idx != old_best_idx {
@@ -580,15 +801,7 @@ impl PingBalancerContext {
         if self.mode.enable_udp() && check_udp {
             let old_best_idx = self.best_udp_idx.load(Ordering::Acquire);
 
-            let mut best_idx = 0;
-            let mut best_score = u32::MAX;
-            for (idx, server) in servers.iter().enumerate() {
-                let score = server.udp_score().score();
-                if score < best_score {
-                    best_idx = idx;
-                    best_score = score;
-                }
-            }
+            let best_idx = PingBalancerContext::best_server_idx(servers, ServerType::Udp);
             self.best_udp_idx.store(best_idx, Ordering::Release);
 
             if best_idx != old_best_idx {
@@ -719,28 +932,136 @@ impl PingBalancer {
         }
     }
 
+    /// Pin all TCP and UDP traffic to the `index`-th server of `servers()`, regardless of scores and circuit breakers
+    ///
+    /// Traffic that the server's mode doesn't enable (TCP of an `udp_only` server, for example) is still balanced.
+    ///
+    /// The pin lasts until `clear_pin()` is called or servers are reset.
+    pub fn pin_server(&self, index: usize) -> io::Result<()> {
+        let context = self.inner.context.load();
+        if index >= context.servers.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "server index {} out of range, balancer has {} servers",
+                    index,
+                    context.servers.len()
+                ),
+            ));
+        }
+
+        info!(
+            "pinned server {}",
+            ServerConfigFormatter::new(context.servers[index].server_config())
+        );
+        context.pinned_idx.store(index, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Go back to choosing servers by scores
+    pub fn clear_pin(&self) {
+        let context = self.inner.context.load();
+        if context.pinned_idx.swap(usize::MAX, Ordering::Relaxed) != usize::MAX {
+            info!("cleared pinned server");
+        }
+    }
+
+    /// Get a snapshot of every server's statistic, in the order of `servers()`
+    ///
+    /// Only atomic counters are read, it is cheap enough to be polled by dashboards.
+    pub fn server_stats(&self) -> Vec<ServerStatSnapshot> {
+        let context = self.inner.context.load();
+        if context.is_empty() {
+            return Vec::new();
+        }
+
+        let best_tcp = context.best_tcp_server();
+        let best_udp = context.best_udp_server();
+
+        context
+            .servers
+            .iter()
+            .map(|server| ServerStatSnapshot {
+                addr: server.server_config().addr().clone(),
+                tcp_latency: server.tcp_score().last_latency(),
+                udp_latency: server.udp_score().last_latency(),
+                tcp_failures: server.tcp_score().failures(),
+                udp_failures: server.udp_score().failures(),
+                tcp_selected: Arc::ptr_eq(server, &best_tcp),
+                udp_selected: Arc::ptr_eq(server, &best_udp),
+            })
+            .collect()
+    }
+
+    /// Handle a transition of the host's network, which is reported by the host
+    ///
+    /// DNS caches are flushed, circuit breakers are closed and all servers are probed again immediately, so that
+    /// the best server on the new network is chosen without waiting for failures. UDP associations recreate their
+    /// outbound sockets on the new network and restart their idle timers.
+    pub async fn network_changed(&self, net_type: NetworkType) {
+        let context = self.inner.context.load_full();
+
+        let prev_type = context.context.set_network_type(net_type).await;
+        info!(
+            "network changed from {} to {}, probing servers again",
+            prev_type, net_type
+        );
+
+        for server in context.servers.iter() {
+            server.tcp_score().reset_circuit_breaker();
+            server.udp_score().reset_circuit_breaker();
+        }
+
+        context.check_once(false).await;
+    }
+
     /// Reset servers in load balancer. Designed for auto-reloading configuration file.
+    ///
+    /// An empty `servers` is rejected and the current servers are kept, otherwise all traffic would be dropped.
     pub async fn reset_servers(&self, servers: Vec<ServerConfig>) -> io::Result<()> {
+        if servers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no servers to reset to, keeping the current servers",
+            ));
+        }
+
         let old_context = self.inner.context.load();
 
-        let servers = servers
-            .into_iter()
-            .map(|s| {
-                Arc::new(ServerIdent::new(
-                    s,
-                    old_context.max_server_rtt,
-                    old_context.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
-                ))
-            })
-            .collect::<Vec<Arc<ServerIdent>>>();
+        let mut new_servers = Vec::with_capacity(servers.len());
+        for s in servers {
+            let mut server = ServerIdent::new(
+                s,
+                old_context.max_server_rtt,
+                old_context.check_interval * EXPECTED_CHECK_POINTS_IN_CHECK_WINDOW,
+                old_context.circuit_breaker,
+                old_context.score_weights,
+            );
+
+            // Servers that persist across reloading keep their scores, so the best server won't be chosen from
+            // scratch, and their active connections, which are still counted by the least connections strategy
+            if let Some(old_server) = old_context
+                .servers
+                .iter()
+                .find(|old_server| old_server.is_same_server(server.server_config()))
+            {
+                server.restore_scores_from(old_server).await;
+                server.share_connections_with(old_server);
+            }
+
+            new_servers.push(Arc::new(server));
+        }
 
         let (shared_context, task_abortable) = PingBalancerContext::new(
-            servers,
+            new_servers,
             old_context.context.clone(),
             old_context.mode,
             old_context.max_server_rtt,
             old_context.check_interval,
             old_context.check_best_interval,
+            old_context.circuit_breaker,
+            old_context.score_weights,
+            old_context.strategy,
         )
         .await?;
 
@@ -979,6 +1300,14 @@ impl PingChecker {
     }
 }
 
+#[inline]
+fn server_score(server: &ServerIdent, server_type: ServerType) -> &ServerScore {
+    match server_type {
+        ServerType::Tcp => server.tcp_score(),
+        ServerType::Udp => server.udp_score(),
+    }
+}
+
 struct ServerConfigFormatter<'a> {
     server_config: &'a ServerConfig,
 }
@@ -1004,6 +1333,25 @@ impl Display for ServerConfigFormatter<'_> {
     }
 }
 
+/// Statistic of a server in `PingBalancer`, returned by `PingBalancer::server_stats`
+#[derive(Debug, Clone)]
+pub struct ServerStatSnapshot {
+    /// Server's address
+    pub addr: ServerAddr,
+    /// Latency of the latest successful TCP check
+    pub tcp_latency: Option<Duration>,
+    /// Latency of the latest successful UDP check
+    pub udp_latency: Option<Duration>,
+    /// Number of failed TCP checks and reported TCP failures
+    pub tcp_failures: u64,
+    /// Number of failed UDP checks and reported UDP failures
+    pub udp_failures: u64,
+    /// Server is currently chosen for TCP
+    pub tcp_selected: bool,
+    /// Server is currently chosen for UDP
+    pub udp_selected: bool,
+}
+
 /// Server Iterator
 pub struct PingServerIter<'a> {
     #[allow(dead_code)]
@@ -1018,3 +1366,272 @@ impl<'a> Iterator for PingServerIter<'a> {
         self.iter.next().map(AsRef::as_ref)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::{config::ServerWeight, crypto::CipherKind};
+
+    use super::{super::circuit_breaker::CircuitState, *};
+
+    fn server_config(addr: &str) -> ServerConfig {
+        ServerConfig::new(addr.parse::<SocketAddr>().unwrap(), "password", CipherKind::AES_256_GCM)
+    }
+
+    #[tokio::test]
+    async fn reset_servers_keeps_persisting_scores() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(server_config("127.0.0.1:1"));
+        builder.add_server(server_config("127.0.0.1:2"));
+        let balancer = builder.build().await.unwrap();
+
+        for server in balancer.servers() {
+            if server.server_config().addr() == server_config("127.0.0.1:1").addr() {
+                for _ in 0..5 {
+                    server.tcp_score().push_score(Score::Latency(10)).await;
+                }
+            }
+        }
+
+        balancer
+            .reset_servers(vec![server_config("127.0.0.1:1"), server_config("127.0.0.1:3")])
+            .await
+            .unwrap();
+
+        let servers = balancer.servers().collect::<Vec<_>>();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[0].server_config().addr(), server_config("127.0.0.1:1").addr());
+        assert_eq!(servers[1].server_config().addr(), server_config("127.0.0.1:3").addr());
+
+        // The persisting server still has its latencies, the new one has only failures from the first check
+        assert!(servers[0].tcp_score().score() < servers[1].tcp_score().score());
+        assert_eq!(
+            balancer.best_tcp_server().server_config().addr(),
+            servers[0].server_config().addr()
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_servers_keeps_active_connections() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(server_config("127.0.0.1:1"));
+        builder.add_server(server_config("127.0.0.1:2"));
+        let balancer = builder.build().await.unwrap();
+
+        let old_server = balancer
+            .servers()
+            .find(|server| server.server_config().addr() == server_config("127.0.0.1:1").addr())
+            .unwrap();
+        let first = old_server.track_connection();
+        let second = old_server.track_connection();
+
+        balancer
+            .reset_servers(vec![server_config("127.0.0.1:1"), server_config("127.0.0.1:3")])
+            .await
+            .unwrap();
+
+        let servers = balancer.servers().collect::<Vec<_>>();
+        assert_eq!(servers[0].active_connections(), 2);
+        assert_eq!(servers[1].active_connections(), 0);
+
+        // Connections opened before reloading are uncounted when they are closed
+        drop(first);
+        assert_eq!(servers[0].active_connections(), 1);
+        let third = servers[0].track_connection();
+        drop(second);
+        assert_eq!(servers[0].active_connections(), 1);
+        drop(third);
+        assert_eq!(servers[0].active_connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn builder_options_after_add_server() {
+        let weights = ScoreWeights {
+            rtt: 1.0,
+            loss: 0.0,
+            jitter: 0.0,
+        };
+
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(server_config("127.0.0.1:1"));
+        builder.circuit_breaker(CircuitBreakerConfig {
+            failure_threshold: 1,
+            ..Default::default()
+        });
+        builder.score_weights(weights);
+        let balancer = builder.build().await.unwrap();
+
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.score_weights(weights);
+        builder.add_server(server_config("127.0.0.1:1"));
+        let expected = builder.build().await.unwrap();
+
+        let server = balancer.servers().next().unwrap();
+        let expected_server = expected.servers().next().unwrap();
+        for score in [Score::Latency(10), Score::Errored, Score::Latency(30)] {
+            assert_eq!(
+                server.tcp_score().push_score(score).await,
+                expected_server.tcp_score().push_score(score).await
+            );
+        }
+
+        server.tcp_score().report_failure().await;
+        assert_eq!(server.tcp_score().circuit_state(), CircuitState::Open);
+    }
+
+    #[tokio::test]
+    async fn reset_servers_rejects_empty() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(server_config("127.0.0.1:1"));
+        builder.add_server(server_config("127.0.0.1:2"));
+        let balancer = builder.build().await.unwrap();
+
+        let err = balancer.reset_servers(Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let addrs = balancer
+            .servers()
+            .map(|server| server.server_config().addr().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addrs,
+            [
+                server_config("127.0.0.1:1").addr().clone(),
+                server_config("127.0.0.1:2").addr().clone()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn server_stats_latency() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(server_config("127.0.0.1:1"));
+        builder.add_server(server_config("127.0.0.1:2"));
+        let balancer = builder.build().await.unwrap();
+
+        let stats = balancer.server_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].addr, *server_config("127.0.0.1:1").addr());
+        assert_eq!(stats[1].addr, *server_config("127.0.0.1:2").addr());
+        assert!(stats.iter().all(|s| s.tcp_latency.is_none()));
+
+        for lat in [30, 20, 10] {
+            for server in balancer.servers() {
+                server.tcp_score().push_score(Score::Latency(lat)).await;
+            }
+
+            let stats = balancer.server_stats();
+            assert!(stats
+                .iter()
+                .all(|s| s.tcp_latency == Some(Duration::from_millis(lat as u64))));
+        }
+
+        let failures = balancer.server_stats()[1].tcp_failures;
+        balancer.servers().nth(1).unwrap().tcp_score().report_failure().await;
+        assert_eq!(balancer.server_stats()[1].tcp_failures, failures + 1);
+        assert_eq!(balancer.server_stats().iter().filter(|s| s.tcp_selected).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn pin_server() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(server_config("127.0.0.1:1"));
+        builder.add_server(server_config("127.0.0.1:2"));
+        let balancer = builder.build().await.unwrap();
+
+        assert!(balancer.pin_server(2).is_err());
+
+        balancer.pin_server(1).unwrap();
+        let pinned_addr = server_config("127.0.0.1:2").addr().clone();
+
+        // Even if the other server is much better
+        for server in balancer.servers() {
+            if server.server_config().addr() != &pinned_addr {
+                for _ in 0..5 {
+                    server.tcp_score().push_score(Score::Latency(1)).await;
+                    server.udp_score().push_score(Score::Latency(1)).await;
+                }
+            }
+        }
+        balancer.servers().nth(1).unwrap().tcp_score().set_down();
+
+        for _ in 0..10 {
+            assert_eq!(balancer.best_tcp_server().server_config().addr(), &pinned_addr);
+            assert_eq!(balancer.best_udp_server().server_config().addr(), &pinned_addr);
+        }
+
+        // Down server is not chosen without the pin
+        balancer.clear_pin();
+        assert_ne!(balancer.best_tcp_server().server_config().addr(), &pinned_addr);
+    }
+
+    #[tokio::test]
+    async fn pin_server_mode_disabled() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpAndUdp);
+        builder.add_server(server_config("127.0.0.1:1"));
+        let mut udp_only = server_config("127.0.0.1:2");
+        udp_only.set_mode(Mode::UdpOnly);
+        builder.add_server(udp_only);
+        let balancer = builder.build().await.unwrap();
+
+        balancer.pin_server(1).unwrap();
+        let pinned_addr = server_config("127.0.0.1:2").addr().clone();
+
+        // TCP falls through to the normal selection, which skips the UDP only server
+        for _ in 0..10 {
+            assert_ne!(balancer.best_tcp_server().server_config().addr(), &pinned_addr);
+            assert_eq!(balancer.best_udp_server().server_config().addr(), &pinned_addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn weighted_strategy() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.strategy(BalancerStrategy::Weighted);
+        for (addr, weight) in [("127.0.0.1:1", 0.75), ("127.0.0.1:2", 0.25)] {
+            let mut svr_weight = ServerWeight::new();
+            svr_weight.set_tcp_weight(weight);
+            let mut svr_cfg = server_config(addr);
+            svr_cfg.set_weight(svr_weight);
+            builder.add_server(svr_cfg);
+        }
+        let balancer = builder.build().await.unwrap();
+
+        // Equal latencies
+        for server in balancer.servers() {
+            for _ in 0..10 {
+                server.tcp_score().push_score(Score::Latency(10)).await;
+            }
+        }
+
+        let heavy_addr = server_config("127.0.0.1:1").addr().clone();
+        let heavy_count = (0..4000)
+            .filter(|_| balancer.best_tcp_server().server_config().addr() == &heavy_addr)
+            .count();
+        assert!((2700..=3300).contains(&heavy_count), "{}", heavy_count);
+    }
+
+    #[tokio::test]
+    async fn zero_weight_failover() {
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        let mut failover_weight = ServerWeight::new();
+        failover_weight.set_tcp_weight(0.0);
+        let mut failover = server_config("127.0.0.1:1");
+        failover.set_weight(failover_weight);
+        builder.add_server(failover);
+        builder.add_server(server_config("127.0.0.1:2"));
+        let balancer = builder.build().await.unwrap();
+
+        // Even if the failover server is much better
+        let servers = balancer.servers().collect::<Vec<_>>();
+        for _ in 0..5 {
+            servers[0].tcp_score().push_score(Score::Latency(1)).await;
+        }
+        servers[1].tcp_score().report_failure().await;
+
+        let failover_addr = server_config("127.0.0.1:1").addr().clone();
+        assert_ne!(balancer.best_tcp_server().server_config().addr(), &failover_addr);
+
+        servers[1].tcp_score().set_down();
+        assert_eq!(balancer.best_tcp_server().server_config().addr(), &failover_addr);
+    }
+}
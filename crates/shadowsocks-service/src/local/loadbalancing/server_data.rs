@@ -2,30 +2,51 @@
 
 use std::{
     fmt::{self, Debug},
-    sync::atomic::{AtomicU32, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
 use shadowsocks::ServerConfig;
 use tokio::sync::Mutex;
 
-use super::server_stat::{Score, ServerStat};
+use super::{
+    circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState},
+    server_stat::{Score, ScoreWeights, ServerStat},
+};
 
 /// Server's statistic score
 pub struct ServerScore {
     stat_data: Mutex<ServerStat>,
     score: AtomicU32,
+    circuit_breaker: CircuitBreaker,
+    down: AtomicBool,
+    /// Latest latency in milliseconds, `u32::MAX` if never succeeded
+    last_latency: AtomicU32,
+    failures: AtomicU64,
 }
 
 impl ServerScore {
     /// Create a `ServerScore`
-    pub fn new(user_weight: f32, max_server_rtt: Duration, check_window: Duration) -> ServerScore {
+    pub fn new(
+        user_weight: f32,
+        max_server_rtt: Duration,
+        check_window: Duration,
+        circuit_breaker: Option<CircuitBreakerConfig>,
+        weights: ScoreWeights,
+    ) -> ServerScore {
         let max_server_rtt = max_server_rtt.as_millis() as u32;
         assert!(max_server_rtt > 0);
 
         ServerScore {
-            stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window)),
+            stat_data: Mutex::new(ServerStat::new(user_weight, max_server_rtt, check_window, weights)),
             score: AtomicU32::new(u32::MAX),
+            circuit_breaker: CircuitBreaker::new(circuit_breaker),
+            down: AtomicBool::new(false),
+            last_latency: AtomicU32::new(u32::MAX),
+            failures: AtomicU64::new(0),
         }
     }
 
@@ -36,6 +57,18 @@ impl ServerScore {
 
     /// Append a `Score` into statistic and recalculate score of the server
     pub async fn push_score(&self, score: Score) -> u32 {
+        match score {
+            Score::Latency(lat) => {
+                self.circuit_breaker.record_success();
+                self.down.store(false, Ordering::Release);
+                self.last_latency.store(lat, Ordering::Release);
+            }
+            Score::Errored => {
+                self.circuit_breaker.record_failure();
+                self.failures.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+
         let updated_score = {
             let mut stat = self.stat_data.lock().await;
             stat.push_score(score)
@@ -44,15 +77,95 @@ impl ServerScore {
         updated_score
     }
 
+    /// Take over the statistic of `other`, usually the same server before reloading
+    ///
+    /// Circuit breaker and down state are not restored, they will be updated by the next check.
+    pub async fn restore_from(&self, other: &ServerScore) {
+        let restored_score = {
+            let other_stat = other.stat_data.lock().await;
+            let mut stat = self.stat_data.lock().await;
+            stat.restore_from(&other_stat)
+        };
+        if let Some(score) = restored_score {
+            self.score.store(score, Ordering::Release);
+        }
+        self.last_latency
+            .store(other.last_latency.load(Ordering::Acquire), Ordering::Release);
+        self.failures.store(other.failures(), Ordering::Release);
+    }
+
     /// Report request failure of this server, which will eventually records an `Errored` score
     pub async fn report_failure(&self) -> u32 {
         self.push_score(Score::Errored).await
     }
+
+    /// Get server's circuit breaker state
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker.state()
+    }
+
+    /// Check if server could be chosen, which means its circuit breaker is not opened and it is not marked down
+    pub fn is_available(&self) -> bool {
+        !self.down.load(Ordering::Acquire) && self.circuit_breaker.is_available()
+    }
+
+    /// Mark server down, for example, its plugin has exited
+    ///
+    /// Server will be available again after the next successful check.
+    pub fn set_down(&self) {
+        self.down.store(true, Ordering::Release);
+    }
+
+    /// Check if server is marked down
+    pub fn is_down(&self) -> bool {
+        self.down.load(Ordering::Acquire)
+    }
+
+    /// Close server's circuit breaker, cooldown of an opened breaker is skipped
+    pub fn reset_circuit_breaker(&self) {
+        self.circuit_breaker.reset();
+    }
+
+    /// Number of times that server's circuit breaker has been opened
+    pub fn circuit_trips(&self) -> u64 {
+        self.circuit_breaker.trips()
+    }
+
+    /// Latency of the latest successful check, `None` if server have never been checked successfully
+    pub fn last_latency(&self) -> Option<Duration> {
+        match self.last_latency.load(Ordering::Acquire) {
+            u32::MAX => None,
+            lat => Some(Duration::from_millis(lat as u64)),
+        }
+    }
+
+    /// Number of failed checks and reported failures
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Acquire)
+    }
 }
 
 impl Debug for ServerScore {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("ServerScore").field("score", &self.score()).finish()
+        f.debug_struct("ServerScore")
+            .field("score", &self.score())
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("down", &self.is_down())
+            .field("last_latency", &self.last_latency())
+            .field("failures", &self.failures())
+            .finish()
+    }
+}
+
+/// Active TCP connection of a server, it is counted until dropped
+#[derive(Debug)]
+pub struct ServerConnectionGuard {
+    active_connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ServerConnectionGuard {
+    fn drop(&mut self) {
+        self.active_connections.fetch_sub(1, Ordering::AcqRel);
     }
 }
 
@@ -62,15 +175,35 @@ pub struct ServerIdent {
     tcp_score: ServerScore,
     udp_score: ServerScore,
     svr_cfg: ServerConfig,
+    active_connections: Arc<AtomicUsize>,
 }
 
 impl ServerIdent {
     /// Create a `ServerIdent`
-    pub fn new(svr_cfg: ServerConfig, max_server_rtt: Duration, check_window: Duration) -> ServerIdent {
+    pub fn new(
+        svr_cfg: ServerConfig,
+        max_server_rtt: Duration,
+        check_window: Duration,
+        circuit_breaker: Option<CircuitBreakerConfig>,
+        weights: ScoreWeights,
+    ) -> ServerIdent {
         ServerIdent {
-            tcp_score: ServerScore::new(svr_cfg.weight().tcp_weight(), max_server_rtt, check_window),
-            udp_score: ServerScore::new(svr_cfg.weight().udp_weight(), max_server_rtt, check_window),
+            tcp_score: ServerScore::new(
+                svr_cfg.weight().tcp_weight(),
+                max_server_rtt,
+                check_window,
+                circuit_breaker,
+                weights,
+            ),
+            udp_score: ServerScore::new(
+                svr_cfg.weight().udp_weight(),
+                max_server_rtt,
+                check_window,
+                circuit_breaker,
+                weights,
+            ),
             svr_cfg,
+            active_connections: Arc::new(AtomicUsize::new(0)),
         }
     }
 
@@ -89,4 +222,84 @@ impl ServerIdent {
     pub fn udp_score(&self) -> &ServerScore {
         &self.udp_score
     }
+
+    /// Check if `other` is the same remote server, which is matched by address and cipher
+    pub fn is_same_server(&self, other: &ServerConfig) -> bool {
+        self.svr_cfg.addr() == other.addr() && self.svr_cfg.method() == other.method()
+    }
+
+    /// Take over TCP and UDP statistic scores of `other`
+    pub async fn restore_scores_from(&self, other: &ServerIdent) {
+        self.tcp_score.restore_from(&other.tcp_score).await;
+        self.udp_score.restore_from(&other.udp_score).await;
+    }
+
+    /// Share the active connection count of `other`
+    ///
+    /// Connections that were opened through `other` are still counted here, and uncounted when they are closed.
+    pub fn share_connections_with(&mut self, other: &ServerIdent) {
+        self.active_connections = other.active_connections.clone();
+    }
+
+    /// Count an active TCP connection proxied by this server, until the returned guard is dropped
+    pub fn track_connection(&self) -> ServerConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::AcqRel);
+        ServerConnectionGuard {
+            active_connections: self.active_connections.clone(),
+        }
+    }
+
+    /// Number of active TCP connections proxied by this server
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn server_score_down_until_checked() {
+        let score = ServerScore::new(
+            1.0,
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            None,
+            ScoreWeights::default(),
+        );
+        assert!(score.is_available());
+
+        score.set_down();
+        assert!(!score.is_available());
+        score.report_failure().await;
+        assert!(!score.is_available());
+
+        score.push_score(Score::Latency(10)).await;
+        assert!(score.is_available());
+    }
+
+    #[test]
+    fn server_ident_active_connections() {
+        let svr_cfg = ServerConfig::new(
+            "127.0.0.1:8388".parse::<std::net::SocketAddr>().unwrap(),
+            "password",
+            shadowsocks::crypto::CipherKind::AES_256_GCM,
+        );
+        let ident = ServerIdent::new(
+            svr_cfg,
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            None,
+            ScoreWeights::default(),
+        );
+
+        let first = ident.track_connection();
+        let second = ident.track_connection();
+        assert_eq!(ident.active_connections(), 2);
+        drop(first);
+        assert_eq!(ident.active_connections(), 1);
+        drop(second);
+        assert_eq!(ident.active_connections(), 0);
+    }
 }
@@ -1,15 +1,43 @@
 //! Server latency statistic
 
-use std::{
-    collections::VecDeque,
-    time::{Duration, Instant},
-};
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
 
 /// Interval between each check
 pub const DEFAULT_CHECK_INTERVAL_SEC: u64 = 10;
 /// Timeout of each check
 pub const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 5; // A common connection timeout of 5 seconds.
 
+/// Weights of the components of a server's score
+///
+/// ```plain
+/// score = (norm_rtt * rtt + loss_rate * loss + norm_stdev * jitter) / (rtt + loss + jitter) / user_weight
+/// ```
+///
+/// `loss_rate` is the ratio of failed probes and reported connection failures in the check window, `norm_rtt` is the
+/// median latency and `norm_stdev` is the latency's standard deviation, both normalized by the max server RTT. Lower
+/// score is better.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ScoreWeights {
+    /// Weight of latency, defaults to 1.0
+    pub rtt: f64,
+    /// Weight of packet loss (failure rate), defaults to 3.0
+    pub loss: f64,
+    /// Weight of jitter (latency's standard deviation), defaults to 1.0
+    pub jitter: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> ScoreWeights {
+        ScoreWeights {
+            rtt: 1.0,
+            loss: 3.0,
+            jitter: 1.0,
+        }
+    }
+}
+
 /// Statistic score
 #[derive(Debug, Copy, Clone)]
 pub enum Score {
@@ -43,6 +71,8 @@ pub struct ServerStat {
     user_weight: f32,
     /// Checking window size
     check_window: Duration,
+    /// Weights of score's components
+    weights: ScoreWeights,
 }
 
 fn max_latency_stdev(max_server_rtt: u32) -> f64 {
@@ -55,8 +85,9 @@ fn max_latency_stdev(max_server_rtt: u32) -> f64 {
 }
 
 impl ServerStat {
-    pub fn new(user_weight: f32, max_server_rtt: u32, check_window: Duration) -> ServerStat {
+    pub fn new(user_weight: f32, max_server_rtt: u32, check_window: Duration, weights: ScoreWeights) -> ServerStat {
         assert!((0.0..=1.0).contains(&user_weight));
+        assert!(weights.rtt >= 0.0 && weights.loss >= 0.0 && weights.jitter >= 0.0);
 
         ServerStat {
             rtt: max_server_rtt,
@@ -68,6 +99,7 @@ impl ServerStat {
             latency_mean: 0.0,
             user_weight,
             check_window,
+            weights,
         }
     }
 
@@ -78,23 +110,23 @@ impl ServerStat {
         // Normalize stdev
         let nstdev = self.latency_stdev / self.max_latency_stdev;
 
-        const SCORE_RTT_WEIGHT: f64 = 1.0;
-        const SCORE_FAIL_WEIGHT: f64 = 3.0;
-        const SCORE_STDEV_WEIGHT: f64 = 1.0;
+        let ScoreWeights { rtt, loss, jitter } = self.weights;
+        // All weights are 0, treats them equally
+        let total_weight = (rtt + loss + jitter).max(f64::EPSILON);
 
-        // [EPSILON, 1]
-        // Just for avoiding divide by 0
-        let user_weight = self.user_weight.max(f32::EPSILON);
+        // (0, 1]
+        // Failover servers (weight 0) are not ranked with other servers, they rank by their own quality
+        let user_weight = if self.user_weight > 0.0 { self.user_weight } else { 1.0 };
 
-        // Score = (norm_lat * 1.0 + prop_err * 3.0 + stdev * 1.0) / 5.0 / user_weight
+        // Score = (norm_lat * rtt + prop_err * loss + stdev * jitter) / (rtt + loss + jitter) / user_weight
+        //
+        // Defaults are rtt = 1.0, loss = 3.0, jitter = 1.0
         //
         // 1. The lower latency, the better
         // 2. The lower errored count, the better
         // 3. The lower latency's stdev, the better
         // 4. The higher user's weight, the better
-        let score = (nrtt * SCORE_RTT_WEIGHT + self.fail_rate * SCORE_FAIL_WEIGHT + nstdev * SCORE_STDEV_WEIGHT)
-            / (SCORE_RTT_WEIGHT + SCORE_FAIL_WEIGHT + SCORE_STDEV_WEIGHT)
-            / user_weight as f64;
+        let score = (nrtt * rtt + self.fail_rate * loss + nstdev * jitter) / total_weight / user_weight as f64;
 
         // Times 10000 converts to u32, for 0.0001 precision
         (score * 10000.0) as u32
@@ -117,6 +149,18 @@ impl ServerStat {
         self.recalculate_score()
     }
 
+    /// Take over probe data of `other`, usually the same server before reloading
+    ///
+    /// Returns the recalculated score, or `None` if `other` haven't been probed yet.
+    pub fn restore_from(&mut self, other: &ServerStat) -> Option<u32> {
+        if other.latency_queue.is_empty() {
+            return None;
+        }
+
+        self.latency_queue = other.latency_queue.clone();
+        Some(self.recalculate_score())
+    }
+
     fn recalculate_score(&mut self) -> u32 {
         if self.latency_queue.is_empty() {
             return self.score();
@@ -168,3 +212,45 @@ impl ServerStat {
         self.score()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lossy_and_slow(weights: ScoreWeights) -> (u32, u32) {
+        let check_window = Duration::from_secs(60);
+
+        // Fast, but loses 1/4 of probes
+        let mut lossy = ServerStat::new(1.0, 1000, check_window, weights);
+        let mut lossy_score = 0;
+        for score in [
+            Score::Latency(50),
+            Score::Errored,
+            Score::Latency(50),
+            Score::Latency(50),
+        ] {
+            lossy_score = lossy.push_score(score);
+        }
+
+        // Slow, but stable
+        let mut slow = ServerStat::new(1.0, 1000, check_window, weights);
+        let mut slow_score = 0;
+        for _ in 0..4 {
+            slow_score = slow.push_score(Score::Latency(400));
+        }
+
+        (lossy_score, slow_score)
+    }
+
+    #[test]
+    fn score_weights_loss() {
+        let (lossy, slow) = lossy_and_slow(ScoreWeights {
+            loss: 0.5,
+            ..Default::default()
+        });
+        assert!(lossy < slow);
+
+        let (lossy, slow) = lossy_and_slow(ScoreWeights::default());
+        assert!(lossy > slow);
+    }
+}
@@ -3,34 +3,46 @@
 use std::{
     future::Future,
     io::{self, ErrorKind},
-    pin::Pin,
+    net::SocketAddr,
     sync::Arc,
-    task::{Context, Poll},
     time::Duration,
 };
 
-use futures::{future, ready};
-use log::trace;
+use futures::future::{self, Either};
+use log::{info, trace, warn};
 use shadowsocks::{
     config::Mode,
     net::{AcceptOpts, ConnectOpts},
 };
-use tokio::task::JoinHandle;
+use tokio::time;
 
 #[cfg(feature = "local-flow-stat")]
-use crate::{config::LocalFlowStatAddress, net::FlowStat};
+use crate::config::LocalFlowStatAddress;
 use crate::{
-    config::{Config, ConfigType, ProtocolType},
+    config::{clamp_socket_buffer_size, Config, ConfigType, ProtocolType},
     dns::build_dns_resolver,
+    net::{
+        liveness::DEFAULT_LIVENESS_INTERVAL,
+        utils::ServerHandle,
+        AccessLog,
+        ConcurrencyLimit,
+        DestinationFlowStat,
+        FlowStat,
+        LivenessFile,
+        PlaintextCapture,
+    },
 };
 
 use self::{
-    context::ServiceContext,
-    loadbalancing::{PingBalancer, PingBalancerBuilder},
+    context::{NetworkType, ServiceContext},
+    loadbalancing::{CircuitBreakerConfig, PingBalancer, PingBalancerBuilder, ScoreWeights},
+    net::UdpAssociationStat,
 };
 
 #[cfg(feature = "local-dns")]
 use self::dns::{Dns, DnsBuilder};
+#[cfg(feature = "local-health-api")]
+use self::health::HealthApi;
 #[cfg(feature = "local-http")]
 use self::http::{Http, HttpBuilder};
 #[cfg(feature = "local-redir")]
@@ -44,6 +56,8 @@ use self::tunnel::{Tunnel, TunnelBuilder};
 pub mod context;
 #[cfg(feature = "local-dns")]
 pub mod dns;
+#[cfg(feature = "local-health-api")]
+pub mod health;
 #[cfg(feature = "local-http")]
 pub mod http;
 pub mod loadbalancing;
@@ -62,30 +76,22 @@ pub mod utils;
 /// This is borrowed from Go's `net` library's default setting
 pub(crate) const LOCAL_DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(15);
 
-struct ServerHandle(JoinHandle<io::Result<()>>);
-
-impl Drop for ServerHandle {
-    #[inline]
-    fn drop(&mut self) {
-        self.0.abort();
-    }
-}
-
-impl Future for ServerHandle {
-    type Output = io::Result<()>;
-
-    #[inline]
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match ready!(Pin::new(&mut self.0).poll(cx)) {
-            Ok(res) => res.into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
-        }
-    }
+/// Address that a listener of local servers is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenAddr {
+    /// Protocol of the local server
+    pub protocol: ProtocolType,
+    /// `true` for the UDP listener, `false` for the TCP listener
+    pub udp: bool,
+    /// Bound address, with the actual port if `local_port` is 0
+    pub addr: SocketAddr,
 }
 
 /// Local Server instance
 pub struct Server {
     balancer: PingBalancer,
+    relay_concurrency: ConcurrencyLimit,
+    liveness_file: Option<LivenessFile>,
     socks_servers: Vec<Socks>,
     #[cfg(feature = "local-tunnel")]
     tunnel_servers: Vec<Tunnel>,
@@ -99,8 +105,11 @@ pub struct Server {
     redir_servers: Vec<Redir>,
     #[cfg(feature = "local-flow-stat")]
     local_stat_addr: Option<LocalFlowStatAddress>,
-    #[cfg(feature = "local-flow-stat")]
+    #[cfg(feature = "local-health-api")]
+    health_api: Option<HealthApi>,
     flow_stat: Arc<FlowStat>,
+    destination_flow_stat: Arc<DestinationFlowStat>,
+    acl_contexts: Vec<(Option<usize>, Arc<ServiceContext>)>,
 }
 
 impl Server {
@@ -145,8 +154,12 @@ impl Server {
 
             ..Default::default()
         };
-        connect_opts.tcp.send_buffer_size = config.outbound_send_buffer_size;
-        connect_opts.tcp.recv_buffer_size = config.outbound_recv_buffer_size;
+        connect_opts.tcp.send_buffer_size = config
+            .outbound_send_buffer_size
+            .map(|bs| clamp_socket_buffer_size("outbound_send_buffer_size", bs));
+        connect_opts.tcp.recv_buffer_size = config
+            .outbound_recv_buffer_size
+            .map(|bs| clamp_socket_buffer_size("outbound_recv_buffer_size", bs));
         connect_opts.tcp.nodelay = config.no_delay;
         connect_opts.tcp.fastopen = config.fast_open;
         connect_opts.tcp.keepalive = config.keep_alive.or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT));
@@ -158,8 +171,12 @@ impl Server {
             ipv6_only: config.ipv6_only,
             ..Default::default()
         };
-        accept_opts.tcp.send_buffer_size = config.inbound_send_buffer_size;
-        accept_opts.tcp.recv_buffer_size = config.inbound_recv_buffer_size;
+        accept_opts.tcp.send_buffer_size = config
+            .inbound_send_buffer_size
+            .map(|bs| clamp_socket_buffer_size("inbound_send_buffer_size", bs));
+        accept_opts.tcp.recv_buffer_size = config
+            .inbound_recv_buffer_size
+            .map(|bs| clamp_socket_buffer_size("inbound_recv_buffer_size", bs));
         accept_opts.tcp.nodelay = config.no_delay;
         accept_opts.tcp.fastopen = config.fast_open;
         accept_opts.tcp.keepalive = config.keep_alive.or(Some(LOCAL_DEFAULT_KEEPALIVE_TIMEOUT));
@@ -181,6 +198,8 @@ impl Server {
         if config.ipv6_first {
             context.set_ipv6_first(config.ipv6_first);
         }
+        context.set_dns_ipv4_only(config.dns_ipv4_only);
+        context.set_dns_ipv6_only(config.dns_ipv6_only);
 
         if let Some(acl) = config.acl {
             context.set_acl(Arc::new(acl));
@@ -188,6 +207,60 @@ impl Server {
 
         context.set_security_config(&config.security);
 
+        context.relay_concurrency().set_limit(config.relay_concurrency);
+        if let Some(limit) = config.outbound_connect_concurrency {
+            context.connect_concurrency().set_limit(Some(limit));
+        }
+
+        context
+            .retry_budget()
+            .set_retries_per_second(config.outbound_retry_budget);
+
+        if let Some(rate) = config.connection_log_sample_rate {
+            context.connection_log_sampler().set_rate(rate);
+        }
+
+        if let Some(jitter) = config.outbound_connect_jitter {
+            context.set_connect_jitter(jitter);
+        }
+
+        context.set_connect_timeout(config.outbound_connect_timeout);
+
+        context.set_log_selected_server(config.log_selected_server);
+        context.set_proxy_dns(config.proxy_dns);
+
+        if let Some(ref path) = config.access_log {
+            let access_log = AccessLog::open(path).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to open access log {}, error: {}", path.display(), err),
+                )
+            })?;
+            context.set_access_log(Arc::new(access_log));
+        }
+
+        if let Some(ref capture) = config.plaintext_capture {
+            let plaintext_capture = PlaintextCapture::open(capture.target.clone(), &capture.path, capture.max_size)
+                .map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "failed to open plaintext capture {}, error: {}",
+                            capture.path.display(),
+                            err
+                        ),
+                    )
+                })?;
+            warn!(
+                "plaintext capture is ENABLED, DECRYPTED data of TCP tunnels to {} will be written to {} (at most {} bytes), \
+                 it is meant for debugging only",
+                capture.target,
+                capture.path.display(),
+                capture.max_size
+            );
+            context.set_plaintext_capture(Arc::new(plaintext_capture));
+        }
+
         assert!(!config.local.is_empty(), "no valid local server configuration");
 
         // Create a service balancer for choosing between multiple servers
@@ -219,6 +292,33 @@ impl Server {
                 balancer_builder.check_best_interval(intv);
             }
 
+            // circuit_breaker have to be set before add_server
+            if let Some(failures) = config.balancer.circuit_breaker_failures {
+                if failures > 0 {
+                    let mut breaker = CircuitBreakerConfig {
+                        failure_threshold: failures,
+                        ..Default::default()
+                    };
+                    if let Some(window) = config.balancer.circuit_breaker_window {
+                        breaker.failure_window = window;
+                    }
+                    if let Some(cooldown) = config.balancer.circuit_breaker_cooldown {
+                        breaker.cooldown = cooldown;
+                    }
+                    balancer_builder.circuit_breaker(breaker);
+                }
+            }
+
+            // score_weights have to be set before add_server
+            let default_weights = ScoreWeights::default();
+            balancer_builder.score_weights(ScoreWeights {
+                rtt: config.balancer.latency_weight.unwrap_or(default_weights.rtt),
+                loss: config.balancer.loss_weight.unwrap_or(default_weights.loss),
+                jitter: config.balancer.jitter_weight.unwrap_or(default_weights.jitter),
+            });
+
+            balancer_builder.strategy(config.balancer.strategy);
+
             for server in config.server {
                 balancer_builder.add_server(server.config);
             }
@@ -228,6 +328,10 @@ impl Server {
 
         let mut local_server = Server {
             balancer: balancer.clone(),
+            relay_concurrency: context.relay_concurrency().clone(),
+            liveness_file: config
+                .liveness_file
+                .map(|p| LivenessFile::new(p, config.liveness_interval.unwrap_or(DEFAULT_LIVENESS_INTERVAL))),
             socks_servers: Vec::new(),
             #[cfg(feature = "local-tunnel")]
             tunnel_servers: Vec::new(),
@@ -241,11 +345,21 @@ impl Server {
             redir_servers: Vec::new(),
             #[cfg(feature = "local-flow-stat")]
             local_stat_addr: config.local_stat_addr,
-            #[cfg(feature = "local-flow-stat")]
+            #[cfg(feature = "local-health-api")]
+            health_api: match config.health_addr {
+                Some(addr) => Some(HealthApi::bind(addr, context.relay_concurrency().clone(), balancer.clone()).await?),
+                None => None,
+            },
             flow_stat: context.flow_stat(),
+            destination_flow_stat: context.destination_flow_stat(),
+            acl_contexts: Vec::new(),
         };
 
-        for local_instance in config.local {
+        if context.acl().is_some() {
+            local_server.acl_contexts.push((None, Arc::new(context.clone())));
+        }
+
+        for (local_index, local_instance) in config.local.into_iter().enumerate() {
             let local_config = local_instance.config;
 
             // Clone from global ServiceContext instance
@@ -253,11 +367,15 @@ impl Server {
             let mut context = context.clone();
 
             // Private ACL
+            let has_private_acl = local_instance.acl.is_some();
             if let Some(acl) = local_instance.acl {
                 context.set_acl(Arc::new(acl))
             }
 
             let context = Arc::new(context);
+            if has_private_acl {
+                local_server.acl_contexts.push((Some(local_index), context.clone()));
+            }
             let balancer = balancer.clone();
 
             match local_config.protocol {
@@ -270,6 +388,14 @@ impl Server {
                     let mut server_builder = SocksBuilder::with_context(context.clone(), client_addr, balancer);
                     server_builder.set_mode(local_config.mode);
                     server_builder.set_socks5_auth(local_config.socks5_auth);
+                    if let Some(t) = local_config.socks_greeting_timeout {
+                        server_builder.set_greeting_timeout(t);
+                    }
+                    #[cfg(feature = "local-http")]
+                    server_builder.set_greeting_allow_http(local_config.socks_greeting_allow_http);
+                    if let Some(d) = local_config.socks5_udp_associate_grace_period {
+                        server_builder.set_udp_associate_grace_period(d);
+                    }
 
                     if let Some(c) = config.udp_max_associations {
                         server_builder.set_udp_capacity(c);
@@ -300,10 +426,12 @@ impl Server {
                         None => return Err(io::Error::new(ErrorKind::Other, "tunnel requires local address")),
                     };
 
-                    let forward_addr = local_config.forward_addr.expect("tunnel requires forward address");
+                    let mut forward_addrs = Vec::with_capacity(1 + local_config.forward_addrs.len());
+                    forward_addrs.extend(local_config.forward_addr);
+                    forward_addrs.extend(local_config.forward_addrs);
 
                     let mut server_builder =
-                        TunnelBuilder::with_context(context.clone(), forward_addr.clone(), client_addr, balancer);
+                        TunnelBuilder::with_context(context.clone(), forward_addrs, client_addr, balancer);
 
                     if let Some(c) = config.udp_max_associations {
                         server_builder.set_udp_capacity(c);
@@ -392,6 +520,9 @@ impl Server {
                         )
                     };
                     server_builder.set_mode(local_config.mode);
+                    if let Some(answer_cache_size) = local_config.answer_cache_size {
+                        server_builder.set_answer_cache_size(answer_cache_size);
+                    }
 
                     #[cfg(target_os = "macos")]
                     if let Some(n) = local_config.launchd_tcp_socket_name {
@@ -417,6 +548,9 @@ impl Server {
                     if let Some(name) = local_config.tun_interface_name {
                         builder.name(&name);
                     }
+                    if let Some(mtu) = local_config.tun_interface_mtu {
+                        builder.mtu(mtu);
+                    }
                     if let Some(c) = config.udp_max_associations {
                         builder.udp_capacity(c);
                     }
@@ -484,6 +618,12 @@ impl Server {
             }
         }
 
+        #[cfg(feature = "local-health-api")]
+        if let Some(mut health_api) = local_server.health_api.take() {
+            health_api.set_listen_addrs(local_server.listen_addrs()?);
+            local_server.health_api = Some(health_api);
+        }
+
         Ok(local_server)
     }
 
@@ -492,32 +632,32 @@ impl Server {
         let mut vfut = Vec::new();
 
         for svr in self.socks_servers {
-            vfut.push(ServerHandle(tokio::spawn(svr.run())));
+            vfut.push(ServerHandle::new("socks", tokio::spawn(svr.run())));
         }
 
         #[cfg(feature = "local-tunnel")]
         for svr in self.tunnel_servers {
-            vfut.push(ServerHandle(tokio::spawn(svr.run())));
+            vfut.push(ServerHandle::new("tunnel", tokio::spawn(svr.run())));
         }
 
         #[cfg(feature = "local-http")]
         for svr in self.http_servers {
-            vfut.push(ServerHandle(tokio::spawn(svr.run())));
+            vfut.push(ServerHandle::new("http", tokio::spawn(svr.run())));
         }
 
         #[cfg(feature = "local-tun")]
         for svr in self.tun_servers {
-            vfut.push(ServerHandle(tokio::spawn(svr.run())));
+            vfut.push(ServerHandle::new("tun", tokio::spawn(svr.run())));
         }
 
         #[cfg(feature = "local-dns")]
         for svr in self.dns_servers {
-            vfut.push(ServerHandle(tokio::spawn(svr.run())));
+            vfut.push(ServerHandle::new("dns", tokio::spawn(svr.run())));
         }
 
         #[cfg(feature = "local-redir")]
         for svr in self.redir_servers {
-            vfut.push(ServerHandle(tokio::spawn(svr.run())));
+            vfut.push(ServerHandle::new("redir", tokio::spawn(svr.run())));
         }
 
         #[cfg(feature = "local-flow-stat")]
@@ -525,18 +665,202 @@ impl Server {
             // For Android's flow statistic
 
             let report_fut = flow_report_task(stat_addr, self.flow_stat);
-            vfut.push(ServerHandle(tokio::spawn(report_fut)));
+            vfut.push(ServerHandle::new("flow report", tokio::spawn(report_fut)));
+        }
+
+        #[cfg(feature = "local-health-api")]
+        if let Some(health_api) = self.health_api {
+            vfut.push(ServerHandle::new("health api", tokio::spawn(health_api.run())));
         }
 
-        let (res, ..) = future::select_all(vfut).await;
+        // Liveness file stops being touched if any of the servers exited
+        let (res, ..) = match self.liveness_file {
+            Some(liveness_file) => liveness_file.watch(future::select_all(vfut)).await,
+            None => future::select_all(vfut).await,
+        };
         res
     }
 
+    /// Run until `shutdown` resolves, then close all listeners and drain in-flight TCP connections
+    ///
+    /// TCP relays of SOCKS, HTTP, tunnel and redir that are still running have at most `drain_timeout` to finish, or
+    /// are closed immediately if it is `None`. UDP associations, DNS and tun flows are owned by their servers and are
+    /// closed with the listeners.
+    ///
+    /// Returns `true` if stopped by `shutdown`, `false` if the server exited by itself, which should never happen.
+    pub async fn run_until_shutdown<F>(self, shutdown: F, drain_timeout: Option<Duration>) -> io::Result<bool>
+    where
+        F: Future,
+    {
+        let relay_concurrency = self.relay_concurrency.clone();
+        // Boxed for dropping it while draining, which aborts all the accept loops
+        let server = Box::pin(self.run());
+        let shutdown = Box::pin(shutdown);
+
+        match future::select(server, shutdown).await {
+            Either::Left((res, ..)) => res.map(|_| false),
+            Either::Right((_, server)) => {
+                if let Some(drain_timeout) = drain_timeout {
+                    // Close listeners, then wait for in-flight connections to finish
+                    relay_concurrency.pause();
+                    drop(server);
+
+                    info!(
+                        "waiting {:?} for {} in-flight connections to finish",
+                        drain_timeout,
+                        relay_concurrency.active()
+                    );
+                    if time::timeout(drain_timeout, relay_concurrency.wait_idle())
+                        .await
+                        .is_err()
+                    {
+                        info!("shutdown timed out, closing {} connections", relay_concurrency.active());
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
     /// Get the internal server balancer
     pub fn server_balancer(&self) -> &PingBalancer {
         &self.balancer
     }
 
+    /// Live UDP associations of SOCKS5 and tunnel UDP relays
+    ///
+    /// They are shared with the relays, and could be kept for observing associations after `run` is called.
+    pub fn udp_association_stats(&self) -> Vec<UdpAssociationStat> {
+        #[allow(unused_mut)]
+        let mut stats: Vec<UdpAssociationStat> = self
+            .socks_servers
+            .iter()
+            .filter_map(|s| s.udp_server())
+            .map(|s| s.association_stat().clone())
+            .collect();
+
+        #[cfg(feature = "local-tunnel")]
+        stats.extend(
+            self.tunnel_servers
+                .iter()
+                .filter_map(|s| s.udp_server())
+                .map(|s| s.association_stat().clone()),
+        );
+
+        stats
+    }
+
+    /// Get the HTTP health endpoint, if `health_addr` is configured
+    #[cfg(feature = "local-health-api")]
+    pub fn health_api(&self) -> Option<&HealthApi> {
+        self.health_api.as_ref()
+    }
+
+    /// Contexts that own ACLs, the global one and instances' private ones, with the index of their instances in
+    /// `Config::local` (`None` for the global one)
+    ///
+    /// `run` consumes the server, clone them for reloading ACLs with `ServiceContext::reload_acl` while running.
+    pub fn acl_contexts(&self) -> &[(Option<usize>, Arc<ServiceContext>)] {
+        &self.acl_contexts
+    }
+
+    /// Get the TCP relay's concurrency limit, which could be adjusted while running
+    pub fn relay_concurrency(&self) -> &ConcurrencyLimit {
+        &self.relay_concurrency
+    }
+
+    /// Pause accepting new TCP connections without unbinding listeners
+    ///
+    /// Established connections keep relaying while paused, and new connections wait in the listen backlog
+    /// until `resume` (or clients give up). UDP associations, DNS and tun are not paused.
+    ///
+    /// `run` consumes the server, clone `relay_concurrency()` for pausing it while running.
+    pub fn pause(&self) {
+        self.relay_concurrency.pause();
+    }
+
+    /// Resume accepting new TCP connections
+    pub fn resume(&self) {
+        self.relay_concurrency.resume();
+    }
+
+    /// Check if accepting new TCP connections is paused
+    pub fn is_paused(&self) -> bool {
+        self.relay_concurrency.is_paused()
+    }
+
+    /// Notify that the host's network has changed, see `PingBalancer::network_changed`
+    ///
+    /// `run` consumes the server, clone `server_balancer()` for notifying it while running.
+    pub async fn network_changed(&self, net_type: NetworkType) {
+        self.balancer.network_changed(net_type).await;
+    }
+
+    /// Get the flow statistic of all local instances
+    pub fn flow_stat(&self) -> &FlowStat {
+        &self.flow_stat
+    }
+
+    /// Get the flow statistic of recently used destination hosts, which are accounted when TCP tunnels are closed
+    pub fn destination_flow_stat(&self) -> &DestinationFlowStat {
+        &self.destination_flow_stat
+    }
+
+    /// Reset flow statistic, including destination hosts
+    pub fn reset_flow_stat(&self) {
+        self.flow_stat.reset();
+        self.destination_flow_stat.reset();
+    }
+
+    /// Addresses that listeners of all local servers are bound to, in the order of `Config::local`'s protocols
+    ///
+    /// Tun servers are not included, they don't have listeners.
+    pub fn listen_addrs(&self) -> io::Result<Vec<ListenAddr>> {
+        let mut addrs = Vec::new();
+
+        macro_rules! push_addr {
+            ($protocol:expr, $udp:expr, $server:expr) => {
+                if let Some(server) = $server {
+                    addrs.push(ListenAddr {
+                        protocol: $protocol,
+                        udp: $udp,
+                        addr: server.local_addr()?,
+                    });
+                }
+            };
+        }
+
+        for server in self.socks_servers.iter() {
+            push_addr!(ProtocolType::Socks, false, server.tcp_server());
+            push_addr!(ProtocolType::Socks, true, server.udp_server());
+        }
+
+        #[cfg(feature = "local-tunnel")]
+        for server in self.tunnel_servers.iter() {
+            push_addr!(ProtocolType::Tunnel, false, server.tcp_server());
+            push_addr!(ProtocolType::Tunnel, true, server.udp_server());
+        }
+
+        #[cfg(feature = "local-http")]
+        for server in self.http_servers.iter() {
+            push_addr!(ProtocolType::Http, false, Some(server));
+        }
+
+        #[cfg(feature = "local-dns")]
+        for server in self.dns_servers.iter() {
+            push_addr!(ProtocolType::Dns, false, server.tcp_server());
+            push_addr!(ProtocolType::Dns, true, server.udp_server());
+        }
+
+        #[cfg(feature = "local-redir")]
+        for server in self.redir_servers.iter() {
+            push_addr!(ProtocolType::Redir, false, server.tcp_server());
+            push_addr!(ProtocolType::Redir, true, server.udp_server());
+        }
+
+        Ok(addrs)
+    }
+
     /// Get SOCKS server instances
     pub fn socks_servers(&self) -> &[Socks] {
         &self.socks_servers
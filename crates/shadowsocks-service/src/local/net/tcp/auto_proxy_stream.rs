@@ -1,32 +1,76 @@
 //! A `ProxyStream` that bypasses or proxies data through proxy server automatically
 
 use std::{
+    future::Future,
     io::{self, IoSlice},
     net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{self, Poll},
+    time::Duration,
 };
 
 use pin_project::pin_project;
+use rand::Rng;
 use shadowsocks::{
     net::TcpStream,
     relay::{socks5::Address, tcprelay::proxy_stream::ProxyClientStream},
 };
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
 
 use crate::{
-    local::{context::ServiceContext, loadbalancing::ServerIdent},
-    net::MonProxyStream,
+    local::{
+        context::ServiceContext,
+        loadbalancing::{ServerConnectionGuard, ServerIdent},
+    },
+    net::{utils::normalize_address, ConcurrencyPermit, MonProxyStream},
 };
 
 use super::auto_proxy_io::AutoProxyIo;
 
+/// Maximum time to wait for a connect permit if the connect timeout isn't configured
+const DEFAULT_CONNECT_PERMIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Wait for a permit of the connect concurrency limit
+///
+/// Fails with `TimedOut` if the limit stays saturated for the connect timeout, instead of queuing forever behind
+/// connects that are stuck.
+async fn acquire_connect_permit(context: &ServiceContext) -> io::Result<ConcurrencyPermit> {
+    let timeout = context.connect_timeout().unwrap_or(DEFAULT_CONNECT_PERMIT_TIMEOUT);
+    match time::timeout(timeout, context.connect_concurrency().acquire()).await {
+        Ok(permit) => Ok(permit),
+        Err(..) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out waiting for a connect permit, too many connections are connecting",
+        )),
+    }
+}
+
+/// Run `connect`, fails with `TimedOut` if it doesn't complete in `timeout`
+async fn with_connect_timeout<F, T>(timeout: Option<Duration>, connect: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match timeout {
+        Some(d) => match time::timeout(d, connect).await {
+            Ok(r) => r,
+            Err(..) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+        },
+        None => connect.await,
+    }
+}
+
 /// Unified stream for bypassed and proxied connections
 #[allow(clippy::large_enum_variant)]
 #[pin_project(project = AutoProxyClientStreamProj)]
 pub enum AutoProxyClientStream {
-    Proxied(#[pin] ProxyClientStream<MonProxyStream<TcpStream>>),
+    Proxied(
+        #[pin] ProxyClientStream<MonProxyStream<TcpStream>>,
+        Option<ServerConnectionGuard>,
+    ),
     Bypassed(#[pin] TcpStream),
 }
 
@@ -40,7 +84,7 @@ impl AutoProxyClientStream {
     where
         A: Into<Address>,
     {
-        let addr = addr.into();
+        let addr = normalize_address(addr.into());
         if context.check_target_bypassed(&addr).await {
             AutoProxyClientStream::connect_bypassed(context, addr).await
         } else {
@@ -54,9 +98,13 @@ impl AutoProxyClientStream {
         A: Into<Address>,
     {
         // Connect directly.
-        let addr = addr.into();
-        let stream =
-            TcpStream::connect_remote_with_opts(context.context_ref(), &addr, context.connect_opts_ref()).await?;
+        let addr = normalize_address(addr.into());
+        let _permit = acquire_connect_permit(&context).await?;
+        let stream = with_connect_timeout(
+            context.connect_timeout(),
+            TcpStream::connect_remote_with_opts(context.context_ref(), &addr, context.connect_opts_ref()),
+        )
+        .await?;
         Ok(AutoProxyClientStream::Bypassed(stream))
     }
 
@@ -69,13 +117,25 @@ impl AutoProxyClientStream {
     where
         A: Into<Address>,
     {
+        // Spread connections that are made at the same time, e.g. reconnecting after network changes
+        let max_jitter = context.connect_jitter();
+        if !max_jitter.is_zero() {
+            let jitter = rand::thread_rng().gen_range(Duration::ZERO..=max_jitter);
+            time::sleep(jitter).await;
+        }
+
+        let addr = addr.into();
+        let _permit = acquire_connect_permit(&context).await?;
         let flow_stat = context.flow_stat();
-        let stream = match ProxyClientStream::connect_with_opts_map(
-            context.context(),
-            server.server_config(),
-            addr,
-            context.connect_opts_ref(),
-            |stream| MonProxyStream::from_stream(stream, flow_stat),
+        let stream = match with_connect_timeout(
+            context.connect_timeout(),
+            ProxyClientStream::connect_with_opts_map(
+                context.context(),
+                server.server_config(),
+                addr,
+                context.connect_opts_ref(),
+                |stream| MonProxyStream::from_stream(stream, flow_stat),
+            ),
         )
         .await
         {
@@ -85,20 +145,58 @@ impl AutoProxyClientStream {
                 return Err(err);
             }
         };
-        Ok(AutoProxyClientStream::Proxied(stream))
+        Ok(AutoProxyClientStream::Proxied(stream, Some(server.track_connection())))
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         match *self {
-            AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().local_addr(),
+            AutoProxyClientStream::Proxied(ref s, ..) => s.get_ref().get_ref().local_addr(),
             AutoProxyClientStream::Bypassed(ref s) => s.local_addr(),
         }
     }
 
+    /// Target address of this connection
+    ///
+    /// Bypassed connections don't keep the requested address, their target is the resolved address they are
+    /// connected to.
+    pub fn target_address(&self) -> io::Result<Address> {
+        match *self {
+            AutoProxyClientStream::Proxied(ref s, ..) => Ok(s.target_addr().clone()),
+            AutoProxyClientStream::Bypassed(ref s) => s.peer_addr().map(Address::SocketAddress),
+        }
+    }
+
+    /// Check if this connection is a DNS query, which is connecting to port 53
+    pub fn is_dns(&self) -> bool {
+        let port = match *self {
+            AutoProxyClientStream::Proxied(ref s, ..) => s.target_addr().port(),
+            AutoProxyClientStream::Bypassed(ref s) => match s.peer_addr() {
+                Ok(addr) => addr.port(),
+                Err(..) => return false,
+            },
+        };
+        port == 53
+    }
+
+    /// Sets `TCP_NODELAY` of the outbound socket, which is connected to the proxy server if proxied
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.outbound_stream().set_nodelay(nodelay)
+    }
+
+    /// Sets `SO_SNDBUF` of the outbound socket, which is connected to the proxy server if proxied
+    pub fn set_send_buffer_size(&self, size: u32) -> io::Result<()> {
+        self.outbound_stream().set_send_buffer_size(size)
+    }
+
+    /// Sets `SO_RCVBUF` of the outbound socket, which is connected to the proxy server if proxied
+    pub fn set_recv_buffer_size(&self, size: u32) -> io::Result<()> {
+        self.outbound_stream().set_recv_buffer_size(size)
+    }
+
+    fn outbound_stream(&self) -> &TcpStream {
         match *self {
-            AutoProxyClientStream::Proxied(ref s) => s.get_ref().get_ref().set_nodelay(nodelay),
-            AutoProxyClientStream::Bypassed(ref s) => s.set_nodelay(nodelay),
+            AutoProxyClientStream::Proxied(ref s, ..) => s.get_ref().get_ref(),
+            AutoProxyClientStream::Bypassed(ref s) => s,
         }
     }
 }
@@ -112,7 +210,7 @@ impl AutoProxyIo for AutoProxyClientStream {
 impl AsyncRead for AutoProxyClientStream {
     fn poll_read(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_read(cx, buf),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_read(cx, buf),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_read(cx, buf),
         }
     }
@@ -121,21 +219,21 @@ impl AsyncRead for AutoProxyClientStream {
 impl AsyncWrite for AutoProxyClientStream {
     fn poll_write(self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_write(cx, buf),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_write(cx, buf),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write(cx, buf),
         }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_flush(cx),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_flush(cx),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_flush(cx),
         }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_shutdown(cx),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_shutdown(cx),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_shutdown(cx),
         }
     }
@@ -146,7 +244,7 @@ impl AsyncWrite for AutoProxyClientStream {
         bufs: &[IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
         match self.project() {
-            AutoProxyClientStreamProj::Proxied(s) => s.poll_write_vectored(cx, bufs),
+            AutoProxyClientStreamProj::Proxied(s, ..) => s.poll_write_vectored(cx, bufs),
             AutoProxyClientStreamProj::Bypassed(s) => s.poll_write_vectored(cx, bufs),
         }
     }
@@ -154,6 +252,190 @@ impl AsyncWrite for AutoProxyClientStream {
 
 impl From<ProxyClientStream<MonProxyStream<TcpStream>>> for AutoProxyClientStream {
     fn from(s: ProxyClientStream<MonProxyStream<TcpStream>>) -> Self {
-        AutoProxyClientStream::Proxied(s)
+        AutoProxyClientStream::Proxied(s, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::{config::ServerConfig, crypto::CipherKind};
+    use tokio::net::TcpListener;
+
+    use crate::local::loadbalancing::ScoreWeights;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn proxied_stream_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = ServerIdent::new(
+            ServerConfig::new(listener.local_addr().unwrap(), "password", CipherKind::AES_256_GCM),
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            None,
+            ScoreWeights::default(),
+        );
+
+        let target = Address::DomainNameAddress("example.com".to_owned(), 80);
+        let stream = AutoProxyClientStream::connect_proxied(Arc::new(ServiceContext::new()), &server, target)
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+
+        stream.set_nodelay(true).unwrap();
+        stream.set_send_buffer_size(64 * 1024).unwrap();
+        stream.set_recv_buffer_size(64 * 1024).unwrap();
+
+        let outbound = match stream {
+            AutoProxyClientStream::Proxied(ref s, ..) => s.get_ref().get_ref(),
+            AutoProxyClientStream::Bypassed(..) => unreachable!("stream should be proxied"),
+        };
+        assert_eq!(outbound.peer_addr().unwrap(), listener.local_addr().unwrap());
+        assert_eq!(outbound.local_addr().unwrap(), accepted.peer_addr().unwrap());
+        assert!(outbound.nodelay().unwrap());
+        // Kernel may round or double the sizes
+        assert!(outbound.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(outbound.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn target_address_is_dns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let context = Arc::new(ServiceContext::new());
+        let server = ServerIdent::new(
+            ServerConfig::new(listener.local_addr().unwrap(), "password", CipherKind::AES_256_GCM),
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            None,
+            ScoreWeights::default(),
+        );
+
+        for (port, is_dns) in [(53, true), (443, false)] {
+            let target = Address::DomainNameAddress("example.com".to_owned(), port);
+            let stream = AutoProxyClientStream::connect_proxied(context.clone(), &server, target.clone())
+                .await
+                .unwrap();
+            assert!(stream.is_proxied());
+            assert_eq!(stream.target_address().unwrap(), target);
+            assert_eq!(stream.is_dns(), is_dns);
+        }
+
+        let target = Address::SocketAddress(listener.local_addr().unwrap());
+        let stream = AutoProxyClientStream::connect_bypassed(context, target.clone())
+            .await
+            .unwrap();
+        assert!(!stream.is_proxied());
+        assert_eq!(stream.target_address().unwrap(), target);
+        assert!(!stream.is_dns());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn connect_timeout_reports_failure() {
+        use socket2::{Domain, Socket, Type};
+
+        // Linux drops SYNs to a listener whose backlog is full, so connects to it hang
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        socket.listen(0).unwrap();
+        let listener: std::net::TcpListener = socket.into();
+        let server_addr = listener.local_addr().unwrap();
+        let _backlog = std::net::TcpStream::connect(server_addr).unwrap();
+
+        let mut context = ServiceContext::new();
+        context.set_connect_timeout(Some(Duration::from_millis(500)));
+        let context = Arc::new(context);
+        let server = ServerIdent::new(
+            ServerConfig::new(server_addr, "password", CipherKind::AES_256_GCM),
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            None,
+            ScoreWeights::default(),
+        );
+
+        let start = time::Instant::now();
+        let target = Address::DomainNameAddress("example.com".to_owned(), 80);
+        let err = AutoProxyClientStream::connect_proxied(context.clone(), &server, target)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(server.tcp_score().failures(), 1);
+
+        let start = time::Instant::now();
+        let err = AutoProxyClientStream::connect_bypassed(context, server_addr)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn connect_permit_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = Address::SocketAddress(listener.local_addr().unwrap());
+
+        let mut context = ServiceContext::new();
+        context.set_connect_timeout(Some(Duration::from_millis(200)));
+        context.connect_concurrency().set_limit(Some(1));
+        let context = Arc::new(context);
+
+        // Limit is saturated by a connect that never completes
+        let permit = context.connect_concurrency().acquire().await;
+
+        let start = time::Instant::now();
+        let err = AutoProxyClientStream::connect_bypassed(context.clone(), target.clone())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        drop(permit);
+        let stream = AutoProxyClientStream::connect_bypassed(context.clone(), target)
+            .await
+            .unwrap();
+        assert!(!stream.is_proxied());
+        assert_eq!(context.connect_concurrency().active(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_proxied_jitter() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = ServerIdent::new(
+            ServerConfig::new(listener.local_addr().unwrap(), "password", CipherKind::AES_256_GCM),
+            Duration::from_secs(1),
+            Duration::from_secs(60),
+            None,
+            ScoreWeights::default(),
+        );
+
+        let max_jitter = Duration::from_millis(500);
+        let mut context = ServiceContext::new();
+        context.set_connect_jitter(max_jitter);
+        let context = Arc::new(context);
+
+        let target = Address::DomainNameAddress("example.com".to_owned(), 80);
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            let start = time::Instant::now();
+            let _stream = AutoProxyClientStream::connect_proxied(context.clone(), &server, target.clone())
+                .await
+                .unwrap();
+            delays.push(start.elapsed());
+        }
+        assert!(delays.iter().all(|delay| *delay <= max_jitter), "{:?}", delays);
+        // Connects are spread, instead of being made at the same time
+        assert!(delays.iter().any(|delay| *delay != delays[0]), "{:?}", delays);
+
+        // Bypassed connections are not delayed
+        let start = time::Instant::now();
+        let _stream = AutoProxyClientStream::connect_bypassed(context, listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::ZERO);
     }
 }
@@ -5,7 +5,10 @@ use std::{
     io::{self, ErrorKind},
     marker::PhantomData,
     net::{SocketAddr, SocketAddrV6},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
@@ -48,6 +51,35 @@ pub trait UdpInboundWrite {
 
 type AssociationMap<W> = LruCache<SocketAddr, UdpAssociation<W>>;
 
+/// Live associations of a UDP relay, shared with the relay while it is running
+///
+/// Expired associations are counted until they are cleaned up, which happens every expiry duration.
+#[derive(Debug, Clone)]
+pub struct UdpAssociationStat {
+    active: Arc<AtomicUsize>,
+    capacity: Option<usize>,
+}
+
+impl UdpAssociationStat {
+    /// Create for a relay keeping at most `capacity` associations
+    pub fn new(capacity: Option<usize>) -> UdpAssociationStat {
+        UdpAssociationStat {
+            active: Arc::new(AtomicUsize::new(0)),
+            capacity,
+        }
+    }
+
+    /// Number of live associations
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Maximum number of associations, unlimited if `None`
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+}
+
 /// UDP association manager
 pub struct UdpAssociationManager<W>
 where
@@ -56,6 +88,7 @@ where
     respond_writer: W,
     context: Arc<ServiceContext>,
     assoc_map: AssociationMap<W>,
+    association_stat: UdpAssociationStat,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     balancer: PingBalancer,
     server_session_expire_duration: Duration,
@@ -67,16 +100,18 @@ where
 {
     /// Create a new `UdpAssociationManager`
     ///
+    /// Associations are limited by `association_stat`'s capacity, and counted by it.
+    ///
     /// Returns (`UdpAssociationManager`, Cleanup Interval, Keep-alive Receiver<SocketAddr>)
     pub fn new(
         context: Arc<ServiceContext>,
         respond_writer: W,
         time_to_live: Option<Duration>,
-        capacity: Option<usize>,
+        association_stat: UdpAssociationStat,
         balancer: PingBalancer,
     ) -> (UdpAssociationManager<W>, Duration, mpsc::Receiver<SocketAddr>) {
         let time_to_live = time_to_live.unwrap_or(crate::DEFAULT_UDP_EXPIRY_DURATION);
-        let assoc_map = match capacity {
+        let assoc_map = match association_stat.capacity() {
             Some(capacity) => LruCache::with_expiry_duration_and_capacity(time_to_live, capacity),
             None => LruCache::with_expiry_duration(time_to_live),
         };
@@ -88,6 +123,7 @@ where
                 respond_writer,
                 context,
                 assoc_map,
+                association_stat,
                 keepalive_tx,
                 balancer,
                 server_session_expire_duration: time_to_live,
@@ -112,6 +148,7 @@ where
             self.balancer.clone(),
             self.respond_writer.clone(),
             self.server_session_expire_duration,
+            self.association_stat.active.clone(),
         );
 
         debug!("created udp association for {}", peer_addr);
@@ -131,6 +168,29 @@ where
     pub async fn keep_alive(&mut self, peer_addr: &SocketAddr) {
         self.assoc_map.get(peer_addr);
     }
+
+    /// Close associations whose peer addresses match `predicate`
+    ///
+    /// Returns the number of associations that were closed.
+    pub fn close_associations<F>(&mut self, mut predicate: F) -> usize
+    where
+        F: FnMut(&SocketAddr) -> bool,
+    {
+        let peer_addrs: Vec<SocketAddr> = self
+            .assoc_map
+            .peek_iter()
+            .map(|(peer_addr, _)| *peer_addr)
+            .filter(|peer_addr| predicate(peer_addr))
+            .collect();
+
+        for peer_addr in peer_addrs.iter() {
+            // Dropping the association aborts its relay task and releases its outbound socket
+            self.assoc_map.remove(peer_addr);
+            debug!("closed udp association for {}", peer_addr);
+        }
+
+        peer_addrs.len()
+    }
 }
 
 struct UdpAssociation<W>
@@ -139,6 +199,7 @@ where
 {
     assoc_handle: JoinHandle<()>,
     sender: mpsc::Sender<(Address, Bytes)>,
+    active: Arc<AtomicUsize>,
     writer: PhantomData<W>,
 }
 
@@ -148,6 +209,7 @@ where
 {
     fn drop(&mut self) {
         self.assoc_handle.abort();
+        self.active.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
@@ -162,6 +224,7 @@ where
         balancer: PingBalancer,
         respond_writer: W,
         server_session_expire_duration: Duration,
+        active: Arc<AtomicUsize>,
     ) -> UdpAssociation<W> {
         let (assoc_handle, sender) = UdpAssociationContext::create(
             context,
@@ -171,9 +234,11 @@ where
             respond_writer,
             server_session_expire_duration,
         );
+        active.fetch_add(1, Ordering::Relaxed);
         UdpAssociation {
             assoc_handle,
             sender,
+            active,
             writer: PhantomData,
         }
     }
@@ -214,6 +279,8 @@ where
     bypassed_ipv4_socket: Option<ShadowUdpSocket>,
     bypassed_ipv6_socket: Option<ShadowUdpSocket>,
     proxied_socket: Option<MonProxySocket>,
+    // Proxied socket was reset because of failures, recreating it is a retry
+    proxied_socket_failed: bool,
     keepalive_tx: mpsc::Sender<SocketAddr>,
     keepalive_flag: bool,
     balancer: PingBalancer,
@@ -222,6 +289,8 @@ where
     client_packet_id: u64,
     server_session: Option<ServerSessionContext>,
     server_session_expire_duration: Duration,
+    // Host's network that outbound sockets were created on
+    network_generation: u64,
 }
 
 impl<W> Drop for UdpAssociationContext<W>
@@ -259,12 +328,14 @@ where
         // being OOM.
         let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
 
+        let network_generation = context.network_generation();
         let mut assoc = UdpAssociationContext {
             context,
             peer_addr,
             bypassed_ipv4_socket: None,
             bypassed_ipv6_socket: None,
             proxied_socket: None,
+            proxied_socket_failed: false,
             keepalive_tx,
             keepalive_flag: false,
             balancer,
@@ -275,6 +346,7 @@ where
             client_packet_id: 0,
             server_session: None,
             server_session_expire_duration,
+            network_generation,
         };
         let handle = tokio::spawn(async move { assoc.dispatch_packet(receiver).await });
 
@@ -338,6 +410,7 @@ where
                             error!("udp relay {} <- ... (proxied) failed, error: {}", self.peer_addr, err);
                             // Socket failure. Reset for recreation.
                             self.proxied_socket = None;
+                            self.proxied_socket_failed = true;
                             continue;
                         }
                     };
@@ -375,6 +448,8 @@ where
                 }
 
                 _ = keepalive_interval.tick() => {
+                    self.check_network_changed();
+
                     if self.keepalive_flag {
                         if self.keepalive_tx.try_send(self.peer_addr).is_err() {
                             debug!("udp relay {} keep-alive failed, channel full or closed", self.peer_addr);
@@ -419,7 +494,30 @@ where
         }
     }
 
+    /// Drop outbound sockets if the host's network has changed since they were created
+    ///
+    /// They are bound to the previous network, and are recreated on the next packet. The association's idle timer
+    /// is restarted, it shouldn't expire because of the time that the host was switching networks.
+    fn check_network_changed(&mut self) {
+        let network_generation = self.context.network_generation();
+        if self.network_generation == network_generation {
+            return;
+        }
+        self.network_generation = network_generation;
+
+        debug!(
+            "udp association for {} network changed, dropping outbound sockets",
+            self.peer_addr
+        );
+        self.bypassed_ipv4_socket = None;
+        self.bypassed_ipv6_socket = None;
+        self.proxied_socket = None;
+        self.keepalive_flag = true;
+    }
+
     async fn dispatch_received_packet(&mut self, target_addr: &Address, data: &[u8]) {
+        self.check_network_changed();
+
         // Check if target should be bypassed. If so, send packets directly.
         let bypassed = self.balancer.is_empty() || self.context.check_target_bypassed(target_addr).await;
 
@@ -562,14 +660,33 @@ where
             None => {
                 // Create a new connection to proxy server
 
+                if self.proxied_socket_failed && !self.context.retry_budget().try_acquire() {
+                    debug!(
+                        "{} -> {} (proxied) reconnecting throttled, retry budget is exhausted",
+                        self.peer_addr, target_addr
+                    );
+                    return Err(io::Error::new(ErrorKind::Other, "retry budget exhausted"));
+                }
+
                 let server = self.balancer.best_udp_server();
                 let svr_cfg = server.server_config();
 
-                let socket =
-                    ProxySocket::connect_with_opts(self.context.context(), svr_cfg, self.context.connect_opts_ref())
-                        .await?;
+                let socket = match ProxySocket::connect_with_opts(
+                    self.context.context(),
+                    svr_cfg,
+                    self.context.connect_opts_ref(),
+                )
+                .await
+                {
+                    Ok(s) => s,
+                    Err(err) => {
+                        self.proxied_socket_failed = true;
+                        return Err(err.into());
+                    }
+                };
                 let socket = MonProxySocket::from_socket(socket, self.context.flow_stat());
 
+                self.proxied_socket_failed = false;
                 self.proxied_socket.insert(socket)
             }
         };
@@ -591,6 +708,7 @@ where
 
                 // Drop the socket and reconnect to another server.
                 self.proxied_socket = None;
+                self.proxied_socket_failed = true;
             }
         }
 
@@ -630,3 +748,73 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::config::Mode;
+    use tokio::net::UdpSocket;
+
+    use crate::local::{context::NetworkType, loadbalancing::PingBalancerBuilder};
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+    #[async_trait]
+    impl UdpInboundWrite for ChannelWriter {
+        async fn send_to(&self, _peer_addr: SocketAddr, _remote_addr: &Address, data: &[u8]) -> io::Result<()> {
+            let _ = self.0.send(data.to_vec()).await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn rebind_after_network_changed() {
+        let context = Arc::new(ServiceContext::new());
+        // No servers, all packets are bypassed
+        let balancer = PingBalancerBuilder::new(context.clone(), Mode::UdpOnly)
+            .build()
+            .await
+            .unwrap();
+        let (respond_tx, mut respond_rx) = mpsc::channel(4);
+        let (mut manager, _, _) = UdpAssociationManager::new(
+            context.clone(),
+            ChannelWriter(respond_tx),
+            None,
+            UdpAssociationStat::new(None),
+            balancer,
+        );
+
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = Address::from(target.local_addr().unwrap());
+        let peer_addr = "127.0.0.1:10000".parse::<SocketAddr>().unwrap();
+
+        let mut buf = [0u8; 16];
+        let mut source_ports = Vec::new();
+        for payload in [&b"first"[..], b"second"] {
+            manager.send_to(peer_addr, target_addr.clone(), payload).await.unwrap();
+
+            let (n, source_addr) = time::timeout(Duration::from_secs(5), target.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], payload);
+            source_ports.push(source_addr.port());
+
+            // Responses are relayed through the new socket
+            target.send_to(payload, source_addr).await.unwrap();
+            let response = time::timeout(Duration::from_secs(5), respond_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(response, payload);
+
+            context.set_network_type(NetworkType::Wifi).await;
+        }
+
+        // The same association is kept, with its outbound socket recreated
+        assert_eq!(manager.assoc_map.len(), 1);
+        assert_ne!(source_ports[0], source_ports[1]);
+    }
+}
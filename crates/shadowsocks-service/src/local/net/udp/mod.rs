@@ -1,4 +1,4 @@
-pub use self::association::{UdpAssociationManager, UdpInboundWrite};
+pub use self::association::{UdpAssociationManager, UdpAssociationStat, UdpInboundWrite};
 
 pub mod association;
 pub mod listener;
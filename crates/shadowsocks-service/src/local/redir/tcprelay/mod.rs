@@ -7,7 +7,7 @@ use std::{
     time::Duration,
 };
 
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use shadowsocks::{lookup_then, net::TcpListener as ShadowTcpListener, relay::socks5::Address, ServerAddr};
 use tokio::{
     net::{TcpListener, TcpStream},
@@ -23,7 +23,7 @@ use crate::{
         redir::redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt},
         utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
     },
-    net::utils::to_ipv4_mapped,
+    net::{utils::to_ipv4_mapped, ConnectionId},
 };
 
 #[allow(unused_imports)]
@@ -36,26 +36,28 @@ async fn establish_client_tcp_redir<'a>(
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     mut stream: TcpStream,
+    id: ConnectionId,
     peer_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
     if balancer.is_empty() {
-        let mut remote = AutoProxyClientStream::connect_bypassed(context, addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr).await;
+        let mut remote = AutoProxyClientStream::connect_bypassed(context.clone(), addr).await?;
+        return establish_tcp_tunnel_bypassed(&context, &mut stream, &mut remote, id, peer_addr, addr).await;
     }
 
     let server = balancer.best_tcp_server();
     let svr_cfg = server.server_config();
 
-    let mut remote = AutoProxyClientStream::connect(context, &server, addr).await?;
+    let mut remote = AutoProxyClientStream::connect(context.clone(), &server, addr).await?;
 
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    establish_tcp_tunnel(&context, svr_cfg, &mut stream, &mut remote, id, peer_addr, addr).await
 }
 
 async fn handle_redir_client(
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     s: TcpStream,
+    id: ConnectionId,
     peer_addr: SocketAddr,
     mut daddr: SocketAddr,
 ) -> io::Result<()> {
@@ -68,7 +70,7 @@ async fn handle_redir_client(
         }
     }
     let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr).await
+    establish_client_tcp_redir(context, balancer, s, id, peer_addr, &target_addr).await
 }
 
 /// Redir TCP server instance
@@ -122,7 +124,10 @@ impl RedirTcpServer {
             self.redir_ty, actual_local_addr
         );
 
+        let nodelay = self.context.accept_opts().tcp.nodelay;
+
         loop {
+            let permit = self.context.relay_concurrency().acquire().await;
             let (socket, peer_addr) = match listener.accept().await {
                 Ok(s) => s,
                 Err(err) => {
@@ -132,25 +137,34 @@ impl RedirTcpServer {
                 }
             };
 
-            trace!("got connection {}", peer_addr);
+            let id = ConnectionId::next();
+            trace!("got connection {} {}", id, peer_addr);
+
+            // Redir listeners are not ShadowTcpListener, accepted sockets have to be set manually
+            if nodelay {
+                if let Err(err) = socket.set_nodelay(true) {
+                    warn!("failed to set TCP_NODELAY for {}, error: {}", peer_addr, err);
+                }
+            }
 
             let context = self.context.clone();
             let balancer = self.balancer.clone();
             let redir_ty = self.redir_ty;
             tokio::spawn(async move {
+                let _permit = permit;
                 let dst_addr = match socket.destination_addr(redir_ty) {
                     Ok(d) => d,
                     Err(err) => {
                         error!(
-                            "TCP redirect couldn't get destination, peer: {}, error: {}",
-                            peer_addr, err
+                            "TCP redirect {} couldn't get destination, peer: {}, error: {}",
+                            id, peer_addr, err
                         );
                         return;
                     }
                 };
 
-                if let Err(err) = handle_redir_client(context, balancer, socket, peer_addr, dst_addr).await {
-                    debug!("TCP redirect client, error: {:?}", err);
+                if let Err(err) = handle_redir_client(context, balancer, socket, id, peer_addr, dst_addr).await {
+                    debug!("TCP redirect client {}, error: {:?}", id, err);
                 }
             });
         }
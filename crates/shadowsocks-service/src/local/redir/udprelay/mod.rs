@@ -26,7 +26,7 @@ use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
-        net::{UdpAssociationManager, UdpInboundWrite},
+        net::{UdpAssociationManager, UdpAssociationStat, UdpInboundWrite},
         redir::redir_ext::{RedirSocketOpts, UdpSocketRedirExt},
     },
     net::utils::to_ipv4_mapped,
@@ -260,6 +260,11 @@ impl RedirUdpServer {
         })
     }
 
+    /// Get server local address
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
     pub async fn run(self) -> io::Result<()> {
         let local_addr = self.listener.local_addr().expect("determine port bound to");
         info!(
@@ -272,7 +277,7 @@ impl RedirUdpServer {
             self.context.clone(),
             UdpRedirInboundWriter::new(self.redir_ty, self.context.connect_opts_ref()),
             self.time_to_live,
-            self.capacity,
+            UdpAssociationStat::new(self.capacity),
             self.balancer,
         );
 
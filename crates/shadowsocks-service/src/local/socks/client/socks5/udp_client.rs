@@ -13,7 +13,7 @@ use super::tcp_client::Socks5TcpClient;
 pub struct Socks5UdpClient {
     socket: UdpSocket,
     // Socks5 protocol requires to keep this TCP connection alive
-    // Theoretically if this connection is broken, the association is broken too, but the UDP Socks5 server in this crate doesn't behave like that
+    // If this connection is broken, the association is broken too
     #[allow(dead_code)]
     assoc_client: Option<Socks5TcpClient>,
 }
@@ -4,8 +4,15 @@ use std::{io, sync::Arc, time::Duration};
 
 use futures::{future, FutureExt};
 use shadowsocks::{config::Mode, ServerAddr};
+use tokio::{
+    io::AsyncRead,
+    time::{self, Instant},
+};
 
-use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
+use crate::{
+    local::{context::ServiceContext, loadbalancing::PingBalancer},
+    net::utils::ignore_until_end,
+};
 
 pub use self::server::{SocksTcpServer, SocksTcpServerBuilder, SocksUdpServer};
 use self::socks5::Socks5UdpServerBuilder;
@@ -18,6 +25,16 @@ mod server;
 mod socks4;
 mod socks5;
 
+/// Probe resistance, discard everything the client sends until the greeting `deadline`
+///
+/// Connections that sent unexpected bytes are closed at the same time as the ones that sent nothing.
+async fn discard_until_deadline<S>(stream: &mut S, deadline: Instant)
+where
+    S: AsyncRead + Unpin,
+{
+    let _ = time::timeout_at(deadline, ignore_until_end(stream)).await;
+}
+
 /// SOCKS4/4a, SOCKS5 Local Server builder
 pub struct SocksBuilder {
     context: Arc<ServiceContext>,
@@ -26,6 +43,10 @@ pub struct SocksBuilder {
     udp_capacity: Option<usize>,
     udp_bind_addr: Option<ServerAddr>,
     socks5_auth: Socks5AuthConfig,
+    greeting_timeout: Option<Duration>,
+    #[cfg(feature = "local-http")]
+    greeting_allow_http: bool,
+    udp_associate_grace_period: Option<Duration>,
     client_config: ServerAddr,
     balancer: PingBalancer,
     #[cfg(target_os = "macos")]
@@ -54,6 +75,10 @@ impl SocksBuilder {
             udp_capacity: None,
             udp_bind_addr: None,
             socks5_auth: Socks5AuthConfig::default(),
+            greeting_timeout: None,
+            #[cfg(feature = "local-http")]
+            greeting_allow_http: false,
+            udp_associate_grace_period: None,
             client_config,
             balancer,
             #[cfg(target_os = "macos")]
@@ -91,6 +116,27 @@ impl SocksBuilder {
         self.socks5_auth = p;
     }
 
+    /// Set timeout for clients to send the SOCKS greeting
+    ///
+    /// Enables probe resistance: timed out connections and connections that sent unexpected bytes
+    /// will be closed silently without any response.
+    pub fn set_greeting_timeout(&mut self, d: Duration) {
+        self.greeting_timeout = Some(d);
+    }
+
+    /// Keep serving HTTP requests on the SOCKS port while probe resistance is enabled
+    #[cfg(feature = "local-http")]
+    pub fn set_greeting_allow_http(&mut self, allow: bool) {
+        self.greeting_allow_http = allow;
+    }
+
+    /// Close UDP associations after their UDP ASSOCIATE TCP connections have been closed for duration `d`
+    ///
+    /// Associations are closed as soon as their UDP ASSOCIATE TCP connections are closed if it is not set.
+    pub fn set_udp_associate_grace_period(&mut self, d: Duration) {
+        self.udp_associate_grace_period = Some(d);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_tcp_socket_name(&mut self, n: String) {
@@ -104,7 +150,7 @@ impl SocksBuilder {
     }
 
     pub async fn build(self) -> io::Result<Socks> {
-        let udp_bind_addr = self.udp_bind_addr.unwrap_or_else(|| self.client_config.clone());
+        let mut udp_bind_addr = self.udp_bind_addr.unwrap_or_else(|| self.client_config.clone());
 
         let mut udp_server = None;
         if self.mode.enable_udp() {
@@ -122,7 +168,17 @@ impl SocksBuilder {
                 builder.set_launchd_socket_name(s);
             }
 
+            if let Some(d) = self.udp_associate_grace_period {
+                builder.set_associate_grace_period(d);
+            }
+
             let server = builder.build().await?;
+
+            // Port is assigned by the OS, UDP_ASSOCIATE have to respond with the actual address
+            if udp_bind_addr.port() == 0 {
+                udp_bind_addr = ServerAddr::SocketAddr(server.local_addr()?);
+            }
+
             udp_server = Some(server);
         }
 
@@ -143,6 +199,17 @@ impl SocksBuilder {
                 builder.set_launchd_socket_name(s);
             }
 
+            if let Some(d) = self.greeting_timeout {
+                builder.set_greeting_timeout(d);
+            }
+
+            #[cfg(feature = "local-http")]
+            builder.set_greeting_allow_http(self.greeting_allow_http);
+
+            if let Some(ref udp_server) = udp_server {
+                builder.set_udp_associate_control(udp_server.associate_control());
+            }
+
             let server = builder.build().await?;
             tcp_server = Some(server);
         }
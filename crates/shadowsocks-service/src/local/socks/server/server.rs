@@ -2,20 +2,28 @@ use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use log::{error, info};
 use shadowsocks::{config::Mode, net::TcpListener as ShadowTcpListener, ServerAddr};
-use tokio::{net::TcpStream, time};
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant},
+};
 
 #[cfg(feature = "local-http")]
 use crate::local::http::HttpConnectionHandler;
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::tcp::listener::create_standard_tcp_listener,
-    socks::config::Socks5AuthConfig,
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::tcp::listener::create_standard_tcp_listener,
+        socks::config::Socks5AuthConfig,
+    },
+    net::ConnectionId,
 };
 
+#[cfg(any(feature = "local-socks4", feature = "local-http"))]
+use super::discard_until_deadline;
 #[cfg(feature = "local-socks4")]
 use super::socks4::Socks4TcpHandler;
-use super::socks5::{Socks5TcpHandler, Socks5UdpServer};
+use super::socks5::{Socks5TcpHandler, Socks5UdpAssociateControl, Socks5UdpServer};
 
 pub struct SocksTcpServerBuilder {
     context: Arc<ServiceContext>,
@@ -24,6 +32,10 @@ pub struct SocksTcpServerBuilder {
     balancer: PingBalancer,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
+    greeting_timeout: Option<Duration>,
+    #[cfg(feature = "local-http")]
+    greeting_allow_http: bool,
+    udp_associate_control: Option<Arc<Socks5UdpAssociateControl>>,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -44,11 +56,31 @@ impl SocksTcpServerBuilder {
             balancer,
             mode,
             socks5_auth: Arc::new(socks5_auth),
+            greeting_timeout: None,
+            #[cfg(feature = "local-http")]
+            greeting_allow_http: false,
+            udp_associate_control: None,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
     }
 
+    /// Timeout for clients to send the SOCKS greeting, enables probe resistance
+    pub fn set_greeting_timeout(&mut self, d: Duration) {
+        self.greeting_timeout = Some(d);
+    }
+
+    /// Keep serving HTTP requests while probe resistance is enabled
+    #[cfg(feature = "local-http")]
+    pub fn set_greeting_allow_http(&mut self, allow: bool) {
+        self.greeting_allow_http = allow;
+    }
+
+    /// Bind UDP associations to the TCP connections that sent UDP ASSOCIATE
+    pub(crate) fn set_udp_associate_control(&mut self, control: Arc<Socks5UdpAssociateControl>) {
+        self.udp_associate_control = Some(control);
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_socket_name(&mut self, n: String) {
@@ -80,6 +112,10 @@ impl SocksTcpServerBuilder {
             balancer: self.balancer,
             mode: self.mode,
             socks5_auth: self.socks5_auth,
+            greeting_timeout: self.greeting_timeout,
+            #[cfg(feature = "local-http")]
+            greeting_allow_http: self.greeting_allow_http,
+            udp_associate_control: self.udp_associate_control,
         })
     }
 }
@@ -92,6 +128,10 @@ pub struct SocksTcpServer {
     balancer: PingBalancer,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
+    greeting_timeout: Option<Duration>,
+    #[cfg(feature = "local-http")]
+    greeting_allow_http: bool,
+    udp_associate_control: Option<Arc<Socks5UdpAssociateControl>>,
 }
 
 impl SocksTcpServer {
@@ -110,6 +150,7 @@ impl SocksTcpServer {
         let http_handler = HttpConnectionHandler::new(self.context.clone(), self.balancer.clone());
 
         loop {
+            let permit = self.context.relay_concurrency().acquire().await;
             let (stream, peer_addr) = match self.listener.accept().await {
                 Ok(s) => s,
                 Err(err) => {
@@ -120,6 +161,7 @@ impl SocksTcpServer {
             };
 
             let handler = SocksTcpHandler {
+                id: ConnectionId::next(),
                 context: self.context.clone(),
                 udp_bind_addr: udp_bind_addr.clone(),
                 stream,
@@ -127,13 +169,19 @@ impl SocksTcpServer {
                 peer_addr,
                 mode: self.mode,
                 socks5_auth: self.socks5_auth.clone(),
+                greeting_timeout: self.greeting_timeout,
+                #[cfg(feature = "local-http")]
+                greeting_allow_http: self.greeting_allow_http,
+                udp_associate_control: self.udp_associate_control.clone(),
                 #[cfg(feature = "local-http")]
                 http_handler: http_handler.clone(),
             };
 
+            let id = handler.id;
             tokio::spawn(async move {
+                let _permit = permit;
                 if let Err(err) = handler.handle_tcp_client().await {
-                    error!("socks5 tcp client handler error: {}", err);
+                    error!("socks5 tcp client {} handler error: {}", id, err);
                 }
             });
         }
@@ -141,6 +189,7 @@ impl SocksTcpServer {
 }
 
 struct SocksTcpHandler {
+    id: ConnectionId,
     context: Arc<ServiceContext>,
     udp_bind_addr: Arc<ServerAddr>,
     stream: TcpStream,
@@ -148,6 +197,10 @@ struct SocksTcpHandler {
     peer_addr: SocketAddr,
     mode: Mode,
     socks5_auth: Arc<Socks5AuthConfig>,
+    greeting_timeout: Option<Duration>,
+    #[cfg(feature = "local-http")]
+    greeting_allow_http: bool,
+    udp_associate_control: Option<Arc<Socks5UdpAssociateControl>>,
     #[cfg(feature = "local-http")]
     http_handler: HttpConnectionHandler,
 }
@@ -155,22 +208,38 @@ struct SocksTcpHandler {
 impl SocksTcpHandler {
     #[cfg(not(any(feature = "local-socks4", feature = "local-http")))]
     async fn handle_tcp_client(self) -> io::Result<()> {
-        let handler = Socks5TcpHandler::new(
+        let mut handler = Socks5TcpHandler::new(
             self.context,
             self.udp_bind_addr,
             self.balancer,
             self.mode,
             self.socks5_auth,
         );
-        handler.handle_socks5_client(self.stream, self.peer_addr).await
+        handler.set_greeting_deadline(self.greeting_timeout.map(|d| Instant::now() + d));
+        handler.set_udp_associate_control(self.udp_associate_control);
+        handler.handle_socks5_client(self.stream, self.id, self.peer_addr).await
     }
 
     #[cfg(any(feature = "local-socks4", feature = "local-http"))]
-    async fn handle_tcp_client(self) -> io::Result<()> {
+    async fn handle_tcp_client(mut self) -> io::Result<()> {
         use std::io::ErrorKind;
 
+        use log::debug;
+
+        // Peeking the version and reading the greeting share the same deadline
+        let greeting_deadline = self.greeting_timeout.map(|d| Instant::now() + d);
+
         let mut version_buffer = [0u8; 1];
-        let n = self.stream.peek(&mut version_buffer).await?;
+        let n = match greeting_deadline {
+            None => self.stream.peek(&mut version_buffer).await?,
+            Some(deadline) => match time::timeout_at(deadline, self.stream.peek(&mut version_buffer)).await {
+                Ok(r) => r?,
+                Err(..) => {
+                    debug!("socks {} greeting timed out, peer: {}", self.id, self.peer_addr);
+                    return Ok(());
+                }
+            },
+        };
         if n == 0 {
             return Err(ErrorKind::UnexpectedEof.into());
         }
@@ -178,38 +247,62 @@ impl SocksTcpHandler {
         match version_buffer[0] {
             #[cfg(feature = "local-socks4")]
             0x04 => {
-                let handler = Socks4TcpHandler::new(self.context, self.balancer, self.mode);
-                handler.handle_socks4_client(self.stream, self.peer_addr).await
+                let mut handler = Socks4TcpHandler::new(self.context, self.balancer, self.mode);
+                handler.set_greeting_deadline(greeting_deadline);
+                handler.handle_socks4_client(self.stream, self.id, self.peer_addr).await
             }
 
             0x05 => {
-                let handler = Socks5TcpHandler::new(
+                let mut handler = Socks5TcpHandler::new(
                     self.context,
                     self.udp_bind_addr,
                     self.balancer,
                     self.mode,
                     self.socks5_auth,
                 );
-                handler.handle_socks5_client(self.stream, self.peer_addr).await
+                handler.set_greeting_deadline(greeting_deadline);
+                handler.set_udp_associate_control(self.udp_associate_control);
+                handler.handle_socks5_client(self.stream, self.id, self.peer_addr).await
             }
 
             #[cfg(feature = "local-http")]
-            b'G' | b'g' | b'H' | b'h' | b'P' | b'p' | b'D' | b'd' | b'C' | b'c' | b'O' | b'o' | b'T' | b't' => {
+            b'G' | b'g' | b'H' | b'h' | b'P' | b'p' | b'D' | b'd' | b'C' | b'c' | b'O' | b'o' | b'T' | b't'
+                if greeting_deadline.is_none() || self.greeting_allow_http =>
+            {
                 // GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS, TRACE, PATCH
-                match self.http_handler.serve_connection(self.stream, self.peer_addr).await {
+                // Probe resistance doesn't answer HTTP requests unless they are allowed explicitly
+                match self
+                    .http_handler
+                    .serve_connection(self.stream, self.id, self.peer_addr)
+                    .await
+                {
                     Ok(..) => Ok(()),
                     Err(err) => {
-                        error!("HTTP connection {} handler failed with error: {}", self.peer_addr, err);
+                        error!(
+                            "HTTP connection {} {} handler failed with error: {}",
+                            self.id, self.peer_addr, err
+                        );
                         Err(io::Error::new(ErrorKind::Other, err))
                     }
                 }
             }
 
-            version => {
-                error!("unsupported socks version {:x}", version);
-                let err = io::Error::new(ErrorKind::Other, "unsupported socks version");
-                Err(err)
-            }
+            version => match greeting_deadline {
+                Some(deadline) => {
+                    // Probe resistance, close silently as if the greeting timed out
+                    debug!(
+                        "socks {} unexpected socks version {:x}, peer: {}",
+                        self.id, version, self.peer_addr
+                    );
+                    discard_until_deadline(&mut self.stream, deadline).await;
+                    Ok(())
+                }
+                None => {
+                    error!("unsupported socks version {:x}", version);
+                    let err = io::Error::new(ErrorKind::Other, "unsupported socks version");
+                    Err(err)
+                }
+            },
         }
     }
 }
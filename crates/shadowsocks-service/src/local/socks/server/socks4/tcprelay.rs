@@ -11,13 +11,18 @@ use shadowsocks::config::Mode;
 use tokio::{
     io::{AsyncWriteExt, BufReader},
     net::TcpStream,
+    time::{self, Instant},
 };
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::AutoProxyClientStream,
-    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::AutoProxyClientStream,
+        socks::server::discard_until_deadline,
+        utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+    },
+    net::ConnectionId,
 };
 
 use crate::local::socks::socks4::{
@@ -33,6 +38,7 @@ pub struct Socks4TcpHandler {
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     mode: Mode,
+    greeting_deadline: Option<Instant>,
 }
 
 impl Socks4TcpHandler {
@@ -41,33 +47,62 @@ impl Socks4TcpHandler {
             context,
             balancer,
             mode,
+            greeting_deadline: None,
         }
     }
 
-    pub async fn handle_socks4_client(self, stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    /// Deadline for reading the handshake request, connections will be closed silently if it is set
+    pub fn set_greeting_deadline(&mut self, deadline: Option<Instant>) {
+        self.greeting_deadline = deadline;
+    }
+
+    pub async fn handle_socks4_client(
+        self,
+        stream: TcpStream,
+        id: ConnectionId,
+        peer_addr: SocketAddr,
+    ) -> io::Result<()> {
         // 1. Handshake
 
         // NOTE: Wraps it with BufReader for reading NULL terminated information in HandshakeRequest
         let mut s = BufReader::new(stream);
-        let handshake_req = match HandshakeRequest::read_from(&mut s).await {
+        let handshake_result = match self.greeting_deadline {
+            None => HandshakeRequest::read_from(&mut s).await,
+            Some(deadline) => match time::timeout_at(deadline, HandshakeRequest::read_from(&mut s)).await {
+                Ok(r) => r,
+                Err(..) => {
+                    debug!("socks4 {} handshake timed out. peer: {}", id, peer_addr);
+                    return Ok(());
+                }
+            },
+        };
+
+        let handshake_req = match handshake_result {
             Ok(r) => r,
             Err(Socks4Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
-                trace!("socks4 handshake early eof. peer: {}", peer_addr);
+                trace!("socks4 {} handshake early eof. peer: {}", id, peer_addr);
                 return Ok(());
             }
             Err(err) => {
-                error!("socks4 handshake error: {}", err);
+                if let Some(deadline) = self.greeting_deadline {
+                    // Probe resistance, close silently as if the handshake timed out
+                    debug!("socks4 {} handshake error: {}, peer: {}", id, err, peer_addr);
+                    discard_until_deadline(&mut s, deadline).await;
+                    return Ok(());
+                }
+
+                error!("socks4 {} handshake error: {}", id, err);
                 return Err(err.into());
             }
         };
 
-        trace!("socks4 {:?} peer: {}", handshake_req, peer_addr);
+        trace!("socks4 {} {:?} peer: {}", id, handshake_req, peer_addr);
 
         match handshake_req.cd {
             Command::Connect => {
-                debug!("CONNECT {}", handshake_req.dst);
+                debug!("socks4 {} CONNECT {}", id, handshake_req.dst);
 
-                self.handle_socks4_connect(s, peer_addr, handshake_req.dst).await
+                self.handle_socks4_connect(s, id, peer_addr, handshake_req.dst).await
             }
             Command::Bind => {
                 warn!("BIND is not supported");
@@ -83,6 +118,7 @@ impl Socks4TcpHandler {
     async fn handle_socks4_connect(
         self,
         mut stream: BufReader<TcpStream>,
+        id: ConnectionId,
         peer_addr: SocketAddr,
         target_addr: Address,
     ) -> io::Result<()> {
@@ -98,11 +134,11 @@ impl Socks4TcpHandler {
         let target_addr = target_addr.into();
         let mut server_opt = None;
         let server_result = if self.balancer.is_empty() {
-            AutoProxyClientStream::connect_bypassed(self.context, &target_addr).await
+            AutoProxyClientStream::connect_bypassed(self.context.clone(), &target_addr).await
         } else {
             let server = self.balancer.best_tcp_server();
 
-            let r = AutoProxyClientStream::connect(self.context, &server, &target_addr).await;
+            let r = AutoProxyClientStream::connect(self.context.clone(), &server, &target_addr).await;
             server_opt = Some(server);
 
             r
@@ -144,9 +180,21 @@ impl Socks4TcpHandler {
         match server_opt {
             Some(server) => {
                 let svr_cfg = server.server_config();
-                establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+                establish_tcp_tunnel(
+                    &self.context,
+                    svr_cfg,
+                    &mut stream,
+                    &mut remote,
+                    id,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
+            }
+            None => {
+                establish_tcp_tunnel_bypassed(&self.context, &mut stream, &mut remote, id, peer_addr, &target_addr)
+                    .await
             }
-            None => establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &target_addr).await,
         }
     }
 }
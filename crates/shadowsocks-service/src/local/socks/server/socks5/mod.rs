@@ -1,5 +1,6 @@
 //! SOCKS5 Local Server
 
+pub(crate) use self::udprelay::Socks5UdpAssociateControl;
 pub use self::{
     tcprelay::Socks5TcpHandler,
     udprelay::{Socks5UdpServer, Socks5UdpServerBuilder},
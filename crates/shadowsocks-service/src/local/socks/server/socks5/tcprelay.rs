@@ -25,25 +25,32 @@ use shadowsocks::{
     },
     ServerAddr,
 };
-use tokio::net::TcpStream;
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant},
+};
 
 use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
         net::AutoProxyClientStream,
-        socks::config::Socks5AuthConfig,
+        socks::{config::Socks5AuthConfig, server::discard_until_deadline},
         utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
     },
-    net::utils::ignore_until_end,
+    net::{utils::ignore_until_end, ConnectionId},
 };
 
+use super::udprelay::Socks5UdpAssociateControl;
+
 pub struct Socks5TcpHandler {
     context: Arc<ServiceContext>,
     udp_bind_addr: Arc<ServerAddr>,
     balancer: PingBalancer,
     mode: Mode,
     auth: Arc<Socks5AuthConfig>,
+    greeting_deadline: Option<Instant>,
+    udp_associate_control: Option<Arc<Socks5UdpAssociateControl>>,
 }
 
 impl Socks5TcpHandler {
@@ -60,9 +67,21 @@ impl Socks5TcpHandler {
             balancer,
             mode,
             auth,
+            greeting_deadline: None,
+            udp_associate_control: None,
         }
     }
 
+    /// Deadline for reading the handshake request, connections will be closed silently if it is set
+    pub fn set_greeting_deadline(&mut self, deadline: Option<Instant>) {
+        self.greeting_deadline = deadline;
+    }
+
+    /// Close UDP associations of the client after the UDP ASSOCIATE connection is closed
+    pub(crate) fn set_udp_associate_control(&mut self, control: Option<Arc<Socks5UdpAssociateControl>>) {
+        self.udp_associate_control = control;
+    }
+
     async fn check_auth(&self, stream: &mut TcpStream, handshake_req: &HandshakeRequest) -> io::Result<()> {
         use std::io::Error;
 
@@ -94,10 +113,16 @@ impl Socks5TcpHandler {
             }
         }
 
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
-        resp.write_to(stream).await?;
+        match self.greeting_deadline {
+            // Probe resistance, close without any response as if the handshake timed out
+            Some(deadline) => discard_until_deadline(stream, deadline).await,
+            None => {
+                let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+                resp.write_to(stream).await?;
 
-        trace!("reply handshake {:?}", resp);
+                trace!("reply handshake {:?}", resp);
+            }
+        }
 
         Err(Error::new(
             ErrorKind::Other,
@@ -178,50 +203,76 @@ impl Socks5TcpHandler {
         }
     }
 
-    pub async fn handle_socks5_client(self, mut stream: TcpStream, peer_addr: SocketAddr) -> io::Result<()> {
+    pub async fn handle_socks5_client(
+        self,
+        mut stream: TcpStream,
+        id: ConnectionId,
+        peer_addr: SocketAddr,
+    ) -> io::Result<()> {
         // 1. Handshake
 
-        let handshake_req = match HandshakeRequest::read_from(&mut stream).await {
+        let handshake_result = match self.greeting_deadline {
+            None => HandshakeRequest::read_from(&mut stream).await,
+            Some(deadline) => match time::timeout_at(deadline, HandshakeRequest::read_from(&mut stream)).await {
+                Ok(r) => r,
+                Err(..) => {
+                    debug!("socks5 {} handshake timed out. peer: {}", id, peer_addr);
+                    return Ok(());
+                }
+            },
+        };
+
+        let handshake_req = match handshake_result {
             Ok(r) => r,
             Err(Socks5Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
-                trace!("socks5 handshake early eof. peer: {}", peer_addr);
+                trace!("socks5 {} handshake early eof. peer: {}", id, peer_addr);
                 return Ok(());
             }
             Err(err) => {
-                error!("socks5 handshake error: {}", err);
+                if let Some(deadline) = self.greeting_deadline {
+                    // Probe resistance, close silently as if the handshake timed out
+                    debug!("socks5 {} handshake error: {}, peer: {}", id, err, peer_addr);
+                    discard_until_deadline(&mut stream, deadline).await;
+                    return Ok(());
+                }
+
+                error!("socks5 {} handshake error: {}", id, err);
                 return Err(err.into());
             }
         };
 
-        trace!("socks5 {:?}", handshake_req);
+        trace!("socks5 {} {:?}", id, handshake_req);
         self.check_auth(&mut stream, &handshake_req).await?;
 
         // 2. Fetch headers
         let header = match TcpRequestHeader::read_from(&mut stream).await {
             Ok(h) => h,
             Err(err) => {
-                error!("failed to get TcpRequestHeader: {}, peer: {}", err, peer_addr);
+                error!(
+                    "socks5 {} failed to get TcpRequestHeader: {}, peer: {}",
+                    id, err, peer_addr
+                );
                 let rh = TcpResponseHeader::new(err.as_reply(), Address::SocketAddress(peer_addr));
                 rh.write_to(&mut stream).await?;
                 return Err(err.into());
             }
         };
 
-        trace!("socks5 {:?} peer: {}", header, peer_addr);
+        trace!("socks5 {} {:?} peer: {}", id, header, peer_addr);
 
         let addr = header.address;
 
         // 3. Handle Command
         match header.command {
             Command::TcpConnect => {
-                debug!("CONNECT {}", addr);
+                debug!("socks5 {} CONNECT {}", id, addr);
 
-                self.handle_tcp_connect(stream, peer_addr, addr).await
+                self.handle_tcp_connect(stream, id, peer_addr, addr).await
             }
             Command::UdpAssociate => {
-                debug!("UDP ASSOCIATE from {}", addr);
+                debug!("socks5 {} UDP ASSOCIATE from {}", id, addr);
 
-                self.handle_udp_associate(stream, addr).await
+                self.handle_udp_associate(stream, peer_addr, addr).await
             }
             Command::TcpBind => {
                 warn!("BIND is not supported");
@@ -236,6 +287,7 @@ impl Socks5TcpHandler {
     async fn handle_tcp_connect(
         self,
         mut stream: TcpStream,
+        id: ConnectionId,
         peer_addr: SocketAddr,
         target_addr: Address,
     ) -> io::Result<()> {
@@ -289,13 +341,30 @@ impl Socks5TcpHandler {
         match server_opt {
             Some(server) => {
                 let svr_cfg = server.server_config();
-                establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, &target_addr).await
+                establish_tcp_tunnel(
+                    &self.context,
+                    svr_cfg,
+                    &mut stream,
+                    &mut remote,
+                    id,
+                    peer_addr,
+                    &target_addr,
+                )
+                .await
+            }
+            None => {
+                establish_tcp_tunnel_bypassed(&self.context, &mut stream, &mut remote, id, peer_addr, &target_addr)
+                    .await
             }
-            None => establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, &target_addr).await,
         }
     }
 
-    async fn handle_udp_associate(self, mut stream: TcpStream, client_addr: Address) -> io::Result<()> {
+    async fn handle_udp_associate(
+        self,
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        client_addr: Address,
+    ) -> io::Result<()> {
         if !self.mode.enable_udp() {
             warn!("socks5 udp is disabled");
 
@@ -310,6 +379,12 @@ impl Socks5TcpHandler {
         let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, self.udp_bind_addr.as_ref().into());
         rh.write_to(&mut stream).await?;
 
+        // Associations of this client are closed after all its control connections are closed
+        let _associate_guard = self
+            .udp_associate_control
+            .as_ref()
+            .map(|control| control.open(peer_addr.ip()));
+
         // Hold connection until EOF.
         let _ = ignore_until_end(&mut stream).await;
 
@@ -1,9 +1,10 @@
 //! UDP Tunnel server
 
 use std::{
+    collections::HashMap,
     io::{self, Cursor},
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
@@ -18,23 +19,152 @@ use shadowsocks::{
     },
     ServerAddr,
 };
-use tokio::{net::UdpSocket, time};
+use tokio::{net::UdpSocket, sync::mpsc, time};
 
 use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
-        net::{udp::listener::create_standard_udp_listener, UdpAssociationManager, UdpInboundWrite},
+        net::{
+            udp::listener::create_standard_udp_listener,
+            UdpAssociationManager,
+            UdpAssociationStat,
+            UdpInboundWrite,
+        },
     },
     net::utils::to_ipv4_mapped,
 };
 
+/// Converts IPv4-mapped IPv6 addresses, clients may connect to a dual-stack listener with both forms
+fn normalize_client_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ref v6) => match to_ipv4_mapped(v6) {
+            Some(v4) => IpAddr::from(v4),
+            None => ip,
+        },
+        IpAddr::V4(..) => ip,
+    }
+}
+
+/// Binds lifetimes of UDP associations to the TCP connections that sent the UDP ASSOCIATE commands
+///
+/// Associations are identified by client IP addresses, because clients are allowed to send UDP packets from a
+/// different port than the one declared in UDP ASSOCIATE. Associations of a client are closed after all of its
+/// TCP control connections have been closed for the grace period.
+pub(crate) struct Socks5UdpAssociateControl {
+    grace_period: Duration,
+    controls: Mutex<Socks5UdpAssociateControls>,
+    close_tx: mpsc::UnboundedSender<(IpAddr, u64)>,
+}
+
+#[derive(Default)]
+struct Socks5UdpAssociateControls {
+    clients: HashMap<IpAddr, Socks5UdpAssociateClient>,
+    next_close_seq: u64,
+}
+
+struct Socks5UdpAssociateClient {
+    connections: usize,
+    /// Sequence of the latest close of all its control connections
+    close_seq: u64,
+}
+
+impl Socks5UdpAssociateControl {
+    fn new(grace_period: Duration) -> (Arc<Socks5UdpAssociateControl>, mpsc::UnboundedReceiver<(IpAddr, u64)>) {
+        let (close_tx, close_rx) = mpsc::unbounded_channel();
+        let control = Socks5UdpAssociateControl {
+            grace_period,
+            controls: Mutex::new(Socks5UdpAssociateControls::default()),
+            close_tx,
+        };
+        (Arc::new(control), close_rx)
+    }
+
+    /// Register a TCP control connection from `client_ip`, which is held until the returned guard is dropped
+    pub fn open(self: &Arc<Self>, client_ip: IpAddr) -> Socks5UdpAssociateGuard {
+        let client_ip = normalize_client_ip(client_ip);
+        self.controls
+            .lock()
+            .unwrap()
+            .clients
+            .entry(client_ip)
+            .or_insert(Socks5UdpAssociateClient {
+                connections: 0,
+                close_seq: 0,
+            })
+            .connections += 1;
+        Socks5UdpAssociateGuard {
+            control: self.clone(),
+            client_ip,
+        }
+    }
+
+    /// Check if associations of `client_ip` should be closed by the close with sequence `close_seq`
+    ///
+    /// Client may have sent another UDP ASSOCIATE in the grace period, or closed it again, which starts a new grace
+    /// period that earlier closes must not end.
+    fn take_closed(&self, client_ip: &IpAddr, close_seq: u64) -> bool {
+        let mut controls = self.controls.lock().unwrap();
+        match controls.clients.get(client_ip) {
+            Some(client) if client.connections == 0 && client.close_seq == close_seq => {
+                controls.clients.remove(client_ip);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn release(&self, client_ip: IpAddr) {
+        let close_seq = {
+            let mut controls = self.controls.lock().unwrap();
+            let close_seq = controls.next_close_seq;
+            match controls.clients.get_mut(&client_ip) {
+                Some(client) if client.connections > 1 => {
+                    client.connections -= 1;
+                    return;
+                }
+                Some(client) => {
+                    client.connections = 0;
+                    client.close_seq = close_seq;
+                }
+                None => return,
+            }
+            controls.next_close_seq += 1;
+            close_seq
+        };
+
+        if self.grace_period.is_zero() {
+            let _ = self.close_tx.send((client_ip, close_seq));
+        } else {
+            let close_tx = self.close_tx.clone();
+            let grace_period = self.grace_period;
+            tokio::spawn(async move {
+                time::sleep(grace_period).await;
+                let _ = close_tx.send((client_ip, close_seq));
+            });
+        }
+    }
+}
+
+/// TCP control connection of UDP associations, see `Socks5UdpAssociateControl`
+pub(crate) struct Socks5UdpAssociateGuard {
+    control: Arc<Socks5UdpAssociateControl>,
+    client_ip: IpAddr,
+}
+
+impl Drop for Socks5UdpAssociateGuard {
+    fn drop(&mut self) {
+        self.control.release(self.client_ip);
+    }
+}
+
 pub struct Socks5UdpServerBuilder {
     context: Arc<ServiceContext>,
     client_config: ServerAddr,
     time_to_live: Option<Duration>,
     capacity: Option<usize>,
     balancer: PingBalancer,
+    associate_grace_period: Duration,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -53,11 +183,19 @@ impl Socks5UdpServerBuilder {
             time_to_live,
             capacity,
             balancer,
+            associate_grace_period: Duration::ZERO,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
     }
 
+    /// Close associations after their UDP ASSOCIATE TCP connections have been closed for duration `d`
+    ///
+    /// Associations are closed as soon as their UDP ASSOCIATE TCP connections are closed if it is not set.
+    pub fn set_associate_grace_period(&mut self, d: Duration) {
+        self.associate_grace_period = d;
+    }
+
     /// macOS launchd activate socket
     #[cfg(target_os = "macos")]
     pub fn set_launchd_socket_name(&mut self, n: String) {
@@ -81,12 +219,16 @@ impl Socks5UdpServerBuilder {
             }
         }
 
+        let (associate_control, associate_close_rx) = Socks5UdpAssociateControl::new(self.associate_grace_period);
+
         Ok(Socks5UdpServer {
             context: self.context,
             time_to_live: self.time_to_live,
-            capacity: self.capacity,
+            association_stat: UdpAssociationStat::new(self.capacity),
             listener: Arc::new(socket),
             balancer: self.balancer,
+            associate_control,
+            associate_close_rx,
         })
     }
 }
@@ -131,9 +273,11 @@ impl UdpInboundWrite for Socks5UdpInboundWriter {
 pub struct Socks5UdpServer {
     context: Arc<ServiceContext>,
     time_to_live: Option<Duration>,
-    capacity: Option<usize>,
+    association_stat: UdpAssociationStat,
     listener: Arc<UdpSocket>,
     balancer: PingBalancer,
+    associate_control: Arc<Socks5UdpAssociateControl>,
+    associate_close_rx: mpsc::UnboundedReceiver<(IpAddr, u64)>,
 }
 
 impl Socks5UdpServer {
@@ -142,8 +286,18 @@ impl Socks5UdpServer {
         self.listener.local_addr()
     }
 
+    /// Live associations and the capacity
+    pub fn association_stat(&self) -> &UdpAssociationStat {
+        &self.association_stat
+    }
+
+    /// Control of associations' lifetimes, for TCP connections that sent UDP ASSOCIATE
+    pub(crate) fn associate_control(&self) -> Arc<Socks5UdpAssociateControl> {
+        self.associate_control.clone()
+    }
+
     /// Run server accept loop
-    pub async fn run(self) -> io::Result<()> {
+    pub async fn run(mut self) -> io::Result<()> {
         info!("shadowsocks socks5 UDP listening on {}", self.listener.local_addr()?);
 
         let (mut manager, cleanup_interval, mut keepalive_rx) = UdpAssociationManager::new(
@@ -152,7 +306,7 @@ impl Socks5UdpServer {
                 inbound: self.listener.clone(),
             },
             self.time_to_live,
-            self.capacity,
+            self.association_stat.clone(),
             self.balancer,
         );
 
@@ -167,10 +321,22 @@ impl Socks5UdpServer {
                 }
 
                 peer_addr_opt = keepalive_rx.recv() => {
-                    let peer_addr = peer_addr_opt.expect("keep-alive channel closed unexpectly");
+                    let peer_addr = peer_addr_opt.expect("keep-alive channel closed unexpectedly");
                     manager.keep_alive(&peer_addr).await;
                 }
 
+                close_opt = self.associate_close_rx.recv() => {
+                    // Sender is owned by self.associate_control, channel won't be closed
+                    let (client_ip, close_seq) = close_opt.expect("associate close channel closed unexpectedly");
+
+                    if !self.associate_control.take_closed(&client_ip, close_seq) {
+                        continue;
+                    }
+
+                    let n = manager.close_associations(|peer_addr| normalize_client_ip(peer_addr.ip()) == client_ip);
+                    debug!("udp associate control connections from {} closed, closed {} associations", client_ip, n);
+                }
+
                 recv_result = self.listener.recv_from(&mut buffer) => {
                     let (n, peer_addr) = match recv_result {
                         Ok(s) => s,
@@ -221,3 +387,92 @@ impl Socks5UdpServer {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::{config::Mode, crypto::CipherKind, ServerConfig};
+
+    use crate::local::{
+        loadbalancing::PingBalancerBuilder,
+        socks::{client::Socks5TcpClient, SocksBuilder},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn udp_associate_closed_with_control_connection() {
+        let context = Arc::new(ServiceContext::new());
+
+        // Receives packets from associations' outbound sockets
+        let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let svr_cfg = ServerConfig::new(remote.local_addr().unwrap(), "password", CipherKind::AES_256_GCM);
+
+        let mut balancer_builder = PingBalancerBuilder::new(context.clone(), Mode::TcpAndUdp);
+        balancer_builder.add_server(svr_cfg);
+        let balancer = balancer_builder.build().await.unwrap();
+
+        let mut builder = SocksBuilder::with_context(
+            context,
+            ServerAddr::SocketAddr("127.0.0.1:0".parse().unwrap()),
+            balancer,
+        );
+        builder.set_mode(Mode::TcpAndUdp);
+        let socks = builder.build().await.unwrap();
+        let tcp_addr = socks.tcp_server().unwrap().local_addr().unwrap();
+        let udp_addr = socks.udp_server().unwrap().local_addr().unwrap();
+        let association_stat = socks.udp_server().unwrap().association_stat().clone();
+        let server_task = tokio::spawn(socks.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let (first_control, _) = Socks5TcpClient::udp_associate(client_addr, tcp_addr).await.unwrap();
+        let (second_control, _) = Socks5TcpClient::udp_associate(client_addr, tcp_addr).await.unwrap();
+
+        let mut packet = BytesMut::new();
+        UdpAssociateHeader::new(0, Address::SocketAddress("127.0.0.1:53".parse().unwrap())).write_to_buf(&mut packet);
+        packet.put_slice(b"hello");
+        client.send_to(&packet, udp_addr).await.unwrap();
+
+        let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+        time::timeout(Duration::from_secs(5), remote.recv_from(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(association_stat.active(), 1);
+
+        // Association is kept while there is still a control connection
+        drop(first_control);
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(association_stat.active(), 1);
+
+        // Association is removed after the last control connection is closed
+        drop(second_control);
+        let mut removed = false;
+        for _ in 0..50 {
+            if association_stat.active() == 0 {
+                removed = true;
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(removed);
+
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    async fn udp_associate_close_in_earlier_grace_period() {
+        let client_ip = IpAddr::from([127, 0, 0, 1]);
+        let (control, mut close_rx) = Socks5UdpAssociateControl::new(Duration::ZERO);
+
+        drop(control.open(client_ip));
+        let (_, first_close) = close_rx.recv().await.unwrap();
+
+        // Client reconnected and closed again, a new grace period starts
+        drop(control.open(client_ip));
+        let (_, second_close) = close_rx.recv().await.unwrap();
+
+        assert!(!control.take_closed(&client_ip, first_close));
+        assert!(control.take_closed(&client_ip, second_close));
+    }
+}
@@ -32,11 +32,17 @@ mod tcp;
 mod udp;
 mod virt_device;
 
+/// MTU of tun devices if it is not configured and couldn't be read from the device
+const DEFAULT_TUN_MTU: i32 = 1500;
+
 /// Tun service builder
 pub struct TunBuilder {
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     tun_config: TunConfiguration,
+    #[cfg(unix)]
+    raw_fd: Option<RawFd>,
+    mtu: Option<u16>,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
     mode: Mode,
@@ -49,6 +55,9 @@ impl TunBuilder {
             context,
             balancer,
             tun_config: TunConfiguration::default(),
+            #[cfg(unix)]
+            raw_fd: None,
+            mtu: None,
             udp_expiry_duration: None,
             udp_capacity: None,
             mode: Mode::TcpOnly,
@@ -67,9 +76,21 @@ impl TunBuilder {
         self.tun_config.name(name);
     }
 
+    /// Set MTU of the tun interface
+    ///
+    /// It is also used by the TCP stack even if the device's MTU couldn't be changed, like adopted file descriptors.
+    pub fn mtu(&mut self, mtu: u16) {
+        self.tun_config.mtu(mtu as i32);
+        self.mtu = Some(mtu);
+    }
+
+    /// Adopt a pre-opened tun device, instead of creating a new interface
+    ///
+    /// The ownership of `fd` is transferred to the Tun service, it is validated in `build`.
     #[cfg(unix)]
     pub fn file_descriptor(&mut self, fd: RawFd) {
         self.tun_config.raw_fd(fd);
+        self.raw_fd = Some(fd);
     }
 
     pub fn udp_expiry_duration(&mut self, udp_expiry_duration: Duration) {
@@ -86,6 +107,18 @@ impl TunBuilder {
 
     /// Build Tun server
     pub async fn build(mut self) -> io::Result<Tun> {
+        #[cfg(unix)]
+        if let Some(fd) = self.raw_fd {
+            // Fail early, otherwise the device would report obscure errors on the first read
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                let err = io::Error::last_os_error();
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!("invalid tun file descriptor {fd}, error: {err}"),
+                ));
+            }
+        }
+
         self.tun_config.layer(Layer::L3).up();
 
         #[cfg(target_os = "linux")]
@@ -107,11 +140,11 @@ impl TunBuilder {
             self.udp_capacity,
         );
 
-        let tcp = TcpTun::new(
-            self.context,
-            self.balancer,
-            device.get_ref().mtu().unwrap_or(1500) as u32,
-        );
+        let mtu = match self.mtu {
+            Some(mtu) => mtu as u32,
+            None => device.get_ref().mtu().unwrap_or(DEFAULT_TUN_MTU) as u32,
+        };
+        let tcp = TcpTun::new(self.context, self.balancer, mtu);
 
         Ok(Tun {
             device,
@@ -36,7 +36,7 @@ use crate::{
         net::AutoProxyClientStream,
         utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
     },
-    net::utils::to_ipv4_mapped,
+    net::{utils::to_ipv4_mapped, ConnectionId},
 };
 
 use super::virt_device::VirtTunDevice;
@@ -528,11 +528,15 @@ impl TcpTun {
             .await;
 
             // establish a tunnel
+            let id = ConnectionId::next();
             let context = self.context.clone();
             let balancer = self.balancer.clone();
             tokio::spawn(async move {
-                if let Err(err) = handle_redir_client(context, balancer, connection, src_addr, dst_addr).await {
-                    error!("TCP tunnel failure, {} <-> {}, error: {}", src_addr, dst_addr, err);
+                if let Err(err) = handle_redir_client(context, balancer, connection, id, src_addr, dst_addr).await {
+                    error!(
+                        "TCP tunnel failure {}, {} <-> {}, error: {}",
+                        id, src_addr, dst_addr, err
+                    );
                 }
             });
         }
@@ -565,25 +569,27 @@ async fn establish_client_tcp_redir<'a>(
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     mut stream: TcpConnection,
+    id: ConnectionId,
     peer_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
     if balancer.is_empty() {
-        let mut remote = AutoProxyClientStream::connect_bypassed(context, addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, addr).await;
+        let mut remote = AutoProxyClientStream::connect_bypassed(context.clone(), addr).await?;
+        return establish_tcp_tunnel_bypassed(&context, &mut stream, &mut remote, id, peer_addr, addr).await;
     }
 
     let server = balancer.best_tcp_server();
     let svr_cfg = server.server_config();
 
-    let mut remote = AutoProxyClientStream::connect(context, &server, addr).await?;
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, addr).await
+    let mut remote = AutoProxyClientStream::connect(context.clone(), &server, addr).await?;
+    establish_tcp_tunnel(&context, svr_cfg, &mut stream, &mut remote, id, peer_addr, addr).await
 }
 
 async fn handle_redir_client(
     context: Arc<ServiceContext>,
     balancer: PingBalancer,
     s: TcpConnection,
+    id: ConnectionId,
     peer_addr: SocketAddr,
     mut daddr: SocketAddr,
 ) -> io::Result<()> {
@@ -596,5 +602,5 @@ async fn handle_redir_client(
         }
     }
     let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(context, balancer, s, peer_addr, &target_addr).await
+    establish_client_tcp_redir(context, balancer, s, id, peer_addr, &target_addr).await
 }
@@ -16,7 +16,7 @@ use crate::{
     local::{
         context::ServiceContext,
         loadbalancing::PingBalancer,
-        net::{UdpAssociationManager, UdpInboundWrite},
+        net::{UdpAssociationManager, UdpAssociationStat, UdpInboundWrite},
     },
     net::utils::to_ipv4_mapped,
 };
@@ -38,7 +38,7 @@ impl UdpTun {
             context,
             UdpTunInboundWriter::new(tun_tx),
             time_to_live,
-            capacity,
+            UdpAssociationStat::new(capacity),
             balancer,
         );
 
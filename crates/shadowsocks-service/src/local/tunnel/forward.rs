@@ -0,0 +1,102 @@
+//! Forward targets of tunnels
+
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use arc_swap::ArcSwap;
+use shadowsocks::relay::socks5::Address;
+
+/// Forward addresses of a tunnel
+///
+/// Targets are chosen in round-robin order, once per TCP connection and once per UDP association.
+/// They could be replaced while the tunnel is running, which only affects connections and associations created later.
+#[derive(Debug)]
+pub struct ForwardAddrs {
+    addrs: ArcSwap<Vec<Address>>,
+    next: AtomicUsize,
+}
+
+impl ForwardAddrs {
+    /// Create with non-empty `addrs`
+    pub fn new(addrs: Vec<Address>) -> io::Result<ForwardAddrs> {
+        check_forward_addrs(&addrs)?;
+        Ok(ForwardAddrs {
+            addrs: ArcSwap::from_pointee(addrs),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Choose the target of a new connection or association
+    pub fn pick(&self) -> Address {
+        let addrs = self.addrs.load();
+        if addrs.len() == 1 {
+            return addrs[0].clone();
+        }
+
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % addrs.len();
+        addrs[idx].clone()
+    }
+
+    /// Replace targets with non-empty `addrs`
+    ///
+    /// Current targets are kept if `addrs` is empty.
+    pub fn set(&self, addrs: Vec<Address>) -> io::Result<()> {
+        check_forward_addrs(&addrs)?;
+        self.addrs.store(Arc::new(addrs));
+        Ok(())
+    }
+
+    /// Replace targets with a single `addr`
+    pub fn set_one(&self, addr: Address) {
+        self.addrs.store(Arc::new(vec![addr]));
+    }
+}
+
+fn check_forward_addrs(addrs: &[Address]) -> io::Result<()> {
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "tunnel requires at least one forward address",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn forward_addrs_round_robin() {
+        let a = Address::from(("a.example.com".to_owned(), 80));
+        let b = Address::from(("b.example.com".to_owned(), 80));
+
+        let single = ForwardAddrs::new(vec![a.clone()]).unwrap();
+        assert_eq!(single.pick(), a);
+        assert_eq!(single.pick(), a);
+
+        let addrs = ForwardAddrs::new(vec![a.clone(), b.clone()]).unwrap();
+        let picked = (0..4).map(|_| addrs.pick()).collect::<Vec<_>>();
+        assert_eq!(picked, [a.clone(), b.clone(), a.clone(), b.clone()]);
+
+        addrs.set(vec![b.clone()]).unwrap();
+        assert_eq!(addrs.pick(), b);
+        assert_eq!(addrs.pick(), b);
+    }
+
+    #[test]
+    fn forward_addrs_empty() {
+        let a = Address::from(("a.example.com".to_owned(), 80));
+
+        assert_eq!(ForwardAddrs::new(Vec::new()).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        let addrs = ForwardAddrs::new(vec![a.clone()]).unwrap();
+        assert_eq!(addrs.set(Vec::new()).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(addrs.pick(), a);
+    }
+}
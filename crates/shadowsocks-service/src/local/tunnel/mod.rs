@@ -1,7 +1,11 @@
 //! Shadowsocks Local Tunnel Server
 
-pub use self::server::{Tunnel, TunnelBuilder};
+pub use self::{
+    forward::ForwardAddrs,
+    server::{Tunnel, TunnelBuilder, TunnelHandle},
+};
 
+mod forward;
 pub mod server;
 mod tcprelay;
 mod udprelay;
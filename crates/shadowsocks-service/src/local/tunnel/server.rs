@@ -1,6 +1,10 @@
 //! Shadowsocks Local Tunnel Server
 
-use std::{io, sync::Arc, time::Duration};
+use std::{
+    io,
+    sync::Arc,
+    time::Duration,
+};
 
 use futures::{future, FutureExt};
 use shadowsocks::{config::Mode, relay::socks5::Address, ServerAddr};
@@ -8,13 +12,14 @@ use shadowsocks::{config::Mode, relay::socks5::Address, ServerAddr};
 use crate::local::{context::ServiceContext, loadbalancing::PingBalancer};
 
 use super::{
+    forward::ForwardAddrs,
     tcprelay::{TunnelTcpServer, TunnelTcpServerBuilder},
     udprelay::{TunnelUdpServer, TunnelUdpServerBuilder},
 };
 
 pub struct TunnelBuilder {
     context: Arc<ServiceContext>,
-    forward_addr: Address,
+    forward_addrs: Vec<Address>,
     mode: Mode,
     udp_expiry_duration: Option<Duration>,
     udp_capacity: Option<usize>,
@@ -28,22 +33,24 @@ pub struct TunnelBuilder {
 }
 
 impl TunnelBuilder {
-    /// Create a new Tunnel server forwarding to `forward_addr`
-    pub fn new(forward_addr: Address, client_addr: ServerAddr, balancer: PingBalancer) -> TunnelBuilder {
+    /// Create a new Tunnel server forwarding to `forward_addrs`
+    ///
+    /// Each TCP connection and UDP association chooses one of `forward_addrs` in round-robin order.
+    pub fn new(forward_addrs: Vec<Address>, client_addr: ServerAddr, balancer: PingBalancer) -> TunnelBuilder {
         let context = ServiceContext::new();
-        TunnelBuilder::with_context(Arc::new(context), forward_addr, client_addr, balancer)
+        TunnelBuilder::with_context(Arc::new(context), forward_addrs, client_addr, balancer)
     }
 
     /// Create a new Tunnel server with context
     pub fn with_context(
         context: Arc<ServiceContext>,
-        forward_addr: Address,
+        forward_addrs: Vec<Address>,
         client_addr: ServerAddr,
         balancer: PingBalancer,
     ) -> TunnelBuilder {
         TunnelBuilder {
             context,
-            forward_addr,
+            forward_addrs,
             mode: Mode::TcpOnly,
             udp_expiry_duration: None,
             udp_capacity: None,
@@ -90,6 +97,8 @@ impl TunnelBuilder {
     }
 
     pub async fn build(self) -> io::Result<Tunnel> {
+        let forward_addrs = Arc::new(ForwardAddrs::new(self.forward_addrs)?);
+
         let mut tcp_server = None;
         if self.mode.enable_tcp() {
             #[allow(unused_mut)]
@@ -97,7 +106,7 @@ impl TunnelBuilder {
                 self.context.clone(),
                 self.client_addr.clone(),
                 self.balancer.clone(),
-                self.forward_addr.clone(),
+                forward_addrs.clone(),
             );
 
             #[cfg(target_os = "macos")]
@@ -120,7 +129,7 @@ impl TunnelBuilder {
                 self.udp_expiry_duration,
                 self.udp_capacity,
                 self.balancer,
-                self.forward_addr,
+                forward_addrs.clone(),
             );
 
             #[cfg(target_os = "macos")]
@@ -132,7 +141,11 @@ impl TunnelBuilder {
             udp_server = Some(server);
         }
 
-        Ok(Tunnel { tcp_server, udp_server })
+        Ok(Tunnel {
+            tcp_server,
+            udp_server,
+            forward_addrs,
+        })
     }
 }
 
@@ -140,6 +153,7 @@ impl TunnelBuilder {
 pub struct Tunnel {
     tcp_server: Option<TunnelTcpServer>,
     udp_server: Option<TunnelUdpServer>,
+    forward_addrs: Arc<ForwardAddrs>,
 }
 
 impl Tunnel {
@@ -153,6 +167,13 @@ impl Tunnel {
         self.udp_server.as_ref()
     }
 
+    /// Handle for reconfiguring the tunnel, which could be kept after `run` is called
+    pub fn handle(&self) -> TunnelHandle {
+        TunnelHandle {
+            forward_addrs: self.forward_addrs.clone(),
+        }
+    }
+
     /// Start serving
     pub async fn run(self) -> io::Result<()> {
         let mut vfut = Vec::new();
@@ -169,3 +190,25 @@ impl Tunnel {
         res
     }
 }
+
+/// Handle of a running `Tunnel`
+#[derive(Debug, Clone)]
+pub struct TunnelHandle {
+    forward_addrs: Arc<ForwardAddrs>,
+}
+
+impl TunnelHandle {
+    /// Forward new connections and UDP associations to `addr`
+    ///
+    /// Established connections and associations keep their current target.
+    pub fn set_forward_addr(&self, addr: Address) {
+        self.forward_addrs.set_one(addr);
+    }
+
+    /// Forward new connections and UDP associations to `addrs` in round-robin order
+    ///
+    /// Returns `InvalidInput` and keeps the current targets if `addrs` is empty.
+    pub fn set_forward_addrs(&self, addrs: Vec<Address>) -> io::Result<()> {
+        self.forward_addrs.set(addrs)
+    }
+}
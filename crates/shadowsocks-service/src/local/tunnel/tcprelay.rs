@@ -6,18 +6,23 @@ use log::{error, info, trace};
 use shadowsocks::{net::TcpListener as ShadowTcpListener, relay::socks5::Address, ServerAddr};
 use tokio::{net::TcpStream, time};
 
-use crate::local::{
-    context::ServiceContext,
-    loadbalancing::PingBalancer,
-    net::{tcp::listener::create_standard_tcp_listener, AutoProxyClientStream},
-    utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+use crate::{
+    local::{
+        context::ServiceContext,
+        loadbalancing::PingBalancer,
+        net::{tcp::listener::create_standard_tcp_listener, AutoProxyClientStream},
+        utils::{establish_tcp_tunnel, establish_tcp_tunnel_bypassed},
+    },
+    net::ConnectionId,
 };
 
+use super::forward::ForwardAddrs;
+
 pub struct TunnelTcpServerBuilder {
     context: Arc<ServiceContext>,
     client_config: ServerAddr,
     balancer: PingBalancer,
-    forward_addr: Address,
+    forward_addrs: Arc<ForwardAddrs>,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -27,13 +32,13 @@ impl TunnelTcpServerBuilder {
         context: Arc<ServiceContext>,
         client_config: ServerAddr,
         balancer: PingBalancer,
-        forward_addr: Address,
+        forward_addrs: Arc<ForwardAddrs>,
     ) -> TunnelTcpServerBuilder {
         TunnelTcpServerBuilder {
             context,
             client_config,
             balancer,
-            forward_addr,
+            forward_addrs,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
@@ -67,7 +72,7 @@ impl TunnelTcpServerBuilder {
             context: self.context,
             listener,
             balancer: self.balancer,
-            forward_addr: self.forward_addr,
+            forward_addrs: self.forward_addrs,
         })
     }
 }
@@ -77,7 +82,7 @@ pub struct TunnelTcpServer {
     context: Arc<ServiceContext>,
     listener: ShadowTcpListener,
     balancer: PingBalancer,
-    forward_addr: Address,
+    forward_addrs: Arc<ForwardAddrs>,
 }
 
 impl TunnelTcpServer {
@@ -90,8 +95,8 @@ impl TunnelTcpServer {
     pub async fn run(self) -> io::Result<()> {
         info!("shadowsocks TCP tunnel listening on {}", self.listener.local_addr()?);
 
-        let forward_addr = Arc::new(self.forward_addr);
         loop {
+            let permit = self.context.relay_concurrency().acquire().await;
             let (stream, peer_addr) = match self.listener.accept().await {
                 Ok(s) => s,
                 Err(err) => {
@@ -101,13 +106,14 @@ impl TunnelTcpServer {
                 }
             };
 
-            tokio::spawn(handle_tcp_client(
-                self.context.clone(),
-                stream,
-                self.balancer.clone(),
-                peer_addr,
-                forward_addr.clone(),
-            ));
+            let id = ConnectionId::next();
+            let context = self.context.clone();
+            let balancer = self.balancer.clone();
+            let forward_addr = self.forward_addrs.pick();
+            tokio::spawn(async move {
+                let _permit = permit;
+                handle_tcp_client(context, stream, balancer, id, peer_addr, forward_addr).await
+            });
         }
     }
 }
@@ -116,28 +122,35 @@ async fn handle_tcp_client(
     context: Arc<ServiceContext>,
     mut stream: TcpStream,
     balancer: PingBalancer,
+    id: ConnectionId,
     peer_addr: SocketAddr,
-    forward_addr: Arc<Address>,
+    forward_addr: Address,
 ) -> io::Result<()> {
     let forward_addr: &Address = &forward_addr;
 
     if balancer.is_empty() {
-        trace!("establishing tcp tunnel {} <-> {} direct", peer_addr, forward_addr);
-
-        let mut remote = AutoProxyClientStream::connect_bypassed(context, forward_addr).await?;
-        return establish_tcp_tunnel_bypassed(&mut stream, &mut remote, peer_addr, forward_addr).await;
+        trace!(
+            "establishing tcp tunnel {} {} <-> {} direct",
+            id,
+            peer_addr,
+            forward_addr
+        );
+
+        let mut remote = AutoProxyClientStream::connect_bypassed(context.clone(), forward_addr).await?;
+        return establish_tcp_tunnel_bypassed(&context, &mut stream, &mut remote, id, peer_addr, forward_addr).await;
     }
 
     let server = balancer.best_tcp_server();
     let svr_cfg = server.server_config();
     trace!(
-        "establishing tcp tunnel {} <-> {} through sever {} (outbound: {})",
+        "establishing tcp tunnel {} {} <-> {} through sever {} (outbound: {})",
+        id,
         peer_addr,
         forward_addr,
         svr_cfg.tcp_external_addr(),
         svr_cfg.addr(),
     );
 
-    let mut remote = AutoProxyClientStream::connect_proxied(context, &server, forward_addr).await?;
-    establish_tcp_tunnel(svr_cfg, &mut stream, &mut remote, peer_addr, forward_addr).await
+    let mut remote = AutoProxyClientStream::connect_proxied(context.clone(), &server, forward_addr).await?;
+    establish_tcp_tunnel(&context, svr_cfg, &mut stream, &mut remote, id, peer_addr, forward_addr).await
 }
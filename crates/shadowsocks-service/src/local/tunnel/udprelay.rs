@@ -4,6 +4,7 @@ use std::{io, net::SocketAddr, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 use log::{debug, error, info};
+use lru_time_cache::LruCache;
 use shadowsocks::{
     relay::{socks5::Address, udprelay::MAXIMUM_UDP_PAYLOAD_SIZE},
     ServerAddr,
@@ -13,16 +14,18 @@ use tokio::{net::UdpSocket, time};
 use crate::local::{
     context::ServiceContext,
     loadbalancing::PingBalancer,
-    net::{udp::listener::create_standard_udp_listener, UdpAssociationManager, UdpInboundWrite},
+    net::{udp::listener::create_standard_udp_listener, UdpAssociationManager, UdpAssociationStat, UdpInboundWrite},
 };
 
+use super::forward::ForwardAddrs;
+
 pub struct TunnelUdpServerBuilder {
     context: Arc<ServiceContext>,
     client_config: ServerAddr,
     time_to_live: Option<Duration>,
     capacity: Option<usize>,
     balancer: PingBalancer,
-    forward_addr: Address,
+    forward_addrs: Arc<ForwardAddrs>,
     #[cfg(target_os = "macos")]
     launchd_socket_name: Option<String>,
 }
@@ -34,7 +37,7 @@ impl TunnelUdpServerBuilder {
         time_to_live: Option<Duration>,
         capacity: Option<usize>,
         balancer: PingBalancer,
-        forward_addr: Address,
+        forward_addrs: Arc<ForwardAddrs>,
     ) -> TunnelUdpServerBuilder {
         TunnelUdpServerBuilder {
             context,
@@ -42,7 +45,7 @@ impl TunnelUdpServerBuilder {
             time_to_live,
             capacity,
             balancer,
-            forward_addr,
+            forward_addrs,
             #[cfg(target_os = "macos")]
             launchd_socket_name: None,
         }
@@ -74,10 +77,10 @@ impl TunnelUdpServerBuilder {
         Ok(TunnelUdpServer {
             context: self.context,
             time_to_live: self.time_to_live,
-            capacity: self.capacity,
+            association_stat: UdpAssociationStat::new(self.capacity),
             listener: Arc::new(socket),
             balancer: self.balancer,
-            forward_addr: self.forward_addr,
+            forward_addrs: self.forward_addrs,
         })
     }
 }
@@ -97,10 +100,10 @@ impl UdpInboundWrite for TunnelUdpInboundWriter {
 pub struct TunnelUdpServer {
     context: Arc<ServiceContext>,
     time_to_live: Option<Duration>,
-    capacity: Option<usize>,
+    association_stat: UdpAssociationStat,
     listener: Arc<UdpSocket>,
     balancer: PingBalancer,
-    forward_addr: Address,
+    forward_addrs: Arc<ForwardAddrs>,
 }
 
 impl TunnelUdpServer {
@@ -109,6 +112,11 @@ impl TunnelUdpServer {
         self.listener.local_addr()
     }
 
+    /// Live associations and the capacity
+    pub fn association_stat(&self) -> &UdpAssociationStat {
+        &self.association_stat
+    }
+
     /// Start serving
     pub async fn run(self) -> io::Result<()> {
         info!("shadowsocks UDP tunnel listening on {}", self.listener.local_addr()?);
@@ -119,10 +127,16 @@ impl TunnelUdpServer {
                 inbound: self.listener.clone(),
             },
             self.time_to_live,
-            self.capacity,
+            self.association_stat.clone(),
             self.balancer,
         );
 
+        // Forward address chosen by each association, expires with the association
+        let mut assoc_forward_addrs: LruCache<SocketAddr, Address> = match self.association_stat.capacity() {
+            Some(capacity) => LruCache::with_expiry_duration_and_capacity(cleanup_interval, capacity),
+            None => LruCache::with_expiry_duration(cleanup_interval),
+        };
+
         let mut buffer = [0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let mut cleanup_timer = time::interval(cleanup_interval);
 
@@ -136,6 +150,7 @@ impl TunnelUdpServer {
                 peer_addr_opt = keepalive_rx.recv() => {
                     let peer_addr = peer_addr_opt.expect("keep-alive channel closed unexpectly");
                     manager.keep_alive(&peer_addr).await;
+                    let _ = assoc_forward_addrs.get(&peer_addr);
                 }
 
                 recv_result = self.listener.recv_from(&mut buffer) => {
@@ -159,14 +174,23 @@ impl TunnelUdpServer {
                         continue;
                     }
 
+                    let forward_addr = match assoc_forward_addrs.get(&peer_addr) {
+                        Some(addr) => addr.clone(),
+                        None => {
+                            let addr = self.forward_addrs.pick();
+                            assoc_forward_addrs.insert(peer_addr, addr.clone());
+                            addr
+                        }
+                    };
+
                     let data = &buffer[..n];
-                    if let Err(err) = manager.send_to(peer_addr, self.forward_addr.clone(), data)
+                    if let Err(err) = manager.send_to(peer_addr, forward_addr.clone(), data)
                         .await
                     {
                         debug!(
                             "udp packet relay {} -> {} with {} bytes failed, error: {}",
                             peer_addr,
-                            self.forward_addr,
+                            forward_addr,
                             data.len(),
                             err
                         );
@@ -1,23 +1,169 @@
 //! Shadowsocks Local Utilities
 
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
 
-use log::{debug, trace};
+use log::{info, log, trace, Level};
 use shadowsocks::{
     config::ServerConfig,
     relay::{socks5::Address, tcprelay::utils::copy_encrypted_bidirectional},
 };
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     time,
 };
 
-use crate::local::net::AutoProxyIo;
+use crate::{
+    local::{context::ServiceContext, net::AutoProxyIo},
+    net::{AccessLogEntry, CaptureStream, ConnectionId, MonPlainStream},
+};
+
+/// Account bytes transferred with `target_addr`, `tx` is sent to the target and `rx` is received from it
+fn record_destination_flow(context: &ServiceContext, target_addr: &Address, tx: u64, rx: u64) {
+    let stat = context.destination_flow_stat_ref();
+    match *target_addr {
+        Address::SocketAddress(ref saddr) => stat.record(&saddr.ip().to_string(), tx, rx),
+        Address::DomainNameAddress(ref host, ..) => stat.record(host, tx, rx),
+    }
+}
+
+/// Append a closed tunnel to the access log if it is enabled
+#[allow(clippy::too_many_arguments)]
+fn record_access_log(
+    context: &ServiceContext,
+    id: ConnectionId,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+    proxied: bool,
+    established_at: Instant,
+    (tx, rx): (u64, u64),
+    errored: bool,
+) {
+    if let Some(access_log) = context.access_log() {
+        access_log.record(&AccessLogEntry {
+            id,
+            peer_addr,
+            target_addr,
+            proxied,
+            tx,
+            rx,
+            duration: established_at.elapsed(),
+            errored,
+        });
+    }
+}
+
+/// Client side of a tunnel, counting bytes that are known even if the tunnel is closed with error
+struct CountedStream<S> {
+    stream: S,
+    /// Read from the client, sent to the target
+    tx: u64,
+    /// Received from the target, written to the client
+    rx: u64,
+}
+
+impl<S> CountedStream<S> {
+    fn new(stream: S) -> CountedStream<S> {
+        CountedStream { stream, tx: 0, rx: 0 }
+    }
+
+    fn transferred(&self) -> (u64, u64) {
+        (self.tx, self.rx)
+    }
+}
+
+impl<S> AsyncRead for CountedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.tx += (buf.filled().len() - filled) as u64;
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for CountedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.rx += n as u64;
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Logs of a connection, the connection is sampled only once for all of them
+struct TunnelLog {
+    /// Lifecycle logs are `info` if it is chosen by the sampler
+    established_level: Level,
+    closed_level: Level,
+    /// Log the chosen server at `info`, see `ServiceContext::should_log_selected_server`
+    selected_server: bool,
+}
+
+impl TunnelLog {
+    fn sample(context: &ServiceContext) -> TunnelLog {
+        let sampled = context.connection_log_sampler().sample();
+        let (established_level, closed_level) = if sampled {
+            (Level::Info, Level::Info)
+        } else {
+            (Level::Debug, Level::Trace)
+        };
+
+        TunnelLog {
+            established_level,
+            closed_level,
+            selected_server: context.should_log_selected_server(sampled),
+        }
+    }
+}
 
 pub(crate) async fn establish_tcp_tunnel<P, S>(
+    context: &ServiceContext,
+    svr_cfg: &ServerConfig,
+    plain: &mut P,
+    shadow: &mut S,
+    id: ConnectionId,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
+{
+    if let Some(capture) = context.plaintext_capture().filter(|c| c.matches(target_addr)) {
+        let mut plain = CaptureStream::new(plain, capture, peer_addr, target_addr);
+        return copy_tcp_tunnel(context, svr_cfg, &mut plain, shadow, id, peer_addr, target_addr).await;
+    }
+    copy_tcp_tunnel(context, svr_cfg, plain, shadow, id, peer_addr, target_addr).await
+}
+
+async fn copy_tcp_tunnel<P, S>(
+    context: &ServiceContext,
     svr_cfg: &ServerConfig,
     plain: &mut P,
     shadow: &mut S,
+    id: ConnectionId,
     peer_addr: SocketAddr,
     target_addr: &Address,
 ) -> io::Result<()>
@@ -25,18 +171,50 @@ where
     P: AsyncRead + AsyncWrite + Unpin,
     S: AsyncRead + AsyncWrite + AutoProxyIo + Unpin,
 {
+    let TunnelLog {
+        established_level,
+        closed_level,
+        selected_server,
+    } = TunnelLog::sample(context);
+    let established_at = Instant::now();
+
     if shadow.is_proxied() {
-        debug!(
-            "established tcp tunnel {} <-> {} through sever {} (outbound: {})",
+        // Established log has already shown the server
+        if selected_server && established_level != Level::Info {
+            info!(
+                "tcp tunnel {} {} <-> {} through server {}",
+                id,
+                peer_addr,
+                target_addr,
+                svr_cfg.addr()
+            );
+        }
+
+        log!(
+            established_level,
+            "established tcp tunnel {} {} <-> {} through sever {} (outbound: {})",
+            id,
             peer_addr,
             target_addr,
             svr_cfg.tcp_external_addr(),
             svr_cfg.addr(),
         );
     } else {
-        return establish_tcp_tunnel_bypassed(plain, shadow, peer_addr, target_addr).await;
+        return copy_tcp_tunnel_bypassed(
+            context,
+            plain,
+            shadow,
+            id,
+            peer_addr,
+            target_addr,
+            established_level,
+            closed_level,
+        )
+        .await;
     }
 
+    let mut plain = MonPlainStream::from_stream(CountedStream::new(plain), context.flow_stat());
+
     // https://github.com/shadowsocks/shadowsocks-rust/issues/232
     //
     // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
@@ -46,8 +224,17 @@ where
         let mut buffer = [0u8; 8192];
         match time::timeout(Duration::from_millis(500), plain.read(&mut buffer)).await {
             Ok(Ok(0)) => {
-                // EOF. Just terminate right here.
-                return Ok(());
+                // EOF. Client half-closed without sending anything, but it may still be waiting for the response.
+                //
+                // Send handshake to server, the copy below will propagate the shutdown.
+                let _ = shadow.write(&[]).await?;
+
+                trace!(
+                    "tcp tunnel {} {} -> {} (proxied) half-closed before sending data",
+                    id,
+                    peer_addr,
+                    target_addr
+                );
             }
             Ok(Ok(n)) => {
                 // Send the first packet.
@@ -59,7 +246,8 @@ where
                 let _ = shadow.write(&[]).await?;
 
                 trace!(
-                    "tcp tunnel {} -> {} (proxied) sent handshake without data",
+                    "tcp tunnel {} {} -> {} (proxied) sent handshake without data",
+                    id,
                     peer_addr,
                     target_addr
                 );
@@ -67,10 +255,26 @@ where
         }
     }
 
-    match copy_encrypted_bidirectional(svr_cfg.method(), shadow, plain).await {
-        Ok((wn, rn)) => {
-            trace!(
-                "tcp tunnel {} <-> {} (proxied) closed, L2R {} bytes, R2L {} bytes",
+    let result = copy_encrypted_bidirectional(svr_cfg.method(), shadow, &mut plain).await;
+    // Including the first packet
+    let (rn, wn) = plain.get_ref().transferred();
+    match result {
+        Ok(..) => {
+            record_destination_flow(context, target_addr, rn, wn);
+            record_access_log(
+                context,
+                id,
+                peer_addr,
+                target_addr,
+                true,
+                established_at,
+                (rn, wn),
+                false,
+            );
+            log!(
+                closed_level,
+                "tcp tunnel {} {} <-> {} (proxied) closed, L2R {} bytes, R2L {} bytes",
+                id,
                 peer_addr,
                 target_addr,
                 rn,
@@ -78,8 +282,21 @@ where
             );
         }
         Err(err) => {
-            trace!(
-                "tcp tunnel {} <-> {} (proxied) closed with error: {}",
+            record_destination_flow(context, target_addr, rn, wn);
+            record_access_log(
+                context,
+                id,
+                peer_addr,
+                target_addr,
+                true,
+                established_at,
+                (rn, wn),
+                true,
+            );
+            log!(
+                closed_level,
+                "tcp tunnel {} {} <-> {} (proxied) closed with error: {}",
+                id,
                 peer_addr,
                 target_addr,
                 err
@@ -91,21 +308,93 @@ where
 }
 
 pub(crate) async fn establish_tcp_tunnel_bypassed<P, S>(
+    context: &ServiceContext,
+    plain: &mut P,
+    shadow: &mut S,
+    id: ConnectionId,
+    peer_addr: SocketAddr,
+    target_addr: &Address,
+) -> io::Result<()>
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let TunnelLog {
+        established_level,
+        closed_level,
+        ..
+    } = TunnelLog::sample(context);
+    if let Some(capture) = context.plaintext_capture().filter(|c| c.matches(target_addr)) {
+        let mut plain = CaptureStream::new(plain, capture, peer_addr, target_addr);
+        return copy_tcp_tunnel_bypassed(
+            context,
+            &mut plain,
+            shadow,
+            id,
+            peer_addr,
+            target_addr,
+            established_level,
+            closed_level,
+        )
+        .await;
+    }
+    copy_tcp_tunnel_bypassed(
+        context,
+        plain,
+        shadow,
+        id,
+        peer_addr,
+        target_addr,
+        established_level,
+        closed_level,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn copy_tcp_tunnel_bypassed<P, S>(
+    context: &ServiceContext,
     plain: &mut P,
     shadow: &mut S,
+    id: ConnectionId,
     peer_addr: SocketAddr,
     target_addr: &Address,
+    established_level: Level,
+    closed_level: Level,
 ) -> io::Result<()>
 where
     P: AsyncRead + AsyncWrite + Unpin,
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    debug!("established tcp tunnel {} <-> {} bypassed", peer_addr, target_addr);
+    log!(
+        established_level,
+        "established tcp tunnel {} {} <-> {} bypassed",
+        id,
+        peer_addr,
+        target_addr
+    );
+    let established_at = Instant::now();
 
-    match copy_bidirectional(plain, shadow).await {
-        Ok((rn, wn)) => {
-            trace!(
-                "tcp tunnel {} <-> {} (bypassed) closed, L2R {} bytes, R2L {} bytes",
+    let mut plain = CountedStream::new(plain);
+    let result = copy_bidirectional(&mut plain, shadow).await;
+    let (rn, wn) = plain.transferred();
+    match result {
+        Ok(..) => {
+            record_destination_flow(context, target_addr, rn, wn);
+            record_access_log(
+                context,
+                id,
+                peer_addr,
+                target_addr,
+                false,
+                established_at,
+                (rn, wn),
+                false,
+            );
+            log!(
+                closed_level,
+                "tcp tunnel {} {} <-> {} (bypassed) closed, L2R {} bytes, R2L {} bytes",
+                id,
                 peer_addr,
                 target_addr,
                 rn,
@@ -113,8 +402,21 @@ where
             );
         }
         Err(err) => {
-            trace!(
-                "tcp tunnel {} <-> {} (bypassed) closed with error: {}",
+            record_destination_flow(context, target_addr, rn, wn);
+            record_access_log(
+                context,
+                id,
+                peer_addr,
+                target_addr,
+                false,
+                established_at,
+                (rn, wn),
+                true,
+            );
+            log!(
+                closed_level,
+                "tcp tunnel {} {} <-> {} (bypassed) closed with error: {}",
+                id,
                 peer_addr,
                 target_addr,
                 err
@@ -124,3 +426,181 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs,
+        pin::Pin,
+        process,
+        sync::Arc,
+        task::{self, Poll},
+    };
+
+    use shadowsocks::crypto::CipherKind;
+    use tokio::io::{DuplexStream, ReadBuf};
+
+    use super::*;
+    use crate::net::{AccessLog, DestinationFlow, PlaintextCapture};
+
+    /// Proxied remote stream without encryption
+    struct FakeProxiedStream(DuplexStream);
+
+    impl AutoProxyIo for FakeProxiedStream {
+        fn is_proxied(&self) -> bool {
+            true
+        }
+    }
+
+    impl AsyncRead for FakeProxiedStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for FakeProxiedStream {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn tunnel_first_packet_captured() {
+        let path = env::temp_dir().join(format!("shadowsocks-tunnel-capture-test-{}.raw", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut context = ServiceContext::new();
+        let capture = PlaintextCapture::open("www.example.com".to_owned(), &path, 1024).unwrap();
+        context.set_plaintext_capture(Arc::new(capture));
+
+        let svr_cfg = ServerConfig::new(("127.0.0.1", 8388), "", CipherKind::NONE);
+        let target_addr = Address::DomainNameAddress("www.example.com".to_owned(), 80);
+        let peer_addr = "127.0.0.1:50000".parse().unwrap();
+
+        let (mut client, mut plain) = tokio::io::duplex(64);
+        let (mut remote, shadow) = tokio::io::duplex(64);
+        let mut shadow = FakeProxiedStream(shadow);
+
+        // Sent before the tunnel starts, so it is relayed as the first packet
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let tunnel = establish_tcp_tunnel(
+            &context,
+            &svr_cfg,
+            &mut plain,
+            &mut shadow,
+            ConnectionId::from_raw(1),
+            peer_addr,
+            &target_addr,
+        );
+        let remote = async {
+            let mut buf = [0u8; 4];
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            remote.write_all(b"pong").await.unwrap();
+            remote.shutdown().await.unwrap();
+        };
+        let (result, ..) = tokio::join!(tunnel, remote);
+        result.unwrap();
+
+        let expected = "--- 127.0.0.1:50000 -> www.example.com:80 L2R 4 bytes ---\nping\n\
+                        --- 127.0.0.1:50000 -> www.example.com:80 R2L 4 bytes ---\npong\n";
+        let captured = read_written_file(&path, |content| content.len() >= expected.len()).await;
+        let _ = fs::remove_file(&path);
+        assert_eq!(captured, expected);
+    }
+
+    #[tokio::test]
+    async fn tunnel_closed_with_error_access_log() {
+        let path = env::temp_dir().join(format!("shadowsocks-tunnel-access-log-test-{}.log", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut context = ServiceContext::new();
+        context.set_access_log(Arc::new(AccessLog::open(&path).unwrap()));
+
+        let target_addr = Address::DomainNameAddress("www.example.com".to_owned(), 80);
+        let peer_addr = "127.0.0.1:50000".parse().unwrap();
+
+        let (mut client, mut plain) = tokio::io::duplex(64);
+        let (mut remote, mut shadow) = tokio::io::duplex(64);
+
+        let tunnel = establish_tcp_tunnel_bypassed(
+            &context,
+            &mut plain,
+            &mut shadow,
+            ConnectionId::from_raw(1),
+            peer_addr,
+            &target_addr,
+        );
+        let peers = async move {
+            let mut buf = [0u8; 4];
+            client.write_all(b"ping").await.unwrap();
+            remote.read_exact(&mut buf).await.unwrap();
+            remote.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+
+            // Target is gone, the next data couldn't be relayed
+            drop(remote);
+            client.write_all(b"more").await.unwrap();
+            client
+        };
+        let (result, _client) = tokio::join!(tunnel, peers);
+        result.unwrap();
+
+        let logged = read_written_file(&path, |content| content.ends_with('\n')).await;
+        let _ = fs::remove_file(&path);
+        assert!(logged.contains(" 502 4 bypassed 8 "), "{}", logged);
+
+        // Flow of the destination is accounted too
+        assert_eq!(
+            context.destination_flow_stat_ref().top(1),
+            [("www.example.com".to_owned(), DestinationFlow { tx: 8, rx: 4 })]
+        );
+    }
+
+    /// Wait until `done` is satisfied by content of the file that is written in background
+    async fn read_written_file(path: &std::path::Path, done: impl Fn(&str) -> bool) -> String {
+        let mut content = String::new();
+        for _ in 0..100 {
+            content = fs::read_to_string(path).unwrap();
+            if done(&content) {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        content
+    }
+
+    #[test]
+    fn tunnel_log_sampled_once() {
+        let mut context = ServiceContext::new();
+        context.set_log_selected_server(true);
+
+        // Without sampling, servers of all connections are logged
+        let logs = (0..4).map(|_| TunnelLog::sample(&context)).collect::<Vec<_>>();
+        assert!(logs
+            .iter()
+            .all(|log| log.selected_server && log.established_level == Level::Debug));
+
+        // Server and lifecycle of the same connections are logged
+        context.connection_log_sampler().set_rate(2);
+        let logs = (0..4).map(|_| TunnelLog::sample(&context)).collect::<Vec<_>>();
+        assert_eq!(logs.iter().filter(|log| log.selected_server).count(), 2);
+        assert!(logs
+            .iter()
+            .all(|log| log.selected_server == (log.established_level == Level::Info)));
+    }
+}
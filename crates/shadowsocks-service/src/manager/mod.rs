@@ -8,7 +8,7 @@ use log::trace;
 use shadowsocks::net::{AcceptOpts, ConnectOpts};
 
 use crate::{
-    config::{Config, ConfigType},
+    config::{clamp_socket_buffer_size, Config, ConfigType},
     dns::build_dns_resolver,
     server::SERVER_DEFAULT_KEEPALIVE_TIMEOUT,
 };
@@ -46,8 +46,12 @@ pub async fn run(config: Config) -> io::Result<()> {
         ..Default::default()
     };
 
-    connect_opts.tcp.send_buffer_size = config.outbound_send_buffer_size;
-    connect_opts.tcp.recv_buffer_size = config.outbound_recv_buffer_size;
+    connect_opts.tcp.send_buffer_size = config
+        .outbound_send_buffer_size
+        .map(|bs| clamp_socket_buffer_size("outbound_send_buffer_size", bs));
+    connect_opts.tcp.recv_buffer_size = config
+        .outbound_recv_buffer_size
+        .map(|bs| clamp_socket_buffer_size("outbound_recv_buffer_size", bs));
     connect_opts.tcp.nodelay = config.no_delay;
     connect_opts.tcp.fastopen = config.fast_open;
     connect_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
@@ -58,8 +62,12 @@ pub async fn run(config: Config) -> io::Result<()> {
         ipv6_only: config.ipv6_only,
         ..Default::default()
     };
-    accept_opts.tcp.send_buffer_size = config.inbound_send_buffer_size;
-    accept_opts.tcp.recv_buffer_size = config.inbound_recv_buffer_size;
+    accept_opts.tcp.send_buffer_size = config
+        .inbound_send_buffer_size
+        .map(|bs| clamp_socket_buffer_size("inbound_send_buffer_size", bs));
+    accept_opts.tcp.recv_buffer_size = config
+        .inbound_recv_buffer_size
+        .map(|bs| clamp_socket_buffer_size("inbound_recv_buffer_size", bs));
     accept_opts.tcp.nodelay = config.no_delay;
     accept_opts.tcp.fastopen = config.fast_open;
     accept_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
@@ -72,6 +80,8 @@ pub async fn run(config: Config) -> io::Result<()> {
         manager_builder.set_dns_resolver(Arc::new(resolver));
     }
     manager_builder.set_ipv6_first(config.ipv6_first);
+    manager_builder.set_dns_ipv4_only(config.dns_ipv4_only);
+    manager_builder.set_dns_ipv6_only(config.dns_ipv6_only);
 
     manager_builder.set_connect_opts(connect_opts);
     manager_builder.set_accept_opts(accept_opts);
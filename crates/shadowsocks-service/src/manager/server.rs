@@ -84,6 +84,8 @@ pub struct ManagerBuilder {
     udp_capacity: Option<usize>,
     acl: Option<Arc<AccessControl>>,
     ipv6_first: bool,
+    dns_ipv4_only: bool,
+    dns_ipv6_only: bool,
     security: SecurityConfig,
     worker_count: usize,
 }
@@ -105,6 +107,8 @@ impl ManagerBuilder {
             udp_capacity: None,
             acl: None,
             ipv6_first: false,
+            dns_ipv4_only: false,
+            dns_ipv6_only: false,
             security: SecurityConfig::default(),
             worker_count: 1,
         }
@@ -151,6 +155,16 @@ impl ManagerBuilder {
         self.ipv6_first = ipv6_first;
     }
 
+    /// Use only IPv4 addresses of resolved hostnames
+    pub fn set_dns_ipv4_only(&mut self, dns_ipv4_only: bool) {
+        self.dns_ipv4_only = dns_ipv4_only;
+    }
+
+    /// Use only IPv6 addresses of resolved hostnames
+    pub fn set_dns_ipv6_only(&mut self, dns_ipv6_only: bool) {
+        self.dns_ipv6_only = dns_ipv6_only;
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: SecurityConfig) {
         self.security = security;
@@ -177,6 +191,8 @@ impl ManagerBuilder {
             udp_capacity: self.udp_capacity,
             acl: self.acl,
             ipv6_first: self.ipv6_first,
+            dns_ipv4_only: self.dns_ipv4_only,
+            dns_ipv6_only: self.dns_ipv6_only,
             security: self.security,
             worker_count: self.worker_count,
             listener,
@@ -195,6 +211,8 @@ pub struct Manager {
     udp_capacity: Option<usize>,
     acl: Option<Arc<AccessControl>>,
     ipv6_first: bool,
+    dns_ipv4_only: bool,
+    dns_ipv6_only: bool,
     security: SecurityConfig,
     worker_count: usize,
     listener: ManagerListener,
@@ -290,6 +308,8 @@ impl Manager {
         if self.ipv6_first {
             server_builder.set_ipv6_first(self.ipv6_first);
         }
+        server_builder.set_dns_ipv4_only(self.dns_ipv4_only);
+        server_builder.set_dns_ipv6_only(self.dns_ipv6_only);
 
         server_builder.set_security_config(&self.security);
 
@@ -500,27 +520,14 @@ impl Manager {
 
         let mut svr_cfg = ServerConfig::new(addr, req.password.clone(), method);
 
-        if let Some(ref plugin) = req.plugin {
-            let p = PluginConfig {
-                plugin: plugin.clone(),
-                plugin_opts: req.plugin_opts.clone(),
-                plugin_args: Vec::new(),
-                plugin_mode: match req.plugin_mode {
-                    None => Mode::TcpOnly,
-                    Some(ref mode) => match mode.parse::<Mode>() {
-                        Ok(m) => m,
-                        Err(..) => {
-                            error!("unrecognized plugin_mode \"{}\", req: {:?}", mode, req);
-
-                            let err = format!("unrecognized plugin_mode \"{}\"", mode);
-                            return Ok(AddResponse(err));
-                        }
-                    },
-                },
-            };
-            svr_cfg.set_plugin(p);
-        } else if let Some(ref plugin) = self.svr_cfg.plugin {
-            svr_cfg.set_plugin(plugin.clone());
+        match request_plugin_config(req) {
+            Ok(Some(p)) => svr_cfg.set_plugin(p),
+            Ok(None) => {
+                if let Some(ref plugin) = self.svr_cfg.plugin {
+                    svr_cfg.set_plugin(plugin.clone());
+                }
+            }
+            Err(err) => return Ok(AddResponse(err)),
         }
 
         let mode = match req.mode {
@@ -602,17 +609,8 @@ impl Manager {
                 users = Some(vu);
             }
 
-            let sc = protocol::ServerConfig {
-                server_port: svr_cfg.addr().port(),
-                password: svr_cfg.password().to_owned(),
-                method: None,
-                no_delay: None,
-                plugin: None,
-                plugin_opts: None,
-                plugin_mode: None,
-                mode: None,
-                users,
-            };
+            let mut sc = protocol::ServerConfig::new(svr_cfg.addr().port(), svr_cfg.password().to_owned());
+            sc.users = users;
             servers.push(sc);
         }
 
@@ -710,3 +708,63 @@ impl Manager {
         }
     }
 }
+
+/// Plugin of an `add` request, `None` if the request doesn't have one
+///
+/// Returns the error message for the response if the request is invalid.
+fn request_plugin_config(req: &AddRequest) -> Result<Option<PluginConfig>, String> {
+    let plugin = match req.plugin {
+        Some(ref plugin) => plugin,
+        None => return Ok(None),
+    };
+
+    let plugin_mode = match req.plugin_mode {
+        None => Mode::TcpOnly,
+        Some(ref mode) => match mode.parse::<Mode>() {
+            Ok(m) => m,
+            Err(..) => {
+                error!("unrecognized plugin_mode \"{}\", req: {:?}", mode, req);
+                return Err(format!("unrecognized plugin_mode \"{mode}\""));
+            }
+        },
+    };
+
+    Ok(Some(PluginConfig {
+        plugin: plugin.clone(),
+        plugin_opts: req.plugin_opts.clone(),
+        plugin_args: req.plugin_args.clone().unwrap_or_default(),
+        plugin_mode,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use shadowsocks::manager::protocol::ManagerProtocol;
+
+    use super::*;
+
+    #[test]
+    fn add_request_plugin_args() {
+        let req = AddRequest::from_bytes(
+            br#"add: {"server_port":8388,"password":"hello-kitty","plugin":"v2ray-plugin","plugin_opts":"server","plugin_args":["-fast-open","-loglevel","none"]}"#,
+        )
+        .unwrap();
+        let plugin = request_plugin_config(&req).unwrap().unwrap();
+        assert_eq!(plugin.plugin, "v2ray-plugin");
+        assert_eq!(plugin.plugin_opts.as_deref(), Some("server"));
+        assert_eq!(plugin.plugin_args, ["-fast-open", "-loglevel", "none"]);
+        assert!(matches!(plugin.plugin_mode, Mode::TcpOnly));
+
+        let mut req = protocol::ServerConfig::new(8388, "hello-kitty".to_owned());
+        assert!(request_plugin_config(&req).unwrap().is_none());
+
+        req.plugin = Some("v2ray-plugin".to_owned());
+        assert!(request_plugin_config(&req).unwrap().unwrap().plugin_args.is_empty());
+
+        req.plugin_mode = Some("invalid".to_owned());
+        assert_eq!(
+            request_plugin_config(&req).unwrap_err(),
+            "unrecognized plugin_mode \"invalid\""
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Access log of relayed connections in Common Log Format
+//!
+//! Each closed TCP tunnel appends a line:
+//!
+//! ```plain
+//! 127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "CONNECT www.example.com:443 TCP" 200 2326 proxied 512 1.024 #7
+//! ```
+//!
+//! Fields after the request line are: status (`200` for clean close, `502` for closed with error), bytes sent to
+//! the client, `proxied` or `bypassed`, bytes received from the client, seconds that the tunnel has lasted and the
+//! connection id, which matches the one in debug log lines.
+//!
+//! Lines are written in order by a background thread, so they never interleave.
+
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use shadowsocks::relay::socks5::Address;
+
+use super::{connection_id::ConnectionId, file_writer::BackgroundFileWriter, utils::to_ipv4_mapped};
+
+/// A closed TCP tunnel
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry<'a> {
+    /// Id of the client's connection
+    pub id: ConnectionId,
+    /// Client's address
+    pub peer_addr: SocketAddr,
+    /// Target address that the client requested
+    pub target_addr: &'a Address,
+    /// Whether the tunnel is relayed through a remote server
+    pub proxied: bool,
+    /// Bytes sent from the client to the target
+    pub tx: u64,
+    /// Bytes sent from the target to the client
+    pub rx: u64,
+    /// Duration since the tunnel was established
+    pub duration: Duration,
+    /// Whether the tunnel was closed with error
+    pub errored: bool,
+}
+
+/// Access log file, separated from the debug log
+#[derive(Debug)]
+pub struct AccessLog {
+    path: PathBuf,
+    writer: BackgroundFileWriter,
+}
+
+impl AccessLog {
+    /// Open (or create) the access log file on `path`, records are appended
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<AccessLog> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(AccessLog {
+            writer: BackgroundFileWriter::new(file, path.clone()),
+            path,
+        })
+    }
+
+    /// Path of the access log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a record of `entry`
+    pub fn record(&self, entry: &AccessLogEntry<'_>) {
+        let line = format_entry(entry, SystemTime::now());
+        self.writer.write(line.into_bytes());
+    }
+}
+
+fn format_entry(entry: &AccessLogEntry<'_>, now: SystemTime) -> String {
+    let peer_ip = match entry.peer_addr {
+        SocketAddr::V6(ref v6) => match to_ipv4_mapped(v6.ip()) {
+            Some(v4) => v4.to_string(),
+            None => v6.ip().to_string(),
+        },
+        SocketAddr::V4(ref v4) => v4.ip().to_string(),
+    };
+
+    let mut line = String::new();
+    let _ = writeln!(
+        line,
+        "{} - - [{}] \"CONNECT {} TCP\" {} {} {} {} {}.{:03} {}",
+        peer_ip,
+        format_clf_time(now),
+        entry.target_addr,
+        if entry.errored { 502 } else { 200 },
+        entry.rx,
+        if entry.proxied { "proxied" } else { "bypassed" },
+        entry.tx,
+        entry.duration.as_secs(),
+        entry.duration.subsec_millis(),
+        entry.id,
+    );
+    line
+}
+
+/// Format `time` as `10/Oct/2000:13:55:36 +0000` in UTC
+fn format_clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01, by Howard Hinnant's algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn access_log_common_log_format() {
+        let target_addr = Address::DomainNameAddress("www.example.com".to_owned(), 443);
+        let entry = AccessLogEntry {
+            id: ConnectionId::from_raw(7),
+            peer_addr: "[::ffff:127.0.0.1]:50000".parse().unwrap(),
+            target_addr: &target_addr,
+            proxied: true,
+            tx: 512,
+            rx: 2326,
+            duration: Duration::from_millis(1024),
+            errored: false,
+        };
+
+        let now = UNIX_EPOCH + Duration::from_secs(971186136);
+        assert_eq!(
+            format_entry(&entry, now),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"CONNECT www.example.com:443 TCP\" 200 2326 proxied 512 1.024 #7\n"
+        );
+
+        assert_eq!(
+            format_clf_time(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "29/Feb/2000:00:00:00 +0000"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Plaintext capture of relayed TCP streams, for debugging application protocols through the proxy
+//!
+//! Captured data are DECRYPTED bytes, so it must be enabled explicitly and only for one target. Each chunk is
+//! appended to the raw capture file after a header line:
+//!
+//! ```plain
+//! --- 127.0.0.1:50000 -> www.example.com:80 L2R 78 bytes ---
+//! GET / HTTP/1.1
+//! ...
+//! ```
+//!
+//! `L2R` is sent by the client, `R2L` is received from the target. Capturing stops after the size limit is reached.
+//!
+//! The capture file is only readable by its owner on Unix, records are written by a background thread.
+
+use std::{
+    fs::OpenOptions,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{self, Poll},
+};
+
+use log::info;
+use shadowsocks::relay::socks5::Address;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::file_writer::BackgroundFileWriter;
+
+/// Default limit of captured bytes
+pub const DEFAULT_CAPTURE_MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Plaintext capture file of streams to one target
+#[derive(Debug)]
+pub struct PlaintextCapture {
+    target: String,
+    path: PathBuf,
+    writer: BackgroundFileWriter,
+    max_size: u64,
+    captured: AtomicU64,
+}
+
+impl PlaintextCapture {
+    /// Open (or create) the capture file on `path` for streams to `target`, records are appended
+    ///
+    /// `target` is `host:port`, or `host` for matching all ports. Capturing stops after `max_size` bytes.
+    pub fn open<P: Into<PathBuf>>(target: String, path: P, max_size: u64) -> io::Result<PlaintextCapture> {
+        let path = path.into();
+
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            // Decrypted traffic shouldn't be readable by other users
+            options.mode(0o600);
+        }
+        let file = options.open(&path)?;
+
+        Ok(PlaintextCapture {
+            target,
+            writer: BackgroundFileWriter::new(file, path.clone()),
+            path,
+            max_size,
+            captured: AtomicU64::new(0),
+        })
+    }
+
+    /// Path of the capture file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Check if streams to `target_addr` should be captured
+    pub fn matches(&self, target_addr: &Address) -> bool {
+        let host = match *target_addr {
+            Address::SocketAddress(ref saddr) => saddr.ip().to_string(),
+            Address::DomainNameAddress(ref host, ..) => host.clone(),
+        };
+        self.target == host || self.target == target_addr.to_string()
+    }
+
+    fn record(&self, peer_addr: SocketAddr, target_addr: &Address, direction: &str, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let captured = self.captured.fetch_add(data.len() as u64, Ordering::AcqRel);
+        if captured >= self.max_size {
+            return;
+        }
+        let remaining = (self.max_size - captured).min(data.len() as u64) as usize;
+        let data = &data[..remaining];
+
+        let mut record = format!(
+            "--- {} -> {} {} {} bytes ---\n",
+            peer_addr,
+            target_addr,
+            direction,
+            data.len()
+        )
+        .into_bytes();
+        record.extend_from_slice(data);
+        record.push(b'\n');
+
+        self.writer.write(record);
+
+        if captured + remaining as u64 >= self.max_size {
+            info!(
+                "plaintext capture {} reached its limit {} bytes, capturing stopped",
+                self.path.display(),
+                self.max_size
+            );
+        }
+    }
+}
+
+/// Stream of a client that copies relayed bytes into a `PlaintextCapture`
+pub struct CaptureStream<'a, S> {
+    stream: S,
+    capture: &'a PlaintextCapture,
+    peer_addr: SocketAddr,
+    target_addr: &'a Address,
+}
+
+impl<'a, S> CaptureStream<'a, S> {
+    /// Capture bytes read from and written to the client `stream`
+    pub fn new(
+        stream: S,
+        capture: &'a PlaintextCapture,
+        peer_addr: SocketAddr,
+        target_addr: &'a Address,
+    ) -> CaptureStream<'a, S> {
+        CaptureStream {
+            stream,
+            capture,
+            peer_addr,
+            target_addr,
+        }
+    }
+}
+
+impl<S> AsyncRead for CaptureStream<'_, S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.capture
+                .record(self.peer_addr, self.target_addr, "L2R", &buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+impl<S> AsyncWrite for CaptureStream<'_, S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.capture.record(self.peer_addr, self.target_addr, "R2L", &buf[..n]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{env, fs, process, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time,
+    };
+
+    use super::*;
+
+    /// Wait until the background writer has written `expected_len` bytes
+    async fn read_capture(path: &Path, expected_len: usize) -> String {
+        for _ in 0..100 {
+            let captured = fs::read_to_string(path).unwrap();
+            if captured.len() >= expected_len {
+                return captured;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn plaintext_capture_limited() {
+        let path = env::temp_dir().join(format!("shadowsocks-capture-test-{}.raw", process::id()));
+        let _ = fs::remove_file(&path);
+        let capture = PlaintextCapture::open("www.example.com".to_owned(), &path, 6).unwrap();
+
+        let target_addr = Address::DomainNameAddress("www.example.com".to_owned(), 80);
+        assert!(capture.matches(&target_addr));
+        assert!(!capture.matches(&Address::DomainNameAddress("example.com".to_owned(), 80)));
+
+        let peer_addr = "127.0.0.1:50000".parse().unwrap();
+        let (client, mut remote) = tokio::io::duplex(64);
+        let mut stream = CaptureStream::new(client, &capture, peer_addr, &target_addr);
+
+        remote.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(b"pong").await.unwrap();
+
+        let expected = "--- 127.0.0.1:50000 -> www.example.com:80 L2R 4 bytes ---\nping\n\
+                        --- 127.0.0.1:50000 -> www.example.com:80 R2L 2 bytes ---\npo\n";
+        let captured = read_capture(&path, expected.len()).await;
+        let _ = fs::remove_file(&path);
+        assert_eq!(captured, expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn plaintext_capture_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("shadowsocks-capture-mode-test-{}.raw", process::id()));
+        let _ = fs::remove_file(&path);
+        let _capture = PlaintextCapture::open("www.example.com".to_owned(), &path, 6).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let _ = fs::remove_file(&path);
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
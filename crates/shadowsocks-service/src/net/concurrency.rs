@@ -0,0 +1,236 @@
+//! Adjustable concurrency limit for relay tasks
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::Notify;
+
+#[derive(Debug)]
+struct ConcurrencyLimitInner {
+    // 0 for unlimited
+    limit: AtomicUsize,
+    active: AtomicUsize,
+    paused: AtomicBool,
+    notify: Notify,
+}
+
+/// Concurrency limit of relay tasks
+///
+/// Tokio's worker count is fixed when runtime is built, so the effective parallelism is controlled here
+/// by limiting how many connections could be accepted and relayed at the same time. The limit
+/// could be changed at runtime, accept loops will be parked if the limit is reached, and unparked when
+/// permits are released or the limit is raised.
+///
+/// Accepting could also be paused without unbinding listeners, see `pause`.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    inner: Arc<ConcurrencyLimitInner>,
+}
+
+impl Default for ConcurrencyLimit {
+    fn default() -> Self {
+        ConcurrencyLimit::new(None)
+    }
+}
+
+impl ConcurrencyLimit {
+    /// Create a new limit, `None` (or `Some(0)`) for unlimited
+    pub fn new(limit: Option<usize>) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            inner: Arc::new(ConcurrencyLimitInner {
+                limit: AtomicUsize::new(limit.unwrap_or(0)),
+                active: AtomicUsize::new(0),
+                paused: AtomicBool::new(false),
+                notify: Notify::new(),
+            }),
+        }
+    }
+
+    /// Current limit, `None` for unlimited
+    pub fn limit(&self) -> Option<usize> {
+        match self.inner.limit.load(Ordering::Acquire) {
+            0 => None,
+            n => Some(n),
+        }
+    }
+
+    /// Change the limit, `None` (or `Some(0)`) for unlimited
+    ///
+    /// Lowering the limit won't interrupt the running tasks, new tasks will wait until enough permits are released.
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.inner.limit.store(limit.unwrap_or(0), Ordering::Release);
+        self.inner.notify.notify_waiters();
+    }
+
+    /// Number of permits that are currently held
+    pub fn active(&self) -> usize {
+        self.inner.active.load(Ordering::Acquire)
+    }
+
+    /// Pause accepting new connections, for example, when a mobile app enters the background
+    ///
+    /// Listeners are kept, connections that arrive while paused wait in the listen backlog until resumed,
+    /// or are timed out by clients. Connections that are already established keep relaying.
+    pub fn pause(&self) {
+        self.inner.paused.store(true, Ordering::Release);
+    }
+
+    /// Resume accepting new connections
+    pub fn resume(&self) {
+        self.inner.paused.store(false, Ordering::Release);
+        self.inner.notify.notify_waiters();
+    }
+
+    /// Check if accepting is paused
+    pub fn is_paused(&self) -> bool {
+        self.inner.paused.load(Ordering::Acquire)
+    }
+
+    /// Try to acquire a permit without waiting, fails if the limit is reached or accepting is paused
+    pub fn try_acquire(&self) -> Option<ConcurrencyPermit> {
+        if self.is_paused() {
+            return None;
+        }
+
+        let mut active = self.inner.active.load(Ordering::Acquire);
+        loop {
+            let limit = self.inner.limit.load(Ordering::Acquire);
+            if limit != 0 && active >= limit {
+                return None;
+            }
+
+            match self
+                .inner
+                .active
+                .compare_exchange_weak(active, active + 1, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(..) => {
+                    return Some(ConcurrencyPermit {
+                        inner: self.inner.clone(),
+                    })
+                }
+                Err(n) => active = n,
+            }
+        }
+    }
+
+    /// Acquire a permit, waits if the limit is reached
+    pub async fn acquire(&self) -> ConcurrencyPermit {
+        loop {
+            let notified = self.inner.notify.notified();
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            notified.await;
+        }
+    }
+
+    /// Wait until all permits are released, for draining connections before exiting
+    ///
+    /// It won't stop new permits from being acquired, `pause` first or stop the accept loops.
+    pub async fn wait_idle(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if self.active() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Permit acquired from `ConcurrencyLimit`, released when dropped
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    inner: Arc<ConcurrencyLimitInner>,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        let active = self.inner.active.fetch_sub(1, Ordering::AcqRel);
+        self.inner.notify.notify_one();
+        if active == 1 {
+            // Wake up all `wait_idle`s, the single permit above may be taken by an accept loop
+            self.inner.notify.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrency_limit_resize() {
+        let limit = ConcurrencyLimit::new(Some(1));
+
+        let p1 = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        limit.set_limit(Some(2));
+        let p2 = limit.try_acquire().unwrap();
+        assert_eq!(limit.active(), 2);
+
+        limit.set_limit(Some(1));
+        drop(p1);
+        assert!(limit.try_acquire().is_none());
+        drop(p2);
+
+        let waiter = {
+            let limit = limit.clone();
+            let _p = limit.acquire().await;
+            tokio::spawn(async move { limit.acquire().await })
+        };
+        let _p = waiter.await.unwrap();
+        assert_eq!(limit.active(), 1);
+
+        limit.set_limit(None);
+        let _ps = (0..16).map(|_| limit.try_acquire().unwrap()).collect::<Vec<_>>();
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_pause_resume() {
+        let limit = ConcurrencyLimit::new(None);
+        let held = limit.try_acquire().unwrap();
+
+        limit.pause();
+        assert!(limit.try_acquire().is_none());
+
+        let waiter = {
+            let limit = limit.clone();
+            tokio::spawn(async move { limit.acquire().await })
+        };
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        // Permits acquired before pausing are kept
+        assert_eq!(limit.active(), 1);
+
+        limit.resume();
+        let _p = waiter.await.unwrap();
+        assert_eq!(limit.active(), 2);
+        drop(held);
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_wait_idle() {
+        let limit = ConcurrencyLimit::new(None);
+        limit.wait_idle().await;
+
+        let held = limit.try_acquire().unwrap();
+        let waiter = {
+            let limit = limit.clone();
+            tokio::spawn(async move { limit.wait_idle().await })
+        };
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+
+        drop(held);
+        tokio::time::timeout(std::time::Duration::from_secs(1), waiter)
+            .await
+            .expect("wait_idle after the last permit released")
+            .unwrap();
+    }
+}
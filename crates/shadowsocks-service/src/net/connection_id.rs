@@ -0,0 +1,57 @@
+//! Identifiers of accepted connections
+//!
+//! Every accepted TCP connection is assigned a [`ConnectionId`] from a process-wide monotonic counter, which is
+//! shown as `#<id>` in all log lines and access log records of that connection.
+//!
+//! Ids start from 1 when the process starts and are never persisted, so they restart after the process restarts and
+//! are only meaningful within one run. Reloading configuration or recreating servers in the same process doesn't
+//! reset the counter.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifier of an accepted connection, unique in the current process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    /// Allocate the next id, it should be called once per accepted connection
+    #[inline]
+    pub fn next() -> ConnectionId {
+        ConnectionId(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Numeric value of the id
+    #[inline]
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_raw(id: u64) -> ConnectionId {
+        ConnectionId(id)
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connection_id_monotonic() {
+        let a = ConnectionId::next();
+        let b = ConnectionId::next();
+        assert!(b > a);
+        assert_eq!(format!("{}", ConnectionId::from_raw(42)), "#42");
+    }
+}
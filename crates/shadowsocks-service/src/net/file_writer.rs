@@ -0,0 +1,47 @@
+//! Append-only files written by a background thread
+//!
+//! Records are produced inside runtime workers, which must not be blocked by disk I/O.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use log::warn;
+
+/// Writes records into a file on a dedicated thread, in the order that they are sent
+///
+/// The thread exits after the writer is dropped and all pending records are written.
+#[derive(Debug)]
+pub(crate) struct BackgroundFileWriter {
+    sender: Sender<Vec<u8>>,
+}
+
+impl BackgroundFileWriter {
+    /// Start writing records into `file`, which is opened from `path`
+    pub fn new(mut file: File, path: PathBuf) -> BackgroundFileWriter {
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+
+        thread::Builder::new()
+            .name(format!("file-writer-{}", path.display()))
+            .spawn(move || {
+                for record in receiver {
+                    if let Err(err) = file.write_all(&record) {
+                        warn!("failed to write {}, error: {}", path.display(), err);
+                    }
+                }
+            })
+            .expect("failed to spawn file writer thread");
+
+        BackgroundFileWriter { sender }
+    }
+
+    /// Queue `record` to be appended to the file
+    pub fn write(&self, record: Vec<u8>) {
+        // Receiver lives as long as the thread, which never exits before the sender is dropped
+        let _ = self.sender.send(record);
+    }
+}
@@ -1,6 +1,18 @@
 //! Server flow statistic
+//!
+//! `FlowStat::tx` and `FlowStat::rx` are bytes on the wire of both transports. Each transport also has a
+//! `TransportFlowStat` for measuring protocol overhead:
+//!
+//! - wire bytes are counted on the encrypted side, including salts, AEAD tags, length chunks and the target address
+//!   header, but not TCP/IP or UDP headers
+//! - plaintext bytes are payloads relayed to and from the plain side (clients of sslocal, targets of ssserver)
+//!
+//! `tx` is sent on the wire by this instance and `rx` is received from the wire, plaintext counters follow the same
+//! direction. Plaintext of DNS queries relayed by sslocal's DNS server through TCP isn't counted.
 
-use std::sync::atomic::Ordering;
+use std::sync::{atomic::Ordering, Mutex};
+
+use lru_time_cache::LruCache;
 
 #[cfg(target_has_atomic = "64")]
 type FlowCounter = std::sync::atomic::AtomicU64;
@@ -8,18 +20,12 @@ type FlowCounter = std::sync::atomic::AtomicU64;
 type FlowCounter = std::sync::atomic::AtomicU32;
 
 /// Connection flow statistic
+#[derive(Default)]
 pub struct FlowStat {
     tx: FlowCounter,
     rx: FlowCounter,
-}
-
-impl Default for FlowStat {
-    fn default() -> Self {
-        FlowStat {
-            tx: FlowCounter::new(0),
-            rx: FlowCounter::new(0),
-        }
-    }
+    tcp: TransportFlowStat,
+    udp: TransportFlowStat,
 }
 
 impl FlowStat {
@@ -47,4 +53,233 @@ impl FlowStat {
     pub fn incr_rx(&self, n: u64) {
         self.rx.fetch_add(n as _, Ordering::AcqRel);
     }
+
+    /// Wire and plaintext bytes of TCP
+    pub fn tcp(&self) -> &TransportFlowStat {
+        &self.tcp
+    }
+
+    /// Wire and plaintext bytes of UDP
+    pub fn udp(&self) -> &TransportFlowStat {
+        &self.udp
+    }
+
+    /// Reset all counters to 0
+    pub fn reset(&self) {
+        self.tx.store(0, Ordering::Release);
+        self.rx.store(0, Ordering::Release);
+        self.tcp.reset();
+        self.udp.reset();
+    }
+}
+
+/// Wire and plaintext bytes of one transport
+#[derive(Default)]
+pub struct TransportFlowStat {
+    wire_tx: FlowCounter,
+    wire_rx: FlowCounter,
+    plain_tx: FlowCounter,
+    plain_rx: FlowCounter,
+}
+
+impl TransportFlowStat {
+    /// Increase bytes sent on the wire
+    pub fn incr_wire_tx(&self, n: u64) {
+        self.wire_tx.fetch_add(n as _, Ordering::AcqRel);
+    }
+
+    /// Increase bytes received from the wire
+    pub fn incr_wire_rx(&self, n: u64) {
+        self.wire_rx.fetch_add(n as _, Ordering::AcqRel);
+    }
+
+    /// Increase plaintext bytes that are sent after encrypted
+    pub fn incr_plain_tx(&self, n: u64) {
+        self.plain_tx.fetch_add(n as _, Ordering::AcqRel);
+    }
+
+    /// Increase plaintext bytes that are received and decrypted
+    pub fn incr_plain_rx(&self, n: u64) {
+        self.plain_rx.fetch_add(n as _, Ordering::AcqRel);
+    }
+
+    /// Current values of all counters
+    pub fn snapshot(&self) -> TransportFlow {
+        TransportFlow {
+            wire_tx: self.wire_tx.load(Ordering::Relaxed) as _,
+            wire_rx: self.wire_rx.load(Ordering::Relaxed) as _,
+            plain_tx: self.plain_tx.load(Ordering::Relaxed) as _,
+            plain_rx: self.plain_rx.load(Ordering::Relaxed) as _,
+        }
+    }
+
+    fn reset(&self) {
+        self.wire_tx.store(0, Ordering::Release);
+        self.wire_rx.store(0, Ordering::Release);
+        self.plain_tx.store(0, Ordering::Release);
+        self.plain_rx.store(0, Ordering::Release);
+    }
+}
+
+/// Snapshot of `TransportFlowStat`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransportFlow {
+    /// Bytes sent on the wire
+    pub wire_tx: u64,
+    /// Bytes received from the wire
+    pub wire_rx: u64,
+    /// Plaintext bytes that are sent after encrypted
+    pub plain_tx: u64,
+    /// Plaintext bytes that are received and decrypted
+    pub plain_rx: u64,
+}
+
+impl TransportFlow {
+    /// Wire bytes per plaintext byte of both directions, `None` if no plaintext has been relayed
+    pub fn overhead_ratio(&self) -> Option<f64> {
+        let plain = self.plain_tx.saturating_add(self.plain_rx);
+        if plain == 0 {
+            return None;
+        }
+        Some(self.wire_tx.saturating_add(self.wire_rx) as f64 / plain as f64)
+    }
+}
+
+/// Default number of destinations tracked by `DestinationFlowStat`
+pub const DEFAULT_DESTINATION_FLOW_STAT_CAPACITY: usize = 128;
+
+/// Flow statistic of a destination host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DestinationFlow {
+    /// Bytes sent to the destination
+    pub tx: u64,
+    /// Bytes received from the destination
+    pub rx: u64,
+}
+
+impl DestinationFlow {
+    /// Total bytes of both directions
+    pub fn total(&self) -> u64 {
+        self.tx.saturating_add(self.rx)
+    }
+}
+
+/// Flow statistic of recently used destination hosts, the heaviest ones are listed by `top`
+///
+/// At most `capacity` hosts are tracked. When it is full, the least recently used host is evicted for the new one,
+/// so hosts that keep transferring stay in the table while the ones that are no longer used are swapped out.
+pub struct DestinationFlowStat {
+    capacity: usize,
+    hosts: Mutex<LruCache<String, DestinationFlow>>,
+}
+
+impl Default for DestinationFlowStat {
+    fn default() -> Self {
+        DestinationFlowStat::new(DEFAULT_DESTINATION_FLOW_STAT_CAPACITY)
+    }
+}
+
+impl DestinationFlowStat {
+    /// Create an empty statistic that tracks at most `capacity` hosts
+    pub fn new(capacity: usize) -> DestinationFlowStat {
+        DestinationFlowStat {
+            capacity,
+            hosts: Mutex::new(LruCache::with_capacity(capacity)),
+        }
+    }
+
+    /// Add bytes transferred with `host`
+    pub fn record(&self, host: &str, tx: u64, rx: u64) {
+        if self.capacity == 0 || (tx == 0 && rx == 0) {
+            return;
+        }
+
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(flow) = hosts.get_mut(host) {
+            flow.tx = flow.tx.saturating_add(tx);
+            flow.rx = flow.rx.saturating_add(rx);
+            return;
+        }
+        hosts.insert(host.to_owned(), DestinationFlow { tx, rx });
+    }
+
+    /// Get at most `n` hosts, ordered by total bytes in descending order
+    pub fn top(&self, n: usize) -> Vec<(String, DestinationFlow)> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut top = hosts
+            .peek_iter()
+            .map(|(host, flow)| (host.clone(), *flow))
+            .collect::<Vec<_>>();
+        drop(hosts);
+
+        top.sort_unstable_by(|a, b| b.1.total().cmp(&a.1.total()).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    /// Clear all tracked hosts
+    pub fn reset(&self) {
+        self.hosts.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transport_flow_overhead_ratio() {
+        let stat = FlowStat::new();
+        assert_eq!(stat.tcp().snapshot().overhead_ratio(), None);
+
+        stat.tcp().incr_plain_tx(100);
+        stat.tcp().incr_wire_tx(134);
+        stat.tcp().incr_plain_rx(100);
+        stat.tcp().incr_wire_rx(116);
+        let flow = stat.tcp().snapshot();
+        assert_eq!(flow.overhead_ratio(), Some(1.25));
+        assert_eq!(stat.udp().snapshot(), TransportFlow::default());
+
+        stat.reset();
+        assert_eq!(stat.tcp().snapshot(), TransportFlow::default());
+    }
+
+    #[test]
+    fn destination_flow_stat_bounded_top() {
+        let stat = DestinationFlowStat::new(2);
+        stat.record("a.example", 100, 100);
+        stat.record("b.example", 10, 0);
+        stat.record("a.example", 0, 50);
+
+        // b.example is the least recently used one
+        stat.record("c.example", 20, 20);
+
+        let top = stat.top(10);
+        assert_eq!(
+            top,
+            vec![
+                ("a.example".to_owned(), DestinationFlow { tx: 100, rx: 150 }),
+                ("c.example".to_owned(), DestinationFlow { tx: 20, rx: 20 }),
+            ]
+        );
+        assert_eq!(stat.top(1).len(), 1);
+
+        stat.reset();
+        assert!(stat.top(10).is_empty());
+    }
+
+    #[test]
+    fn destination_flow_stat_new_hosts_stay() {
+        let stat = DestinationFlowStat::new(2);
+        stat.record("heavy.example", 1000, 1000);
+        stat.record("a.example", 1, 0);
+
+        // A new host isn't evicted by the next one only because it is still light
+        stat.record("b.example", 1, 0);
+        stat.record("a.example", 1, 0);
+        stat.record("c.example", 1, 0);
+
+        let hosts = stat.top(10).into_iter().map(|(host, _)| host).collect::<Vec<_>>();
+        assert_eq!(hosts, ["a.example", "c.example"]);
+    }
 }
@@ -0,0 +1,107 @@
+//! Liveness file that is touched periodically while services are running
+
+use std::{
+    fs,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{trace, warn};
+use tokio::time;
+
+/// Default interval between each touch
+pub const DEFAULT_LIVENESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Liveness file for container orchestration
+///
+/// The file is rewritten with the current UNIX timestamp every `interval`, so a sidecar could detect hangs by
+/// checking its mtime. Touching stops once the watched services exit, and the file is removed when it is dropped.
+#[derive(Debug, Clone)]
+pub struct LivenessFile {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl LivenessFile {
+    /// Create a liveness file on `path`, touched every `interval`
+    pub fn new<P: Into<PathBuf>>(path: P, interval: Duration) -> LivenessFile {
+        LivenessFile {
+            path: path.into(),
+            interval,
+        }
+    }
+
+    /// Path of the liveness file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Interval between each touch
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Run `fut`, and touch the liveness file until it finishes
+    pub async fn watch<F: Future>(&self, fut: F) -> F::Output {
+        let _guard = LivenessFileGuard { path: &self.path };
+
+        tokio::pin!(fut);
+
+        let mut interval = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                biased;
+
+                output = &mut fut => return output,
+                _ = interval.tick() => {
+                    if let Err(err) = self.touch().await {
+                        warn!("failed to touch liveness file {}, error: {}", self.path.display(), err);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn touch(&self) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        // Writing may block on slow or network filesystems, which shouldn't stall the runtime's workers
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || fs::write(path, now.as_secs().to_string())).await??;
+        trace!("touched liveness file {}", self.path.display());
+        Ok(())
+    }
+}
+
+struct LivenessFileGuard<'a> {
+    path: &'a Path,
+}
+
+impl Drop for LivenessFileGuard<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.path);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn liveness_file_touch_and_cleanup() {
+        let path = std::env::temp_dir().join(format!("ss-liveness-test-{}", std::process::id()));
+        let liveness = LivenessFile::new(&path, Duration::from_millis(10));
+
+        let output = liveness
+            .watch(async {
+                time::sleep(Duration::from_millis(50)).await;
+                assert!(path.exists());
+                42
+            })
+            .await;
+
+        assert_eq!(output, 42);
+        assert!(!path.exists());
+    }
+}
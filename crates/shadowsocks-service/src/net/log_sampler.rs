@@ -0,0 +1,60 @@
+//! Sampling for connection lifecycle logs
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Choose 1-in-N connections for logging their lifecycle
+///
+/// It is counter based, which is cheap enough to be called for every connection.
+#[derive(Debug, Default)]
+pub struct ConnectionLogSampler {
+    // 0 for disabled
+    rate: AtomicU32,
+    counter: AtomicU64,
+}
+
+impl ConnectionLogSampler {
+    /// Create a sampler that samples 1-in-`rate` connections, 0 for disabled
+    pub fn new(rate: u32) -> ConnectionLogSampler {
+        ConnectionLogSampler {
+            rate: AtomicU32::new(rate),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Set sample rate, log 1-in-`rate` connections, 0 for disabled
+    pub fn set_rate(&self, rate: u32) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// Get sample rate
+    pub fn rate(&self) -> u32 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Check if the next connection should be logged
+    pub fn sample(&self) -> bool {
+        match self.rate.load(Ordering::Relaxed) {
+            0 => false,
+            1 => true,
+            rate => self.counter.fetch_add(1, Ordering::Relaxed) % u64::from(rate) == 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connection_log_sampler_rate() {
+        let sampler = ConnectionLogSampler::default();
+        assert!(!(0..10).any(|_| sampler.sample()));
+
+        sampler.set_rate(3);
+        let sampled = (0..9).filter(|_| sampler.sample()).count();
+        assert_eq!(sampled, 3);
+
+        sampler.set_rate(1);
+        assert!((0..10).all(|_| sampler.sample()));
+    }
+}
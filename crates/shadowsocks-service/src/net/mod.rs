@@ -1,13 +1,32 @@
 //! Shadowsocks Service Network Utilities
 
-pub use self::{flow::FlowStat, mon_socket::MonProxySocket, mon_stream::MonProxyStream};
+pub use self::{
+    access_log::{AccessLog, AccessLogEntry},
+    capture::{CaptureStream, PlaintextCapture},
+    concurrency::{ConcurrencyLimit, ConcurrencyPermit},
+    connection_id::ConnectionId,
+    flow::{DestinationFlow, DestinationFlowStat, FlowStat, TransportFlow, TransportFlowStat},
+    liveness::LivenessFile,
+    log_sampler::ConnectionLogSampler,
+    mon_socket::MonProxySocket,
+    mon_stream::{MonPlainStream, MonProxyStream},
+    retry_budget::RetryBudget,
+};
 
+pub mod access_log;
+pub mod capture;
+pub mod concurrency;
+pub mod connection_id;
+mod file_writer;
 pub mod flow;
 #[cfg(target_os = "macos")]
 pub mod launch_activate_socket;
+pub mod liveness;
+pub mod log_sampler;
 pub mod mon_socket;
 pub mod mon_stream;
 pub mod packet_window;
+pub mod retry_budget;
 pub mod utils;
 
 /// Packet size for all UDP associations' send queue
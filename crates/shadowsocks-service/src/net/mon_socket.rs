@@ -27,6 +27,8 @@ impl MonProxySocket {
     pub async fn send(&self, addr: &Address, payload: &[u8]) -> io::Result<()> {
         let n = self.socket.send(addr, payload).await?;
         self.flow_stat.incr_tx(n as u64);
+        self.flow_stat.udp().incr_wire_tx(n as u64);
+        self.flow_stat.udp().incr_plain_tx(payload.len() as u64);
 
         Ok(())
     }
@@ -41,6 +43,8 @@ impl MonProxySocket {
     ) -> io::Result<()> {
         let n = self.socket.send_with_ctrl(addr, control, payload).await?;
         self.flow_stat.incr_tx(n as u64);
+        self.flow_stat.udp().incr_wire_tx(n as u64);
+        self.flow_stat.udp().incr_plain_tx(payload.len() as u64);
 
         Ok(())
     }
@@ -50,6 +54,8 @@ impl MonProxySocket {
     pub async fn send_to<A: ToSocketAddrs>(&self, target: A, addr: &Address, payload: &[u8]) -> io::Result<()> {
         let n = self.socket.send_to(target, addr, payload).await?;
         self.flow_stat.incr_tx(n as u64);
+        self.flow_stat.udp().incr_wire_tx(n as u64);
+        self.flow_stat.udp().incr_plain_tx(payload.len() as u64);
 
         Ok(())
     }
@@ -65,6 +71,8 @@ impl MonProxySocket {
     ) -> io::Result<()> {
         let n = self.socket.send_to_with_ctrl(target, addr, control, payload).await?;
         self.flow_stat.incr_tx(n as u64);
+        self.flow_stat.udp().incr_wire_tx(n as u64);
+        self.flow_stat.udp().incr_plain_tx(payload.len() as u64);
 
         Ok(())
     }
@@ -78,6 +86,8 @@ impl MonProxySocket {
     pub async fn recv(&self, recv_buf: &mut [u8]) -> io::Result<(usize, Address)> {
         let (n, addr, recv_n) = self.socket.recv(recv_buf).await?;
         self.flow_stat.incr_rx(recv_n as u64);
+        self.flow_stat.udp().incr_wire_rx(recv_n as u64);
+        self.flow_stat.udp().incr_plain_rx(n as u64);
 
         Ok((n, addr))
     }
@@ -94,6 +104,8 @@ impl MonProxySocket {
     ) -> io::Result<(usize, Address, Option<UdpSocketControlData>)> {
         let (n, addr, recv_n, control) = self.socket.recv_with_ctrl(recv_buf).await?;
         self.flow_stat.incr_rx(recv_n as u64);
+        self.flow_stat.udp().incr_wire_rx(recv_n as u64);
+        self.flow_stat.udp().incr_plain_rx(n as u64);
 
         Ok((n, addr, control))
     }
@@ -107,6 +119,8 @@ impl MonProxySocket {
     pub async fn recv_from(&self, recv_buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Address)> {
         let (n, peer_addr, addr, recv_n) = self.socket.recv_from(recv_buf).await?;
         self.flow_stat.incr_rx(recv_n as u64);
+        self.flow_stat.udp().incr_wire_rx(recv_n as u64);
+        self.flow_stat.udp().incr_plain_rx(n as u64);
 
         Ok((n, peer_addr, addr))
     }
@@ -123,6 +137,8 @@ impl MonProxySocket {
     ) -> io::Result<(usize, SocketAddr, Address, Option<UdpSocketControlData>)> {
         let (n, peer_addr, addr, recv_n, control) = self.socket.recv_from_with_ctrl(recv_buf).await?;
         self.flow_stat.incr_rx(recv_n as u64);
+        self.flow_stat.udp().incr_wire_rx(recv_n as u64);
+        self.flow_stat.udp().incr_plain_rx(n as u64);
 
         Ok((n, peer_addr, addr, control))
     }
@@ -12,7 +12,7 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use super::flow::FlowStat;
 
-/// Monitored `ProxyStream`
+/// Monitored `ProxyStream`, counting bytes on the wire
 #[pin_project]
 pub struct MonProxyStream<S> {
     #[pin]
@@ -54,6 +54,7 @@ where
             Poll::Ready(Ok(())) => {
                 let n = buf.filled().len();
                 this.flow_stat.incr_rx(n as u64);
+                this.flow_stat.tcp().incr_wire_rx(n as u64);
                 Poll::Ready(Ok(()))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -72,6 +73,7 @@ where
             Poll::Pending => Poll::Pending,
             Poll::Ready(Ok(n)) => {
                 this.flow_stat.incr_tx(n as u64);
+                this.flow_stat.tcp().incr_wire_tx(n as u64);
                 Poll::Ready(Ok(n))
             }
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
@@ -97,3 +99,118 @@ where
         self.project().stream.poll_write_vectored(cx, bufs)
     }
 }
+
+/// Monitored plain side of a TCP tunnel, counting plaintext bytes
+///
+/// Bytes read from it are sent to the wire after encrypted (`plain_tx`), bytes written to it are received from the
+/// wire (`plain_rx`).
+#[pin_project]
+pub struct MonPlainStream<S> {
+    #[pin]
+    stream: S,
+    flow_stat: Arc<FlowStat>,
+}
+
+impl<S> MonPlainStream<S> {
+    #[inline]
+    pub fn from_stream(stream: S, flow_stat: Arc<FlowStat>) -> MonPlainStream<S> {
+        MonPlainStream { stream, flow_stat }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+}
+
+impl<S> AsyncRead for MonPlainStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    #[inline]
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        match this.stream.poll_read(cx, buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(())) => {
+                let n = buf.filled().len() - filled;
+                this.flow_stat.tcp().incr_plain_tx(n as u64);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MonPlainStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        match this.stream.poll_write(cx, buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(n)) => {
+                this.flow_stat.tcp().incr_plain_rx(n as u64);
+                Poll::Ready(Ok(n))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::poll_fn;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn plain_stream_counts_plaintext() {
+        let (plain, mut peer) = tokio::io::duplex(64);
+        let flow_stat = Arc::new(FlowStat::new());
+        let mut stream = MonPlainStream::from_stream(plain, flow_stat.clone());
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut received = [0u8; 5];
+        peer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"hello");
+
+        // Only bytes read by this call are counted, not those already in the buffer
+        peer.write_all(b"world").await.unwrap();
+        let mut buffer = [0u8; 16];
+        let mut read_buf = ReadBuf::new(&mut buffer);
+        read_buf.put_slice(b"abc");
+        poll_fn(|cx| Pin::new(&mut stream).poll_read(cx, &mut read_buf))
+            .await
+            .unwrap();
+        assert_eq!(read_buf.filled(), b"abcworld");
+
+        let flow = flow_stat.tcp().snapshot();
+        assert_eq!(flow.plain_rx, 5);
+        assert_eq!(flow.plain_tx, 5);
+        assert_eq!(flow.wire_tx, 0);
+        assert_eq!(flow.wire_rx, 0);
+        assert_eq!(flow_stat.tx(), 0);
+        assert_eq!(flow_stat.rx(), 0);
+    }
+}
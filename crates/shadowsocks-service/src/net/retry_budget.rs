@@ -0,0 +1,139 @@
+//! Global budget of retries, preventing retry storms during outages
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use spin::Mutex as SpinMutex;
+
+#[derive(Debug)]
+struct TokenBucket {
+    // Tokens refilled per second, which is also the capacity of the bucket
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RetryBudgetInner {
+    // None for unlimited
+    bucket: SpinMutex<Option<TokenBucket>>,
+    throttled: AtomicU64,
+}
+
+/// Token bucket shared by all retries of a local instance
+///
+/// Every retry (not the first attempt) takes a token, tokens are refilled at `retries_per_second` and at most
+/// `retries_per_second` tokens could be saved for bursts. Retries fail fast without a token, so a pathological
+/// network won't amplify the load on the device and servers.
+#[derive(Debug, Clone)]
+pub struct RetryBudget {
+    inner: Arc<RetryBudgetInner>,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget::new(None)
+    }
+}
+
+impl RetryBudget {
+    /// Create a new budget, `None` (or `Some(0)`) for unlimited
+    pub fn new(retries_per_second: Option<u32>) -> RetryBudget {
+        let budget = RetryBudget {
+            inner: Arc::new(RetryBudgetInner {
+                bucket: SpinMutex::new(None),
+                throttled: AtomicU64::new(0),
+            }),
+        };
+        budget.set_retries_per_second(retries_per_second);
+        budget
+    }
+
+    /// Current budget, `None` for unlimited
+    pub fn retries_per_second(&self) -> Option<u32> {
+        self.inner.bucket.lock().as_ref().map(|b| b.rate as u32)
+    }
+
+    /// Change the budget, `None` (or `Some(0)`) for unlimited. The bucket starts full.
+    pub fn set_retries_per_second(&self, retries_per_second: Option<u32>) {
+        *self.inner.bucket.lock() = match retries_per_second {
+            None | Some(0) => None,
+            Some(rate) => Some(TokenBucket {
+                rate: rate as f64,
+                tokens: rate as f64,
+                refilled_at: Instant::now(),
+            }),
+        };
+    }
+
+    /// Take a token for one retry, returns `false` if the budget is exhausted and the retry should be given up
+    pub fn try_acquire(&self) -> bool {
+        let acquired = match *self.inner.bucket.lock() {
+            None => true,
+            Some(ref mut bucket) => bucket.try_take(Instant::now()),
+        };
+        if !acquired {
+            self.inner.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+        acquired
+    }
+
+    /// Number of retries that have been given up because the budget was exhausted
+    pub fn throttled(&self) -> u64 {
+        self.inner.throttled.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn retry_budget_token_bucket() {
+        let budget = RetryBudget::new(Some(2));
+        assert!(budget.try_acquire());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+        assert_eq!(budget.throttled(), 1);
+
+        let now = Instant::now();
+        let mut bucket = TokenBucket {
+            rate: 2.0,
+            tokens: 0.0,
+            refilled_at: now,
+        };
+        assert!(!bucket.try_take(now + Duration::from_millis(400)));
+        assert!(bucket.try_take(now + Duration::from_millis(500)));
+        // Capacity is one second of tokens
+        assert!(bucket.try_take(now + Duration::from_secs(10)));
+        assert!(bucket.try_take(now + Duration::from_secs(10)));
+        assert!(!bucket.try_take(now + Duration::from_secs(10)));
+
+        let unlimited = RetryBudget::new(Some(0));
+        assert_eq!(unlimited.retries_per_second(), None);
+        assert!((0..100).all(|_| unlimited.try_acquire()));
+        assert_eq!(unlimited.throttled(), 0);
+    }
+}
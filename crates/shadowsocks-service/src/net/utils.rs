@@ -1,11 +1,20 @@
 //! Network Utilities
 
 use std::{
+    future::Future,
     io,
-    net::{Ipv4Addr, Ipv6Addr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    task::{Context, Poll},
 };
 
-use tokio::io::{AsyncRead, AsyncReadExt};
+use futures::ready;
+use log::error;
+use shadowsocks::relay::socks5::Address;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    task::{JoinError, JoinHandle},
+};
 
 /// Consumes all data from `reader` and throws away until EOF
 pub async fn ignore_until_end<R>(reader: &mut R) -> io::Result<()>
@@ -24,6 +33,58 @@ where
     Ok(())
 }
 
+/// Convert a failed task's `JoinError` to `io::Error`
+///
+/// Panics are logged with the message carried by the panic, so the dead task won't be silently ignored.
+pub(crate) fn join_error_to_io_error(name: &str, err: JoinError) -> io::Error {
+    if !err.is_panic() {
+        return io::Error::new(io::ErrorKind::Other, format!("{name} task aborted"));
+    }
+
+    let payload = err.into_panic();
+    let message = if let Some(s) = payload.downcast_ref::<&'static str>() {
+        *s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.as_str()
+    } else {
+        "unknown panic"
+    };
+
+    error!("{} task panicked: {}", name, message);
+    io::Error::new(io::ErrorKind::Other, format!("{name} task panicked: {message}"))
+}
+
+/// Handle of a server task, panicked or aborted tasks are reported as errors
+pub(crate) struct ServerHandle {
+    name: &'static str,
+    handle: JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    pub fn new(name: &'static str, handle: JoinHandle<io::Result<()>>) -> ServerHandle {
+        ServerHandle { name, handle }
+    }
+}
+
+impl Drop for ServerHandle {
+    #[inline]
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl Future for ServerHandle {
+    type Output = io::Result<()>;
+
+    #[inline]
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match ready!(Pin::new(&mut self.handle).poll(cx)) {
+            Ok(res) => res.into(),
+            Err(err) => Err(join_error_to_io_error(self.name, err)).into(),
+        }
+    }
+}
+
 /// Helper function for converting IPv4 mapped IPv6 address
 ///
 /// This is the same as `Ipv6Addr::to_ipv4_mapped`, but it is still unstable in the current libstd
@@ -34,3 +95,67 @@ pub(crate) fn to_ipv4_mapped(ipv6: &Ipv6Addr) -> Option<Ipv4Addr> {
         _ => None,
     }
 }
+
+/// Normalize IPv4 mapped IPv6 address (`::ffff:a.b.c.d`) to IPv4 (`a.b.c.d`)
+///
+/// Mapped addresses should be handled as IPv4 addresses by ACL rules and connections
+pub fn normalize_ip_addr(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ref v6) => match to_ipv4_mapped(v6) {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        IpAddr::V4(..) => ip,
+    }
+}
+
+/// Normalize IPv4 mapped IPv6 socket address to IPv4
+pub fn normalize_socket_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(normalize_ip_addr(addr.ip()), addr.port())
+}
+
+/// Normalize IPv4 mapped IPv6 socket address to IPv4, domain names are left untouched
+pub fn normalize_address(addr: Address) -> Address {
+    match addr {
+        Address::SocketAddress(saddr) => Address::SocketAddress(normalize_socket_addr(saddr)),
+        addr => addr,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize_ipv4_mapped_addr() {
+        let mapped = "[::ffff:1.2.3.4]:80".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            normalize_socket_addr(mapped),
+            "1.2.3.4:80".parse::<SocketAddr>().unwrap()
+        );
+
+        let v6 = "[2001:db8::1]:80".parse::<SocketAddr>().unwrap();
+        assert_eq!(normalize_socket_addr(v6), v6);
+
+        let domain = Address::DomainNameAddress("::ffff:1.2.3.4".to_owned(), 80);
+        assert_eq!(normalize_address(domain.clone()), domain);
+    }
+
+    #[tokio::test]
+    async fn server_handle_reports_panic() {
+        let handle = ServerHandle::new(
+            "relay",
+            tokio::spawn(async {
+                panic!("injected relay panic");
+            }),
+        );
+
+        let err = handle.await.unwrap_err();
+        assert_eq!(err.to_string(), "relay task panicked: injected relay panic");
+
+        let running = ServerHandle::new("relay", tokio::spawn(futures::future::pending()));
+        running.handle.abort();
+        let err = running.await.unwrap_err();
+        assert_eq!(err.to_string(), "relay task aborted");
+    }
+}
@@ -115,9 +115,25 @@ impl ServiceContext {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Use only IPv4 addresses of resolved hostnames
+    pub fn set_dns_ipv4_only(&mut self, dns_ipv4_only: bool) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set dns_ipv4_only on a shared context");
+        context.set_dns_ipv4_only(dns_ipv4_only);
+    }
+
+    /// Use only IPv6 addresses of resolved hostnames
+    pub fn set_dns_ipv6_only(&mut self, dns_ipv6_only: bool) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set dns_ipv6_only on a shared context");
+        context.set_dns_ipv6_only(dns_ipv6_only);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
         context.set_replay_attack_policy(security.replay_attack.policy);
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(diff) = security.replay_attack.max_timestamp_diff {
+            context.set_aead_2022_max_timestamp_diff(diff);
+        }
     }
 }
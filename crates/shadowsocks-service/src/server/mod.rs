@@ -1,22 +1,15 @@
 //! Shadowsocks server
 
-use std::{
-    future::Future,
-    io::{self, ErrorKind},
-    pin::Pin,
-    sync::Arc,
-    task::{Context, Poll},
-    time::Duration,
-};
+use std::{io, sync::Arc, time::Duration};
 
-use futures::{future, ready};
+use futures::future;
 use log::trace;
 use shadowsocks::net::{AcceptOpts, ConnectOpts};
-use tokio::task::JoinHandle;
 
 use crate::{
-    config::{Config, ConfigType},
+    config::{clamp_socket_buffer_size, Config, ConfigType},
     dns::build_dns_resolver,
+    net::{liveness::DEFAULT_LIVENESS_INTERVAL, utils::ServerHandle, LivenessFile},
 };
 
 pub use self::{
@@ -77,8 +70,12 @@ pub async fn run(config: Config) -> io::Result<()> {
         ..Default::default()
     };
 
-    connect_opts.tcp.send_buffer_size = config.outbound_send_buffer_size;
-    connect_opts.tcp.recv_buffer_size = config.outbound_recv_buffer_size;
+    connect_opts.tcp.send_buffer_size = config
+        .outbound_send_buffer_size
+        .map(|bs| clamp_socket_buffer_size("outbound_send_buffer_size", bs));
+    connect_opts.tcp.recv_buffer_size = config
+        .outbound_recv_buffer_size
+        .map(|bs| clamp_socket_buffer_size("outbound_recv_buffer_size", bs));
     connect_opts.tcp.nodelay = config.no_delay;
     connect_opts.tcp.fastopen = config.fast_open;
     connect_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
@@ -89,8 +86,12 @@ pub async fn run(config: Config) -> io::Result<()> {
         ipv6_only: config.ipv6_only,
         ..Default::default()
     };
-    accept_opts.tcp.send_buffer_size = config.inbound_send_buffer_size;
-    accept_opts.tcp.recv_buffer_size = config.inbound_recv_buffer_size;
+    accept_opts.tcp.send_buffer_size = config
+        .inbound_send_buffer_size
+        .map(|bs| clamp_socket_buffer_size("inbound_send_buffer_size", bs));
+    accept_opts.tcp.recv_buffer_size = config
+        .inbound_recv_buffer_size
+        .map(|bs| clamp_socket_buffer_size("inbound_recv_buffer_size", bs));
     accept_opts.tcp.nodelay = config.no_delay;
     accept_opts.tcp.fastopen = config.fast_open;
     accept_opts.tcp.keepalive = config.keep_alive.or(Some(SERVER_DEFAULT_KEEPALIVE_TIMEOUT));
@@ -141,6 +142,8 @@ pub async fn run(config: Config) -> io::Result<()> {
         if config.ipv6_first {
             server_builder.set_ipv6_first(config.ipv6_first);
         }
+        server_builder.set_dns_ipv4_only(config.dns_ipv4_only);
+        server_builder.set_dns_ipv6_only(config.dns_ipv6_only);
 
         if config.worker_count >= 1 {
             server_builder.set_worker_count(config.worker_count);
@@ -152,38 +155,31 @@ pub async fn run(config: Config) -> io::Result<()> {
         servers.push(server);
     }
 
-    if servers.len() == 1 {
-        let server = servers.pop().unwrap();
-        return server.run().await;
-    }
-
-    let mut vfut = Vec::with_capacity(servers.len());
-
-    for server in servers {
-        vfut.push(ServerHandle(tokio::spawn(async move { server.run().await })));
-    }
-
-    let (res, ..) = future::select_all(vfut).await;
-    res
-}
+    let servers_fut = async move {
+        if servers.len() == 1 {
+            let server = servers.pop().unwrap();
+            return server.run().await;
+        }
 
-struct ServerHandle(JoinHandle<io::Result<()>>);
+        let mut vfut = Vec::with_capacity(servers.len());
 
-impl Drop for ServerHandle {
-    #[inline]
-    fn drop(&mut self) {
-        self.0.abort();
-    }
-}
+        for server in servers {
+            vfut.push(ServerHandle::new(
+                "server",
+                tokio::spawn(async move { server.run().await }),
+            ));
+        }
 
-impl Future for ServerHandle {
-    type Output = io::Result<()>;
+        let (res, ..) = future::select_all(vfut).await;
+        res
+    };
 
-    #[inline]
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match ready!(Pin::new(&mut self.0).poll(cx)) {
-            Ok(res) => res.into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+    // Liveness file stops being touched if any of the servers exited
+    match config.liveness_file {
+        Some(path) => {
+            let interval = config.liveness_interval.unwrap_or(DEFAULT_LIVENESS_INTERVAL);
+            LivenessFile::new(path, interval).watch(servers_fut).await
         }
+        None => servers_fut.await,
     }
 }
@@ -8,7 +8,7 @@ use std::{
 };
 
 use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use log::{error, trace};
+use log::{error, trace, warn};
 use shadowsocks::{
     config::{ManagerAddr, ServerConfig},
     dns_resolver::DnsResolver,
@@ -119,6 +119,18 @@ impl ServerBuilder {
         context.set_ipv6_first(ipv6_first);
     }
 
+    /// Use only IPv4 addresses of resolved hostnames
+    pub fn set_dns_ipv4_only(&mut self, dns_ipv4_only: bool) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set dns_ipv4_only on a shared context");
+        context.set_dns_ipv4_only(dns_ipv4_only);
+    }
+
+    /// Use only IPv6 addresses of resolved hostnames
+    pub fn set_dns_ipv6_only(&mut self, dns_ipv6_only: bool) {
+        let context = Arc::get_mut(&mut self.context).expect("cannot set dns_ipv6_only on a shared context");
+        context.set_dns_ipv6_only(dns_ipv6_only);
+    }
+
     /// Set security config
     pub fn set_security_config(&mut self, security: &SecurityConfig) {
         let context = Arc::get_mut(&mut self.context).expect("cannot set security on a shared context");
@@ -147,6 +159,14 @@ impl ServerBuilder {
 
         let mut udp_server = None;
         if self.svr_cfg.mode().enable_udp() {
+            if self.svr_cfg.fallback_methods().next().is_some() {
+                warn!(
+                    "server {} fallback_methods are only accepted by TCP, UDP clients must use {}",
+                    self.svr_cfg.addr(),
+                    self.svr_cfg.method()
+                );
+            }
+
             let mut server = UdpServer::new(
                 self.context.clone(),
                 self.svr_cfg.clone(),
@@ -10,7 +10,6 @@ use std::{
 
 use log::{debug, error, info, trace, warn};
 use shadowsocks::{
-    crypto::CipherKind,
     net::{AcceptOpts, TcpStream as OutboundTcpStream},
     relay::tcprelay::{utils::copy_encrypted_bidirectional, ProxyServerStream},
     ProxyListener,
@@ -22,7 +21,12 @@ use tokio::{
     time,
 };
 
-use crate::net::{utils::ignore_until_end, MonProxyStream};
+use crate::net::{
+    utils::{ignore_until_end, normalize_address},
+    ConnectionId,
+    MonPlainStream,
+    MonProxyStream,
+};
 
 use super::context::ServiceContext;
 
@@ -81,14 +85,16 @@ impl TcpServer {
                 }
             };
 
+            let id = ConnectionId::next();
+
             if self.context.check_client_blocked(&peer_addr) {
-                warn!("access denied from {} by ACL rules", peer_addr);
+                warn!("access denied from {} {} by ACL rules", id, peer_addr);
                 continue;
             }
 
             let client = TcpServerClient {
+                id,
                 context: self.context.clone(),
-                method: self.svr_cfg.method(),
                 peer_addr,
                 stream: local_stream,
                 timeout: self.svr_cfg.timeout(),
@@ -96,7 +102,7 @@ impl TcpServer {
 
             tokio::spawn(async move {
                 if let Err(err) = client.serve().await {
-                    debug!("tcp server stream aborted with error: {}", err);
+                    debug!("tcp server stream {} aborted with error: {}", id, err);
                 }
             });
         }
@@ -118,8 +124,8 @@ where
 }
 
 struct TcpServerClient {
+    id: ConnectionId,
     context: Arc<ServiceContext>,
-    method: CipherKind,
     peer_addr: SocketAddr,
     stream: ProxyServerStream<MonProxyStream<TokioTcpStream>>,
     timeout: Option<Duration>,
@@ -129,7 +135,7 @@ impl TcpServerClient {
     async fn serve(mut self) -> io::Result<()> {
         // let target_addr = match Address::read_from(&mut self.stream).await {
         let target_addr = match timeout_fut(self.timeout, self.stream.handshake()).await {
-            Ok(a) => a,
+            Ok(a) => normalize_address(a),
             // Err(Socks5Error::IoError(ref err)) if err.kind() == ErrorKind::UnexpectedEof => {
             //     debug!(
             //         "handshake failed, received EOF before a complete target Address, peer: {}",
@@ -139,15 +145,15 @@ impl TcpServerClient {
             // }
             Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
                 debug!(
-                    "tcp handshake failed, received EOF before a complete target Address, peer: {}",
-                    self.peer_addr
+                    "tcp handshake {} failed, received EOF before a complete target Address, peer: {}",
+                    self.id, self.peer_addr
                 );
                 return Ok(());
             }
             Err(err) if err.kind() == ErrorKind::TimedOut => {
                 debug!(
-                    "tcp handshake failed, timeout before a complete target Address, peer: {}",
-                    self.peer_addr
+                    "tcp handshake {} failed, timeout before a complete target Address, peer: {}",
+                    self.id, self.peer_addr
                 );
                 return Ok(());
             }
@@ -155,10 +161,11 @@ impl TcpServerClient {
                 // https://github.com/shadowsocks/shadowsocks-rust/issues/292
                 //
                 // Keep connection open. Except AEAD-2022
-                warn!("tcp handshake failed. peer: {}, {}", self.peer_addr, err);
+                warn!("tcp handshake {} failed. peer: {}, {}", self.id, self.peer_addr, err);
 
                 #[cfg(feature = "aead-cipher-2022")]
-                if self.method.is_aead_2022() {
+                // Fallback methods are never AEAD-2022, so it is always the configured one
+                if self.stream.method().is_aead_2022() {
                     // Set SO_LINGER(0) for misbehave clients, which will eventually receive RST. (ECONNRESET)
                     // This will also prevent the socket entering TIME_WAIT state.
 
@@ -168,7 +175,7 @@ impl TcpServerClient {
                     return Ok(());
                 }
 
-                debug!("tcp silent-drop peer: {}", self.peer_addr);
+                debug!("tcp silent-drop {} peer: {}", self.id, self.peer_addr);
 
                 // Unwrap and get the plain stream.
                 // Otherwise it will keep reporting decryption error before reaching EOF.
@@ -179,7 +186,8 @@ impl TcpServerClient {
                 let res = ignore_until_end(&mut stream).await;
 
                 trace!(
-                    "tcp silent-drop peer: {} is now closing with result {:?}",
+                    "tcp silent-drop {} peer: {} is now closing with result {:?}",
+                    self.id,
                     self.peer_addr,
                     res
                 );
@@ -189,20 +197,21 @@ impl TcpServerClient {
         };
 
         trace!(
-            "accepted tcp client connection {}, establishing tunnel to {}",
+            "accepted tcp client connection {} {}, establishing tunnel to {}",
+            self.id,
             self.peer_addr,
             target_addr
         );
 
         if self.context.check_outbound_blocked(&target_addr).await {
             error!(
-                "tcp client {} outbound {} blocked by ACL rules",
-                self.peer_addr, target_addr
+                "tcp client {} {} outbound {} blocked by ACL rules",
+                self.id, self.peer_addr, target_addr
             );
             return Ok(());
         }
 
-        let mut remote_stream = match timeout_fut(
+        let remote_stream = match timeout_fut(
             self.timeout,
             OutboundTcpStream::connect_remote_with_opts(
                 self.context.context_ref(),
@@ -215,13 +224,15 @@ impl TcpServerClient {
             Ok(s) => s,
             Err(err) => {
                 error!(
-                    "tcp tunnel {} -> {} connect failed, error: {}",
-                    self.peer_addr, target_addr, err
+                    "tcp tunnel {} {} -> {} connect failed, error: {}",
+                    self.id, self.peer_addr, target_addr, err
                 );
                 return Err(err);
             }
         };
 
+        let mut remote_stream = MonPlainStream::from_stream(remote_stream, self.context.flow_stat());
+
         // https://github.com/shadowsocks/shadowsocks-rust/issues/232
         //
         // Protocols like FTP, clients will wait for servers to send Welcome Message without sending anything.
@@ -244,7 +255,8 @@ impl TcpServerClient {
                     timeout_fut(self.timeout, remote_stream.write(&[])).await?;
 
                     trace!(
-                        "tcp tunnel {} -> {} sent TFO connect without data",
+                        "tcp tunnel {} {} -> {} sent TFO connect without data",
+                        self.id,
                         self.peer_addr,
                         target_addr
                     );
@@ -253,16 +265,18 @@ impl TcpServerClient {
         }
 
         debug!(
-            "established tcp tunnel {} <-> {} with {:?}",
+            "established tcp tunnel {} {} <-> {} with {:?}",
+            self.id,
             self.peer_addr,
             target_addr,
             self.context.connect_opts_ref()
         );
 
-        match copy_encrypted_bidirectional(self.method, &mut self.stream, &mut remote_stream).await {
+        match copy_encrypted_bidirectional(self.stream.method(), &mut self.stream, &mut remote_stream).await {
             Ok((rn, wn)) => {
                 trace!(
-                    "tcp tunnel {} <-> {} closed, L2R {} bytes, R2L {} bytes",
+                    "tcp tunnel {} {} <-> {} closed, L2R {} bytes, R2L {} bytes",
+                    self.id,
                     self.peer_addr,
                     target_addr,
                     rn,
@@ -271,7 +285,8 @@ impl TcpServerClient {
             }
             Err(err) => {
                 trace!(
-                    "tcp tunnel {} <-> {} closed with error: {}",
+                    "tcp tunnel {} {} <-> {} closed with error: {}",
+                    self.id,
                     self.peer_addr,
                     target_addr,
                     err
@@ -21,7 +21,7 @@ use thiserror::Error;
 use url::{self, Url};
 
 use crate::{
-    crypto::{v1::openssl_bytes_to_key, CipherKind},
+    crypto::{v1::openssl_bytes_to_key, CipherCategory, CipherKind},
     plugin::PluginConfig,
     relay::socks5::Address,
 };
@@ -325,6 +325,9 @@ pub struct ServerConfig {
     /// For server, support multi-users with EIH
     user_manager: Option<Arc<ServerUserManager>>,
 
+    /// Methods that are also accepted from clients, with keys derived from the same password (Server, TCP only)
+    fallback_methods: Arc<Vec<(CipherKind, Bytes)>>,
+
     /// Plugin config
     plugin: Option<PluginConfig>,
     /// Plugin address
@@ -342,27 +345,50 @@ pub struct ServerConfig {
     weight: ServerWeight,
 }
 
+/// Server's password doesn't meet the requirement of its method
+#[derive(Debug, Clone, Error)]
+pub enum ServerPasswordError {
+    /// Key is not base64 encoded
+    #[error("expected base64 key for {method}, error: {error}")]
+    InvalidKeyEncoding {
+        method: CipherKind,
+        error: base64::DecodeError,
+    },
+    /// Key doesn't have the same length as the method's
+    #[error("expected {expected}-byte base64 key for {method}, got {actual} bytes")]
+    InvalidKeyLength {
+        method: CipherKind,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// Decode AEAD 2022 password, which is a base64 form of the key
+#[cfg(feature = "aead-cipher-2022")]
+fn decode_aead_2022_key(method: CipherKind, password: &str) -> Result<Vec<u8>, ServerPasswordError> {
+    let key = AEAD2022_PASSWORD_BASE64_ENGINE
+        .decode(password)
+        .map_err(|error| ServerPasswordError::InvalidKeyEncoding { method, error })?;
+
+    if key.len() != method.key_len() {
+        return Err(ServerPasswordError::InvalidKeyLength {
+            method,
+            expected: method.key_len(),
+            actual: key.len(),
+        });
+    }
+
+    Ok(key)
+}
+
 #[cfg(feature = "aead-cipher-2022")]
 #[inline]
 fn make_derived_key(method: CipherKind, password: &str, enc_key: &mut [u8]) {
     if method.is_aead_2022() {
         // AEAD 2022 password is a base64 form of enc_key
-        match AEAD2022_PASSWORD_BASE64_ENGINE.decode(password) {
-            Ok(v) => {
-                if v.len() != enc_key.len() {
-                    panic!(
-                        "{} is expecting a {} bytes key, but password: {} ({} bytes after decode)",
-                        method,
-                        enc_key.len(),
-                        password,
-                        v.len()
-                    );
-                }
-                enc_key.copy_from_slice(&v);
-            }
-            Err(err) => {
-                panic!("{method} password {password} is not base64 encoded, error: {err}");
-            }
+        match decode_aead_2022_key(method, password) {
+            Ok(v) => enc_key.copy_from_slice(&v),
+            Err(err) => panic!("{method} password {password} is invalid, {err}"),
         }
     } else {
         openssl_bytes_to_key(password.as_bytes(), enc_key);
@@ -430,6 +456,27 @@ where
 }
 
 impl ServerConfig {
+    /// Check if `password` could be used with `method`
+    ///
+    /// AEAD 2022 methods require base64 encoded keys of exactly `method.key_len()` bytes (and iPSKs
+    /// of Extensible Identity Headers), `ServerConfig::new` panics with invalid ones.
+    #[allow(unused_variables)]
+    pub fn check_password(method: CipherKind, password: &str) -> Result<(), ServerPasswordError> {
+        #[cfg(feature = "aead-cipher-2022")]
+        if method.is_aead_2022() {
+            if method_support_eih(method) {
+                // iPSK1:iPSK2:iPSK3:...:uPSK
+                for key in password.split(':') {
+                    decode_aead_2022_key(method, key)?;
+                }
+            } else {
+                decode_aead_2022_key(method, password)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Create a new `ServerConfig`
     pub fn new<A, P>(addr: A, password: P, method: CipherKind) -> ServerConfig
     where
@@ -445,6 +492,7 @@ impl ServerConfig {
             enc_key,
             identity_keys: Arc::new(identity_keys),
             user_manager: None,
+            fallback_methods: Arc::new(Vec::new()),
             timeout: None,
             plugin: None,
             plugin_addr: None,
@@ -467,6 +515,13 @@ impl ServerConfig {
         self.password = password;
         self.enc_key = enc_key;
         self.identity_keys = Arc::new(identity_keys);
+
+        // Keys of fallback methods are derived from the password, they are dropped if `method` couldn't be mixed
+        let fallback_methods = self
+            .fallback_methods()
+            .filter(|_| method.category() == CipherCategory::Aead)
+            .collect::<Vec<_>>();
+        self.set_fallback_methods(&fallback_methods);
     }
 
     /// Set plugin
@@ -512,6 +567,42 @@ impl ServerConfig {
         self.user_manager = Some(Arc::new(user_manager));
     }
 
+    /// Set methods that are also accepted from clients, besides `method()` (Server)
+    ///
+    /// The cipher of each TCP connection is chosen by trying to decrypt the client's first chunk with `method()`
+    /// and then these methods in order, keys are derived from the same password. Only AEAD (not AEAD-2022) methods
+    /// could be mixed on one port, panics if any of them, including `method()`, is not.
+    pub fn set_fallback_methods(&mut self, methods: &[CipherKind]) {
+        let mut fallback_methods = Vec::with_capacity(methods.len());
+        for &method in methods {
+            assert!(
+                self.method.category() == CipherCategory::Aead && method.category() == CipherCategory::Aead,
+                "fallback method {} with {} is not supported, only AEAD methods could be mixed",
+                method,
+                self.method
+            );
+
+            if method == self.method || fallback_methods.iter().any(|(m, _)| *m == method) {
+                continue;
+            }
+
+            let mut enc_key = vec![0u8; method.key_len()];
+            make_derived_key(method, &self.password, &mut enc_key);
+            fallback_methods.push((method, Bytes::from(enc_key)));
+        }
+        self.fallback_methods = Arc::new(fallback_methods);
+    }
+
+    /// Get methods that are also accepted from clients (Server)
+    pub fn fallback_methods(&self) -> impl Iterator<Item = CipherKind> + '_ {
+        self.fallback_methods.iter().map(|(method, _)| *method)
+    }
+
+    /// Clone fallback methods with their keys (Server)
+    pub fn clone_fallback_method_keys(&self) -> Arc<Vec<(CipherKind, Bytes)>> {
+        self.fallback_methods.clone()
+    }
+
     /// Get user manager (Server)
     pub fn user_manager(&self) -> Option<&ServerUserManager> {
         self.user_manager.as_deref()
@@ -782,6 +873,10 @@ impl ServerConfig {
         };
 
         let method = method.parse().expect("method");
+        if let Err(err) = ServerConfig::check_password(method, &pwd) {
+            error!("invalid password in SIP002 URL, {}", err);
+            return Err(UrlParseError::InvalidAuthInfo);
+        }
         let mut svrconfig = ServerConfig::new(addr, pwd, method);
 
         if let Some(q) = parsed.query() {
@@ -1142,3 +1237,45 @@ impl FromStr for ReplayAttackPolicy {
         }
     }
 }
+
+#[cfg(all(test, feature = "aead-cipher-2022"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_aead_2022_password() {
+        let method = CipherKind::AEAD2022_BLAKE3_AES_256_GCM;
+        let key_32 = AEAD2022_PASSWORD_BASE64_ENGINE.encode([1u8; 32]);
+        let key_16 = AEAD2022_PASSWORD_BASE64_ENGINE.encode([1u8; 16]);
+
+        assert!(ServerConfig::check_password(method, &key_32).is_ok());
+        assert!(ServerConfig::check_password(method, &format!("{key_32}:{key_32}")).is_ok());
+
+        let err = ServerConfig::check_password(method, &key_16).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected 32-byte base64 key for 2022-blake3-aes-256-gcm, got 16 bytes"
+        );
+        // iPSK of Extensible Identity Headers
+        assert!(ServerConfig::check_password(method, &format!("{key_16}:{key_32}")).is_err());
+        assert!(matches!(
+            ServerConfig::check_password(method, "not base64!"),
+            Err(ServerPasswordError::InvalidKeyEncoding { .. })
+        ));
+
+        assert!(ServerConfig::check_password(CipherKind::AEAD2022_BLAKE3_AES_128_GCM, &key_16).is_ok());
+
+        // Other methods accept any passwords
+        assert!(ServerConfig::check_password(CipherKind::AES_256_GCM, "password").is_ok());
+
+        // SIP002 URL with a wrong length key is rejected instead of panicking
+        let url = format!(
+            "ss://2022-blake3-aes-256-gcm:{}@127.0.0.1:8388",
+            key_16.replace('=', "%3D")
+        );
+        assert!(matches!(
+            ServerConfig::from_url(&url),
+            Err(UrlParseError::InvalidAuthInfo)
+        ));
+    }
+}
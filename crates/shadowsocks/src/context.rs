@@ -1,10 +1,14 @@
 //! Shadowsocks service context
 
+#[cfg(feature = "aead-cipher-2022")]
+use std::time::Duration;
 use std::{io, net::SocketAddr, sync::Arc};
 
 use byte_string::ByteStr;
 use log::warn;
 
+#[cfg(feature = "aead-cipher-2022")]
+use crate::relay::tcprelay::proxy_stream::protocol::v2::SERVER_STREAM_TIMESTAMP_MAX_DIFF;
 use crate::{
     config::{ReplayAttackPolicy, ServerType},
     crypto::{v1::random_iv_or_salt, CipherKind},
@@ -19,12 +23,18 @@ pub struct Context {
     replay_protector: ReplayProtector,
     // Policy against replay attack
     replay_policy: ReplayAttackPolicy,
+    // Maximum difference between AEAD-2022 requests' timestamps and now, in seconds
+    #[cfg(feature = "aead-cipher-2022")]
+    aead_2022_max_timestamp_diff: u64,
 
     // hickory-dns resolver, which supports REAL asynchronous resolving, and also customizable
     dns_resolver: Arc<DnsResolver>,
 
     // Connect IPv6 address first
     ipv6_first: bool,
+    // Use only IPv4 or IPv6 addresses of resolved hostnames
+    dns_ipv4_only: bool,
+    dns_ipv6_only: bool,
 }
 
 /// `Context` for sharing between services
@@ -36,8 +46,12 @@ impl Context {
         Context {
             replay_protector: ReplayProtector::new(config_type),
             replay_policy: ReplayAttackPolicy::Default,
+            #[cfg(feature = "aead-cipher-2022")]
+            aead_2022_max_timestamp_diff: SERVER_STREAM_TIMESTAMP_MAX_DIFF,
             dns_resolver: Arc::new(DnsResolver::system_resolver()),
             ipv6_first: false,
+            dns_ipv4_only: false,
+            dns_ipv6_only: false,
         }
     }
 
@@ -122,9 +136,28 @@ impl Context {
     }
 
     /// Resolves DNS address to `SocketAddr`s
+    ///
+    /// Addresses of the other family are skipped if `dns_ipv4_only` or `dns_ipv6_only` is set, and it fails if
+    /// none is left.
     #[allow(clippy::needless_lifetimes)]
     pub async fn dns_resolve<'a>(&self, addr: &'a str, port: u16) -> io::Result<impl Iterator<Item = SocketAddr> + 'a> {
-        self.dns_resolver.resolve(addr, port).await
+        let (ipv4_only, ipv6_only) = (self.dns_ipv4_only, self.dns_ipv6_only);
+        let mut addrs = self
+            .dns_resolver
+            .resolve(addr, port)
+            .await?
+            .filter(move |addr| !(ipv4_only && addr.is_ipv6() || ipv6_only && addr.is_ipv4()))
+            .peekable();
+
+        if (ipv4_only || ipv6_only) && addrs.peek().is_none() {
+            let family = if ipv4_only { "IPv4" } else { "IPv6" };
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!("dns resolve {addr}:{port} error: no {family} address"),
+            );
+            return Err(err);
+        }
+        Ok(addrs)
     }
 
     /// Try to connect IPv6 addresses first if hostname could be resolved to both IPv4 and IPv6
@@ -137,6 +170,26 @@ impl Context {
         self.ipv6_first
     }
 
+    /// Use only IPv4 addresses of resolved hostnames
+    pub fn set_dns_ipv4_only(&mut self, dns_ipv4_only: bool) {
+        self.dns_ipv4_only = dns_ipv4_only;
+    }
+
+    /// Use only IPv4 addresses of resolved hostnames
+    pub fn dns_ipv4_only(&self) -> bool {
+        self.dns_ipv4_only
+    }
+
+    /// Use only IPv6 addresses of resolved hostnames
+    pub fn set_dns_ipv6_only(&mut self, dns_ipv6_only: bool) {
+        self.dns_ipv6_only = dns_ipv6_only;
+    }
+
+    /// Use only IPv6 addresses of resolved hostnames
+    pub fn dns_ipv6_only(&self) -> bool {
+        self.dns_ipv6_only
+    }
+
     /// Set policy against replay attack
     pub fn set_replay_attack_policy(&mut self, replay_policy: ReplayAttackPolicy) {
         self.replay_policy = replay_policy;
@@ -146,4 +199,104 @@ impl Context {
     pub fn replay_attack_policy(&self) -> ReplayAttackPolicy {
         self.replay_policy
     }
+
+    /// Set the maximum difference between timestamps of AEAD-2022 streams / packets and now, 30 seconds by default
+    ///
+    /// Widening it allows peers with unreliable clocks, but salts have to be remembered for longer (twice the
+    /// difference) for rejecting replayed requests.
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn set_aead_2022_max_timestamp_diff(&mut self, diff: Duration) {
+        let diff = diff.as_secs().max(1);
+        self.aead_2022_max_timestamp_diff = diff;
+        self.replay_protector
+            .set_aead_2022_nonce_expiry(Duration::from_secs(diff * 2));
+    }
+
+    /// Get the maximum difference between timestamps of AEAD-2022 streams / packets and now
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn aead_2022_max_timestamp_diff(&self) -> Duration {
+        Duration::from_secs(self.aead_2022_max_timestamp_diff)
+    }
+
+    /// Check timestamp of an AEAD-2022 stream / packet, returns the maximum difference if it is out of range
+    #[cfg(feature = "aead-cipher-2022")]
+    pub(crate) fn check_aead_2022_timestamp(&self, timestamp: u64, now: u64) -> Result<(), u64> {
+        if now.abs_diff(timestamp) > self.aead_2022_max_timestamp_diff {
+            Err(self.aead_2022_max_timestamp_diff)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use async_trait::async_trait;
+
+    use crate::dns_resolver::DnsResolve;
+
+    use super::*;
+
+    struct DualStackResolver;
+
+    #[async_trait]
+    impl DnsResolve for DualStackResolver {
+        async fn resolve(&self, _addr: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+            ])
+        }
+    }
+
+    #[tokio::test]
+    async fn dns_resolve_ipv4_only() {
+        let mut context = Context::new(ServerType::Local);
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(DualStackResolver)));
+        assert_eq!(context.dns_resolve("dual.example.com", 80).await.unwrap().count(), 2);
+
+        context.set_dns_ipv4_only(true);
+        let addrs = context
+            .dns_resolve("dual.example.com", 80)
+            .await
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, [SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 80)]);
+    }
+
+    struct EmptyResolver;
+
+    #[async_trait]
+    impl DnsResolve for EmptyResolver {
+        async fn resolve(&self, _addr: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn dns_resolve_no_address_left() {
+        let mut context = Context::new(ServerType::Local);
+        context.set_dns_resolver(Arc::new(DnsResolver::custom_resolver(EmptyResolver)));
+
+        // Result of the resolver is returned as is without any family option
+        assert_eq!(context.dns_resolve("empty.example.com", 80).await.unwrap().count(), 0);
+
+        context.set_dns_ipv4_only(true);
+        let err = context.dns_resolve("empty.example.com", 80).await.err().unwrap();
+        assert!(err.to_string().contains("no IPv4 address"));
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    #[test]
+    fn aead_2022_timestamp_window() {
+        let mut context = Context::new(ServerType::Server);
+        assert_eq!(context.check_aead_2022_timestamp(1000, 1030), Ok(()));
+        assert_eq!(context.check_aead_2022_timestamp(1000, 1031), Err(30));
+
+        context.set_aead_2022_max_timestamp_diff(Duration::from_secs(120));
+        assert_eq!(context.check_aead_2022_timestamp(1120, 1000), Ok(()));
+        assert_eq!(context.check_aead_2022_timestamp(1121, 1000), Err(120));
+    }
 }
@@ -402,6 +402,20 @@ impl DnsResolver {
         }
     }
 
+    /// Drop cached records, for example, after the host's network has changed
+    ///
+    /// System and custom resolvers don't have caches in this library.
+    pub fn clear_cache(&self) {
+        match *self {
+            DnsResolver::System => {}
+            #[cfg(feature = "hickory-dns")]
+            DnsResolver::HickoryDnsSystem { ref inner, .. } => inner.resolver.load().clear_cache(),
+            #[cfg(feature = "hickory-dns")]
+            DnsResolver::HickoryDns(ref resolver) => resolver.clear_cache(),
+            DnsResolver::Custom(..) => {}
+        }
+    }
+
     /// Check if currently using system resolver
     pub fn is_system_resolver(&self) -> bool {
         matches!(*self, DnsResolver::System)
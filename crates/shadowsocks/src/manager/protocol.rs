@@ -24,7 +24,10 @@ pub struct ServerUserConfig {
 }
 
 /// Server's configuration
+///
+/// New optional fields may be added, create it with `ServerConfig::new`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[non_exhaustive]
 pub struct ServerConfig {
     pub server_port: u16,
     pub password: String,
@@ -37,6 +40,8 @@ pub struct ServerConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub plugin_opts: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugin_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub plugin_mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mode: Option<String>,
@@ -44,6 +49,24 @@ pub struct ServerConfig {
     pub users: Option<Vec<ServerUserConfig>>,
 }
 
+impl ServerConfig {
+    /// Create a configuration with `server_port` and `password`, other fields are left empty
+    pub fn new(server_port: u16, password: String) -> ServerConfig {
+        ServerConfig {
+            server_port,
+            password,
+            method: None,
+            no_delay: None,
+            plugin: None,
+            plugin_opts: None,
+            plugin_args: None,
+            plugin_mode: None,
+            mode: None,
+            users: None,
+        }
+    }
+}
+
 /// `add` request
 pub type AddRequest = ServerConfig;
 
@@ -378,3 +401,26 @@ impl From<Error> for io::Error {
         io::Error::new(ErrorKind::Other, err.to_string())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn add_request_plugin_args() {
+        let req = AddRequest::from_bytes(
+            br#"add: {"server_port":8388,"password":"hello-kitty","plugin":"v2ray-plugin","plugin_args":["-fast-open","-loglevel","none"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            req.plugin_args.as_deref(),
+            Some(&["-fast-open".to_owned(), "-loglevel".to_owned(), "none".to_owned()][..])
+        );
+
+        let req = AddRequest::from_bytes(br#"add: {"server_port":8388,"password":"hello-kitty"}"#).unwrap();
+        assert_eq!(req.plugin_args, None);
+        assert!(!String::from_utf8(req.to_bytes().unwrap())
+            .unwrap()
+            .contains("plugin_args"));
+    }
+}
@@ -99,7 +99,8 @@ fn set_tcp_keepalive(socket: &Socket, tcp: &TcpSocketOpts) -> io::Result<()> {
 }
 
 #[inline(always)]
-fn socket_call_warp<S: AsRawFd, F: FnOnce(&Socket) -> io::Result<()>>(stream: &S, f: F) -> io::Result<()> {
+/// Call `f` with a `Socket` borrowing `stream`'s file descriptor
+pub fn socket_call_warp<S: AsRawFd, T, F: FnOnce(&Socket) -> io::Result<T>>(stream: &S, f: F) -> io::Result<T> {
     let socket = unsafe { Socket::from_raw_fd(stream.as_raw_fd()) };
     let result = f(&socket);
     let _ = socket.into_raw_fd();
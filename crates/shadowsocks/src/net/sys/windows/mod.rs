@@ -529,7 +529,8 @@ pub async fn bind_outbound_udp_socket(bind_addr: &SocketAddr, opts: &ConnectOpts
 }
 
 #[inline(always)]
-fn socket_call_warp<S: AsRawSocket, F: FnOnce(&Socket) -> io::Result<()>>(stream: &S, f: F) -> io::Result<()> {
+/// Call `f` with a `Socket` borrowing `stream`'s socket handle
+pub fn socket_call_warp<S: AsRawSocket, T, F: FnOnce(&Socket) -> io::Result<T>>(stream: &S, f: F) -> io::Result<T> {
     let socket = unsafe { Socket::from_raw_socket(stream.as_raw_socket()) };
     let result = f(&socket);
     let _ = socket.into_raw_socket();
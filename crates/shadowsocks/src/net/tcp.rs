@@ -28,6 +28,7 @@ use super::{
         set_common_sockopt_after_accept,
         set_tcp_fastopen,
         socket_bind_dual_stack,
+        socket_call_warp,
         TcpStream as SysTcpStream,
     },
     AcceptOpts,
@@ -102,6 +103,26 @@ impl TcpStream {
     pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
         self.0.set_nodelay(nodelay)
     }
+
+    /// Gets the value of the `SO_SNDBUF` option on this socket.
+    pub fn send_buffer_size(&self) -> io::Result<u32> {
+        socket_call_warp(self, |socket| socket.send_buffer_size().map(|size| size as u32))
+    }
+
+    /// Sets the value of the `SO_SNDBUF` option on this socket.
+    pub fn set_send_buffer_size(&self, size: u32) -> io::Result<()> {
+        socket_call_warp(self, |socket| socket.set_send_buffer_size(size as usize))
+    }
+
+    /// Gets the value of the `SO_RCVBUF` option on this socket.
+    pub fn recv_buffer_size(&self) -> io::Result<u32> {
+        socket_call_warp(self, |socket| socket.recv_buffer_size().map(|size| size as u32))
+    }
+
+    /// Sets the value of the `SO_RCVBUF` option on this socket.
+    pub fn set_recv_buffer_size(&self, size: u32) -> io::Result<()> {
+        socket_call_warp(self, |socket| socket.set_recv_buffer_size(size as usize))
+    }
 }
 
 impl AsyncRead for TcpStream {
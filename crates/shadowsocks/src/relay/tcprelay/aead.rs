@@ -31,6 +31,10 @@
 //! |      2       |     Fixed     |   Variable   |   Fixed    |
 //! +--------------+---------------+--------------+------------+
 //! ```
+//!
+//! Rekeying is an extension of this crate, both sides must enable it. The writer sends an empty chunk with the
+//! current key, then a new salt, and all following chunks are encrypted with the subkey derived from the new salt.
+//! The writer never sends empty data chunks, so markers couldn't be confused with them.
 use std::{
     io::{self, ErrorKind},
     marker::Unpin,
@@ -51,6 +55,8 @@ use crate::{
     crypto::{v1::Cipher, CipherKind},
 };
 
+use super::{buffer_pool, utils::sniff_plaintext_protocol};
+
 /// AEAD packet payload must be smaller than 0x3FFF
 pub const MAX_PACKET_SIZE: usize = 0x3FFF;
 
@@ -61,6 +67,8 @@ pub enum ProtocolError {
     IoError(#[from] io::Error),
     #[error("header too short, expecting {0} bytes, but found {1} bytes")]
     HeaderTooShort(usize, usize),
+    #[error("received {0} instead of salt, peer is not speaking shadowsocks with the same method")]
+    UnexpectedPlaintext(&'static str),
     #[error("decrypt data failed")]
     DecryptDataError,
     #[error("decrypt length failed")]
@@ -81,11 +89,23 @@ impl From<ProtocolError> for io::Error {
     }
 }
 
+/// Replace `err` with `UnexpectedPlaintext` if the first `received` bytes are obviously another protocol
+///
+/// Only for reads or decryptions that have already failed, random salts could look like plaintext too.
+fn explain_plaintext(received: &[u8], err: ProtocolError) -> ProtocolError {
+    match sniff_plaintext_protocol(received) {
+        Some(protocol) => ProtocolError::UnexpectedPlaintext(protocol),
+        None => err,
+    }
+}
+
 enum DecryptReadState {
     WaitSalt { key: Bytes },
     ReadLength,
     ReadData { length: usize },
     BufferedData { pos: usize },
+    ReadRekey,
+    WaitRekeySalt,
 }
 
 /// Reader wrapper that will decrypt data automatically
@@ -94,8 +114,13 @@ pub struct DecryptedReader {
     cipher: Option<Cipher>,
     buffer: BytesMut,
     method: CipherKind,
+    key: Bytes,
     salt: Option<Bytes>,
     has_handshaked: bool,
+    fallback_methods: Vec<(CipherKind, Bytes)>,
+    fallback_method: Option<Bytes>,
+    pending: BytesMut,
+    accept_rekey: bool,
 }
 
 impl DecryptedReader {
@@ -108,8 +133,13 @@ impl DecryptedReader {
                 cipher: None,
                 buffer: BytesMut::with_capacity(method.salt_len()),
                 method,
+                key: Bytes::copy_from_slice(key),
                 salt: None,
                 has_handshaked: false,
+                fallback_methods: Vec::new(),
+                fallback_method: None,
+                pending: BytesMut::new(),
+                accept_rekey: false,
             }
         } else {
             DecryptedReader {
@@ -117,8 +147,13 @@ impl DecryptedReader {
                 cipher: Some(Cipher::new(method, key, &[])),
                 buffer: BytesMut::with_capacity(2 + method.tag_len()),
                 method,
+                key: Bytes::copy_from_slice(key),
                 salt: None,
                 has_handshaked: false,
+                fallback_methods: Vec::new(),
+                fallback_method: None,
+                pending: BytesMut::new(),
+                accept_rekey: false,
             }
         }
     }
@@ -127,6 +162,22 @@ impl DecryptedReader {
         self.salt.as_deref()
     }
 
+    /// Also accept `fallback_methods` with their keys, which are tried in order if the first chunk couldn't be
+    /// decrypted by the primary method
+    pub fn set_fallback_methods(&mut self, fallback_methods: &[(CipherKind, Bytes)]) {
+        self.fallback_methods = fallback_methods.to_vec();
+    }
+
+    /// Get the fallback method and its key that has decrypted the first chunk
+    pub fn fallback_method(&self) -> Option<(CipherKind, &[u8])> {
+        self.fallback_method.as_deref().map(|key| (self.method, key))
+    }
+
+    /// Treat empty chunks as rekey markers, which are followed by a new salt
+    pub fn set_accept_rekey(&mut self, accept_rekey: bool) {
+        self.accept_rekey = accept_rekey;
+    }
+
     /// Attempt to read decrypted data from stream
     pub fn poll_read_decrypted<S>(
         &mut self,
@@ -142,7 +193,11 @@ impl DecryptedReader {
             match self.state {
                 DecryptReadState::WaitSalt { ref key } => {
                     let key = unsafe { &*(key.as_ref() as *const _) };
-                    ready!(self.poll_read_salt(cx, stream, key))?;
+                    if self.fallback_methods.is_empty() {
+                        ready!(self.poll_read_salt(cx, stream, key))?;
+                    } else {
+                        ready!(self.poll_read_salt_detect_method(cx, stream, key))?;
+                    }
 
                     self.buffer.clear();
                     self.state = DecryptReadState::ReadLength;
@@ -153,6 +208,11 @@ impl DecryptedReader {
                     None => {
                         return Ok(()).into();
                     }
+                    Some(0) if self.accept_rekey => {
+                        self.buffer.clear();
+                        self.state = DecryptReadState::ReadRekey;
+                        self.buffer.reserve(self.method.tag_len());
+                    }
                     Some(length) => {
                         self.buffer.clear();
                         self.state = DecryptReadState::ReadData { length };
@@ -164,6 +224,22 @@ impl DecryptedReader {
 
                     self.state = DecryptReadState::BufferedData { pos: 0 };
                 }
+                DecryptReadState::ReadRekey => {
+                    // Authenticate the empty chunk before accepting the new salt
+                    ready!(self.poll_read_data(cx, context, stream, 0))?;
+
+                    self.buffer.clear();
+                    self.state = DecryptReadState::WaitRekeySalt;
+                    self.buffer.reserve(self.method.salt_len());
+                }
+                DecryptReadState::WaitRekeySalt => {
+                    let key = self.key.clone();
+                    ready!(self.poll_read_salt(cx, stream, &key))?;
+
+                    self.buffer.clear();
+                    self.state = DecryptReadState::ReadLength;
+                    self.buffer.reserve(2 + self.method.tag_len());
+                }
                 DecryptReadState::BufferedData { ref mut pos } => {
                     if *pos < self.buffer.len() {
                         let buffered = &self.buffer[*pos..];
@@ -190,9 +266,16 @@ impl DecryptedReader {
     {
         let salt_len = self.method.salt_len();
 
-        let n = ready!(self.poll_read_exact(cx, stream, salt_len))?;
-        if n < salt_len {
-            return Err(io::Error::from(ErrorKind::UnexpectedEof).into()).into();
+        match ready!(self.poll_read_exact(cx, stream, salt_len)) {
+            Ok(n) if n == salt_len => {}
+            result => {
+                // Peer closed before sending a complete salt
+                let err = match result {
+                    Err(err) => err,
+                    Ok(..) => io::Error::from(ErrorKind::UnexpectedEof),
+                };
+                return Err(explain_plaintext(&self.buffer, err.into())).into();
+            }
         }
 
         let salt = &self.buffer[..salt_len];
@@ -210,6 +293,84 @@ impl DecryptedReader {
         Ok(()).into()
     }
 
+    /// Read salt and the first length chunk, then choose the method that could decrypt it
+    ///
+    /// Bytes after the salt are kept in `pending` for reading the first chunk again with the chosen method.
+    fn poll_read_salt_detect_method<S>(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        stream: &mut S,
+        key: &[u8],
+    ) -> Poll<ProtocolResult<()>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        let header_len = self
+            .fallback_methods
+            .iter()
+            .map(|(method, _)| *method)
+            .chain(Some(self.method))
+            .map(|method| method.salt_len() + 2 + method.tag_len())
+            .max()
+            .unwrap_or(0);
+
+        // Valid clients always send a chunk of target address after the length, so it won't wait forever
+        self.buffer.reserve(header_len);
+        match ready!(self.poll_read_exact(cx, stream, header_len)) {
+            Ok(n) if n == header_len => {}
+            result => {
+                // Peer closed before sending a complete header
+                let err = match result {
+                    Err(err) => err,
+                    Ok(..) => io::Error::from(ErrorKind::UnexpectedEof),
+                };
+                return Err(explain_plaintext(&self.buffer, err.into())).into();
+            }
+        }
+
+        let buffer = &self.buffer;
+        let decrypt_length = |method: CipherKind, key: &[u8]| -> bool {
+            let salt_len = method.salt_len();
+            let mut length = buffer[salt_len..salt_len + 2 + method.tag_len()].to_vec();
+            let mut cipher = Cipher::new(method, key, &buffer[..salt_len]);
+            cipher.decrypt_packet(&mut length)
+        };
+
+        let fallback = if decrypt_length(self.method, key) {
+            None
+        } else {
+            match self
+                .fallback_methods
+                .iter()
+                .find(|(method, key)| decrypt_length(*method, key))
+            {
+                Some((method, key)) => Some((*method, key.clone())),
+                None => return Err(explain_plaintext(buffer, ProtocolError::DecryptLengthError)).into(),
+            }
+        };
+
+        if let Some((method, key)) = fallback {
+            trace!("AEAD client is using fallback method {}", method);
+            self.method = method;
+            self.key = key.clone();
+            self.fallback_method = Some(key);
+        }
+
+        let method = self.method;
+        let key = self.fallback_method.as_deref().unwrap_or(key);
+
+        let salt = self.buffer.split_to(method.salt_len()).freeze();
+        self.pending = self.buffer.split();
+
+        trace!("got AEAD salt {:?}", ByteStr::new(&salt));
+
+        self.cipher = Some(Cipher::new(method, key, &salt));
+        // #442 Remember salt in filter after first successful decryption.
+        self.salt = Some(salt);
+
+        Ok(()).into()
+    }
+
     fn poll_read_length<S>(&mut self, cx: &mut task::Context<'_>, stream: &mut S) -> Poll<ProtocolResult<Option<usize>>>
     where
         S: AsyncRead + Unpin + ?Sized,
@@ -224,7 +385,14 @@ impl DecryptedReader {
         let cipher = self.cipher.as_mut().expect("cipher is None");
 
         let m = &mut self.buffer[..length_len];
-        let length = DecryptedReader::decrypt_length(cipher, m)?;
+        let length = match DecryptedReader::decrypt_length(cipher, m) {
+            // Salt is kept until the first chunk is decrypted
+            Err(ProtocolError::DecryptLengthError) => match self.salt {
+                Some(ref salt) => return Err(explain_plaintext(salt, ProtocolError::DecryptLengthError)).into(),
+                None => return Err(ProtocolError::DecryptLengthError).into(),
+            },
+            result => result?,
+        };
 
         Ok(Some(length)).into()
     }
@@ -273,6 +441,14 @@ impl DecryptedReader {
 
         while self.buffer.len() < size {
             let remaining = size - self.buffer.len();
+
+            // Bytes that have been read ahead while choosing the method
+            if !self.pending.is_empty() {
+                let n = remaining.min(self.pending.len());
+                let pending = self.pending.split_to(n);
+                self.buffer.extend_from_slice(&pending);
+                continue;
+            }
             let buffer = &mut self.buffer.chunk_mut()[..remaining];
 
             let mut read_buf =
@@ -329,23 +505,28 @@ enum EncryptWriteState {
 /// Writer wrapper that will encrypt data automatically
 pub struct EncryptedWriter {
     cipher: Cipher,
+    method: CipherKind,
     buffer: BytesMut,
     state: EncryptWriteState,
     salt: Bytes,
+    // Key and nonce of the next subkey
+    pending_rekey: Option<(Bytes, Bytes)>,
 }
 
 impl EncryptedWriter {
     /// Creates a new EncryptedWriter
     pub fn new(method: CipherKind, key: &[u8], nonce: &[u8]) -> EncryptedWriter {
         // nonce should be sent with the first packet
-        let mut buffer = BytesMut::with_capacity(nonce.len());
+        let mut buffer = buffer_pool::take(nonce.len());
         buffer.put(nonce);
 
         EncryptedWriter {
             cipher: Cipher::new(method, key, nonce),
+            method,
             buffer,
             state: EncryptWriteState::AssemblePacket,
             salt: Bytes::copy_from_slice(nonce),
+            pending_rekey: None,
         }
     }
 
@@ -354,6 +535,37 @@ impl EncryptedWriter {
         self.salt.as_ref()
     }
 
+    /// Switch to a subkey derived from `key` and a new `nonce`, which is sent before the next chunk
+    ///
+    /// The peer's reader must accept rekeying, see `DecryptedReader::set_accept_rekey`.
+    pub fn rekey(&mut self, key: &[u8], nonce: &[u8]) {
+        self.pending_rekey = Some((Bytes::copy_from_slice(key), Bytes::copy_from_slice(nonce)));
+    }
+
+    fn put_chunk(&mut self, buf: &[u8]) {
+        // Step 1. Append Length
+        let length_size = 2 + self.cipher.tag_len();
+        self.buffer.reserve(length_size);
+
+        let mbuf = &mut self.buffer.chunk_mut()[..length_size];
+        let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
+
+        self.buffer.put_u16(buf.len() as u16);
+        self.cipher.encrypt_packet(mbuf);
+        unsafe { self.buffer.advance_mut(self.cipher.tag_len()) };
+
+        // Step 2. Append data
+        let data_size = buf.len() + self.cipher.tag_len();
+        self.buffer.reserve(data_size);
+
+        let mbuf = &mut self.buffer.chunk_mut()[..data_size];
+        let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
+
+        self.buffer.put_slice(buf);
+        self.cipher.encrypt_packet(mbuf);
+        unsafe { self.buffer.advance_mut(self.cipher.tag_len()) };
+    }
+
     /// Attempt to write encrypted data into the writer
     pub fn poll_write_encrypted<S>(
         &mut self,
@@ -371,29 +583,21 @@ impl EncryptedWriter {
         loop {
             match self.state {
                 EncryptWriteState::AssemblePacket => {
-                    // Step 1. Append Length
-                    let length_size = 2 + self.cipher.tag_len();
-                    self.buffer.reserve(length_size);
+                    // Rekey marker, an empty chunk followed by the new salt
+                    if let Some((key, nonce)) = self.pending_rekey.take() {
+                        self.put_chunk(&[]);
 
-                    let mbuf = &mut self.buffer.chunk_mut()[..length_size];
-                    let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
-
-                    self.buffer.put_u16(buf.len() as u16);
-                    self.cipher.encrypt_packet(mbuf);
-                    unsafe { self.buffer.advance_mut(self.cipher.tag_len()) };
-
-                    // Step 2. Append data
-                    let data_size = buf.len() + self.cipher.tag_len();
-                    self.buffer.reserve(data_size);
-
-                    let mbuf = &mut self.buffer.chunk_mut()[..data_size];
-                    let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
+                        // `salt` is kept as the one sent in handshake, which identifies this stream
+                        self.buffer.put_slice(&nonce);
+                        self.cipher = Cipher::new(self.method, &key, &nonce);
+                    }
 
-                    self.buffer.put_slice(buf);
-                    self.cipher.encrypt_packet(mbuf);
-                    unsafe { self.buffer.advance_mut(self.cipher.tag_len()) };
+                    // Empty chunks are rekey markers, so empty writes send nothing but the pending salt
+                    if !buf.is_empty() {
+                        self.put_chunk(buf);
+                    }
 
-                    // Step 3. Write all
+                    // Write all
                     self.state = EncryptWriteState::Writing { pos: 0 };
                 }
                 EncryptWriteState::Writing { ref mut pos } => {
@@ -415,3 +619,9 @@ impl EncryptedWriter {
         }
     }
 }
+
+impl Drop for EncryptedWriter {
+    fn drop(&mut self) {
+        buffer_pool::give_back(&mut self.buffer);
+    }
+}
@@ -43,6 +43,8 @@
 //! |      2       |     Fixed     |   Variable   |   Fixed    |
 //! +--------------+---------------+--------------+------------+
 //! ```
+//!
+//! Rekeying works the same as AEAD, see `super::aead`.
 use std::{
     io::{self, Cursor, ErrorKind, Read},
     marker::Unpin,
@@ -63,10 +65,10 @@ use aes::{
 use byte_string::ByteStr;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::ready;
-use log::{error, trace};
+use log::{debug, error, trace};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
-use super::{crypto_io::StreamType, proxy_stream::protocol::v2::SERVER_STREAM_TIMESTAMP_MAX_DIFF};
+use super::{buffer_pool, crypto_io::StreamType, utils::sniff_plaintext_protocol};
 use crate::{
     config::{method_support_eih, ServerUserManager},
     context::Context,
@@ -93,6 +95,8 @@ pub enum ProtocolError {
     IoError(#[from] io::Error),
     #[error("header too short, expecting {0} bytes, but found {1} bytes")]
     HeaderTooShort(usize, usize),
+    #[error("received {0} instead of salt, peer is not speaking shadowsocks with the same method")]
+    UnexpectedPlaintext(&'static str),
     #[error("missing extended identity header")]
     MissingExtendedIdentityHeader,
     #[error("invalid client user identity {:?}", ByteStr::new(.0))]
@@ -105,7 +109,7 @@ pub enum ProtocolError {
     DecryptLengthError,
     #[error("invalid stream type, expecting {0:#x}, but found {1:#x}")]
     InvalidStreamType(u8, u8),
-    #[error("invalid timestamp {0} - now {1} = {}", *.0 as i64 - *.1 as i64)]
+    #[error("invalid timestamp {0} - now {1} = {}, clocks may be out of sync", *.0 as i64 - *.1 as i64)]
     InvalidTimestamp(u64, u64),
 }
 
@@ -121,11 +125,23 @@ impl From<ProtocolError> for io::Error {
     }
 }
 
+/// Replace `err` with `UnexpectedPlaintext` if the first `received` bytes are obviously another protocol
+///
+/// Only for reads or decryptions that have already failed, random salts could look like plaintext too.
+fn explain_plaintext(received: &[u8], err: ProtocolError) -> ProtocolError {
+    match sniff_plaintext_protocol(received) {
+        Some(protocol) => ProtocolError::UnexpectedPlaintext(protocol),
+        None => err,
+    }
+}
+
 enum DecryptReadState {
     ReadHeader { key: Bytes },
     ReadLength,
     ReadData { length: usize },
     BufferedData { pos: usize },
+    ReadRekey,
+    WaitRekeySalt,
 }
 
 /// Reader wrapper that will decrypt data automatically
@@ -135,12 +151,14 @@ pub struct DecryptedReader {
     cipher: Option<TcpCipher>,
     buffer: BytesMut,
     method: CipherKind,
+    key: Bytes,
     salt: Option<Bytes>,
     request_salt: Option<Bytes>,
     data_chunk_count: u64,
     user_manager: Option<Arc<ServerUserManager>>,
     user_key: Option<Bytes>,
     has_handshaked: bool,
+    accept_rekey: bool,
 }
 
 impl DecryptedReader {
@@ -163,12 +181,14 @@ impl DecryptedReader {
                 cipher: None,
                 buffer: BytesMut::new(),
                 method,
+                key: Bytes::copy_from_slice(key),
                 salt: None,
                 request_salt: None,
                 data_chunk_count: 0,
                 user_manager,
                 user_key: None,
                 has_handshaked: false,
+                accept_rekey: false,
             }
         } else {
             DecryptedReader {
@@ -179,12 +199,14 @@ impl DecryptedReader {
                 cipher: Some(TcpCipher::new(method, key, &[])),
                 buffer: BytesMut::new(),
                 method,
+                key: Bytes::copy_from_slice(key),
                 salt: None,
                 request_salt: None,
                 data_chunk_count: 0,
                 user_manager,
                 user_key: None,
                 has_handshaked: false,
+                accept_rekey: false,
             }
         }
     }
@@ -197,6 +219,11 @@ impl DecryptedReader {
         self.request_salt.as_deref().filter(|&n| !n.is_empty())
     }
 
+    /// Treat empty chunks as rekey markers, which are followed by a new salt
+    pub fn set_accept_rekey(&mut self, accept_rekey: bool) {
+        self.accept_rekey = accept_rekey;
+    }
+
     /// Attempt to read decrypted data from stream
     pub fn poll_read_decrypted<S>(
         &mut self,
@@ -228,6 +255,11 @@ impl DecryptedReader {
                     None => {
                         return Ok(()).into();
                     }
+                    Some(0) if self.accept_rekey => {
+                        self.buffer.clear();
+                        self.state = DecryptReadState::ReadRekey;
+                        self.buffer.reserve(self.method.tag_len());
+                    }
                     Some(length) => {
                         self.buffer.clear();
                         self.state = DecryptReadState::ReadData { length };
@@ -240,6 +272,31 @@ impl DecryptedReader {
                     self.state = DecryptReadState::BufferedData { pos: 0 };
                     self.data_chunk_count = self.data_chunk_count.wrapping_add(1);
                 }
+                DecryptReadState::ReadRekey => {
+                    // Authenticate the empty chunk before accepting the new salt
+                    ready!(self.poll_read_data(cx, stream, 0))?;
+
+                    self.buffer.clear();
+                    self.state = DecryptReadState::WaitRekeySalt;
+                    self.buffer.reserve(self.method.salt_len());
+                }
+                DecryptReadState::WaitRekeySalt => {
+                    let salt_len = self.method.salt_len();
+                    let n = ready!(self.poll_read_exact(cx, stream, salt_len))?;
+                    if n < salt_len {
+                        return Err(io::Error::from(ErrorKind::UnexpectedEof).into()).into();
+                    }
+
+                    let salt = &self.buffer[..salt_len];
+                    trace!("got AEAD rekey salt {:?}", ByteStr::new(salt));
+
+                    let key = self.user_key.as_ref().unwrap_or(&self.key);
+                    self.cipher = Some(TcpCipher::new(self.method, key, salt));
+
+                    self.buffer.clear();
+                    self.state = DecryptReadState::ReadLength;
+                    self.buffer.reserve(2 + self.method.tag_len());
+                }
                 DecryptReadState::BufferedData { ref mut pos } => {
                     if *pos < self.buffer.len() {
                         let buffered = &self.buffer[*pos..];
@@ -291,7 +348,8 @@ impl DecryptedReader {
             // EOF.
             return Ok(None).into();
         } else if header_buf.len() != header_len {
-            return Err(ProtocolError::HeaderTooShort(header_len, header_buf.len())).into();
+            let err = ProtocolError::HeaderTooShort(header_len, header_buf.len());
+            return Err(explain_plaintext(header_buf, err)).into();
         }
 
         let (salt, mut header_chunk) = header_buf.split_at_mut(salt_len);
@@ -335,7 +393,8 @@ impl DecryptedReader {
 
                 match user_manager.get_user_by_hash(user_hash) {
                     None => {
-                        return Err(ProtocolError::InvalidClientUser(Bytes::copy_from_slice(user_hash))).into();
+                        let err = ProtocolError::InvalidClientUser(Bytes::copy_from_slice(user_hash));
+                        return Err(explain_plaintext(salt, err)).into();
                     }
                     Some(user) => {
                         trace!("{:?} chosen by EIH", user);
@@ -352,7 +411,7 @@ impl DecryptedReader {
 
         // Decrypt the header chunk
         if !cipher.decrypt_packet(header_chunk) {
-            return Err(ProtocolError::DecryptHeaderChunkError).into();
+            return Err(explain_plaintext(salt, ProtocolError::DecryptHeaderChunkError)).into();
         }
 
         let mut header_reader = Cursor::new(header_chunk);
@@ -368,7 +427,11 @@ impl DecryptedReader {
 
         let timestamp = header_reader.get_u64();
         let now = get_now_timestamp();
-        if now.abs_diff(timestamp) > SERVER_STREAM_TIMESTAMP_MAX_DIFF {
+        if let Err(max_diff) = context.check_aead_2022_timestamp(timestamp, now) {
+            debug!(
+                "AEAD-2022 timestamp {} exceeds the allowed difference {}s from now {}",
+                timestamp, max_diff, now
+            );
             return Err(ProtocolError::InvalidTimestamp(timestamp, now)).into();
         }
 
@@ -527,6 +590,8 @@ pub struct EncryptedWriter {
     state: EncryptWriteState,
     salt: Bytes,
     request_salt: Option<Bytes>,
+    // Key and nonce of the next subkey
+    pending_rekey: Option<(Bytes, Bytes)>,
 }
 
 impl EncryptedWriter {
@@ -545,7 +610,7 @@ impl EncryptedWriter {
         identity_keys: &[Bytes],
     ) -> EncryptedWriter {
         // nonce should be sent with the first packet
-        let mut buffer = BytesMut::with_capacity(nonce.len() + identity_keys.len() * 16);
+        let mut buffer = buffer_pool::take(nonce.len() + identity_keys.len() * 16);
         buffer.put(nonce);
 
         // Extensible Identity Headers
@@ -615,6 +680,7 @@ impl EncryptedWriter {
             state: EncryptWriteState::AssembleHeader,
             salt: Bytes::copy_from_slice(nonce),
             request_salt: None,
+            pending_rekey: None,
         }
     }
 
@@ -634,6 +700,37 @@ impl EncryptedWriter {
         self.cipher = TcpCipher::new(self.method, key, &self.salt);
     }
 
+    /// Switch to a subkey derived from `key` and a new `nonce`, which is sent before the next chunk after the header
+    ///
+    /// The peer's reader must accept rekeying, see `DecryptedReader::set_accept_rekey`.
+    pub fn rekey(&mut self, key: &[u8], nonce: &[u8]) {
+        self.pending_rekey = Some((Bytes::copy_from_slice(key), Bytes::copy_from_slice(nonce)));
+    }
+
+    fn put_chunk(&mut self, buf: &[u8]) {
+        // Step 1. Append Length
+        let length_size = 2 + self.cipher.tag_len();
+        self.buffer.reserve(length_size);
+
+        let mbuf = &mut self.buffer.chunk_mut()[..length_size];
+        let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
+
+        self.buffer.put_u16(buf.len() as u16);
+        self.cipher.encrypt_packet(mbuf);
+        unsafe { self.buffer.advance_mut(self.cipher.tag_len()) };
+
+        // Step 2. Append data
+        let data_size = buf.len() + self.cipher.tag_len();
+        self.buffer.reserve(data_size);
+
+        let mbuf = &mut self.buffer.chunk_mut()[..data_size];
+        let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
+
+        self.buffer.put_slice(buf);
+        self.cipher.encrypt_packet(mbuf);
+        unsafe { self.buffer.advance_mut(self.cipher.tag_len()) };
+    }
+
     /// Attempt to write encrypted data into the writer
     pub fn poll_write_encrypted<S>(
         &mut self,
@@ -691,29 +788,21 @@ impl EncryptedWriter {
                 }
 
                 EncryptWriteState::AssemblePacket => {
-                    // Step 1. Append Length
-                    let length_size = 2 + self.cipher.tag_len();
-                    self.buffer.reserve(length_size);
-
-                    let mbuf = &mut self.buffer.chunk_mut()[..length_size];
-                    let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
-
-                    self.buffer.put_u16(buf.len() as u16);
-                    self.cipher.encrypt_packet(mbuf);
-                    unsafe { self.buffer.advance_mut(self.cipher.tag_len()) };
-
-                    // Step 2. Append data
-                    let data_size = buf.len() + self.cipher.tag_len();
-                    self.buffer.reserve(data_size);
+                    // Rekey marker, an empty chunk followed by the new salt
+                    if let Some((key, nonce)) = self.pending_rekey.take() {
+                        self.put_chunk(&[]);
 
-                    let mbuf = &mut self.buffer.chunk_mut()[..data_size];
-                    let mbuf = unsafe { slice::from_raw_parts_mut(mbuf.as_mut_ptr(), mbuf.len()) };
+                        // `salt` is kept as the one sent in handshake, which identifies this stream
+                        self.buffer.put_slice(&nonce);
+                        self.cipher = TcpCipher::new(self.method, &key, &nonce);
+                    }
 
-                    self.buffer.put_slice(buf);
-                    self.cipher.encrypt_packet(mbuf);
-                    unsafe { self.buffer.advance_mut(self.cipher.tag_len()) };
+                    // Empty chunks are rekey markers, so empty writes send nothing but the pending salt
+                    if !buf.is_empty() {
+                        self.put_chunk(buf);
+                    }
 
-                    // Step 3. Write all
+                    // Write all
                     self.state = EncryptWriteState::Writing { pos: 0 };
                 }
                 EncryptWriteState::Writing { ref mut pos } => {
@@ -735,3 +824,9 @@ impl EncryptedWriter {
         }
     }
 }
+
+impl Drop for EncryptedWriter {
+    fn drop(&mut self) {
+        buffer_pool::give_back(&mut self.buffer);
+    }
+}
@@ -0,0 +1,69 @@
+//! Pool of encryption buffers of TCP writers
+//!
+//! Every `EncryptedWriter` keeps a buffer for assembling chunks, which grows to about one maximum chunk
+//! (0x3FFF + tags) on the first large write and stays until the connection is closed. Under high connection churn
+//! that is one or more allocations (plus reallocations while growing) per connection. Buffers are now taken from a
+//! thread-local pool and returned when the writer is dropped, so a thread serving short-lived connections allocates
+//! at most `POOL_CAPACITY` buffers in steady state instead of one per connection.
+
+use std::{cell::RefCell, mem};
+
+use bytes::BytesMut;
+
+/// Buffers kept in each thread
+const POOL_CAPACITY: usize = 64;
+/// Buffers grown larger than this (for example, by writing a long header) are freed instead of returned
+const MAX_BUFFER_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    static BUFFER_POOL: RefCell<Vec<BytesMut>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Take an empty buffer from the pool, or allocate a new one with `capacity`
+pub fn take(capacity: usize) -> BytesMut {
+    let buffer = BUFFER_POOL.try_with(|pool| pool.borrow_mut().pop()).ok().flatten();
+
+    match buffer {
+        Some(mut buffer) => {
+            buffer.reserve(capacity);
+            buffer
+        }
+        None => BytesMut::with_capacity(capacity),
+    }
+}
+
+/// Return `buffer` to the pool
+pub fn give_back(buffer: &mut BytesMut) {
+    let mut buffer = mem::take(buffer);
+    if buffer.capacity() == 0 || buffer.capacity() > MAX_BUFFER_CAPACITY {
+        return;
+    }
+    buffer.clear();
+
+    // The pool may have been destroyed if the thread is exiting
+    let _ = BUFFER_POOL.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < POOL_CAPACITY {
+            pool.push(buffer);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BufMut;
+
+    use super::*;
+
+    #[test]
+    fn buffer_pool_reuse() {
+        let mut buffer = take(16);
+        buffer.put_slice(&[1u8; 1024]);
+        give_back(&mut buffer);
+        assert_eq!(buffer.capacity(), 0);
+
+        let buffer = take(16);
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 1024);
+    }
+}
@@ -1,6 +1,7 @@
 //! IO facilities for TCP relay
 
 use std::{
+    fmt,
     io,
     marker::Unpin,
     pin::Pin,
@@ -17,7 +18,7 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use crate::{
     config::ServerUserManager,
     context::Context,
-    crypto::{CipherCategory, CipherKind},
+    crypto::{available_ciphers, CipherCategory, CipherKind},
 };
 
 use super::aead::{DecryptedReader as AeadDecryptedReader, EncryptedWriter as AeadEncryptedWriter};
@@ -138,6 +139,25 @@ impl DecryptedReader {
         }
     }
 
+    /// Attempt to read decrypted data from `stream`, returns the number of bytes filled into `buf` by this call
+    ///
+    /// `0` with non-empty `buf` means EOF.
+    #[inline]
+    pub fn poll_read_decrypted_len<S>(
+        &mut self,
+        cx: &mut task::Context<'_>,
+        context: &Context,
+        stream: &mut S,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<ProtocolResult<usize>>
+    where
+        S: AsyncRead + Unpin + ?Sized,
+    {
+        let filled = buf.filled().len();
+        ready!(self.poll_read_decrypted(cx, context, stream, buf))?;
+        Ok(buf.filled().len() - filled).into()
+    }
+
     /// Get received IV (Stream) or Salt (AEAD, AEAD2022)
     pub fn nonce(&self) -> Option<&[u8]> {
         match *self {
@@ -174,6 +194,32 @@ impl DecryptedReader {
         }
     }
 
+    /// Also accept `fallback_methods`, which are tried if the first chunk couldn't be decrypted (AEAD)
+    pub fn set_fallback_methods(&mut self, fallback_methods: &[(CipherKind, Bytes)]) {
+        match *self {
+            DecryptedReader::Aead(ref mut reader) => reader.set_fallback_methods(fallback_methods),
+            _ => panic!("only AEAD cipher could fallback to other methods"),
+        }
+    }
+
+    /// Get the fallback method and its key that has decrypted the first chunk (AEAD)
+    pub fn fallback_method(&self) -> Option<(CipherKind, &[u8])> {
+        match *self {
+            DecryptedReader::Aead(ref reader) => reader.fallback_method(),
+            _ => None,
+        }
+    }
+
+    /// Accept rekeying from peer (AEAD, AEAD2022), ignored by other ciphers
+    pub fn set_accept_rekey(&mut self, accept_rekey: bool) {
+        match *self {
+            DecryptedReader::Aead(ref mut reader) => reader.set_accept_rekey(accept_rekey),
+            #[cfg(feature = "aead-cipher-2022")]
+            DecryptedReader::Aead2022(ref mut reader) => reader.set_accept_rekey(accept_rekey),
+            _ => {}
+        }
+    }
+
     pub fn handshaked(&self) -> bool {
         match *self {
             #[cfg(feature = "stream-cipher")]
@@ -280,13 +326,21 @@ impl EncryptedWriter {
     }
 
     /// Set request nonce (for server stream of AEAD2022)
-    pub fn set_request_nonce(&mut self, request_nonce: Bytes) {
+    ///
+    /// Fails with `Unsupported` if the cipher isn't AEAD-2022.
+    pub fn set_request_nonce(&mut self, request_nonce: Bytes) -> io::Result<()> {
         match *self {
             #[cfg(feature = "aead-cipher-2022")]
-            EncryptedWriter::Aead2022(ref mut writer) => writer.set_request_salt(request_nonce),
+            EncryptedWriter::Aead2022(ref mut writer) => {
+                writer.set_request_salt(request_nonce);
+                Ok(())
+            }
             _ => {
                 let _ = request_nonce;
-                panic!("only AEAD-2022 cipher could send request salt");
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only AEAD-2022 cipher could send request salt",
+                ))
             }
         }
     }
@@ -302,6 +356,24 @@ impl EncryptedWriter {
             }
         }
     }
+
+    /// Switch to a subkey derived from `key` and a new `nonce` before the next chunk (AEAD, AEAD2022)
+    ///
+    /// Fails with `Unsupported` if the cipher isn't AEAD or AEAD-2022.
+    pub fn rekey(&mut self, key: &[u8], nonce: &[u8]) -> io::Result<()> {
+        match *self {
+            EncryptedWriter::Aead(ref mut writer) => writer.rekey(key, nonce),
+            #[cfg(feature = "aead-cipher-2022")]
+            EncryptedWriter::Aead2022(ref mut writer) => writer.rekey(key, nonce),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only AEAD and AEAD-2022 ciphers could rekey",
+                ))
+            }
+        }
+        Ok(())
+    }
 }
 
 /// A bidirectional stream for read/write encrypted data in shadowsocks' tunnel
@@ -310,9 +382,23 @@ pub struct CryptoStream<S> {
     dec: DecryptedReader,
     enc: EncryptedWriter,
     method: CipherKind,
+    // Key of the writer, for rekeying
+    key: Bytes,
     has_handshaked: bool,
 }
 
+impl<S: fmt::Debug> fmt::Debug for CryptoStream<S> {
+    // Keys, salts and nonces are never printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CryptoStream")
+            .field("stream", &self.stream)
+            .field("method", &self.method_name())
+            .field("category", &self.category())
+            .field("has_handshaked", &self.has_handshaked)
+            .finish_non_exhaustive()
+    }
+}
+
 impl<S> CryptoStream<S> {
     /// Create a new CryptoStream with the underlying stream connection
     pub fn from_stream(
@@ -383,6 +469,7 @@ impl<S> CryptoStream<S> {
             dec: DecryptedReader::with_user_manager(stream_ty, method, key, user_manager),
             enc: EncryptedWriter::with_identity(stream_ty, method, key, &iv, identity_keys),
             method,
+            key: Bytes::copy_from_slice(key),
             has_handshaked: false,
         }
     }
@@ -393,6 +480,7 @@ impl<S> CryptoStream<S> {
             dec: DecryptedReader::None,
             enc: EncryptedWriter::None,
             method,
+            key: Bytes::new(),
             has_handshaked: false,
         }
     }
@@ -431,22 +519,68 @@ impl<S> CryptoStream<S> {
     }
 
     /// Set request nonce (for server stream of AEAD2022)
+    ///
+    /// Fails with `Unsupported` if the cipher isn't AEAD-2022, the connection should be dropped.
     #[inline]
-    pub fn set_request_nonce(&mut self, request_nonce: &[u8]) {
+    pub fn set_request_nonce(&mut self, request_nonce: &[u8]) -> io::Result<()> {
         self.enc.set_request_nonce(Bytes::copy_from_slice(request_nonce))
     }
 
+    /// Set request nonce with the received salt, returns `false` if the salt hasn't been received
     #[cfg(feature = "aead-cipher-2022")]
-    pub(crate) fn set_request_nonce_with_received(&mut self) -> bool {
+    pub(crate) fn set_request_nonce_with_received(&mut self) -> io::Result<bool> {
         match self.dec.nonce() {
-            None => false,
+            None => Ok(false),
             Some(nonce) => {
-                self.enc.set_request_nonce(Bytes::copy_from_slice(nonce));
-                true
+                self.enc.set_request_nonce(Bytes::copy_from_slice(nonce))?;
+                Ok(true)
             }
         }
     }
 
+    /// Also accept clients of `fallback_methods` (AEAD server stream)
+    pub(crate) fn set_fallback_methods(&mut self, fallback_methods: &[(CipherKind, Bytes)]) {
+        self.dec.set_fallback_methods(fallback_methods);
+    }
+
+    /// Accept rekeying from peer (AEAD, AEAD2022), see `rekey`
+    pub fn set_accept_rekey(&mut self, accept_rekey: bool) {
+        self.dec.set_accept_rekey(accept_rekey);
+    }
+
+    /// Regenerate salt and switch to the derived subkey for all the following chunks (AEAD, AEAD2022)
+    ///
+    /// The new salt is sent before the next chunk, so it won't take effect until the next write. This is an
+    /// extension of this crate, the peer must have been configured with `set_accept_rekey`, otherwise it will fail
+    /// to decrypt the stream. Stream and none ciphers are not supported.
+    pub fn rekey(&mut self, context: &Context) -> ProtocolResult<()> {
+        let method = self.method;
+        let salt = match method.category() {
+            CipherCategory::Aead => {
+                let mut salt = vec![0u8; method.salt_len()];
+                context.generate_nonce(method, &mut salt, true);
+                salt
+            }
+            #[cfg(feature = "aead-cipher-2022")]
+            CipherCategory::Aead2022 => {
+                let mut salt = vec![0u8; method.salt_len()];
+                context.generate_nonce(method, &mut salt, false);
+                salt
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("{method} doesn't support rekeying, only AEAD and AEAD-2022 ciphers do"),
+                )
+                .into());
+            }
+        };
+        trace!("generated AEAD rekey salt {:?}", ByteStr::new(&salt));
+
+        self.enc.rekey(&self.key, &salt)?;
+        Ok(())
+    }
+
     /// Get remaining bytes in the current data chunk
     ///
     /// Returning (DataChunkCount, RemainingBytes)
@@ -468,6 +602,18 @@ pub trait CryptoRead {
         context: &Context,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<ProtocolResult<()>>;
+
+    /// Same as `poll_read_decrypted`, but returns the number of decrypted bytes filled into `buf` by this call
+    fn poll_read_decrypted_len(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        context: &Context,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<ProtocolResult<usize>> {
+        let filled = buf.filled().len();
+        ready!(self.poll_read_decrypted(cx, context, buf))?;
+        Ok(buf.filled().len() - filled).into()
+    }
 }
 
 /// Cryptographic writer trait
@@ -484,6 +630,21 @@ impl<S> CryptoStream<S> {
     pub fn method(&self) -> CipherKind {
         self.method
     }
+
+    /// Get canonical name of the encryption method, for example, `"2022-blake3-aes-256-gcm"`
+    pub fn method_name(&self) -> &'static str {
+        let name = self.method.to_string();
+        available_ciphers()
+            .iter()
+            .copied()
+            .find(|n| *n == name)
+            .unwrap_or("unknown")
+    }
+
+    /// Get category of the encryption method
+    pub fn category(&self) -> CipherCategory {
+        self.method.category()
+    }
 }
 
 impl<S> CryptoRead for CryptoStream<S>
@@ -502,17 +663,30 @@ where
             ref mut dec,
             ref mut enc,
             ref mut stream,
+            ref mut method,
+            ref mut key,
             ref mut has_handshaked,
-            ..
         } = *self;
         ready!(dec.poll_read_decrypted(cx, context, stream, buf))?;
 
         if !*has_handshaked && dec.handshaked() {
             *has_handshaked = true;
 
+            // Client is using one of the fallback methods, respond with the same method
+            if let Some((fallback_method, fallback_key)) = dec.fallback_method() {
+                let mut local_salt = vec![0u8; fallback_method.salt_len()];
+                context.generate_nonce(fallback_method, &mut local_salt, true);
+                trace!("generated AEAD cipher salt {:?}", ByteStr::new(&local_salt));
+
+                *enc = EncryptedWriter::new(StreamType::Server, fallback_method, fallback_key, &local_salt);
+                *method = fallback_method;
+                *key = Bytes::copy_from_slice(fallback_key);
+            }
+
             // Reset writer cipher with authenticated user key
             if let Some(user_key) = dec.user_key() {
                 enc.reset_cipher_with_key(user_key);
+                *key = Bytes::copy_from_slice(user_key);
             }
         }
 
@@ -556,3 +730,299 @@ where
         Pin::new(&mut self.stream).poll_shutdown(cx).map_err(Into::into)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use futures::future;
+
+    use crate::config::ServerType;
+
+    use super::*;
+
+    async fn read_decrypted(method: CipherKind, mut data: &[u8]) -> ProtocolResult<()> {
+        let context = Context::new(ServerType::Local);
+        let key = vec![0u8; method.key_len()];
+        let mut reader = DecryptedReader::new(StreamType::Server, method, &key);
+
+        let mut buffer = [0u8; 1024];
+        let mut read_buf = ReadBuf::new(&mut buffer);
+        future::poll_fn(|cx| reader.poll_read_decrypted(cx, &context, &mut data, &mut read_buf)).await
+    }
+
+    #[tokio::test]
+    async fn decrypted_reader_plaintext_http() {
+        let mut methods = vec![CipherKind::AES_256_GCM];
+        #[cfg(feature = "aead-cipher-2022")]
+        methods.push(CipherKind::AEAD2022_BLAKE3_AES_256_GCM);
+
+        for method in methods {
+            let response = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            let err = read_decrypted(method, response).await.unwrap_err();
+            assert!(err.to_string().contains("HTTP response"), "{}: {}", method, err);
+
+            // Closed before sending a complete salt
+            let err = read_decrypted(method, b"HTTP/1.0 200 OK\r\n").await.unwrap_err();
+            assert!(err.to_string().contains("HTTP response"), "{}: {}", method, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn decrypted_reader_plaintext_like_salt() {
+        let mut methods = vec![CipherKind::AES_256_GCM];
+        #[cfg(feature = "aead-cipher-2022")]
+        methods.push(CipherKind::AEAD2022_BLAKE3_AES_256_GCM);
+
+        for method in methods {
+            let context = Context::new(ServerType::Server);
+            let key = vec![0u8; method.key_len()];
+            // Random salts could start with anything
+            let mut salt = vec![0u8; method.salt_len()];
+            salt[..4].copy_from_slice(b"GET ");
+
+            let mut encrypted = Vec::new();
+            let mut writer = EncryptedWriter::new(StreamType::Client, method, &key, &salt);
+            future::poll_fn(|cx| writer.poll_write_encrypted(cx, &mut encrypted, b"hello"))
+                .await
+                .unwrap();
+            assert!(encrypted.starts_with(b"GET "));
+
+            let mut reader = DecryptedReader::new(StreamType::Server, method, &key);
+            let mut stream = &encrypted[..];
+            let mut buffer = [0u8; 64];
+            let mut read_buf = ReadBuf::new(&mut buffer);
+            while read_buf.filled().is_empty() {
+                future::poll_fn(|cx| reader.poll_read_decrypted(cx, &context, &mut stream, &mut read_buf))
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(read_buf.filled(), b"hello", "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn decrypted_reader_len() {
+        let context = Context::new(ServerType::Local);
+        let method = CipherKind::AES_128_GCM;
+        let key = vec![1u8; method.key_len()];
+        let salt = vec![2u8; method.salt_len()];
+
+        let mut encrypted = Vec::new();
+        let mut writer = EncryptedWriter::new(StreamType::Client, method, &key, &salt);
+        for chunk in [&b"hello"[..], &b"shadowsocks"[..]] {
+            future::poll_fn(|cx| writer.poll_write_encrypted(cx, &mut encrypted, chunk))
+                .await
+                .unwrap();
+        }
+
+        let mut reader = DecryptedReader::new(StreamType::Server, method, &key);
+        let mut stream = &encrypted[..];
+        let mut buffer = [0u8; 64];
+        let mut read_buf = ReadBuf::new(&mut buffer);
+        read_buf.put_slice(b"prefix");
+
+        let mut lens = Vec::new();
+        loop {
+            let before = read_buf.filled().len();
+            let n = future::poll_fn(|cx| reader.poll_read_decrypted_len(cx, &context, &mut stream, &mut read_buf))
+                .await
+                .unwrap();
+            assert_eq!(n, read_buf.filled().len() - before);
+            if n == 0 {
+                break;
+            }
+            lens.push(n);
+        }
+        assert_eq!(lens, [5, 11]);
+        assert_eq!(read_buf.filled(), b"prefixhelloshadowsocks");
+    }
+
+    #[tokio::test]
+    async fn encrypted_writer_small_frames() {
+        let context = Context::new(ServerType::Local);
+        let method = CipherKind::CHACHA20_POLY1305;
+        let key = vec![3u8; method.key_len()];
+
+        let mut encrypted = Vec::new();
+        for round in 0..2 {
+            // Writers of the second round take buffers returned by the first one
+            let salt = vec![round as u8; method.salt_len()];
+            let mut writer = EncryptedWriter::new(StreamType::Client, method, &key, &salt);
+
+            encrypted.clear();
+            for i in 0..10000u32 {
+                let frame = i.to_be_bytes();
+                future::poll_fn(|cx| writer.poll_write_encrypted(cx, &mut encrypted, &frame))
+                    .await
+                    .unwrap();
+            }
+            drop(writer);
+
+            let mut reader = DecryptedReader::new(StreamType::Server, method, &key);
+            let mut stream = &encrypted[..];
+            for i in 0..10000u32 {
+                let mut frame = [0u8; 4];
+                let mut read_buf = ReadBuf::new(&mut frame);
+                future::poll_fn(|cx| reader.poll_read_decrypted(cx, &context, &mut stream, &mut read_buf))
+                    .await
+                    .unwrap();
+                assert_eq!(read_buf.filled(), i.to_be_bytes());
+            }
+            assert!(stream.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn crypto_stream_rekey() {
+        let mut methods = vec![CipherKind::AES_256_GCM];
+        #[cfg(feature = "aead-cipher-2022")]
+        methods.push(CipherKind::AEAD2022_BLAKE3_AES_256_GCM);
+
+        for method in methods {
+            let client_context = Context::new(ServerType::Local);
+            let server_context = Context::new(ServerType::Server);
+            let key = vec![7u8; method.key_len()];
+
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let mut client = CryptoStream::from_stream(&client_context, client, StreamType::Client, method, &key);
+            let mut server = CryptoStream::from_stream(&server_context, server, StreamType::Server, method, &key);
+            server.set_accept_rekey(true);
+
+            let data = (0..3000u32).map(|i| i as u8).collect::<Vec<u8>>();
+            let mut sent = 0;
+            for (i, chunk) in data.chunks(1000).enumerate() {
+                if i > 0 {
+                    client.rekey(&client_context).unwrap();
+                }
+                let n = future::poll_fn(|cx| Pin::new(&mut client).poll_write_encrypted(cx, chunk))
+                    .await
+                    .unwrap();
+                assert_eq!(n, chunk.len());
+                sent += n;
+            }
+
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while received.len() < sent {
+                let mut read_buf = ReadBuf::new(&mut buffer);
+                future::poll_fn(|cx| Pin::new(&mut server).poll_read_decrypted(cx, &server_context, &mut read_buf))
+                    .await
+                    .unwrap();
+                assert!(!read_buf.filled().is_empty(), "{}", method);
+                received.extend_from_slice(read_buf.filled());
+            }
+            assert_eq!(received, data, "{}", method);
+        }
+
+        let context = Context::new(ServerType::Local);
+        let (client, _server) = tokio::io::duplex(1024);
+        let mut none = CryptoStream::from_stream(&context, client, StreamType::Client, CipherKind::NONE, &[]);
+        assert!(none.rekey(&context).is_err());
+    }
+
+    #[tokio::test]
+    async fn crypto_stream_rekey_empty_write() {
+        let mut methods = vec![CipherKind::AES_256_GCM];
+        #[cfg(feature = "aead-cipher-2022")]
+        methods.push(CipherKind::AEAD2022_BLAKE3_AES_256_GCM);
+
+        for method in methods {
+            let client_context = Context::new(ServerType::Local);
+            let server_context = Context::new(ServerType::Server);
+            let key = vec![7u8; method.key_len()];
+
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let mut client = CryptoStream::from_stream(&client_context, client, StreamType::Client, method, &key);
+            let mut server = CryptoStream::from_stream(&server_context, server, StreamType::Server, method, &key);
+            server.set_accept_rekey(true);
+
+            // Empty writes, also the one carrying a rekey marker, must not be taken as markers
+            for (rekey, chunk) in [
+                (false, &b"hello"[..]),
+                (false, b""),
+                (false, b"shadow"),
+                (true, b""),
+                (false, b"socks"),
+            ] {
+                if rekey {
+                    client.rekey(&client_context).unwrap();
+                }
+                let n = future::poll_fn(|cx| Pin::new(&mut client).poll_write_encrypted(cx, chunk))
+                    .await
+                    .unwrap();
+                assert_eq!(n, chunk.len());
+            }
+
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 1024];
+            while received.len() < 16 {
+                let mut read_buf = ReadBuf::new(&mut buffer);
+                future::poll_fn(|cx| Pin::new(&mut server).poll_read_decrypted(cx, &server_context, &mut read_buf))
+                    .await
+                    .unwrap();
+                assert!(!read_buf.filled().is_empty(), "{}", method);
+                received.extend_from_slice(read_buf.filled());
+            }
+            assert_eq!(received, b"helloshadowsocks", "{}", method);
+        }
+    }
+
+    #[test]
+    fn set_request_nonce_unsupported() {
+        let context = Context::new(ServerType::Server);
+        for method in [CipherKind::AES_256_GCM, CipherKind::NONE] {
+            let key = vec![0u8; method.key_len()];
+            let (server, _client) = tokio::io::duplex(1024);
+            let mut stream = CryptoStream::from_stream(&context, server, StreamType::Server, method, &key);
+
+            let err = stream.set_request_nonce(&[0u8; 32]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported, "{}", method);
+        }
+    }
+
+    #[test]
+    fn encrypted_writer_rekey_unsupported() {
+        let mut writer = EncryptedWriter::new(StreamType::Client, CipherKind::NONE, &[], &[]);
+        let err = writer.rekey(&[], &[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn crypto_stream_method_name() {
+        let context = Context::new(ServerType::Local);
+        let mut methods = vec![CipherKind::NONE, CipherKind::AES_128_GCM, CipherKind::CHACHA20_POLY1305];
+        #[cfg(feature = "aead-cipher-2022")]
+        methods.push(CipherKind::AEAD2022_BLAKE3_AES_256_GCM);
+
+        for method in methods {
+            let key = vec![0u8; method.key_len()];
+            let (client, _server) = tokio::io::duplex(1024);
+            let stream = CryptoStream::from_stream(&context, client, StreamType::Client, method, &key);
+
+            assert_eq!(stream.method_name().parse::<CipherKind>().unwrap(), method);
+            assert_eq!(stream.category(), method.category());
+        }
+
+        let (client, _server) = tokio::io::duplex(1024);
+        let stream = CryptoStream::from_stream(&context, client, StreamType::Client, CipherKind::NONE, &[]);
+        assert_eq!(stream.method_name(), "none");
+    }
+
+    #[test]
+    fn crypto_stream_debug_redacted() {
+        let context = Context::new(ServerType::Local);
+        let method = CipherKind::AES_256_GCM;
+        let key = vec![0xA5u8; method.key_len()];
+        let (client, _server) = tokio::io::duplex(1024);
+        let stream = CryptoStream::from_stream(&context, client, StreamType::Client, method, &key);
+
+        let output = format!("{:?}", stream);
+        assert!(output.contains("aes-256-gcm"), "{}", output);
+        assert!(!output.contains(&format!("{:?}", stream.sent_nonce())), "{}", output);
+        assert!(
+            !output.contains(&format!("{:?}", ByteStr::new(stream.sent_nonce()))),
+            "{}",
+            output
+        );
+        assert!(!output.contains("165"), "{}", output);
+    }
+}
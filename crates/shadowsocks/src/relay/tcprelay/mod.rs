@@ -8,6 +8,7 @@ pub use self::{
 mod aead;
 #[cfg(feature = "aead-cipher-2022")]
 mod aead_2022;
+mod buffer_pool;
 pub mod crypto_io;
 pub mod proxy_listener;
 pub mod proxy_stream;
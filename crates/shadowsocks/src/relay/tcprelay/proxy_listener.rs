@@ -2,6 +2,7 @@
 
 use std::{io, net::SocketAddr, sync::Arc};
 
+use bytes::Bytes;
 use once_cell::sync::Lazy;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
@@ -23,6 +24,7 @@ pub struct ProxyListener {
     key: Box<[u8]>,
     context: SharedContext,
     user_manager: Option<Arc<ServerUserManager>>,
+    fallback_methods: Arc<Vec<(CipherKind, Bytes)>>,
 }
 
 static DEFAULT_ACCEPT_OPTS: Lazy<AcceptOpts> = Lazy::new(Default::default);
@@ -59,6 +61,7 @@ impl ProxyListener {
             key: svr_cfg.key().to_vec().into_boxed_slice(),
             context,
             user_manager: svr_cfg.clone_user_manager(),
+            fallback_methods: svr_cfg.clone_fallback_method_keys(),
         }
     }
 
@@ -78,13 +81,16 @@ impl ProxyListener {
         let stream = map_fn(stream);
 
         // Create a ProxyServerStream and read the target address from it
-        let stream = ProxyServerStream::from_stream(
+        let mut stream = ProxyServerStream::from_stream(
             self.context.clone(),
             stream,
             self.method,
             &self.key,
             self.user_manager.clone(),
         );
+        if !self.fallback_methods.is_empty() {
+            stream.set_fallback_methods(&self.fallback_methods);
+        }
 
         Ok((stream, peer_addr))
     }
@@ -31,7 +31,7 @@ use crate::{
 };
 
 enum ProxyClientStreamWriteState {
-    Connect(Address),
+    Connect,
     Connecting(BytesMut),
     Connected,
 }
@@ -50,6 +50,7 @@ pub struct ProxyClientStream<S> {
     writer_state: ProxyClientStreamWriteState,
     reader_state: ProxyClientStreamReadState,
     context: SharedContext,
+    target_addr: Address,
 }
 
 static DEFAULT_CONNECT_OPTS: Lazy<ConnectOpts> = Lazy::new(Default::default);
@@ -173,12 +174,18 @@ where
 
         ProxyClientStream {
             stream,
-            writer_state: ProxyClientStreamWriteState::Connect(addr),
+            writer_state: ProxyClientStreamWriteState::Connect,
             reader_state,
             context,
+            target_addr: addr,
         }
     }
 
+    /// Get the target address that this stream is connected to through the server
+    pub fn target_addr(&self) -> &Address {
+        &self.target_addr
+    }
+
     /// Get reference to the underlying stream
     pub fn get_ref(&self) -> &S {
         self.stream.get_ref()
@@ -300,8 +307,8 @@ where
 
         loop {
             match this.writer_state {
-                ProxyClientStreamWriteState::Connect(ref addr) => {
-                    let buffer = make_first_packet_buffer(this.stream.method(), addr, buf);
+                ProxyClientStreamWriteState::Connect => {
+                    let buffer = make_first_packet_buffer(this.stream.method(), this.target_addr, buf);
 
                     // Save the concatenated buffer before it is written successfully.
                     // APIs require buffer to be kept alive before Poll::Ready
@@ -340,8 +347,13 @@ where
         self.project().stream.poll_flush(cx).map_err(Into::into)
     }
 
-    #[inline]
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<(), io::Error>> {
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<(), io::Error>> {
+        // Half-closed before sending anything, the handshake packet has to be sent before FIN,
+        // otherwise the remote server won't know where to relay the opposite direction from.
+        if !matches!(self.writer_state, ProxyClientStreamWriteState::Connected) {
+            ready!(self.as_mut().poll_write(cx, &[]))?;
+        }
+
         self.project().stream.poll_shutdown(cx).map_err(Into::into)
     }
 }
@@ -76,6 +76,16 @@ impl<S> ProxyServerStream<S> {
         }
     }
 
+    /// Also accept clients of `fallback_methods`, the method is chosen when reading the first chunk
+    pub(crate) fn set_fallback_methods(&mut self, fallback_methods: &[(CipherKind, Bytes)]) {
+        self.stream.set_fallback_methods(fallback_methods);
+    }
+
+    /// Get encryption method, which is chosen from the fallback methods after handshake
+    pub fn method(&self) -> CipherKind {
+        self.stream.method()
+    }
+
     /// Get reference of the internal stream
     pub fn get_ref(&self) -> &S {
         self.stream.get_ref()
@@ -179,7 +189,7 @@ where
                 }
                 #[cfg(feature = "aead-cipher-2022")]
                 ProxyServerStreamWriteState::PrepareHeader(ref mut waker) => {
-                    if this.stream.set_request_nonce_with_received() {
+                    if this.stream.set_request_nonce_with_received()? {
                         *(this.writer_state) = ProxyServerStreamWriteState::Established;
                     } else {
                         // Reader didn't receive the salt from client yet.
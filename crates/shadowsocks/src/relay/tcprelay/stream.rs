@@ -18,11 +18,15 @@ use crate::{
     crypto::{v1::Cipher, CipherKind},
 };
 
+use super::utils::sniff_plaintext_protocol;
+
 /// Stream protocol error
 #[derive(thiserror::Error, Debug)]
 pub enum ProtocolError {
     #[error(transparent)]
     IoError(#[from] io::Error),
+    #[error("received {0} instead of IV, peer is not speaking shadowsocks with the same method")]
+    UnexpectedPlaintext(&'static str),
     #[error("decrypt failed")]
     DecryptError,
 }
@@ -39,6 +43,16 @@ impl From<ProtocolError> for io::Error {
     }
 }
 
+/// Replace `err` with `UnexpectedPlaintext` if the first `received` bytes are obviously another protocol
+///
+/// Only for reads that have already failed, random IVs could look like plaintext too.
+fn explain_plaintext(received: &[u8], err: ProtocolError) -> ProtocolError {
+    match sniff_plaintext_protocol(received) {
+        Some(protocol) => ProtocolError::UnexpectedPlaintext(protocol),
+        None => err,
+    }
+}
+
 enum DecryptReadState {
     WaitIv { key: Bytes },
     Read,
@@ -138,9 +152,17 @@ impl DecryptedReader {
     {
         let iv_len = self.method.iv_len();
 
-        let n = ready!(self.poll_read_exact(cx, stream, iv_len))?;
-        if n < iv_len {
-            return Err(io::Error::from(ErrorKind::UnexpectedEof).into()).into();
+        // Stream ciphers can't tell whether decryption has failed, so it is only explained if peer closed before
+        // sending a complete IV
+        match ready!(self.poll_read_exact(cx, stream, iv_len)) {
+            Ok(n) if n == iv_len => {}
+            result => {
+                let err = match result {
+                    Err(err) => err,
+                    Ok(..) => io::Error::from(ErrorKind::UnexpectedEof),
+                };
+                return Err(explain_plaintext(&self.buffer, err.into())).into();
+            }
         }
 
         let iv = &self.buffer[..iv_len];
@@ -164,6 +164,33 @@ fn plain_read_buffer_size(method: CipherKind) -> usize {
     }
 }
 
+/// Signatures of well-known plaintext protocols
+///
+/// A shadowsocks peer always starts with a random salt (IV), so the chance of it beginning with one of these is negligible.
+const PLAINTEXT_PROTOCOL_SIGNATURES: &[(&[u8], &str)] = &[
+    (b"HTTP/", "HTTP response"),
+    (b"GET ", "HTTP request"),
+    (b"HEAD ", "HTTP request"),
+    (b"POST ", "HTTP request"),
+    (b"PUT ", "HTTP request"),
+    (b"DELETE ", "HTTP request"),
+    (b"OPTIONS ", "HTTP request"),
+    (b"CONNECT ", "HTTP request"),
+    (b"PATCH ", "HTTP request"),
+    (b"TRACE ", "HTTP request"),
+    (b"SSH-", "SSH banner"),
+];
+
+/// Check if the first bytes received from peer are obviously a plaintext protocol instead of a salt (IV)
+///
+/// Returns the name of the detected protocol.
+pub(crate) fn sniff_plaintext_protocol(buf: &[u8]) -> Option<&'static str> {
+    PLAINTEXT_PROTOCOL_SIGNATURES
+        .iter()
+        .find(|(sig, _)| buf.starts_with(sig))
+        .map(|(_, name)| *name)
+}
+
 /// Create a buffer for reading from plain channel (not encrypted), for copying data into encrypted channel
 #[inline]
 pub fn alloc_plain_read_buffer(method: CipherKind) -> Box<[u8]> {
@@ -64,7 +64,7 @@ use aes::{
 };
 use byte_string::ByteStr;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use log::{error, trace};
+use log::{debug, error, trace};
 use lru_time_cache::LruCache;
 
 #[cfg(feature = "aead-cipher-2022-extra")]
@@ -86,7 +86,6 @@ use super::options::UdpSocketControlData;
 
 const CLIENT_SOCKET_TYPE: u8 = 0;
 const SERVER_SOCKET_TYPE: u8 = 1;
-const SERVER_PACKET_TIMESTAMP_MAX_DIFF: u64 = 30;
 
 /// AEAD 2022 protocol error
 #[derive(thiserror::Error, Debug)]
@@ -101,7 +100,7 @@ pub enum ProtocolError {
     InvalidClientUser(Bytes),
     #[error("invalid socket type, expecting {0:#x}, but found {1:#x}")]
     InvalidSocketType(u8, u8),
-    #[error("invalid timestamp {0} - now {1} = {}", *.0 as i64 - *.1 as i64)]
+    #[error("invalid timestamp {0} - now {1} = {}, clocks may be out of sync", *.0 as i64 - *.1 as i64)]
     InvalidTimestamp(u64, u64),
     #[error(transparent)]
     IoError(#[from] io::Error),
@@ -554,7 +553,11 @@ pub fn decrypt_client_payload_aead_2022(
     let timestamp = cursor.get_u64();
 
     let now = get_now_timestamp();
-    if now.abs_diff(timestamp) > SERVER_PACKET_TIMESTAMP_MAX_DIFF {
+    if let Err(max_diff) = context.check_aead_2022_timestamp(timestamp, now) {
+        debug!(
+            "AEAD-2022 timestamp {} exceeds the allowed difference {}s from now {}",
+            timestamp, max_diff, now
+        );
         return Err(ProtocolError::InvalidTimestamp(timestamp, now));
     }
 
@@ -658,7 +661,11 @@ pub fn decrypt_server_payload_aead_2022(
     let timestamp = cursor.get_u64();
 
     let now = get_now_timestamp();
-    if now.abs_diff(timestamp) > SERVER_PACKET_TIMESTAMP_MAX_DIFF {
+    if let Err(max_diff) = context.check_aead_2022_timestamp(timestamp, now) {
+        debug!(
+            "AEAD-2022 timestamp {} exceeds the allowed difference {}s from now {}",
+            timestamp, max_diff, now
+        );
         return Err(ProtocolError::InvalidTimestamp(timestamp, now));
     }
 
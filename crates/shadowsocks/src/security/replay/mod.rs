@@ -43,6 +43,14 @@ impl ReplayProtector {
         }
     }
 
+    /// Remember AEAD-2022 nonces for `expiry`, which should cover the whole valid time range of timestamps
+    ///
+    /// Nonces that are already remembered are dropped.
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn set_aead_2022_nonce_expiry(&mut self, expiry: Duration) {
+        self.nonce_set = spin::Mutex::new(LruCache::with_expiry_duration(expiry));
+    }
+
     /// Check if nonce exist or not
     #[inline(always)]
     pub fn check_nonce_and_set(&self, method: CipherKind, nonce: &[u8]) -> bool {
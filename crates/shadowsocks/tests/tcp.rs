@@ -219,3 +219,111 @@ async fn tcp_tunnel_aead_2022_chacha20() {
     .await
     .unwrap();
 }
+
+#[tokio::test]
+async fn tcp_nodelay_sockopt() {
+    use shadowsocks::net::{AcceptOpts, ConnectOpts, TcpListener as ShadowTcpListener, TcpStream as ShadowTcpStream};
+
+    let _ = env_logger::try_init();
+
+    let mut accept_opts = AcceptOpts::default();
+    accept_opts.tcp.nodelay = true;
+    let mut connect_opts = ConnectOpts::default();
+    connect_opts.tcp.nodelay = true;
+
+    let listener = ShadowTcpListener::bind_with_opts(&"127.0.0.1:0".parse().unwrap(), accept_opts)
+        .await
+        .unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    let context = Context::new_shared(ServerType::Local);
+
+    // Outbound, proxied
+    let svr_cfg = ServerConfig::new(listen_addr, "p$p", CipherKind::AES_128_GCM);
+    let proxied = ProxyClientStream::connect_with_opts(
+        context.clone(),
+        &svr_cfg,
+        Address::SocketAddress(listen_addr),
+        &connect_opts,
+    )
+    .await
+    .unwrap();
+    assert!(proxied.get_ref().nodelay().unwrap());
+
+    // Outbound, bypassed
+    let bypassed =
+        ShadowTcpStream::connect_remote_with_opts(&context, &Address::SocketAddress(listen_addr), &connect_opts)
+            .await
+            .unwrap();
+    assert!(bypassed.nodelay().unwrap());
+
+    // Inbound
+    for _ in 0..2 {
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert!(accepted.nodelay().unwrap());
+    }
+}
+
+#[tokio::test]
+async fn tcp_server_fallback_methods() {
+    use tokio::io::AsyncReadExt;
+
+    let _ = env_logger::try_init();
+
+    let mut svr_cfg = ServerConfig::new(
+        "127.0.0.1:0".parse::<SocketAddr>().unwrap(),
+        "p$p",
+        CipherKind::AES_256_GCM,
+    );
+    svr_cfg.set_fallback_methods(&[CipherKind::AES_128_GCM, CipherKind::CHACHA20_POLY1305]);
+
+    let listener = ProxyListener::bind(Context::new_shared(ServerType::Server), &svr_cfg)
+        .await
+        .unwrap();
+    let listen_addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let addr = stream.handshake().await?;
+                assert_eq!(addr, Address::from(("www.example.com".to_owned(), 80)));
+
+                // Echo with the client's method
+                let mut buffer = [0u8; 5];
+                stream.read_exact(&mut buffer).await?;
+                stream.write_all(&buffer).await?;
+                stream.write_all(stream.method().to_string().as_bytes()).await?;
+                stream.shutdown().await
+            });
+        }
+    });
+
+    let context = Context::new_shared(ServerType::Local);
+    for method in [
+        CipherKind::AES_256_GCM,
+        CipherKind::AES_128_GCM,
+        CipherKind::CHACHA20_POLY1305,
+    ] {
+        let client_cfg = ServerConfig::new(listen_addr, "p$p", method);
+        let target_addr = Address::from(("www.example.com".to_owned(), 80));
+        let mut stream = ProxyClientStream::connect(context.clone(), &client_cfg, target_addr)
+            .await
+            .unwrap();
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, format!("hello{}", method).as_bytes(), "{}", method);
+    }
+
+    // Clients that couldn't be decrypted by any of the methods are rejected
+    let client_cfg = ServerConfig::new(listen_addr, "wrong-password", CipherKind::AES_128_GCM);
+    let target_addr = Address::from(("www.example.com".to_owned(), 80));
+    let mut stream = ProxyClientStream::connect(context, &client_cfg, target_addr)
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut response = Vec::new();
+    let result = stream.read_to_end(&mut response).await;
+    assert!(result.is_err() || response.is_empty());
+}
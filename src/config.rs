@@ -6,11 +6,14 @@ use std::{
     io::{self, Read},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
 };
 
 use clap::ArgMatches;
 use directories::ProjectDirs;
 use serde::Deserialize;
+use tokio::runtime::Builder;
 
 /// Default configuration file path
 pub fn get_default_config_path(config_file: &str) -> Option<PathBuf> {
@@ -162,6 +165,19 @@ impl Config {
                 }
             }
 
+            if let Some(shutdown_timeout) = runtime.shutdown_timeout {
+                nruntime.shutdown_timeout = Some(Duration::from_secs(shutdown_timeout));
+            }
+
+            nruntime.thread_name_prefix = runtime.thread_name_prefix;
+
+            if let Some(thread_stack_size) = runtime.thread_stack_size {
+                if thread_stack_size == 0 {
+                    return Err(ConfigError::InvalidValue("thread_stack_size".to_owned()));
+                }
+                nruntime.thread_stack_size = Some(thread_stack_size);
+            }
+
             config.runtime = nruntime;
         }
 
@@ -256,6 +272,30 @@ pub struct RuntimeConfig {
     pub worker_count: Option<usize>,
     /// Runtime Mode, single-thread, multi-thread
     pub mode: RuntimeMode,
+    /// Time to wait for in-flight connections to finish after the stop signal, exits immediately if not configured
+    pub shutdown_timeout: Option<Duration>,
+    /// Prefix of runtime threads' names, followed by `-{n}`. Threads are named `tokio-runtime-worker` if not configured
+    pub thread_name_prefix: Option<String>,
+    /// Stack size of runtime threads in bytes, tokio's default (2MiB) if not configured
+    pub thread_stack_size: Option<usize>,
+}
+
+impl RuntimeConfig {
+    /// Apply thread options to a runtime `builder`
+    pub fn configure_threads(&self, builder: &mut Builder) {
+        if let Some(ref prefix) = self.thread_name_prefix {
+            let prefix = prefix.clone();
+            let thread_id = AtomicUsize::new(0);
+            builder.thread_name_fn(move || {
+                let id = thread_id.fetch_add(1, Ordering::Relaxed);
+                format!("{}-{}", prefix, id)
+            });
+        }
+
+        if let Some(thread_stack_size) = self.thread_stack_size {
+            builder.thread_stack_size(thread_stack_size);
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -284,4 +324,37 @@ struct SSRuntimeConfig {
     #[cfg(feature = "multi-threaded")]
     worker_count: Option<usize>,
     mode: Option<String>,
+    shutdown_timeout: Option<u64>,
+    thread_name_prefix: Option<String>,
+    thread_stack_size: Option<usize>,
+}
+
+#[cfg(all(test, feature = "multi-threaded"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runtime_thread_name_prefix() {
+        let config = Config::load_from_str(
+            r#"{
+                "runtime": {
+                    "mode": "multi_thread",
+                    "worker_count": 2,
+                    "thread_name_prefix": "ss-test-worker",
+                    "thread_stack_size": 4194304
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.runtime.thread_stack_size, Some(4194304));
+
+        let mut builder = Builder::new_multi_thread();
+        builder.worker_threads(2);
+        config.runtime.configure_threads(&mut builder);
+        let runtime = builder.build().unwrap();
+
+        let task = runtime.spawn(async { std::thread::current().name().map(ToOwned::to_owned) });
+        let name = runtime.block_on(task).unwrap().unwrap();
+        assert!(name.starts_with("ss-test-worker-"), "{}", name);
+    }
 }
@@ -0,0 +1,109 @@
+//! Errors while starting service runnables
+
+use std::{io, path::PathBuf};
+
+use shadowsocks_service::config::Error as ConfigError;
+
+use crate::config::ConfigError as ServiceConfigError;
+
+/// Error while creating a service runnable, which is mapped to an exit code by `main`
+///
+/// Messages already include their causes, so causes are not exposed as `source()` to be printed twice.
+#[derive(thiserror::Error, Debug)]
+pub enum StartupError {
+    /// Loading options of the runnable (log, runtime) from configuration file fails
+    #[error("loading config {path:?}, {error}")]
+    LoadServiceConfig {
+        path: PathBuf,
+        error: ServiceConfigError,
+    },
+    /// Loading configuration file fails
+    #[error("loading config {path:?}, {error}")]
+    LoadConfig {
+        path: PathBuf,
+        error: ConfigError,
+    },
+    /// Invalid value in configuration or command line options
+    #[error("{0}")]
+    InvalidConfig(String),
+    /// Configuration integrity check fails
+    #[error("config integrity check failed, {0}")]
+    IntegrityCheck(ConfigError),
+    /// Loading ACL file fails
+    #[error("loading ACL \"{path}\", {error}")]
+    LoadAcl {
+        path: String,
+        error: io::Error,
+    },
+    /// Missing required parameters
+    #[error("{0}")]
+    InsufficientParams(String),
+    /// Changing running user fails
+    #[error("failed to change as user, error: {0}")]
+    RunAsUser(io::Error),
+}
+
+impl StartupError {
+    /// Exit code of the process
+    pub fn exit_code(&self) -> sysexits::ExitCode {
+        match *self {
+            StartupError::LoadServiceConfig { .. }
+            | StartupError::LoadConfig { .. }
+            | StartupError::InvalidConfig(..)
+            | StartupError::IntegrityCheck(..) => crate::EXIT_CODE_LOAD_CONFIG_FAILURE,
+            StartupError::LoadAcl { .. } => crate::EXIT_CODE_LOAD_ACL_FAILURE,
+            StartupError::InsufficientParams(..) | StartupError::RunAsUser(..) => crate::EXIT_CODE_INSUFFICIENT_PARAMS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shadowsocks_service::config::{Config, ConfigType, ErrorKind};
+
+    use super::*;
+
+    #[test]
+    fn startup_error_exit_codes() {
+        let err = StartupError::LoadServiceConfig {
+            path: PathBuf::from("local.json"),
+            error: ServiceConfigError::InvalidValue("runtime.mode".to_owned()),
+        };
+        assert_eq!(
+            err.to_string(),
+            "loading config \"local.json\", Invalid value: runtime.mode"
+        );
+        assert_eq!(err.exit_code(), crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+
+        let err = StartupError::LoadConfig {
+            path: PathBuf::from("local.json"),
+            error: Config::load_from_str("{", ConfigType::Local).unwrap_err(),
+        };
+        assert!(err
+            .to_string()
+            .starts_with("loading config \"local.json\", json parse error"));
+        assert_eq!(err.exit_code(), crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+
+        let err = StartupError::InvalidConfig("invalid `password` for server 127.0.0.1:8388".to_owned());
+        assert_eq!(err.exit_code(), crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+
+        let err = StartupError::IntegrityCheck(Config::new(ConfigType::Local).check_integrity().unwrap_err());
+        assert!(matches!(err, StartupError::IntegrityCheck(ref e) if matches!(e.kind, ErrorKind::MissingField)));
+        assert!(err.to_string().starts_with("config integrity check failed, "));
+        assert_eq!(err.exit_code(), crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+
+        let err = StartupError::LoadAcl {
+            path: "bypass.acl".to_owned(),
+            error: io::Error::new(io::ErrorKind::NotFound, "not found"),
+        };
+        assert_eq!(err.to_string(), "loading ACL \"bypass.acl\", not found");
+        assert_eq!(err.exit_code(), crate::EXIT_CODE_LOAD_ACL_FAILURE);
+
+        let err = StartupError::InsufficientParams("missing `local_address`".to_owned());
+        assert_eq!(err.exit_code(), crate::EXIT_CODE_INSUFFICIENT_PARAMS);
+
+        let err = StartupError::RunAsUser(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert_eq!(err.to_string(), "failed to change as user, error: denied");
+        assert_eq!(err.exit_code(), crate::EXIT_CODE_INSUFFICIENT_PARAMS);
+    }
+}
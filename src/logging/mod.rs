@@ -1,6 +1,8 @@
 //! Logging facilities
 
 use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
 
 use log::warn;
 
@@ -33,3 +35,92 @@ pub fn init_with_config(bin_name: &str, config: &LogConfig) {
 pub fn init_with_default(bin_name: &str) {
     init_with_config(bin_name, &LogConfig::default());
 }
+
+/// Error while changing log level at runtime
+#[derive(thiserror::Error, Debug)]
+pub enum SetLevelError {
+    /// Level is neither a verbosity level nor valid filter directives
+    #[error("invalid log level {0:?}, {1}")]
+    InvalidLevel(String, String),
+    /// Logger is configured by a log4rs configuration file, which should be changed with its `refresh_rate` instead
+    #[error("log level could only be changed for the default logger, not for the log4rs configuration file")]
+    Unsupported,
+    /// Replacing filter of the active logger fails
+    #[error("failed to reload logger, {0}")]
+    Reload(String),
+}
+
+/// Change level of the active logger at runtime
+///
+/// `level` is a verbosity level like `log.level` in the configuration file (`"0"` to `"3"`), or filter directives
+/// like the `RUST_LOG` environment variable (for example `"info,shadowsocks_service=trace"`). It replaces the
+/// filter set by `RUST_LOG` or the configuration when the logger was initialized.
+pub fn set_level(level: &str) -> Result<(), SetLevelError> {
+    tracing::set_level(level)
+}
+
+/// Reload only `log.level` from `config_path` when receiving `SIGUSR2`, other parts of the configuration are untouched
+///
+/// Level set by `-v` command line options is replaced by the one in the configuration file.
+#[cfg(unix)]
+pub fn launch_reload_level_task(config_path: PathBuf) {
+    use log::{error, info};
+    use tokio::signal::unix::{signal, SignalKind};
+
+    use crate::config::Config;
+
+    let mut sigusr2 = match signal(SignalKind::user_defined2()) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("failed to listen SIGUSR2 for reloading log level, error: {}", err);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while sigusr2.recv().await.is_some() {
+            let config = match Config::load_from_file(&config_path) {
+                Ok(c) => c,
+                Err(err) => {
+                    error!(
+                        "reload log level from {} failed with error: {}",
+                        config_path.display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+
+            let level = config.log.level.to_string();
+            match set_level(&level) {
+                Ok(..) => info!("reloaded log level {} from {}", level, config_path.display()),
+                Err(err) => error!(
+                    "reload log level from {} failed with error: {}",
+                    config_path.display(),
+                    err
+                ),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_level_validation() {
+        assert!(matches!(set_level(""), Err(SetLevelError::InvalidLevel(..))));
+        assert!(matches!(
+            set_level("shadowsocks=verbose"),
+            Err(SetLevelError::InvalidLevel(..))
+        ));
+
+        // Logger is not initialized by `init_with_config` in tests
+        assert!(matches!(set_level("2"), Err(SetLevelError::Unsupported)));
+        assert!(matches!(
+            set_level("info,shadowsocks_service=trace"),
+            Err(SetLevelError::Unsupported)
+        ));
+    }
+}
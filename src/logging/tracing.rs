@@ -1,13 +1,20 @@
 //! Logging facilities with tracing
 
-use std::io::IsTerminal;
+use std::{io::IsTerminal, sync::OnceLock};
 
 use time::UtcOffset;
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{fmt::time::OffsetTime, EnvFilter, FmtSubscriber};
+use tracing::{level_filters::LevelFilter, Level};
+use tracing_subscriber::{fmt::time::OffsetTime, reload, EnvFilter, FmtSubscriber};
 
 use crate::config::LogConfig;
 
+use super::SetLevelError;
+
+type ReloadFilter = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+static BIN_NAME: OnceLock<String> = OnceLock::new();
+static RELOAD_FILTER: OnceLock<ReloadFilter> = OnceLock::new();
+
 /// Initialize logger with provided configuration
 pub fn init_with_config(bin_name: &str, config: &LogConfig) {
     let debug_level = config.level;
@@ -45,38 +52,87 @@ pub fn init_with_config(bin_name: &str, config: &LogConfig) {
 
     let filter = match EnvFilter::try_from_default_env() {
         Ok(f) => f,
-        Err(..) => match debug_level {
-            0 => EnvFilter::builder()
-                .with_regex(true)
-                .with_default_directive(LevelFilter::ERROR.into())
-                .parse_lossy(format!(
-                    "warn,{}=info,shadowsocks_rust=info,shadowsocks_service=info,shadowsocks=info",
-                    bin_name
-                )),
-            1 => EnvFilter::builder()
-                .with_regex(true)
-                .with_default_directive(LevelFilter::ERROR.into())
-                .parse_lossy(format!(
-                    "warn,{}=debug,shadowsocks_rust=debug,shadowsocks_service=debug,shadowsocks=debug",
-                    bin_name
-                )),
-            2 => EnvFilter::builder()
-                .with_regex(true)
-                .with_default_directive(LevelFilter::ERROR.into())
-                .parse_lossy(format!(
-                    "warn,{}=trace,shadowsocks_rust=trace,shadowsocks_service=trace,shadowsocks=trace",
-                    bin_name
-                )),
-            _ => EnvFilter::builder()
-                .with_default_directive(LevelFilter::TRACE.into())
-                .parse_lossy(""),
-        },
+        Err(..) => default_filter(bin_name, debug_level),
     };
+    let _ = BIN_NAME.set(bin_name.to_owned());
     let builder = builder.with_env_filter(filter);
 
     if without_time {
-        builder.without_time().init();
+        let builder = builder.without_time().with_filter_reloading();
+        set_reload_handle(builder.reload_handle());
+        builder.init();
     } else {
+        let builder = builder.with_filter_reloading();
+        set_reload_handle(builder.reload_handle());
         builder.init();
     }
 }
+
+fn default_filter(bin_name: &str, debug_level: u32) -> EnvFilter {
+    match debug_level {
+        0 => EnvFilter::builder()
+            .with_regex(true)
+            .with_default_directive(LevelFilter::ERROR.into())
+            .parse_lossy(format!(
+                "warn,{}=info,shadowsocks_rust=info,shadowsocks_service=info,shadowsocks=info",
+                bin_name
+            )),
+        1 => EnvFilter::builder()
+            .with_regex(true)
+            .with_default_directive(LevelFilter::ERROR.into())
+            .parse_lossy(format!(
+                "warn,{}=debug,shadowsocks_rust=debug,shadowsocks_service=debug,shadowsocks=debug",
+                bin_name
+            )),
+        2 => EnvFilter::builder()
+            .with_regex(true)
+            .with_default_directive(LevelFilter::ERROR.into())
+            .parse_lossy(format!(
+                "warn,{}=trace,shadowsocks_rust=trace,shadowsocks_service=trace,shadowsocks=trace",
+                bin_name
+            )),
+        _ => EnvFilter::builder()
+            .with_default_directive(LevelFilter::TRACE.into())
+            .parse_lossy(""),
+    }
+}
+
+fn set_reload_handle<S: 'static>(handle: reload::Handle<EnvFilter, S>) {
+    let _ = RELOAD_FILTER.set(Box::new(move |filter| handle.reload(filter)));
+}
+
+/// Replace the filter of the logger initialized by `init_with_config`
+///
+/// `level` is a verbosity level like `log.level` in the configuration file, or filter directives like `RUST_LOG`
+pub fn set_level(level: &str) -> Result<(), SetLevelError> {
+    let filter = match level.parse::<u32>() {
+        Ok(debug_level) => default_filter(BIN_NAME.get().map(String::as_str).unwrap_or_default(), debug_level),
+        Err(..) if level.trim().is_empty() => {
+            return Err(SetLevelError::InvalidLevel(level.to_owned(), "empty level".to_owned()));
+        }
+        Err(..) => match EnvFilter::builder()
+            .with_regex(true)
+            .with_default_directive(LevelFilter::ERROR.into())
+            .parse(level)
+        {
+            Ok(f) => f,
+            Err(err) => return Err(SetLevelError::InvalidLevel(level.to_owned(), err.to_string())),
+        },
+    };
+
+    let reload_filter = RELOAD_FILTER.get().ok_or(SetLevelError::Unsupported)?;
+    reload_filter(filter).map_err(|err| SetLevelError::Reload(err.to_string()))?;
+
+    // `log` records are filtered by its max level before reaching the subscriber, which was set when initializing
+    let max_level = match LevelFilter::current().into_level() {
+        None => log::LevelFilter::Off,
+        Some(Level::ERROR) => log::LevelFilter::Error,
+        Some(Level::WARN) => log::LevelFilter::Warn,
+        Some(Level::INFO) => log::LevelFilter::Info,
+        Some(Level::DEBUG) => log::LevelFilter::Debug,
+        Some(Level::TRACE) => log::LevelFilter::Trace,
+    };
+    log::set_max_level(max_level);
+
+    Ok(())
+}
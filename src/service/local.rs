@@ -1,10 +1,19 @@
 //! Local server launchers
 
-use std::{future::Future, net::IpAddr, path::PathBuf, process::ExitCode, time::Duration};
+use std::{
+    future::Future,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::{builder::PossibleValuesParser, Arg, ArgAction, ArgGroup, ArgMatches, Command, ValueHint};
-use futures::future::{self, Either};
+use futures::future;
 use log::{info, trace};
+#[cfg(unix)]
+use tokio::signal::unix::SignalKind;
 use tokio::{
     self,
     runtime::{Builder, Runtime},
@@ -17,6 +26,7 @@ use shadowsocks_service::shadowsocks::relay::socks5::Address;
 use shadowsocks_service::{
     acl::AccessControl,
     config::{
+        clamp_socket_buffer_size,
         read_variable_field_value,
         Config,
         ConfigType,
@@ -25,7 +35,7 @@ use shadowsocks_service::{
         ProtocolType,
         ServerInstanceConfig,
     },
-    local::{loadbalancing::PingBalancer, Server},
+    local::{context::ServiceContext, loadbalancing::PingBalancer, Server},
     shadowsocks::{
         config::{Mode, ServerAddr, ServerConfig},
         crypto::{available_ciphers, CipherKind},
@@ -37,6 +47,7 @@ use shadowsocks_service::{
 use crate::logging;
 use crate::{
     config::{Config as ServiceConfig, RuntimeMode},
+    error::StartupError,
     monitor,
     vparser,
 };
@@ -205,16 +216,17 @@ pub fn define_command_line_options(mut app: Command) -> Command {
     )
     .arg(Arg::new("DNS").long("dns").num_args(1).action(ArgAction::Set).help("DNS nameservers, formatted like [(tcp|udp)://]host[:port][,host[:port]]..., or unix:///path/to/dns, or predefined keys like \"google\", \"cloudflare\""))
     .arg(Arg::new("DNS_CACHE_SIZE").long("dns-cache-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(usize)).help("DNS cache size in number of records. Works when trust-dns DNS backend is enabled."))
+    .arg(Arg::new("PROXY_DNS").long("proxy-dns").action(ArgAction::SetTrue).help("Resolve hostnames of proxied targets by remote servers only, won't resolve them locally for ACL IP rules, including queries of the DNS relay"))
     .arg(Arg::new("TCP_NO_DELAY").long("tcp-no-delay").alias("no-delay").action(ArgAction::SetTrue).help("Set TCP_NODELAY option for sockets"))
     .arg(Arg::new("TCP_FAST_OPEN").long("tcp-fast-open").alias("fast-open").action(ArgAction::SetTrue).help("Enable TCP Fast Open (TFO)"))
     .arg(Arg::new("TCP_KEEP_ALIVE").long("tcp-keep-alive").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Set TCP keep alive timeout seconds"))
     .arg(Arg::new("TCP_MULTIPATH").long("tcp-multipath").alias("mptcp").action(ArgAction::SetTrue).help("Enable Multipath-TCP (MPTCP)"))
     .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Timeout seconds for UDP relay"))
     .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(usize)).help("Maximum associations to be kept simultaneously for UDP relay"))
-    .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_SNDBUF option"))
-    .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_RCVBUF option"))
-    .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_SNDBUF option"))
-    .arg(Arg::new("OUTBOUND_RECV_BUFFER_SIZE").long("outbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_RCVBUF option"))
+    .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_SNDBUF option, clamped to 4KiB ~ 64MiB"))
+    .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_RCVBUF option, clamped to 4KiB ~ 64MiB"))
+    .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_SNDBUF option, clamped to 4KiB ~ 64MiB"))
+    .arg(Arg::new("OUTBOUND_RECV_BUFFER_SIZE").long("outbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_RCVBUF option, clamped to 4KiB ~ 64MiB"))
     .arg(Arg::new("OUTBOUND_BIND_ADDR").long("outbound-bind-addr").num_args(1).alias("bind-addr").action(ArgAction::Set).value_parser(vparser::parse_ip_addr).help("Bind address, outbound socket will bind this address"))
     .arg(Arg::new("OUTBOUND_BIND_INTERFACE").long("outbound-bind-interface").num_args(1).action(ArgAction::Set).help("Set SO_BINDTODEVICE / IP_BOUND_IF / IP_UNICAST_IF option for outbound socket"))
     .arg(
@@ -427,6 +439,14 @@ pub fn define_command_line_options(mut app: Command) -> Command {
                     .action(ArgAction::Set)
                     .value_parser(vparser::parse_ipnet)
                     .help("Tun interface destination address (network)"),
+            )
+            .arg(
+                Arg::new("TUN_INTERFACE_MTU")
+                    .long("tun-interface-mtu")
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_parser(clap::value_parser!(u16).range(576..))
+                    .help("Tun interface MTU, uses the device's MTU if not specify"),
             );
 
         #[cfg(unix)]
@@ -485,23 +505,33 @@ pub fn define_command_line_options(mut app: Command) -> Command {
 
     #[cfg(unix)]
     {
-        app = app.arg(
-            Arg::new("USER")
-                .long("user")
-                .short('a')
-                .num_args(1)
-                .action(ArgAction::Set)
-                .value_hint(ValueHint::Username)
-                .help("Run as another user"),
-        );
+        app = app
+            .arg(
+                Arg::new("USER")
+                    .long("user")
+                    .short('a')
+                    .num_args(1)
+                    .action(ArgAction::Set)
+                    .value_hint(ValueHint::Username)
+                    .help("Run as another user"),
+            )
+            .arg(
+                Arg::new("RELOAD_SIGNAL")
+                    .long("reload-signal")
+                    .num_args(1)
+                    .action(ArgAction::Append)
+                    .value_parser(["SIGUSR1", "SIGHUP"])
+                    .default_values(["SIGUSR1", "SIGHUP"])
+                    .help("Signals that reload servers from the configuration file, could be specified multiple times"),
+            );
     }
 
     app
 }
 
 /// Create `Runtime` and `main` entry
-pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = ExitCode>), ExitCode> {
-    let (config, runtime) = {
+pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = ExitCode>), StartupError> {
+    let (config, runtime, shutdown_timeout) = {
         let config_path_opt = matches.get_one::<PathBuf>("CONFIG").cloned().or_else(|| {
             if !matches.contains_id("SERVER_CONFIG") {
                 match crate::config::get_default_config_path("local.json") {
@@ -520,8 +550,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             Some(ref config_path) => match ServiceConfig::load_from_file(config_path) {
                 Ok(c) => c,
                 Err(err) => {
-                    eprintln!("loading config {config_path:?}, {err}");
-                    return Err(crate::EXIT_CODE_LOAD_CONFIG_FAILURE.into());
+                    return Err(StartupError::LoadServiceConfig {
+                        path: config_path.clone(),
+                        error: err,
+                    });
                 }
             },
             None => ServiceConfig::default(),
@@ -544,8 +576,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             Some(cpath) => match Config::load_from_file(&cpath, ConfigType::Local) {
                 Ok(cfg) => cfg,
                 Err(err) => {
-                    eprintln!("loading config {cpath:?}, {err}");
-                    return Err(crate::EXIT_CODE_LOAD_CONFIG_FAILURE.into());
+                    return Err(StartupError::LoadConfig {
+                        path: cpath,
+                        error: err,
+                    })
                 }
             },
             None => Config::new(ConfigType::Local),
@@ -573,6 +607,12 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
                 }
             };
 
+            if let Err(err) = ServerConfig::check_password(method, &password) {
+                return Err(StartupError::InvalidConfig(format!(
+                    "invalid `password` for server {svr_addr}, {err}"
+                )));
+            }
+
             let svr_addr = svr_addr.parse::<ServerAddr>().expect("server-addr");
             let timeout = matches.get_one::<u64>("TIMEOUT").map(|x| Duration::from_secs(*x));
 
@@ -721,6 +761,9 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
                 if let Some(tun_name) = matches.get_one::<String>("TUN_INTERFACE_NAME").cloned() {
                     local_config.tun_interface_name = Some(tun_name);
                 }
+                if let Some(mtu) = matches.get_one::<u16>("TUN_INTERFACE_MTU").cloned() {
+                    local_config.tun_interface_mtu = Some(mtu);
+                }
 
                 #[cfg(unix)]
                 if let Some(fd_path) = matches.get_one::<PathBuf>("TUN_DEVICE_FD_FROM_PATH").cloned() {
@@ -739,6 +782,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             config.local.push(LocalInstanceConfig::with_local_config(local_config));
         }
 
+        if matches.get_flag("PROXY_DNS") {
+            config.proxy_dns = true;
+        }
+
         if matches.get_flag("TCP_NO_DELAY") {
             config.no_delay = true;
         }
@@ -783,8 +830,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             let acl = match AccessControl::load_from_file(acl_file) {
                 Ok(acl) => acl,
                 Err(err) => {
-                    eprintln!("loading ACL \"{acl_file}\", {err}");
-                    return Err(crate::EXIT_CODE_LOAD_ACL_FAILURE.into());
+                    return Err(StartupError::LoadAcl {
+                        path: acl_file.clone(),
+                        error: err,
+                    });
                 }
             };
             config.acl = Some(acl);
@@ -811,16 +860,16 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         }
 
         if let Some(bs) = matches.get_one::<u32>("INBOUND_SEND_BUFFER_SIZE") {
-            config.inbound_send_buffer_size = Some(*bs);
+            config.inbound_send_buffer_size = Some(clamp_socket_buffer_size("inbound-send-buffer-size", *bs));
         }
         if let Some(bs) = matches.get_one::<u32>("INBOUND_RECV_BUFFER_SIZE") {
-            config.inbound_recv_buffer_size = Some(*bs);
+            config.inbound_recv_buffer_size = Some(clamp_socket_buffer_size("inbound-recv-buffer-size", *bs));
         }
         if let Some(bs) = matches.get_one::<u32>("OUTBOUND_SEND_BUFFER_SIZE") {
-            config.outbound_send_buffer_size = Some(*bs);
+            config.outbound_send_buffer_size = Some(clamp_socket_buffer_size("outbound-send-buffer-size", *bs));
         }
         if let Some(bs) = matches.get_one::<u32>("OUTBOUND_RECV_BUFFER_SIZE") {
-            config.outbound_recv_buffer_size = Some(*bs);
+            config.outbound_recv_buffer_size = Some(clamp_socket_buffer_size("outbound-recv-buffer-size", *bs));
         }
 
         if let Some(bind_addr) = matches.get_one::<IpAddr>("OUTBOUND_BIND_ADDR") {
@@ -830,16 +879,15 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         // DONE READING options
 
         if config.local.is_empty() {
-            eprintln!(
+            return Err(StartupError::InsufficientParams(
                 "missing `local_address`, consider specifying it by --local-addr command line option, \
                     or \"local_address\" and \"local_port\" in configuration file"
-            );
-            return Err(crate::EXIT_CODE_INSUFFICIENT_PARAMS.into());
+                    .to_owned(),
+            ));
         }
 
         if let Err(err) = config.check_integrity() {
-            eprintln!("config integrity check failed, {err}");
-            return Err(crate::EXIT_CODE_LOAD_CONFIG_FAILURE.into());
+            return Err(StartupError::IntegrityCheck(err));
         }
 
         #[cfg(unix)]
@@ -851,8 +899,7 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         #[cfg(unix)]
         if let Some(uname) = matches.get_one::<String>("USER") {
             if let Err(err) = crate::sys::run_as_user(uname) {
-                eprintln!("failed to change as user, error: {err}");
-                return Err(crate::EXIT_CODE_INSUFFICIENT_PARAMS.into());
+                return Err(StartupError::RunAsUser(err));
             }
         }
 
@@ -871,39 +918,78 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             }
         };
 
+        service_config.runtime.configure_threads(&mut builder);
+
         let runtime = builder.enable_all().build().expect("create tokio Runtime");
 
-        (config, runtime)
+        (config, runtime, service_config.runtime.shutdown_timeout)
     };
 
+    #[cfg(unix)]
+    let reload_signals: Vec<SignalKind> = matches
+        .get_many::<String>("RELOAD_SIGNAL")
+        .map(|signals| {
+            signals
+                .map(|s| match s.as_str() {
+                    "SIGHUP" => SignalKind::hangup(),
+                    _ => SignalKind::user_defined1(),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
     let main_fut = async move {
         let config_path = config.config_path.clone();
 
-        let instance = Server::new(config).await.expect("create local");
+        #[cfg(all(unix, feature = "logging"))]
+        if let Some(ref config_path) = config_path {
+            logging::launch_reload_level_task(config_path.clone());
+        }
+
+        let instance = match Server::new(config).await {
+            Ok(instance) => instance,
+            Err(err) => {
+                eprintln!("failed to create servers, {err}");
+                return crate::EXIT_CODE_SERVER_ABORTED.into();
+            }
+        };
 
         if let Some(config_path) = config_path {
-            launch_reload_server_task(config_path, instance.server_balancer().clone());
+            let acl_contexts = instance.acl_contexts().to_vec();
+
+            #[cfg(feature = "watch-config")]
+            launch_watch_config_task(
+                config_path.clone(),
+                instance.server_balancer().clone(),
+                acl_contexts.clone(),
+            );
+
+            #[cfg(unix)]
+            launch_reload_server_task(
+                config_path,
+                instance.server_balancer().clone(),
+                acl_contexts,
+                reload_signals,
+            );
+            #[cfg(not(unix))]
+            launch_reload_server_task(config_path, instance.server_balancer().clone(), acl_contexts);
         }
 
         let abort_signal = monitor::create_signal_monitor();
-        let server = instance.run();
-
-        tokio::pin!(abort_signal);
-        tokio::pin!(server);
 
-        match future::select(server, abort_signal).await {
+        match instance.run_until_shutdown(abort_signal, shutdown_timeout).await {
+            // The abort signal future resolved. Means we should just exit.
+            Ok(true) => ExitCode::SUCCESS,
             // Server future resolved without an error. This should never happen.
-            Either::Left((Ok(..), ..)) => {
+            Ok(false) => {
                 eprintln!("server exited unexpectedly");
                 crate::EXIT_CODE_SERVER_EXIT_UNEXPECTEDLY.into()
             }
             // Server future resolved with error, which are listener errors in most cases
-            Either::Left((Err(err), ..)) => {
+            Err(err) => {
                 eprintln!("server aborted with {err}");
                 crate::EXIT_CODE_SERVER_ABORTED.into()
             }
-            // The abort signal future resolved. Means we should just exit.
-            Either::Right(_) => ExitCode::SUCCESS,
         }
     };
 
@@ -915,39 +1001,224 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
 pub fn main(matches: &ArgMatches) -> ExitCode {
     match create(matches) {
         Ok((runtime, main_fut)) => runtime.block_on(main_fut),
-        Err(code) => code,
+        Err(err) => {
+            eprintln!("{err}");
+            err.exit_code().into()
+        }
+    }
+}
+
+/// Reload servers and ACLs of `acl_contexts` from `config_path`, returns `true` if succeeded
+#[cfg(any(unix, windows, feature = "watch-config"))]
+async fn reload_servers(
+    config_path: &Path,
+    balancer: &PingBalancer,
+    acl_contexts: &[(Option<usize>, Arc<ServiceContext>)],
+) -> bool {
+    use log::error;
+
+    let mut config = match Config::load_from_file_async(config_path, ConfigType::Local).await {
+        Ok(c) => c,
+        Err(err) => {
+            error!("auto-reload {} failed with error: {}", config_path.display(), err);
+            return false;
+        }
+    };
+
+    let mut succeeded = reload_acls(&mut config, acl_contexts).await;
+
+    let servers: Vec<ServerConfig> = config.server.into_iter().map(|s| s.config).collect();
+    info!("auto-reload {} with {} servers", config_path.display(), servers.len());
+
+    if let Err(err) = balancer.reset_servers(servers).await {
+        error!("auto-reload {} but found error: {}", config_path.display(), err);
+        succeeded = false;
+    }
+
+    succeeded
+}
+
+/// Replace ACLs of `acl_contexts` with the ones loaded from the reloaded `config`, returns `true` if all succeeded
+///
+/// An ACL that has been removed from `config` is kept as is, adding or removing ACLs requires a restart.
+#[cfg(any(unix, windows, feature = "watch-config"))]
+async fn reload_acls(config: &mut Config, acl_contexts: &[(Option<usize>, Arc<ServiceContext>)]) -> bool {
+    use log::error;
+
+    let mut succeeded = true;
+    for (local_index, context) in acl_contexts {
+        let acl = match *local_index {
+            None => config.acl.take(),
+            Some(index) => config.local.get_mut(index).and_then(|local| local.acl.take()),
+        };
+
+        match acl {
+            Some(acl) => {
+                info!("auto-reload acl {}", acl.file_path().display());
+                context.replace_acl(Arc::new(acl)).await;
+            }
+            None => {
+                error!("auto-reload acl failed, it has been removed from the configuration, restart to apply");
+                succeeded = false;
+            }
+        }
     }
+    succeeded
 }
 
+/// Reload is triggered by any of `reload_signals`, `SIGUSR1` and `SIGHUP` by default
 #[cfg(unix)]
-fn launch_reload_server_task(config_path: PathBuf, balancer: PingBalancer) {
+fn launch_reload_server_task(
+    config_path: PathBuf,
+    balancer: PingBalancer,
+    acl_contexts: Vec<(Option<usize>, Arc<ServiceContext>)>,
+    reload_signals: Vec<SignalKind>,
+) {
+    use futures::FutureExt;
+    use tokio::signal::unix::signal;
+
+    if reload_signals.is_empty() {
+        return;
+    }
+
+    let mut signals: Vec<_> = reload_signals
+        .into_iter()
+        .map(|kind| signal(kind).expect("signal"))
+        .collect();
+
+    tokio::spawn(async move {
+        loop {
+            let (received, ..) = future::select_all(signals.iter_mut().map(|s| s.recv().boxed())).await;
+            if received.is_none() {
+                break;
+            }
+
+            reload_servers(&config_path, &balancer, &acl_contexts).await;
+        }
+    });
+}
+
+/// Windows doesn't have SIGUSR1, reload is triggered by connecting to the named pipe
+/// `\\.\pipe\shadowsocks-rust-sslocal-{PID}`, which replies `ok` or `failed` after reloading.
+#[cfg(windows)]
+fn launch_reload_server_task(config_path: PathBuf, balancer: PingBalancer, acl_contexts: Vec<(Option<usize>, Arc<ServiceContext>)>) {
     use log::error;
-    use tokio::signal::unix::{signal, SignalKind};
+    use tokio::{io::AsyncWriteExt, net::windows::named_pipe::ServerOptions};
+
+    let pipe_name = format!(r"\\.\pipe\shadowsocks-rust-sslocal-{}", std::process::id());
 
     tokio::spawn(async move {
-        let mut sigusr1 = signal(SignalKind::user_defined1()).expect("signal");
+        let mut server = match ServerOptions::new().first_pipe_instance(true).create(&pipe_name) {
+            Ok(s) => s,
+            Err(err) => {
+                error!("failed to create reload named pipe {}, error: {}", pipe_name, err);
+                return;
+            }
+        };
 
-        while sigusr1.recv().await.is_some() {
-            let config = match Config::load_from_file(&config_path, ConfigType::Local) {
-                Ok(c) => c,
+        info!("auto-reload is listening on named pipe {}", pipe_name);
+
+        loop {
+            let connected = server.connect().await;
+
+            // Create the next instance before handling this client, so clients won't see the pipe missing
+            let mut client = server;
+            server = match ServerOptions::new().create(&pipe_name) {
+                Ok(s) => s,
                 Err(err) => {
-                    error!("auto-reload {} failed with error: {}", config_path.display(), err);
-                    continue;
+                    error!("failed to create reload named pipe {}, error: {}", pipe_name, err);
+                    return;
                 }
             };
 
-            let servers: Vec<ServerConfig> = config.server.into_iter().map(|s| s.config).collect();
-            info!("auto-reload {} with {} servers", config_path.display(), servers.len());
-
-            if let Err(err) = balancer.reset_servers(servers).await {
-                error!("auto-reload {} but found error: {}", config_path.display(), err);
+            if let Err(err) = connected {
+                error!("reload named pipe {} connect failed with error: {}", pipe_name, err);
+                continue;
             }
+
+            let reply: &[u8] = if reload_servers(&config_path, &balancer, &acl_contexts).await {
+                b"ok\n"
+            } else {
+                b"failed\n"
+            };
+            let _ = client.write_all(reply).await;
         }
     });
 }
 
-#[cfg(not(unix))]
-fn launch_reload_server_task(_: PathBuf, _: PingBalancer) {}
+#[cfg(not(any(unix, windows)))]
+fn launch_reload_server_task(_: PathBuf, _: PingBalancer, _: Vec<Arc<ServiceContext>>) {}
+
+/// Reload servers and ACLs when `config_path` is modified, rapid successive writes within 500ms are reloaded once
+///
+/// The parent directory is watched, so editors that save by renaming a new file over `config_path` are also caught.
+#[cfg(feature = "watch-config")]
+fn launch_watch_config_task(config_path: PathBuf, balancer: PingBalancer, acl_contexts: Vec<(Option<usize>, Arc<ServiceContext>)>) {
+    use log::error;
+    use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Result as NotifyResult, Watcher};
+    use tokio::{sync::mpsc, time};
+
+    const DEBOUNCE_DURATION: Duration = Duration::from_millis(500);
+
+    let config_path = match std::fs::canonicalize(&config_path) {
+        Ok(p) => p,
+        Err(err) => {
+            error!("failed to watch {}, error: {}", config_path.display(), err);
+            return;
+        }
+    };
+    let (watch_dir, file_name) = match (config_path.parent(), config_path.file_name()) {
+        (Some(d), Some(f)) => (d.to_path_buf(), f.to_os_string()),
+        _ => return,
+    };
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<()>();
+
+    let mut watcher: RecommendedWatcher =
+        match notify::recommended_watcher(move |ev_result: NotifyResult<Event>| match ev_result {
+            Ok(ev) => {
+                if matches!(ev.kind, EventKind::Modify(..) | EventKind::Create(..))
+                    && ev.paths.iter().any(|p| p.file_name() == Some(file_name.as_os_str()))
+                {
+                    let _ = tx.send(());
+                }
+            }
+            Err(err) => {
+                error!("watching config error: {}", err);
+            }
+        }) {
+            Ok(w) => w,
+            Err(err) => {
+                error!("failed to watch {}, error: {}", config_path.display(), err);
+                return;
+            }
+        };
+
+    if let Err(err) = watcher.watch(&watch_dir, RecursiveMode::NonRecursive) {
+        error!("failed to watch {}, error: {}", watch_dir.display(), err);
+        return;
+    }
+
+    info!("auto-reload is watching {}", config_path.display());
+
+    tokio::spawn(async move {
+        // Keep watching until the task is dropped
+        let _watcher = watcher;
+
+        while rx.recv().await.is_some() {
+            // Wait until no more writes in DEBOUNCE_DURATION
+            loop {
+                match time::timeout(DEBOUNCE_DURATION, rx.recv()).await {
+                    Ok(Some(..)) => continue,
+                    Ok(None) => return,
+                    Err(..) => break,
+                }
+            }
+
+            reload_servers(&config_path, &balancer, &acl_contexts).await;
+        }
+    });
+}
 
 #[cfg(test)]
 mod test {
@@ -961,4 +1232,202 @@ mod test {
         app = super::define_command_line_options(app);
         app.debug_assert();
     }
+
+    #[test]
+    fn create_with_bad_server_address() {
+        use std::fs;
+
+        use crate::error::StartupError;
+
+        let config_path = std::env::temp_dir().join(format!("ss-bad-server-{}.json", std::process::id()));
+        fs::write(
+            &config_path,
+            r#"{
+                "local_address": "127.0.0.1",
+                "local_port": 0,
+                "server": "127.0.0.1",
+                "server_port": 0,
+                "password": "password",
+                "method": "aes-256-gcm"
+            }"#,
+        )
+        .unwrap();
+
+        let matches = super::define_command_line_options(Command::new("sslocal"))
+            .try_get_matches_from(["sslocal", "-c", config_path.to_str().unwrap()])
+            .unwrap();
+
+        // Startup failures are returned to the caller instead of exiting the process
+        let result = super::create(&matches);
+        let _ = fs::remove_file(&config_path);
+
+        match result {
+            Err(err @ StartupError::IntegrityCheck(..)) => {
+                assert_eq!(err.exit_code(), crate::EXIT_CODE_LOAD_CONFIG_FAILURE);
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(..) => panic!("config with a bad server address is loaded"),
+        }
+    }
+
+    #[tokio::test]
+    async fn load_large_config_async() {
+        use std::{
+            fs,
+            sync::{
+                atomic::{AtomicUsize, Ordering},
+                Arc,
+            },
+        };
+
+        use shadowsocks_service::config::{Config, ConfigType};
+
+        let dir = std::env::temp_dir().join(format!("ss-large-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json");
+
+        // Long remarks make it a multi-megabyte file
+        let remarks = "x".repeat(32 * 1024);
+        let servers = (0..64)
+            .map(|i| {
+                format!(
+                    r#"{{ "server": "127.0.0.1", "server_port": {}, "password": "password", "method": "aes-256-gcm", "remarks": "{}" }}"#,
+                    i + 1024,
+                    remarks
+                )
+            })
+            .collect::<Vec<_>>();
+        let content = format!(r#"{{ "servers": [{}] }}"#, servers.join(",\n"));
+        assert!(content.len() > 2 * 1024 * 1024);
+        fs::write(&config_path, content).unwrap();
+
+        // Other tasks are still running while the config is being loaded
+        let polled = Arc::new(AtomicUsize::new(0));
+        let ticker = {
+            let polled = polled.clone();
+            tokio::spawn(async move {
+                loop {
+                    polled.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        let config = Config::load_from_file_async(&config_path, ConfigType::Local)
+            .await
+            .unwrap();
+        assert_eq!(config.server.len(), 64);
+        assert!(polled.load(Ordering::Relaxed) > 0);
+
+        ticker.abort();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "watch-config")]
+    #[tokio::test]
+    async fn watch_config_reload_servers() {
+        use std::{fs, sync::Arc, time::Duration};
+
+        use shadowsocks_service::{
+            local::{context::ServiceContext, loadbalancing::PingBalancerBuilder},
+            shadowsocks::{
+                config::{Mode, ServerConfig},
+                crypto::CipherKind,
+            },
+        };
+        use tokio::time::{self, Instant};
+
+        let dir = std::env::temp_dir().join(format!("ss-watch-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json");
+
+        let server = |port: u16| {
+            format!(r#"{{ "server": "127.0.0.1", "server_port": {port}, "password": "p", "method": "aes-128-gcm" }}"#)
+        };
+        fs::write(&config_path, format!(r#"{{ "servers": [{}] }}"#, server(8001))).unwrap();
+
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8001".parse::<std::net::SocketAddr>().unwrap(),
+            "p",
+            CipherKind::AES_128_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        super::launch_watch_config_task(config_path.clone(), balancer.clone(), Vec::new());
+
+        fs::write(
+            &config_path,
+            format!(r#"{{ "servers": [{}, {}] }}"#, server(8001), server(8002)),
+        )
+        .unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(1500);
+        while balancer.servers().count() != 2 && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(balancer.servers().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reload_servers_on_sighup() {
+        use std::{fs, sync::Arc, time::Duration};
+
+        use shadowsocks_service::{
+            local::{context::ServiceContext, loadbalancing::PingBalancerBuilder},
+            shadowsocks::{
+                config::{Mode, ServerConfig},
+                crypto::CipherKind,
+            },
+        };
+        use tokio::{
+            signal::unix::SignalKind,
+            time::{self, Instant},
+        };
+
+        let dir = std::env::temp_dir().join(format!("ss-reload-signal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json");
+
+        let server = |port: u16| {
+            format!(r#"{{ "server": "127.0.0.1", "server_port": {port}, "password": "p", "method": "aes-128-gcm" }}"#)
+        };
+        fs::write(&config_path, format!(r#"{{ "servers": [{}] }}"#, server(8001))).unwrap();
+
+        let mut builder = PingBalancerBuilder::new(Arc::new(ServiceContext::new()), Mode::TcpOnly);
+        builder.add_server(ServerConfig::new(
+            "127.0.0.1:8001".parse::<std::net::SocketAddr>().unwrap(),
+            "p",
+            CipherKind::AES_128_GCM,
+        ));
+        let balancer = builder.build().await.unwrap();
+
+        // Handler is installed here, so SIGHUP won't terminate the test process
+        super::launch_reload_server_task(
+            config_path.clone(),
+            balancer.clone(),
+            Vec::new(),
+            vec![SignalKind::hangup()],
+        );
+
+        fs::write(
+            &config_path,
+            format!(r#"{{ "servers": [{}, {}] }}"#, server(8001), server(8002)),
+        )
+        .unwrap();
+        unsafe {
+            libc::raise(libc::SIGHUP);
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(1500);
+        while balancer.servers().count() != 2 && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(balancer.servers().count(), 2);
+    }
 }
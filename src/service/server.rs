@@ -12,7 +12,14 @@ use tokio::{
 
 use shadowsocks_service::{
     acl::AccessControl,
-    config::{read_variable_field_value, Config, ConfigType, ManagerConfig, ServerInstanceConfig},
+    config::{
+        clamp_socket_buffer_size,
+        read_variable_field_value,
+        Config,
+        ConfigType,
+        ManagerConfig,
+        ServerInstanceConfig,
+    },
     run_server,
     shadowsocks::{
         config::{ManagerAddr, Mode, ServerAddr, ServerConfig},
@@ -25,6 +32,7 @@ use shadowsocks_service::{
 use crate::logging;
 use crate::{
     config::{Config as ServiceConfig, RuntimeMode},
+    error::StartupError,
     monitor,
     vparser,
 };
@@ -141,10 +149,10 @@ pub fn define_command_line_options(mut app: Command) -> Command {
         .arg(Arg::new("TCP_MULTIPATH").long("tcp-multipath").alias("mptcp").action(ArgAction::SetTrue).help("Enable Multipath-TCP (MPTCP)"))
         .arg(Arg::new("UDP_TIMEOUT").long("udp-timeout").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u64)).help("Timeout seconds for UDP relay"))
         .arg(Arg::new("UDP_MAX_ASSOCIATIONS").long("udp-max-associations").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(usize)).help("Maximum associations to be kept simultaneously for UDP relay"))
-        .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_SNDBUF option"))
-        .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_RCVBUF option"))
-        .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_SNDBUF option"))
-        .arg(Arg::new("OUTBOUND_RECV_BUFFER_SIZE").long("outbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_RCVBUF option"))
+        .arg(Arg::new("INBOUND_SEND_BUFFER_SIZE").long("inbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_SNDBUF option, clamped to 4KiB ~ 64MiB"))
+        .arg(Arg::new("INBOUND_RECV_BUFFER_SIZE").long("inbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set inbound sockets' SO_RCVBUF option, clamped to 4KiB ~ 64MiB"))
+        .arg(Arg::new("OUTBOUND_SEND_BUFFER_SIZE").long("outbound-send-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_SNDBUF option, clamped to 4KiB ~ 64MiB"))
+        .arg(Arg::new("OUTBOUND_RECV_BUFFER_SIZE").long("outbound-recv-buffer-size").num_args(1).action(ArgAction::Set).value_parser(clap::value_parser!(u32)).help("Set outbound sockets' SO_RCVBUF option, clamped to 4KiB ~ 64MiB"))
         .arg(
             Arg::new("IPV6_FIRST")
                 .short('6')
@@ -272,7 +280,7 @@ pub fn define_command_line_options(mut app: Command) -> Command {
 }
 
 /// Create `Runtime` and `main` entry
-pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = ExitCode>), ExitCode> {
+pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = ExitCode>), StartupError> {
     let (config, runtime) = {
         let config_path_opt = matches.get_one::<PathBuf>("CONFIG").cloned().or_else(|| {
             if !matches.contains_id("SERVER_CONFIG") {
@@ -292,8 +300,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             Some(ref config_path) => match ServiceConfig::load_from_file(config_path) {
                 Ok(c) => c,
                 Err(err) => {
-                    eprintln!("loading config {config_path:?}, {err}");
-                    return Err(crate::EXIT_CODE_LOAD_CONFIG_FAILURE.into());
+                    return Err(StartupError::LoadServiceConfig {
+                        path: config_path.clone(),
+                        error: err,
+                    });
                 }
             },
             None => ServiceConfig::default(),
@@ -316,8 +326,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             Some(cpath) => match Config::load_from_file(&cpath, ConfigType::Server) {
                 Ok(cfg) => cfg,
                 Err(err) => {
-                    eprintln!("loading config {cpath:?}, {err}");
-                    return Err(crate::EXIT_CODE_LOAD_CONFIG_FAILURE.into());
+                    return Err(StartupError::LoadConfig {
+                        path: cpath,
+                        error: err,
+                    })
                 }
             },
             None => Config::new(ConfigType::Server),
@@ -345,6 +357,12 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
                 }
             };
 
+            if let Err(err) = ServerConfig::check_password(method, &password) {
+                return Err(StartupError::InvalidConfig(format!(
+                    "invalid `password` for server {svr_addr}, {err}"
+                )));
+            }
+
             let svr_addr = svr_addr.parse::<ServerAddr>().expect("server-addr");
             let timeout = matches.get_one::<u64>("TIMEOUT").map(|x| Duration::from_secs(*x));
 
@@ -430,8 +448,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
             let acl = match AccessControl::load_from_file(acl_file) {
                 Ok(acl) => acl,
                 Err(err) => {
-                    eprintln!("loading ACL \"{acl_file}\", {err}");
-                    return Err(crate::EXIT_CODE_LOAD_ACL_FAILURE.into());
+                    return Err(StartupError::LoadAcl {
+                        path: acl_file.clone(),
+                        error: err,
+                    });
                 }
             };
             config.acl = Some(acl);
@@ -458,16 +478,16 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         }
 
         if let Some(bs) = matches.get_one::<u32>("INBOUND_SEND_BUFFER_SIZE") {
-            config.inbound_send_buffer_size = Some(*bs);
+            config.inbound_send_buffer_size = Some(clamp_socket_buffer_size("inbound-send-buffer-size", *bs));
         }
         if let Some(bs) = matches.get_one::<u32>("INBOUND_RECV_BUFFER_SIZE") {
-            config.inbound_recv_buffer_size = Some(*bs);
+            config.inbound_recv_buffer_size = Some(clamp_socket_buffer_size("inbound-recv-buffer-size", *bs));
         }
         if let Some(bs) = matches.get_one::<u32>("OUTBOUND_SEND_BUFFER_SIZE") {
-            config.outbound_send_buffer_size = Some(*bs);
+            config.outbound_send_buffer_size = Some(clamp_socket_buffer_size("outbound-send-buffer-size", *bs));
         }
         if let Some(bs) = matches.get_one::<u32>("OUTBOUND_RECV_BUFFER_SIZE") {
-            config.outbound_recv_buffer_size = Some(*bs);
+            config.outbound_recv_buffer_size = Some(clamp_socket_buffer_size("outbound-recv-buffer-size", *bs));
         }
 
         if let Some(bind_addr) = matches.get_one::<IpAddr>("OUTBOUND_BIND_ADDR") {
@@ -477,17 +497,16 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         // DONE READING options
 
         if config.server.is_empty() {
-            eprintln!(
+            return Err(StartupError::InsufficientParams(
                 "missing proxy servers, consider specifying it by \
                     --server-addr, --encrypt-method, --password command line option, \
                         or configuration file, check more details in https://shadowsocks.org/guide/configs.html"
-            );
-            return Err(crate::EXIT_CODE_INSUFFICIENT_PARAMS.into());
+                    .to_owned(),
+            ));
         }
 
         if let Err(err) = config.check_integrity() {
-            eprintln!("config integrity check failed, {err}");
-            return Err(crate::EXIT_CODE_LOAD_CONFIG_FAILURE.into());
+            return Err(StartupError::IntegrityCheck(err));
         }
 
         #[cfg(unix)]
@@ -499,8 +518,7 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         #[cfg(unix)]
         if let Some(uname) = matches.get_one::<String>("USER") {
             if let Err(err) = crate::sys::run_as_user(uname) {
-                eprintln!("failed to change as user, error: {err}");
-                return Err(crate::EXIT_CODE_INSUFFICIENT_PARAMS.into());
+                return Err(StartupError::RunAsUser(err));
             }
         }
 
@@ -524,12 +542,19 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
         };
         config.worker_count = worker_count;
 
+        service_config.runtime.configure_threads(&mut builder);
+
         let runtime = builder.enable_all().build().expect("create tokio Runtime");
 
         (config, runtime)
     };
 
     let main_fut = async move {
+        #[cfg(all(unix, feature = "logging"))]
+        if let Some(ref config_path) = config.config_path {
+            logging::launch_reload_level_task(config_path.clone());
+        }
+
         let abort_signal = monitor::create_signal_monitor();
         let server = run_server(config);
 
@@ -560,7 +585,10 @@ pub fn create(matches: &ArgMatches) -> Result<(Runtime, impl Future<Output = Exi
 pub fn main(matches: &ArgMatches) -> ExitCode {
     match create(matches) {
         Ok((runtime, main_fut)) => runtime.block_on(main_fut),
-        Err(code) => code,
+        Err(err) => {
+            eprintln!("{err}");
+            err.exit_code().into()
+        }
     }
 }
 
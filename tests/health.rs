@@ -0,0 +1,81 @@
+#![cfg(feature = "local-health-api")]
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType, ProtocolType},
+    local::Server,
+};
+
+#[tokio::test]
+async fn health_api_status() {
+    let _ = env_logger::try_init();
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "locals": [
+                {
+                    "local_address": "127.0.0.1",
+                    "local_port": 0
+                }
+            ],
+            "health_addr": "127.0.0.1:0",
+            "server": "127.0.0.1",
+            "server_port": 8388,
+            "password": "password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server = Server::new(local_config).await.unwrap();
+    let health_addr = server.health_api().unwrap().local_addr().unwrap();
+    let listen_addrs = server.listen_addrs().unwrap();
+    assert_eq!(listen_addrs.len(), 1);
+    assert_eq!(listen_addrs[0].protocol, ProtocolType::Socks);
+    assert!(!listen_addrs[0].udp);
+    // local_port 0 is assigned by the OS
+    let socks_addr = listen_addrs[0].addr;
+    assert_ne!(socks_addr.port(), 0);
+    let server_task = tokio::spawn(server.run());
+
+    let mut stream = TcpStream::connect(health_addr).await.unwrap();
+    stream
+        .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: application/json\r\n"), "{}", response);
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(body.contains(r#""uptime":"#), "{}", body);
+    assert!(body.contains(r#""active_connections":"#), "{}", body);
+    assert!(body.contains(r#""server":"127.0.0.1:8388""#), "{}", body);
+    let listener = format!(r#""listeners":[{{"protocol":"socks","network":"tcp","addr":"{socks_addr}"}}]"#);
+    assert!(body.contains(&listener), "{}", body);
+    TcpStream::connect(socks_addr).await.unwrap();
+
+    // Endpoint is closed with the server
+    server_task.abort();
+    let _ = server_task.await;
+
+    let mut closed = false;
+    for _ in 0..50 {
+        if TcpStream::connect(health_addr).await.is_err() {
+            closed = true;
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(closed);
+}
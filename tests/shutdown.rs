@@ -0,0 +1,119 @@
+#![cfg(all(feature = "local-tunnel", feature = "server"))]
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+    time::{self, Duration},
+};
+
+use shadowsocks_service::{
+    config::{Config, ConfigType},
+    local::Server,
+    run_server,
+};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const CHUNK_COUNT: usize = 64;
+
+fn random_local_tcp_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+#[tokio::test]
+async fn shutdown_drains_in_flight_transfer() {
+    let _ = env_logger::try_init();
+
+    // Target sends CHUNK_COUNT chunks slowly, so the transfer is still running when stopped
+    let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, ..) = target.accept().await.unwrap();
+        let chunk = vec![0x5au8; CHUNK_SIZE];
+        for _ in 0..CHUNK_COUNT {
+            stream.write_all(&chunk).await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        stream.shutdown().await.unwrap();
+    });
+
+    let local_port = random_local_tcp_port();
+    let server_port = random_local_tcp_port();
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+            "locals": [
+                {{
+                    "local_port": {local_port},
+                    "local_address": "127.0.0.1",
+                    "protocol": "tunnel",
+                    "forward_address": "127.0.0.1",
+                    "forward_port": {target_port}
+                }}
+            ],
+            "server": "127.0.0.1",
+            "server_port": {server_port},
+            "password": "password",
+            "method": "aes-256-gcm"
+        }}"#
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+            "server": "127.0.0.1",
+            "server_port": {server_port},
+            "password": "password",
+            "method": "aes-256-gcm"
+        }}"#
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_server(server_config));
+    time::sleep(Duration::from_millis(500)).await;
+
+    let local = Server::new(local_config).await.unwrap();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let local = tokio::spawn(local.run_until_shutdown(stop_rx, Some(Duration::from_secs(10))));
+
+    let mut stream = TcpStream::connect(("127.0.0.1", local_port)).await.unwrap();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut received = stream.read(&mut buffer).await.unwrap();
+    assert!(received > 0);
+
+    // Stop while the transfer is in progress
+    stop_tx.send(()).unwrap();
+
+    // The listener is closed soon
+    let mut closed = false;
+    for _ in 0..20 {
+        if TcpStream::connect(("127.0.0.1", local_port)).await.is_err() {
+            closed = true;
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(closed, "listener is still accepting after stopped");
+    assert!(!local.is_finished());
+
+    // But the in-flight transfer still completes
+    loop {
+        let n = stream.read(&mut buffer).await.unwrap();
+        if n == 0 {
+            break;
+        }
+        assert!(buffer[..n].iter().all(|b| *b == 0x5a));
+        received += n;
+    }
+    assert_eq!(received, CHUNK_SIZE * CHUNK_COUNT);
+    drop(stream);
+
+    let stopped = time::timeout(Duration::from_secs(5), local).await.unwrap().unwrap();
+    assert!(stopped.unwrap());
+}
@@ -7,12 +7,12 @@ use std::{
 
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    time::{self, Duration},
+    time::{self, Duration, Instant},
 };
 
 use shadowsocks_service::{
     config::{Config, ConfigType, LocalConfig, LocalInstanceConfig, ProtocolType, ServerInstanceConfig},
-    local::socks::client::socks5::Socks5TcpClient,
+    local::{socks::client::socks5::Socks5TcpClient, Server},
     run_local,
     run_server,
     shadowsocks::{
@@ -149,3 +149,143 @@ async fn socks5_relay_aead() {
     let http_status = b"HTTP/1.0 200 OK\r\n";
     assert!(buf.starts_with(http_status));
 }
+
+#[tokio::test]
+async fn socks5_local_port_zero() {
+    let _ = env_logger::try_init();
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "local_address": "127.0.0.1",
+            "local_port": 0,
+            "server": "127.0.0.1",
+            "server_port": 8120,
+            "password": "test-password",
+            "method": "aes-256-gcm",
+            "mode": "tcp_and_udp"
+        }"#,
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server = Server::new(local_config).await.unwrap();
+
+    let socks = &server.socks_servers()[0];
+    let tcp_addr = socks.tcp_server().unwrap().local_addr().unwrap();
+    let udp_addr = socks.udp_server().unwrap().local_addr().unwrap();
+    assert_ne!(tcp_addr.port(), 0);
+    assert_ne!(udp_addr.port(), 0);
+
+    tokio::spawn(server.run());
+
+    let (_c, associated) = Socks5TcpClient::udp_associate(Address::SocketAddress(udp_addr), tcp_addr)
+        .await
+        .unwrap();
+    assert_eq!(associated, Address::SocketAddress(udp_addr));
+}
+
+#[tokio::test]
+async fn socks5_greeting_probe_resistance() {
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    let _ = env_logger::try_init();
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "locals": [
+                {
+                    "local_address": "127.0.0.1",
+                    "local_port": 0,
+                    "socks_greeting_timeout": 1
+                }
+            ],
+            "server": "127.0.0.1",
+            "server_port": 8130,
+            "password": "test-password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server = Server::new(local_config).await.unwrap();
+    let tcp_addr = server.socks_servers()[0].tcp_server().unwrap().local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let probes: [&[u8]; 4] = [
+        // TLS ClientHello
+        b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03",
+        // HTTP request
+        b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+        // SOCKS5 greeting without any acceptable method
+        b"\x05\x01\x80",
+        // Nothing, waits for greeting timeout
+        b"",
+    ];
+
+    for probe in probes {
+        let start = Instant::now();
+        let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+        stream.write_all(probe).await.unwrap();
+
+        let mut buf = Vec::new();
+        let n = time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+            .await
+            .expect("connection should be closed by local")
+            .unwrap_or(0);
+        assert_eq!(n, 0, "unexpected response {:?} for probe {:?}", buf, probe);
+
+        // Closed at the greeting deadline, the same as silent clients
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(900) && elapsed < Duration::from_secs(2),
+            "probe {:?} closed after {:?}",
+            probe,
+            elapsed
+        );
+    }
+}
+
+#[cfg(feature = "local-http")]
+#[tokio::test]
+async fn socks5_greeting_allow_http() {
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+
+    let _ = env_logger::try_init();
+
+    let local_config = Config::load_from_str(
+        r#"{
+            "locals": [
+                {
+                    "local_address": "127.0.0.1",
+                    "local_port": 0,
+                    "socks_greeting_timeout": 1,
+                    "socks_greeting_allow_http": true
+                }
+            ],
+            "server": "127.0.0.1",
+            "server_port": 8130,
+            "password": "test-password",
+            "method": "aes-256-gcm"
+        }"#,
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server = Server::new(local_config).await.unwrap();
+    let tcp_addr = server.socks_servers()[0].tcp_server().unwrap().local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let mut stream = TcpStream::connect(tcp_addr).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; 8];
+    time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+        .await
+        .expect("HTTP request should be answered")
+        .unwrap();
+    assert_eq!(&buf, b"HTTP/1.1");
+}
@@ -4,15 +4,18 @@ use byte_string::ByteStr;
 use log::debug;
 use tokio::{
     self,
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{TcpStream, UdpSocket},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
     time::{self, Duration},
 };
 
 use shadowsocks_service::{
     config::{Config, ConfigType},
+    local::{loadbalancing::PingBalancerBuilder, tunnel::TunnelBuilder},
     run_local,
     run_server,
+    server::ServerBuilder,
+    shadowsocks::{config::Mode, crypto::CipherKind, relay::socks5::Address, ServerAddr, ServerConfig},
 };
 
 fn random_local_tcp_port() -> u16 {
@@ -82,6 +85,282 @@ async fn tcp_tunnel() {
     assert!(buf.starts_with(http_status));
 }
 
+#[tokio::test]
+async fn tcp_tunnel_half_close() {
+    let _ = env_logger::try_init();
+
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_port = target_listener.local_addr().unwrap().port();
+
+    let local_port = random_local_tcp_port();
+    let server_port = random_local_tcp_port();
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+            "locals": [
+                {{
+                    "local_port": {local_port},
+                    "local_address": "127.0.0.1",
+                    "protocol": "tunnel",
+                    "forward_address": "127.0.0.1",
+                    "forward_port": {target_port}
+                }}
+            ],
+            "server": "127.0.0.1",
+            "server_port": {server_port},
+            "password": "password",
+            "method": "aes-256-gcm"
+        }}"#
+        ),
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+            "server": "127.0.0.1",
+            "server_port": {server_port},
+            "password": "password",
+            "method": "aes-256-gcm"
+        }}"#
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+    tokio::spawn(run_server(server_config));
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    // Client half-closes after sending the request, then drains the response
+    for request in [&b"request"[..], &b""[..]] {
+        let mut client = TcpStream::connect(("127.0.0.1", local_port)).await.unwrap();
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let mut buf = Vec::new();
+        target.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, request);
+        target.write_all(b"response").await.unwrap();
+        drop(target);
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"response");
+    }
+
+    // Target half-closes after sending the greeting, then drains the client
+    {
+        let mut client = TcpStream::connect(("127.0.0.1", local_port)).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        target.write_all(b"greeting").await.unwrap();
+        target.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"greeting");
+        client.write_all(b"bye").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut buf = Vec::new();
+        target.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"hellobye");
+    }
+}
+
+#[tokio::test]
+async fn tcp_tunnel_multiple_forward_addrs() {
+    let _ = env_logger::try_init();
+
+    let target_listeners = [
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        TcpListener::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let forward_addrs = target_listeners
+        .iter()
+        .map(|l| Address::from(l.local_addr().unwrap()))
+        .collect::<Vec<_>>();
+
+    let server_port = random_local_tcp_port();
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+            "server": "127.0.0.1",
+            "server_port": {server_port},
+            "password": "password",
+            "method": "aes-256-gcm"
+        }}"#
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+    tokio::spawn(run_server(server_config));
+
+    let mut balancer_builder = PingBalancerBuilder::new(Default::default(), Mode::TcpOnly);
+    balancer_builder.add_server(ServerConfig::new(
+        std::net::SocketAddr::from(([127, 0, 0, 1], server_port)),
+        "password",
+        CipherKind::AES_256_GCM,
+    ));
+    let balancer = balancer_builder.build().await.unwrap();
+
+    let client_addr = ServerAddr::from(std::net::SocketAddr::from(([127, 0, 0, 1], 0)));
+    let tunnel = TunnelBuilder::new(forward_addrs, client_addr, balancer)
+        .build()
+        .await
+        .unwrap();
+    let tunnel_addr = tunnel.tcp_server().unwrap().local_addr().unwrap();
+    tokio::spawn(tunnel.run());
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    // Connections alternate between the two targets
+    for round in 0..2 {
+        for target_listener in &target_listeners {
+            let mut client = TcpStream::connect(tunnel_addr).await.unwrap();
+            let request = format!("request-{round}");
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let (mut target, _) = target_listener.accept().await.unwrap();
+            let mut buf = vec![0u8; request.len()];
+            target.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, request.as_bytes());
+        }
+    }
+}
+
+#[tokio::test]
+async fn tcp_tunnel_set_forward_addr() {
+    let _ = env_logger::try_init();
+
+    let old_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let new_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let server_port = random_local_tcp_port();
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+            "server": "127.0.0.1",
+            "server_port": {server_port},
+            "password": "password",
+            "method": "aes-256-gcm"
+        }}"#
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+    tokio::spawn(run_server(server_config));
+
+    let mut balancer_builder = PingBalancerBuilder::new(Default::default(), Mode::TcpOnly);
+    balancer_builder.add_server(ServerConfig::new(
+        std::net::SocketAddr::from(([127, 0, 0, 1], server_port)),
+        "password",
+        CipherKind::AES_256_GCM,
+    ));
+    let balancer = balancer_builder.build().await.unwrap();
+
+    let client_addr = ServerAddr::from(std::net::SocketAddr::from(([127, 0, 0, 1], 0)));
+    let tunnel = TunnelBuilder::new(
+        vec![Address::from(old_listener.local_addr().unwrap())],
+        client_addr,
+        balancer,
+    )
+    .build()
+    .await
+    .unwrap();
+    let tunnel_addr = tunnel.tcp_server().unwrap().local_addr().unwrap();
+    let handle = tunnel.handle();
+    tokio::spawn(tunnel.run());
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut old_client = TcpStream::connect(tunnel_addr).await.unwrap();
+    old_client.write_all(b"old").await.unwrap();
+    let (mut old_target, _) = old_listener.accept().await.unwrap();
+    let mut buf = [0u8; 3];
+    old_target.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"old");
+
+    handle.set_forward_addr(Address::from(new_listener.local_addr().unwrap()));
+
+    // New connections go to the new target
+    let mut new_client = TcpStream::connect(tunnel_addr).await.unwrap();
+    new_client.write_all(b"new").await.unwrap();
+    let (mut new_target, _) = new_listener.accept().await.unwrap();
+    new_target.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"new");
+
+    // The established connection still reaches the old target
+    old_client.write_all(b"old").await.unwrap();
+    old_target.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"old");
+}
+
+#[tokio::test]
+async fn tcp_tunnel_pinned_server() {
+    let _ = env_logger::try_init();
+
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let mut server_addrs = Vec::new();
+    let mut server_flow_stats = Vec::new();
+    for _ in 0..2 {
+        let server_port = random_local_tcp_port();
+        let svr_cfg = ServerConfig::new(
+            std::net::SocketAddr::from(([127, 0, 0, 1], server_port)),
+            "password",
+            CipherKind::AES_256_GCM,
+        );
+        server_addrs.push(svr_cfg.addr().clone());
+
+        let builder = ServerBuilder::new(svr_cfg);
+        server_flow_stats.push(builder.flow_stat());
+        tokio::spawn(builder.build().await.unwrap().run());
+    }
+
+    let mut balancer_builder = PingBalancerBuilder::new(Default::default(), Mode::TcpOnly);
+    balancer_builder.max_server_rtt(Duration::from_millis(500));
+    for addr in &server_addrs {
+        balancer_builder.add_server(ServerConfig::new(addr.clone(), "password", CipherKind::AES_256_GCM));
+    }
+    let balancer = balancer_builder.build().await.unwrap();
+    balancer.pin_server(1).unwrap();
+
+    let client_addr = ServerAddr::from(std::net::SocketAddr::from(([127, 0, 0, 1], 0)));
+    let tunnel = TunnelBuilder::new(
+        vec![Address::from(target_listener.local_addr().unwrap())],
+        client_addr,
+        balancer,
+    )
+    .build()
+    .await
+    .unwrap();
+    let tunnel_addr = tunnel.tcp_server().unwrap().local_addr().unwrap();
+    tokio::spawn(tunnel.run());
+
+    // Servers have been checked while building the balancer
+    let flows_before = server_flow_stats.iter().map(|f| f.rx()).collect::<Vec<_>>();
+
+    for _ in 0..3 {
+        let mut client = TcpStream::connect(tunnel_addr).await.unwrap();
+        client.write_all(b"pinned").await.unwrap();
+
+        let (mut target, _) = target_listener.accept().await.unwrap();
+        let mut buf = [0u8; 6];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pinned");
+    }
+
+    // Only the pinned server relayed the connections
+    assert_eq!(server_flow_stats[0].rx(), flows_before[0]);
+    assert!(server_flow_stats[1].rx() > flows_before[1]);
+}
+
 #[tokio::test]
 async fn udp_tunnel() {
     let _ = env_logger::try_init();
@@ -159,3 +438,77 @@ async fn udp_tunnel() {
 
     assert_eq!(MESSAGE, recv_payload);
 }
+
+#[tokio::test]
+async fn udp_tunnel_association_stat() {
+    let _ = env_logger::try_init();
+
+    // A UDP echo server
+    let echo_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo_socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 65536];
+        loop {
+            let (n, peer_addr) = echo_socket.recv_from(&mut buffer).await.unwrap();
+            echo_socket.send_to(&buffer[..n], peer_addr).await.unwrap();
+        }
+    });
+
+    let server_port = random_local_tcp_port();
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+            "server": "127.0.0.1",
+            "server_port": {server_port},
+            "password": "password",
+            "method": "aes-256-gcm",
+            "mode": "udp_only"
+        }}"#
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+    tokio::spawn(run_server(server_config));
+
+    let mut balancer_builder = PingBalancerBuilder::new(Default::default(), Mode::UdpOnly);
+    balancer_builder.add_server(ServerConfig::new(
+        std::net::SocketAddr::from(([127, 0, 0, 1], server_port)),
+        "password",
+        CipherKind::AES_256_GCM,
+    ));
+    let balancer = balancer_builder.build().await.unwrap();
+
+    let client_addr = ServerAddr::from(std::net::SocketAddr::from(([127, 0, 0, 1], 0)));
+    let mut builder = TunnelBuilder::new(vec![Address::from(echo_addr)], client_addr, balancer);
+    builder.set_mode(Mode::UdpOnly);
+    builder.set_udp_capacity(8);
+    builder.set_udp_expiry_duration(Duration::from_secs(1));
+    let tunnel = builder.build().await.unwrap();
+
+    let udp_server = tunnel.udp_server().unwrap();
+    let tunnel_addr = udp_server.local_addr().unwrap();
+    let stat = udp_server.association_stat().clone();
+    assert_eq!(stat.active(), 0);
+    assert_eq!(stat.capacity(), Some(8));
+    tokio::spawn(tunnel.run());
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    const MESSAGE: &[u8] = b"hello shadowsocks";
+
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.send_to(MESSAGE, tunnel_addr).await.unwrap();
+
+        let mut buf = [0u8; 65536];
+        let n = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], MESSAGE);
+        clients.push(socket);
+    }
+    assert_eq!(stat.active(), 3);
+
+    // Idle associations are removed by the next cleanup after they are expired
+    time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(stat.active(), 0);
+}